@@ -0,0 +1,401 @@
+/// Formulas which turn band powers into valence, arousal or drowsiness, so different formulations can
+/// be compared in one session. One valence and one arousal metric drive the mandala, and one
+/// drowsiness metric the drowsiness view; every active metric is computed from the same band powers
+/// and logged side by side.
+///
+/// Band powers are log10 absolute power as sent by the Muse, indexed by EegBand::index() then
+/// electrode. The two original formulas exponentiate them with e rather than 10 and are kept as they
+/// were for comparison with earlier sessions.
+use crate::muse_model::{average_from_front_electrodes, EegBand};
+use crate::spectrum::BandPowers;
+use std::f32::consts::{E, LN_10};
+
+const AF7: usize = 1;
+const AF8: usize = 2;
+
+/// Names of the built in metrics, for configuration
+pub const BUILT_IN_METRICS: [&str; 7] = [
+    "asymmetry_over_theta",
+    "log_alpha_asymmetry",
+    "theta_over_alpha",
+    "beta_over_alpha",
+    "engagement",
+    "slow_wave_ratio",
+    "theta_alpha_power",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AffectDimension {
+    Valence,
+    Arousal,
+    Drowsiness,
+}
+
+pub trait AffectMetric {
+    /// Name in configuration and CSV headers
+    fn name(&self) -> &'static str;
+    fn dimension(&self) -> AffectDimension;
+    fn compute(&self, band_powers: &BandPowers) -> f32;
+}
+
+/// The original valence: e^(alpha AF8 - alpha AF7) over frontal e^theta
+pub struct AsymmetryOverTheta;
+
+impl AffectMetric for AsymmetryOverTheta {
+    fn name(&self) -> &'static str {
+        "asymmetry_over_theta"
+    }
+
+    fn dimension(&self) -> AffectDimension {
+        AffectDimension::Valence
+    }
+
+    fn compute(&self, band_powers: &BandPowers) -> f32 {
+        let alpha = band_powers[EegBand::Alpha.index()];
+        let theta = band_powers[EegBand::Theta.index()];
+
+        E.powf(alpha[AF8] - alpha[AF7]) / average_from_front_electrodes(&theta)
+    }
+}
+
+/// Frontal alpha asymmetry ln(alpha AF8) - ln(alpha AF7). Alpha is inversely related to activity,
+/// so higher values mean relatively more left frontal activity, associated with approach and positive mood.
+pub struct LogAlphaAsymmetry;
+
+impl AffectMetric for LogAlphaAsymmetry {
+    fn name(&self) -> &'static str {
+        "log_alpha_asymmetry"
+    }
+
+    fn dimension(&self) -> AffectDimension {
+        AffectDimension::Valence
+    }
+
+    fn compute(&self, band_powers: &BandPowers) -> f32 {
+        let alpha = band_powers[EegBand::Alpha.index()];
+
+        (alpha[AF8] - alpha[AF7]) * LN_10
+    }
+}
+
+/// The original arousal: frontal e^theta over e^alpha
+pub struct ThetaOverAlpha;
+
+impl AffectMetric for ThetaOverAlpha {
+    fn name(&self) -> &'static str {
+        "theta_over_alpha"
+    }
+
+    fn dimension(&self) -> AffectDimension {
+        AffectDimension::Arousal
+    }
+
+    fn compute(&self, band_powers: &BandPowers) -> f32 {
+        let alpha = band_powers[EegBand::Alpha.index()];
+        let theta = band_powers[EegBand::Theta.index()];
+        let frontal_apha = (E.powf(alpha[AF7]) + E.powf(alpha[AF8])) / 2.0;
+        let frontal_theta = (E.powf(theta[AF7]) + E.powf(theta[AF8])) / 2.0;
+
+        frontal_theta / (frontal_apha + 1e-6)
+    }
+}
+
+/// Frontal beta over alpha power
+pub struct BetaOverAlpha;
+
+impl AffectMetric for BetaOverAlpha {
+    fn name(&self) -> &'static str {
+        "beta_over_alpha"
+    }
+
+    fn dimension(&self) -> AffectDimension {
+        AffectDimension::Arousal
+    }
+
+    fn compute(&self, band_powers: &BandPowers) -> f32 {
+        frontal_power(band_powers, EegBand::Beta) / frontal_power(band_powers, EegBand::Alpha)
+    }
+}
+
+/// Engagement index, frontal beta / (alpha + theta) power (Pope, Bogart and Bartolome 1995)
+pub struct Engagement;
+
+impl AffectMetric for Engagement {
+    fn name(&self) -> &'static str {
+        "engagement"
+    }
+
+    fn dimension(&self) -> AffectDimension {
+        AffectDimension::Arousal
+    }
+
+    fn compute(&self, band_powers: &BandPowers) -> f32 {
+        frontal_power(band_powers, EegBand::Beta)
+            / (frontal_power(band_powers, EegBand::Alpha)
+                + frontal_power(band_powers, EegBand::Theta))
+    }
+}
+
+/// Slow wave ratio, (delta + theta) / (alpha + beta) power at all electrodes. Rises as slow waves
+/// replace waking rhythms in drowsiness.
+pub struct SlowWaveRatio;
+
+impl AffectMetric for SlowWaveRatio {
+    fn name(&self) -> &'static str {
+        "slow_wave_ratio"
+    }
+
+    fn dimension(&self) -> AffectDimension {
+        AffectDimension::Drowsiness
+    }
+
+    fn compute(&self, band_powers: &BandPowers) -> f32 {
+        (power(band_powers, EegBand::Delta) + power(band_powers, EegBand::Theta))
+            / (power(band_powers, EegBand::Alpha) + power(band_powers, EegBand::Beta))
+    }
+}
+
+/// Theta over alpha power at all electrodes. Theta grows and alpha fades as a resting participant
+/// becomes drowsy.
+pub struct ThetaAlphaPower;
+
+impl AffectMetric for ThetaAlphaPower {
+    fn name(&self) -> &'static str {
+        "theta_alpha_power"
+    }
+
+    fn dimension(&self) -> AffectDimension {
+        AffectDimension::Drowsiness
+    }
+
+    fn compute(&self, band_powers: &BandPowers) -> f32 {
+        power(band_powers, EegBand::Theta) / power(band_powers, EegBand::Alpha)
+    }
+}
+
+/// Mean power of AF7 and AF8 in one band
+fn frontal_power(band_powers: &BandPowers, band: EegBand) -> f32 {
+    let powers = band_powers[band.index()];
+
+    (10f32.powf(powers[AF7]) + 10f32.powf(powers[AF8])) / 2.0
+}
+
+/// Mean power of all electrodes in one band
+fn power(band_powers: &BandPowers, band: EegBand) -> f32 {
+    let powers = band_powers[band.index()];
+
+    powers.iter().map(|power| 10f32.powf(*power)).sum::<f32>() / powers.len() as f32
+}
+
+fn built_in(name: &str) -> Option<Box<dyn AffectMetric>> {
+    match name {
+        "asymmetry_over_theta" => Some(Box::new(AsymmetryOverTheta)),
+        "log_alpha_asymmetry" => Some(Box::new(LogAlphaAsymmetry)),
+        "theta_over_alpha" => Some(Box::new(ThetaOverAlpha)),
+        "beta_over_alpha" => Some(Box::new(BetaOverAlpha)),
+        "engagement" => Some(Box::new(Engagement)),
+        "slow_wave_ratio" => Some(Box::new(SlowWaveRatio)),
+        "theta_alpha_power" => Some(Box::new(ThetaAlphaPower)),
+        _ => None,
+    }
+}
+
+/// Which metrics a session uses
+#[derive(Clone, Debug, PartialEq)]
+pub struct AffectSettings {
+    valence: String,
+    arousal: String,
+    drowsiness: String,
+    logged: Vec<String>,
+}
+
+impl Default for AffectSettings {
+    /// The original formulas drive the mandala, and every built in metric is logged
+    fn default() -> Self {
+        AffectSettings {
+            valence: "asymmetry_over_theta".to_string(),
+            arousal: "theta_over_alpha".to_string(),
+            drowsiness: "slow_wave_ratio".to_string(),
+            logged: BUILT_IN_METRICS
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl AffectSettings {
+    /// `logged` metrics are computed and logged as well as the valence, arousal and drowsiness metrics
+    pub fn new(
+        valence: &str,
+        arousal: &str,
+        drowsiness: &str,
+        logged: &[String],
+    ) -> Result<Self, String> {
+        let check = |name: &str, dimension: Option<AffectDimension>| match built_in(name) {
+            Some(metric) if dimension.is_none() || dimension == Some(metric.dimension()) => Ok(()),
+            Some(metric) => Err(format!(
+                "'{}' is a {:?} metric, not {:?}",
+                name,
+                metric.dimension(),
+                dimension.unwrap()
+            )),
+            None => Err(format!(
+                "unknown affect metric '{}', expected one of {}",
+                name,
+                BUILT_IN_METRICS.join(", ")
+            )),
+        };
+        check(valence, Some(AffectDimension::Valence))?;
+        check(arousal, Some(AffectDimension::Arousal))?;
+        check(drowsiness, Some(AffectDimension::Drowsiness))?;
+        for name in logged {
+            check(name, None)?;
+        }
+
+        Ok(AffectSettings {
+            valence: valence.to_string(),
+            arousal: arousal.to_string(),
+            drowsiness: drowsiness.to_string(),
+            logged: logged.to_vec(),
+        })
+    }
+}
+
+/// The metrics active in a session for one headset
+pub struct AffectMetrics {
+    metrics: Vec<Box<dyn AffectMetric>>, // Valence metric first, then arousal, drowsiness and the others logged
+}
+
+impl AffectMetrics {
+    pub fn new(settings: &AffectSettings) -> Self {
+        let mut names: Vec<&str> = vec![&settings.valence, &settings.arousal, &settings.drowsiness];
+        for name in settings.logged.iter() {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+        let metrics = names
+            .iter()
+            .map(|name| built_in(name).expect("AffectSettings only holds built in names"))
+            .collect();
+
+        AffectMetrics { metrics }
+    }
+
+    /// Metric names in the order of compute()
+    pub fn names(&self) -> Vec<&'static str> {
+        self.metrics.iter().map(|metric| metric.name()).collect()
+    }
+
+    /// Every active metric
+    pub fn compute(&self, band_powers: &BandPowers) -> Vec<f32> {
+        self.metrics
+            .iter()
+            .map(|metric| metric.compute(band_powers))
+            .collect()
+    }
+
+    pub fn valence_name(&self) -> &'static str {
+        self.metrics[0].name()
+    }
+
+    pub fn arousal_name(&self) -> &'static str {
+        self.metrics[1].name()
+    }
+
+    pub fn drowsiness_name(&self) -> &'static str {
+        self.metrics[2].name()
+    }
+
+    /// The valence metric from the values of compute()
+    pub fn valence(&self, values: &[f32]) -> f32 {
+        values[0]
+    }
+
+    /// The arousal metric from the values of compute()
+    pub fn arousal(&self, values: &[f32]) -> f32 {
+        values[1]
+    }
+
+    /// The drowsiness metric from the values of compute()
+    pub fn drowsiness(&self, values: &[f32]) -> f32 {
+        values[2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The same power at every electrode except alpha, which is `alpha_af7` and `alpha_af8`
+    fn band_powers(alpha_af7: f32, alpha_af8: f32) -> BandPowers {
+        let mut band_powers = [[0.5; 4]; 5];
+        band_powers[EegBand::Alpha.index()] = [0.5, alpha_af7, alpha_af8, 0.5];
+
+        band_powers
+    }
+
+    #[test]
+    fn test_log_alpha_asymmetry() {
+        let value = LogAlphaAsymmetry.compute(&band_powers(1.0, 2.0));
+
+        assert!((value - 10f32.ln()).abs() < 1e-5);
+        assert_eq!(0.0, LogAlphaAsymmetry.compute(&band_powers(1.0, 1.0)));
+    }
+
+    #[test]
+    fn test_arousal_ratios() {
+        let band_powers = band_powers(1.0, 1.0);
+
+        assert!((BetaOverAlpha.compute(&band_powers) - 10f32.powf(-0.5)).abs() < 1e-5);
+        assert!(Engagement.compute(&band_powers) < BetaOverAlpha.compute(&band_powers));
+    }
+
+    #[test]
+    fn test_drowsiness_ratios() {
+        let mut band_powers = band_powers(1.0, 1.0);
+        band_powers[EegBand::Alpha.index()] = [0.0; 4];
+
+        let slow = 2.0 * 10f32.powf(0.5);
+        assert!((SlowWaveRatio.compute(&band_powers) - slow / (1.0 + slow / 2.0)).abs() < 1e-5);
+        assert!((ThetaAlphaPower.compute(&band_powers) - 10f32.powf(0.5)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_every_active_metric_computed() {
+        let settings = AffectSettings::new(
+            "log_alpha_asymmetry",
+            "engagement",
+            "theta_alpha_power",
+            &["engagement".to_string(), "theta_over_alpha".to_string()],
+        )
+        .unwrap();
+        let metrics = AffectMetrics::new(&settings);
+        let values = metrics.compute(&band_powers(1.0, 2.0));
+
+        assert_eq!(
+            vec![
+                "log_alpha_asymmetry",
+                "engagement",
+                "theta_alpha_power",
+                "theta_over_alpha"
+            ],
+            metrics.names()
+        );
+        assert_eq!(values[0], metrics.valence(&values));
+        assert_eq!(values[1], metrics.arousal(&values));
+        assert_eq!(values[2], metrics.drowsiness(&values));
+    }
+
+    #[test]
+    fn test_bad_settings() {
+        assert!(AffectSettings::new("engagement", "engagement", "slow_wave_ratio", &[]).is_err());
+        assert!(
+            AffectSettings::new("log_alpha_asymmetry", "calm", "slow_wave_ratio", &[]).is_err()
+        );
+        assert!(
+            AffectSettings::new("log_alpha_asymmetry", "engagement", "engagement", &[]).is_err()
+        );
+    }
+}
@@ -0,0 +1,194 @@
+/// Decide when band powers are contaminated by muscle, head movement or poor contact, so they are
+/// kept out of the valence and arousal history.
+///
+/// Band powers are calculated over the previous second or so of EEG, so a blink, jaw clench or head
+/// movement spoils the values which arrive for a while after it. The headset reports an event a little
+/// after it begins, so the values which arrive just before it are spoiled too: they are held back for
+/// the pre-event margin, and left out if an event follows within it. Poor electrode contact spoils
+/// them for as long as it lasts.
+use chrono::{DateTime, Duration, Local};
+use std::fmt;
+
+const N_ELECTRODES: usize = 4;
+const ELECTRODE_NAMES: [&str; N_ELECTRODES] = ["TP9", "AF7", "AF8", "TP10"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArtifactPolicy {
+    /// Band powers are left out for this long after a blink
+    pub blink_exclusion: Duration,
+    /// Band powers are left out for this long after a jaw clench
+    pub jaw_clench_exclusion: Duration,
+    /// Band powers are left out for this long after the head was moving
+    pub motion_exclusion: Duration,
+    /// Band powers are held back for this long, and left out if a blink, jaw clench or movement follows
+    pub pre_event_margin: Duration,
+    /// Worst acceptable horseshoe fit for each electrode, 1 good, 2 medium, 4 bad. 4 never excludes.
+    pub max_horseshoe: [f32; N_ELECTRODES],
+}
+
+impl Default for ArtifactPolicy {
+    /// Only the front electrodes drive valence and arousal, so only their fit is checked
+    fn default() -> Self {
+        ArtifactPolicy {
+            blink_exclusion: Duration::milliseconds(1000),
+            jaw_clench_exclusion: Duration::milliseconds(1500),
+            motion_exclusion: Duration::milliseconds(1000),
+            pre_event_margin: Duration::milliseconds(250),
+            max_horseshoe: [4.0, 2.0, 2.0, 4.0],
+        }
+    }
+}
+
+/// Why band powers were left out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Artifact {
+    Blink,
+    JawClench,
+    Motion,
+    Horseshoe { electrode: usize },
+}
+
+impl fmt::Display for Artifact {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Artifact::Blink => write!(f, "Blink"),
+            Artifact::JawClench => write!(f, "JawClench"),
+            Artifact::Motion => write!(f, "Motion"),
+            Artifact::Horseshoe { electrode } => {
+                write!(f, "Horseshoe {}", ELECTRODE_NAMES[*electrode])
+            }
+        }
+    }
+}
+
+/// Recent artifact events for one headset
+pub struct ArtifactGate {
+    pub policy: ArtifactPolicy,
+    last_blink: Option<DateTime<Local>>,
+    last_jaw_clench: Option<DateTime<Local>>,
+    last_motion: Option<DateTime<Local>>,
+    horseshoe: [f32; N_ELECTRODES],
+}
+
+impl ArtifactGate {
+    pub fn new(policy: ArtifactPolicy) -> Self {
+        ArtifactGate {
+            policy,
+            last_blink: None,
+            last_jaw_clench: None,
+            last_motion: None,
+            horseshoe: [1.0; N_ELECTRODES], // Assume a good fit until the headset reports otherwise
+        }
+    }
+
+    pub fn blink(&mut self, time: DateTime<Local>) {
+        self.last_blink = Some(time);
+    }
+
+    pub fn jaw_clench(&mut self, time: DateTime<Local>) {
+        self.last_jaw_clench = Some(time);
+    }
+
+    /// The head is moving at `time`
+    pub fn motion(&mut self, time: DateTime<Local>) {
+        self.last_motion = Some(time);
+    }
+
+    pub fn horseshoe(&mut self, horseshoe: [f32; N_ELECTRODES]) {
+        self.horseshoe = horseshoe;
+    }
+
+    /// The reason band powers arriving at `time` should be left out, if any. Check once events up to
+    /// the pre-event margin after `time` have arrived.
+    pub fn check(&self, time: DateTime<Local>) -> Option<Artifact> {
+        let margin = self.policy.pre_event_margin;
+        let within = |event: Option<DateTime<Local>>, exclusion: Duration| match event {
+            Some(event) if time >= event => time - event < exclusion,
+            Some(event) => event - time < margin,
+            None => false,
+        };
+
+        if within(self.last_jaw_clench, self.policy.jaw_clench_exclusion) {
+            return Some(Artifact::JawClench);
+        }
+
+        if within(self.last_blink, self.policy.blink_exclusion) {
+            return Some(Artifact::Blink);
+        }
+
+        if within(self.last_motion, self.policy.motion_exclusion) {
+            return Some(Artifact::Motion);
+        }
+
+        self.horseshoe
+            .iter()
+            .zip(self.policy.max_horseshoe.iter())
+            .position(|(fit, max)| fit > max)
+            .map(|electrode| Artifact::Horseshoe { electrode })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(ms: i64) -> DateTime<Local> {
+        Local.timestamp_millis(1_582_616_149_000 + ms)
+    }
+
+    #[test]
+    fn test_blink_exclusion_window() {
+        let mut gate = ArtifactGate::new(ArtifactPolicy::default());
+        assert_eq!(None, gate.check(at(0)));
+
+        gate.blink(at(0));
+        assert_eq!(Some(Artifact::Blink), gate.check(at(999)));
+        assert_eq!(None, gate.check(at(1000)));
+    }
+
+    #[test]
+    fn test_band_powers_just_before_a_blink() {
+        let mut gate = ArtifactGate::new(ArtifactPolicy::default());
+        gate.blink(at(1000));
+
+        assert_eq!(None, gate.check(at(750)));
+        assert_eq!(Some(Artifact::Blink), gate.check(at(800)));
+        assert_eq!(Some(Artifact::Blink), gate.check(at(1000)));
+    }
+
+    #[test]
+    fn test_jaw_clench_takes_precedence() {
+        let mut gate = ArtifactGate::new(ArtifactPolicy::default());
+        gate.blink(at(0));
+        gate.jaw_clench(at(100));
+
+        assert_eq!(Some(Artifact::JawClench), gate.check(at(500)));
+        assert_eq!(Some(Artifact::JawClench), gate.check(at(1200)));
+        assert_eq!(None, gate.check(at(1600)));
+    }
+
+    #[test]
+    fn test_motion_exclusion_window() {
+        let mut gate = ArtifactGate::new(ArtifactPolicy::default());
+        gate.motion(at(0));
+        gate.motion(at(500));
+
+        assert_eq!(Some(Artifact::Motion), gate.check(at(1400)));
+        assert_eq!(None, gate.check(at(1500)));
+    }
+
+    #[test]
+    fn test_horseshoe_per_electrode() {
+        let mut gate = ArtifactGate::new(ArtifactPolicy::default());
+        gate.horseshoe([4.0, 1.0, 2.0, 4.0]);
+        assert_eq!(None, gate.check(at(0)));
+
+        gate.horseshoe([1.0, 1.0, 4.0, 1.0]);
+        assert_eq!(
+            Some(Artifact::Horseshoe { electrode: 2 }),
+            gate.check(at(0))
+        );
+        assert_eq!("Horseshoe AF8", gate.check(at(0)).unwrap().to_string());
+    }
+}
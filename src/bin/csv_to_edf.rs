@@ -0,0 +1,41 @@
+/// Convert the raw EEG of an earlier session from CSV to EDF+, with the protocol events as annotations
+///
+///   csv_to_edf EEG_CSV [EVENTS_CSV] [--output EDF_FILE] [--patient ID]
+///
+/// EEG_CSV is a headset's eeg.csv, for example "P1 eeg.csv", and EVENTS_CSV the session's events.csv,
+/// or other.csv for sessions from before events.csv.
+/// The output defaults to EEG_CSV with the .edf extension.
+#[path = "../edf.rs"]
+mod edf;
+
+use std::path::PathBuf;
+
+fn main() {
+    let mut eeg_csv: Option<PathBuf> = None;
+    let mut events_csv: Option<PathBuf> = None;
+    let mut output: Option<PathBuf> = None;
+    let mut patient = "X".to_string();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--output" => output = Some(args.next().expect("--output needs a file name").into()),
+            "--patient" => patient = args.next().expect("--patient needs an ID"),
+            other if eeg_csv.is_none() => eeg_csv = Some(other.into()),
+            other => events_csv = Some(other.into()),
+        }
+    }
+    let eeg_csv =
+        eeg_csv.expect("Usage: csv_to_edf EEG_CSV [EVENTS_CSV] [--output EDF_FILE] [--patient ID]");
+    let output = output.unwrap_or_else(|| eeg_csv.with_extension("edf"));
+
+    let result = edf::EdfRecording::read_csv(&patient, &eeg_csv, events_csv.as_deref())
+        .and_then(|recording| recording.write(&output));
+    match result {
+        Ok(records) => println!("Wrote {} seconds of EEG to {}", records, output.display()),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
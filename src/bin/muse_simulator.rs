@@ -0,0 +1,133 @@
+/// Send simulated Muse headset OSC traffic to the meme app, for development and CI without hardware
+///
+///   muse_simulator [SCENARIO | SCRIPT_FILE] [--host 127.0.0.1] [--port 34254] [--seed 1] [--duration SECONDS] [--bundles]
+///
+/// SCENARIO is one of baseline, alpha_surge, asymmetry, dropout, packet_loss, demo. A SCRIPT_FILE
+/// has one "start duration event parameter" line per event, see muse_simulator::Scenario::parse
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+#[path = "../muse_simulator.rs"]
+mod muse_simulator;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn main() {
+    use muse_simulator::{Scenario, SimulatedHeadset, DEFAULT_OSC_PORT};
+    use nannou_osc::{Bundle, Packet, Type};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    const NTP_UNIX_EPOCH_DIFFERENCE_SECONDS: u64 = 2_208_988_800;
+
+    let mut scenario_name = "demo".to_string();
+    let mut host = "127.0.0.1".to_string();
+    let mut port = DEFAULT_OSC_PORT;
+    let mut seed: u64 = 1;
+    let mut duration: Option<f32> = None;
+    let mut bundles = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--host" => host = args.next().expect("--host needs an address"),
+            "--port" => {
+                port = args
+                    .next()
+                    .and_then(|p| p.parse().ok())
+                    .expect("--port needs a number")
+            }
+            "--seed" => {
+                seed = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .expect("--seed needs a number")
+            }
+            "--duration" => {
+                duration = Some(
+                    args.next()
+                        .and_then(|d| d.parse().ok())
+                        .expect("--duration needs a number of seconds"),
+                )
+            }
+            "--bundles" => bundles = true,
+            other => scenario_name = other.to_string(),
+        }
+    }
+
+    let scenario = match Scenario::named(&scenario_name) {
+        Some(scenario) => scenario,
+        None => {
+            let script = std::fs::read_to_string(&scenario_name)
+                .expect("Not a built in scenario name or a readable script file");
+            Scenario::parse(&script).expect("Can not parse scenario script")
+        }
+    };
+
+    let sender = nannou_osc::sender()
+        .expect("Can not create OSC sender")
+        .connect((host.as_ref(), port))
+        .expect("Can not connect OSC sender");
+    let mut headset = SimulatedHeadset::new(scenario, seed);
+    let start = Instant::now();
+    let mut sent: u64 = 0;
+    let mut dropped: u64 = 0;
+
+    println!(
+        "Simulating '{}' to {}:{}{}",
+        scenario_name,
+        host,
+        port,
+        if bundles {
+            " in timetagged bundles"
+        } else {
+            ""
+        }
+    );
+
+    loop {
+        let elapsed = start.elapsed();
+        let elapsed_seconds = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+        if let Some(d) = duration {
+            if elapsed_seconds >= d {
+                break;
+            }
+        }
+
+        while headset.time() < elapsed_seconds {
+            let messages = headset.step();
+            let packets: Vec<Packet> = match bundles {
+                true => {
+                    let now = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .expect("System clock is not set correctly");
+                    let seconds = (now.as_secs() + NTP_UNIX_EPOCH_DIFFERENCE_SECONDS) as u32;
+                    let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+                    vec![Packet::Bundle(Bundle {
+                        timetag: Type::Time(seconds, fraction as u32),
+                        content: messages
+                            .into_iter()
+                            .map(|m| Packet::Message(m).into())
+                            .collect(),
+                    })]
+                }
+                false => messages.into_iter().map(Packet::Message).collect(),
+            };
+
+            for packet in packets {
+                if headset.drop_packet() {
+                    dropped += 1;
+                } else if let Err(e) = sender.send(packet) {
+                    eprintln!("Can not send OSC packet: {}", e);
+                } else {
+                    sent += 1;
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(2));
+    }
+
+    println!("Sent {} packets, dropped {}", sent, dropped);
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn main() {
+    // The simulator sends UDP, which is not available in the browser
+}
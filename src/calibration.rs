@@ -0,0 +1,248 @@
+/// Valence, arousal and drowsiness baseline for each participant.
+///
+/// Once the fit check has passed, the mean and standard deviation of each participant's valence,
+/// arousal and drowsiness are measured for a while. After that the baseline is frozen, so later values are normalized against
+/// the participant at rest rather than against the stimulus being measured. The baseline is saved
+/// as JSON in the calibration directory, one file per participant id, and can be loaded in a later
+/// session instead of measuring again.
+use crate::muse_model::participant_label;
+use chrono::{DateTime, Duration, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+pub const DEFAULT_CALIBRATION_SECONDS: i64 = 60;
+const DEFAULT_CALIBRATION_DIRECTORY: &str = "calibration";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalibrationSettings {
+    /// How long each participant is measured before the baseline is frozen
+    pub duration: Duration,
+    /// Where baseline files are saved and loaded
+    pub directory: PathBuf,
+    /// Name of the participant in each slot, used for the baseline file. Default "P1", "P2", ...
+    pub participant_ids: Vec<String>,
+    /// Load each participant's saved baseline instead of measuring a new one
+    pub reuse: bool,
+}
+
+impl Default for CalibrationSettings {
+    fn default() -> Self {
+        CalibrationSettings {
+            duration: Duration::seconds(DEFAULT_CALIBRATION_SECONDS),
+            directory: PathBuf::from(DEFAULT_CALIBRATION_DIRECTORY),
+            participant_ids: Vec::new(),
+            reuse: false,
+        }
+    }
+}
+
+impl CalibrationSettings {
+    pub fn participant_id(&self, participant: usize) -> String {
+        self.participant_ids
+            .get(participant)
+            .cloned()
+            .unwrap_or_else(|| participant_label(participant))
+    }
+}
+
+/// Mean and standard deviation of one value while calibrating
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Baseline {
+    pub mean: f32,
+    pub deviation: f32,
+}
+
+/// The saved calibration of one participant
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ParticipantBaseline {
+    pub participant_id: String,
+    pub calibrated_at: DateTime<Local>,
+    pub valence_metric: String,
+    pub valence: Baseline,
+    pub arousal_metric: String,
+    pub arousal: Baseline,
+    #[serde(default)]
+    // Empty in files saved before drowsiness was calibrated, so they are not reused
+    pub drowsiness_metric: String,
+    #[serde(default)]
+    pub drowsiness: Baseline,
+}
+
+/// Calibration progress of one headset
+pub struct Calibration {
+    duration: Duration,
+    participant_id: String,
+    path: PathBuf,
+    measuring: bool, // Set by start(), values before that such as during the fit check are not part of the baseline
+    started: Option<DateTime<Local>>,
+    complete: bool,
+}
+
+impl Calibration {
+    pub fn new(settings: &CalibrationSettings, participant: usize) -> Self {
+        let participant_id = settings.participant_id(participant);
+        let path = settings.directory.join(format!("{}.json", participant_id));
+
+        Calibration {
+            duration: settings.duration,
+            participant_id,
+            path,
+            measuring: false,
+            started: None,
+            complete: false,
+        }
+    }
+
+    pub fn participant_id(&self) -> &str {
+        &self.participant_id
+    }
+
+    /// The baseline has been measured or loaded
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Measure from the next update on
+    pub fn start(&mut self) {
+        self.measuring = true;
+    }
+
+    /// The baseline is being measured, or has been measured or loaded
+    pub fn is_started(&self) -> bool {
+        self.measuring || self.complete
+    }
+
+    /// Time runs from the first call after start(). Returns true only at the moment calibration is complete.
+    pub fn update(&mut self, time: DateTime<Local>) -> bool {
+        if self.complete || !self.measuring {
+            return false;
+        }
+
+        let started = *self.started.get_or_insert(time);
+        self.complete = time - started >= self.duration;
+
+        self.complete
+    }
+
+    /// Measure again from the next update, when the baseline could not be frozen
+    pub fn restart(&mut self) {
+        self.started = None;
+        self.complete = false;
+    }
+
+    /// Read the participant's baseline from an earlier session which used the same affect metrics. Calibration is then complete.
+    pub fn load(
+        &mut self,
+        valence_metric: &str,
+        arousal_metric: &str,
+        drowsiness_metric: &str,
+    ) -> Result<ParticipantBaseline, String> {
+        let json = fs::read_to_string(&self.path)
+            .map_err(|e| format!("Can not read {}: {}", self.path.display(), e))?;
+        let baseline: ParticipantBaseline = serde_json::from_str(&json)
+            .map_err(|e| format!("Can not read {}: {}", self.path.display(), e))?;
+        if baseline.valence_metric != valence_metric
+            || baseline.arousal_metric != arousal_metric
+            || baseline.drowsiness_metric != drowsiness_metric
+        {
+            return Err(format!(
+                "{} was calibrated with {}, {} and {}",
+                self.path.display(),
+                baseline.valence_metric,
+                baseline.arousal_metric,
+                baseline.drowsiness_metric
+            ));
+        }
+        self.complete = true;
+
+        Ok(baseline)
+    }
+
+    pub fn save(&self, baseline: &ParticipantBaseline) -> Result<PathBuf, String> {
+        let write = || -> std::io::Result<()> {
+            if let Some(directory) = self.path.parent() {
+                fs::create_dir_all(directory)?;
+            }
+            fs::write(&self.path, serde_json::to_string_pretty(baseline)?)
+        };
+        write().map_err(|e| format!("Can not write {}: {}", self.path.display(), e))?;
+
+        Ok(self.path.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(s: i64) -> DateTime<Local> {
+        Local.timestamp(1_582_616_149 + s, 0)
+    }
+
+    #[test]
+    fn test_calibration_completes_once() {
+        let mut calibration = Calibration::new(&CalibrationSettings::default(), 0);
+        assert!(!calibration.update(at(0)));
+        assert!(!calibration.update(at(100)));
+        assert!(!calibration.is_started());
+
+        calibration.start();
+        assert!(calibration.is_started());
+        assert!(!calibration.update(at(10)));
+        assert!(!calibration.update(at(69)));
+        assert!(calibration.update(at(70)));
+        assert!(!calibration.update(at(80)));
+
+        calibration.restart();
+        assert!(!calibration.is_complete());
+        assert!(!calibration.update(at(90)));
+        assert!(calibration.update(at(150)));
+    }
+
+    #[test]
+    fn test_save_and_load_baseline() {
+        let settings = CalibrationSettings {
+            directory: std::env::temp_dir().join("meme calibration test"),
+            participant_ids: vec!["alice".to_string()],
+            ..CalibrationSettings::default()
+        };
+        let baseline = ParticipantBaseline {
+            participant_id: "alice".to_string(),
+            calibrated_at: at(0),
+            valence_metric: "log_alpha_asymmetry".to_string(),
+            valence: Baseline {
+                mean: 1.5,
+                deviation: 0.25,
+            },
+            arousal_metric: "engagement".to_string(),
+            arousal: Baseline {
+                mean: 0.75,
+                deviation: 0.5,
+            },
+            drowsiness_metric: "slow_wave_ratio".to_string(),
+            drowsiness: Baseline {
+                mean: 2.0,
+                deviation: 1.0,
+            },
+        };
+
+        let path = Calibration::new(&settings, 0).save(&baseline).unwrap();
+        let mut calibration = Calibration::new(&settings, 0);
+        assert!(calibration
+            .load("log_alpha_asymmetry", "beta_over_alpha", "slow_wave_ratio")
+            .is_err());
+        assert_eq!(
+            baseline,
+            calibration
+                .load("log_alpha_asymmetry", "engagement", "slow_wave_ratio")
+                .unwrap()
+        );
+        assert!(calibration.is_complete());
+        assert!(!calibration.update(at(100)));
+        assert_eq!("P2", Calibration::new(&settings, 1).participant_id());
+
+        fs::remove_file(path).unwrap();
+    }
+}
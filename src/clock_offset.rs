@@ -0,0 +1,196 @@
+/// Map the headset's (phone app's) clock onto the local clock using OSC bundle timetags
+use chrono::{DateTime, Duration, Local, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+const NTP_UNIX_EPOCH_DIFFERENCE_SECONDS: i64 = 2_208_988_800; // 1900-01-01 to 1970-01-01
+const NTP_FRACTION_PER_SECOND: f64 = 4_294_967_296.0; // 2^32
+pub const DEFAULT_CLOCK_OFFSET_WINDOW: usize = 256; // About 1 second of EEG bundles
+
+/// OSC bundle timetag in NTP format: seconds since 1900 and 1/2^32 fractions of a second
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OscTimetag {
+    pub seconds: u32,
+    pub fraction: u32,
+}
+
+impl OscTimetag {
+    pub fn new(seconds: u32, fraction: u32) -> Self {
+        Self { seconds, fraction }
+    }
+
+    /// The special value (0, 1) means "process immediately" and carries no sender time
+    pub fn is_immediate(&self) -> bool {
+        self.seconds == 0 && self.fraction <= 1
+    }
+
+    /// Microseconds since UNIX_EPOCH on the sender's clock
+    pub fn unix_micros(&self) -> i64 {
+        let whole = (self.seconds as i64 - NTP_UNIX_EPOCH_DIFFERENCE_SECONDS) * 1_000_000;
+        let part = (self.fraction as f64 / NTP_FRACTION_PER_SECOND * 1_000_000.0) as i64;
+
+        whole + part
+    }
+
+    /// The sender time, uncorrected, expressed in the local time zone
+    pub fn to_date_time(&self) -> DateTime<Local> {
+        let micros = self.unix_micros();
+
+        Local.timestamp(
+            micros.div_euclid(1_000_000),
+            (micros.rem_euclid(1_000_000) * 1_000) as u32,
+        )
+    }
+}
+
+/// How sender timetags are turned into local sample times
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockOffsetMode {
+    /// Ignore sender timetags and use the local packet arrival time, as before timetags were supported
+    ReceiveTime,
+    /// The offset is the smallest (receive - send) difference over the most recent `window` timetags. Network delay only ever adds time, so the minimum is the best estimate of the true clock difference.
+    MinimumDelay { window: usize },
+    /// A known offset (local - sender) in milliseconds, for example measured with an external sync pulse
+    Fixed { offset_ms: i64 },
+}
+
+/// Running estimate of local clock minus headset clock
+pub struct ClockOffsetEstimator {
+    mode: ClockOffsetMode,
+    differences: VecDeque<i64>, // (receive - send) in microseconds, most recent last
+}
+
+impl ClockOffsetEstimator {
+    pub fn new(mode: ClockOffsetMode) -> Self {
+        Self {
+            mode,
+            differences: VecDeque::new(),
+        }
+    }
+
+    /// Change mode, discarding any accumulated history
+    pub fn set_mode(&mut self, mode: ClockOffsetMode) {
+        self.mode = mode;
+        self.differences.clear();
+    }
+
+    /// Add an observation of a timetag and the local time at which its packet arrived
+    pub fn update(&mut self, timetag: OscTimetag, receive_time: DateTime<Local>) {
+        if let ClockOffsetMode::MinimumDelay { window } = self.mode {
+            if timetag.is_immediate() || window == 0 {
+                return;
+            }
+            let receive_micros = receive_time.timestamp() * 1_000_000
+                + receive_time.timestamp_subsec_micros() as i64;
+            self.differences
+                .push_back(receive_micros - timetag.unix_micros());
+            while self.differences.len() > window {
+                self.differences.pop_front();
+            }
+        }
+    }
+
+    /// Current estimate of (local - sender), if there is enough information
+    pub fn offset(&self) -> Option<Duration> {
+        match self.mode {
+            ClockOffsetMode::ReceiveTime => None,
+            ClockOffsetMode::MinimumDelay { .. } => self
+                .differences
+                .iter()
+                .min()
+                .map(|micros| Duration::microseconds(*micros)),
+            ClockOffsetMode::Fixed { offset_ms } => Some(Duration::milliseconds(offset_ms)),
+        }
+    }
+
+    /// Best estimate of the local time at which the sample with this timetag was taken
+    pub fn to_local(
+        &self,
+        timetag: Option<OscTimetag>,
+        receive_time: DateTime<Local>,
+    ) -> DateTime<Local> {
+        match (timetag, self.offset()) {
+            (Some(timetag), Some(offset)) if !timetag.is_immediate() => {
+                timetag.to_date_time() + offset
+            }
+            _ => receive_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timetag_at(date_time: DateTime<Local>) -> OscTimetag {
+        let seconds = (date_time.timestamp() + NTP_UNIX_EPOCH_DIFFERENCE_SECONDS) as u32;
+        let fraction = (date_time.timestamp_subsec_micros() as f64 / 1_000_000.0
+            * NTP_FRACTION_PER_SECOND) as u32;
+
+        OscTimetag::new(seconds, fraction)
+    }
+
+    #[test]
+    fn test_timetag_round_trip() {
+        let now = Local.timestamp(1_582_616_149, 500_000_000);
+        let timetag = timetag_at(now);
+
+        assert_eq!(now, timetag.to_date_time());
+    }
+
+    #[test]
+    fn test_immediate_timetag_uses_receive_time() {
+        let estimator = ClockOffsetEstimator::new(ClockOffsetMode::Fixed { offset_ms: 100 });
+        let receive_time = Local.timestamp(1_582_616_149, 0);
+
+        assert_eq!(
+            receive_time,
+            estimator.to_local(Some(OscTimetag::new(0, 1)), receive_time)
+        );
+    }
+
+    #[test]
+    fn test_minimum_delay_offset() {
+        let mut estimator = ClockOffsetEstimator::new(ClockOffsetMode::MinimumDelay { window: 3 });
+        let sent = Local.timestamp(1_582_616_149, 0);
+        let tag = timetag_at(sent);
+
+        // Headset clock is 2 seconds behind, network adds 30, 10 and 50ms of delay
+        for delay_ms in [30, 10, 50].iter() {
+            estimator.update(
+                tag,
+                sent + Duration::seconds(2) + Duration::milliseconds(*delay_ms),
+            );
+        }
+
+        assert_eq!(Some(Duration::milliseconds(2010)), estimator.offset());
+        assert_eq!(
+            sent + Duration::milliseconds(2010),
+            estimator.to_local(Some(tag), sent)
+        );
+    }
+
+    #[test]
+    fn test_minimum_delay_window_forgets_old_values() {
+        let mut estimator = ClockOffsetEstimator::new(ClockOffsetMode::MinimumDelay { window: 2 });
+        let sent = Local.timestamp(1_582_616_149, 0);
+        let tag = timetag_at(sent);
+
+        for delay_ms in [5, 20, 30].iter() {
+            estimator.update(tag, sent + Duration::milliseconds(*delay_ms));
+        }
+
+        assert_eq!(Some(Duration::milliseconds(20)), estimator.offset());
+    }
+
+    #[test]
+    fn test_receive_time_mode_ignores_timetag() {
+        let mut estimator = ClockOffsetEstimator::new(ClockOffsetMode::ReceiveTime);
+        let receive_time = Local.timestamp(1_582_616_149, 0);
+        let tag = timetag_at(receive_time - Duration::seconds(5));
+        estimator.update(tag, receive_time);
+
+        assert_eq!(None, estimator.offset());
+        assert_eq!(receive_time, estimator.to_local(Some(tag), receive_time));
+    }
+}
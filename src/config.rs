@@ -0,0 +1,925 @@
+/// App settings, layered so a rig can keep a config file and override single values for one run:
+/// built in defaults < config file < environment variables < command line arguments.
+///
+/// The config file is `meme.toml` in the working directory, or the file named by MEME_CONFIG or
+/// `--config <file>`. Keys are those of AppConfig, for example:
+///   listen = ["0.0.0.0:34254", "0.0.0.0:34264"]
+///   clock_offset = "receive_time"
+///
+/// Each key can also be given on the command line as `--listen 34254,34264` and in the environment
+/// as listed in ENV_VARS.
+use crate::affect_metric::{AffectSettings, BUILT_IN_METRICS};
+use crate::artifact::ArtifactPolicy;
+use crate::calibration::CalibrationSettings;
+use crate::clock_offset::{ClockOffsetMode, DEFAULT_CLOCK_OFFSET_WINDOW};
+use crate::eeg_source::{EegSourceConfig, OSC_PORT};
+use crate::filter::FilterSettings;
+use crate::heart_rate::BreathDriver;
+use crate::muse_model::{
+    BaselineStatistic, NormalizationSettings, ParticipantCombination, RepeatedValues, Smoothing,
+};
+use crate::recorder::RecorderSettings;
+use crate::session::SessionSettings;
+use crate::signal_quality::DEFAULT_FIT_CHECK_SECONDS;
+use crate::spectrum::{BandEdges, EEG_SAMPLE_RATE};
+use crate::wire_format::WEBSOCKET_PORT;
+use chrono::Duration;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+
+const CONFIG_FILE: &str = "meme.toml";
+
+/// Environment variable for each config key
+const ENV_VARS: &[(&str, &str)] = &[
+    ("MEME_OSC_LISTEN", "listen"),
+    ("MEME_OSC_RECORD", "record"),
+    ("MEME_OSC_REPLAY", "replay"),
+    ("MEME_OSC_REPLAY_SPEED", "replay_speed"),
+    ("MEME_CSV_REPLAY", "csv_replay"),
+    ("MEME_SIMULATOR", "simulator"),
+    ("MEME_SIMULATOR_SEED", "simulator_seed"),
+    ("MEME_RELAY_SOURCE", "relay_source"),
+    ("MEME_RELAY", "relay"),
+    ("MEME_PARTICIPANTS", "participants"),
+    ("MEME_PARTICIPANT_COMBINATION", "participant_combination"),
+    ("MEME_CLOCK_OFFSET", "clock_offset"),
+    ("MEME_BAND_EDGES", "band_edges"),
+    ("MEME_NOTCH", "notch"),
+    ("MEME_HIGH_PASS", "high_pass"),
+    ("MEME_BAND_PASS", "band_pass"),
+    ("MEME_BLINK_EXCLUSION_MS", "blink_exclusion_ms"),
+    ("MEME_JAW_CLENCH_EXCLUSION_MS", "jaw_clench_exclusion_ms"),
+    ("MEME_MOTION_EXCLUSION_MS", "motion_exclusion_ms"),
+    ("MEME_ARTIFACT_MARGIN_MS", "artifact_margin_ms"),
+    ("MEME_MAX_HORSESHOE", "max_horseshoe"),
+    ("MEME_FIT_CHECK_SECONDS", "fit_check_seconds"),
+    ("MEME_PARTICIPANT_IDS", "participant_ids"),
+    ("MEME_CALIBRATION_SECONDS", "calibration_seconds"),
+    ("MEME_CALIBRATION_DIR", "calibration_dir"),
+    ("MEME_REUSE_CALIBRATION", "reuse_calibration"),
+    ("MEME_NORMALIZATION", "normalization"),
+    ("MEME_NORMALIZATION_WINDOW", "normalization_window"),
+    ("MEME_SMOOTHING_WINDOW", "smoothing_window"),
+    ("MEME_SMOOTHING_ALPHA", "smoothing_alpha"),
+    ("MEME_REPEATED_VALUES", "repeated_values"),
+    ("MEME_VALENCE_METRIC", "valence_metric"),
+    ("MEME_AROUSAL_METRIC", "arousal_metric"),
+    ("MEME_DROWSINESS_METRIC", "drowsiness_metric"),
+    ("MEME_AFFECT_METRICS", "affect_metrics"),
+    ("MEME_BREATH_DRIVER", "breath_driver"),
+    ("MEME_MESSAGE_SINKS", "message_sinks"),
+    ("MEME_DATA_ROOT", "data_root"),
+    ("MEME_RIG_ID", "rig_id"),
+    ("MEME_PROTOCOL", "protocol"),
+    ("MEME_EXPORT_EDF", "export_edf"),
+    ("MEME_XDF", "xdf"),
+];
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    /// OSC listen addresses, "ip:port" or just "port" for all interfaces. Empty means port 34254 on all interfaces.
+    pub listen: Vec<String>,
+    /// Also write every OSC packet to this capture file
+    pub record: Option<PathBuf>,
+    /// Replay this capture file instead of listening
+    pub replay: Option<PathBuf>,
+    /// Multiple of real time for replay, default 1
+    pub replay_speed: Option<f64>,
+    /// Replay the eeg.csv and band power CSV files in this session directory instead of listening
+    pub csv_replay: Option<PathBuf>,
+    /// Run the built in simulator with this scenario name or script file instead of listening
+    pub simulator: Option<String>,
+    pub simulator_seed: Option<u64>,
+    /// Follow another app's WebSocket relay instead of listening, for example "ws://10.0.0.5:34255"
+    pub relay_source: Option<String>,
+    /// Re-broadcast EEG, valence and arousal over WebSocket on this port
+    pub relay: Option<u16>,
+    /// Reserve participant slots in this order for the phone at each IP address
+    pub participants: Vec<String>,
+    /// "average" or "split"
+    pub participant_combination: Option<String>,
+    /// "minimum_delay", "receive_time" or a fixed offset in milliseconds
+    pub clock_offset: Option<String>,
+    /// Band powers computed from raw EEG, "low-high" Hz for delta, theta, alpha, beta and gamma, for example "1-4,4-8,7.5-13,13-30,30-44"
+    pub band_edges: Option<String>,
+    /// Raw EEG mains notch, "50", "60" or "off"
+    pub notch: Option<String>,
+    /// Raw EEG high-pass cut-off in Hz to remove DC, or "off". Default 0.5.
+    pub high_pass: Option<String>,
+    /// Raw EEG band-pass "low-high" in Hz, or "off"
+    pub band_pass: Option<String>,
+    /// Band powers are left out of valence and arousal for this long after a blink. Default 1000.
+    pub blink_exclusion_ms: Option<i64>,
+    /// The same after a jaw clench. Default 1500.
+    pub jaw_clench_exclusion_ms: Option<i64>,
+    /// The same after head movement. Default 1000.
+    pub motion_exclusion_ms: Option<i64>,
+    /// Band powers are held back this long, and left out if a blink, jaw clench or movement follows. Default 250.
+    pub artifact_margin_ms: Option<i64>,
+    /// Worst acceptable horseshoe fit (1 good, 2 medium, 4 bad), one value for all electrodes or "TP9,AF7,AF8,TP10". Default "4,2,2,4".
+    pub max_horseshoe: Option<String>,
+    /// All electrodes must stay good for this long before the session starts. 0 skips the fit check. Default 5.
+    pub fit_check_seconds: Option<i64>,
+    /// Name of the participant in each slot, for their saved calibration. Default "P1", "P2", ...
+    pub participant_ids: Vec<String>,
+    /// Valence and arousal baseline measurement time. Default 60.
+    pub calibration_seconds: Option<i64>,
+    /// Where calibration baselines are saved. Default "calibration".
+    pub calibration_dir: Option<PathBuf>,
+    /// Load each participant's baseline from an earlier session instead of measuring it again
+    pub reuse_calibration: Option<bool>,
+    /// Valence and arousal baseline, "mean" (mean and standard deviation) or "median" (median and median absolute deviation)
+    pub normalization: Option<String>,
+    /// Most recent values in the baseline while calibrating. Default every value.
+    pub normalization_window: Option<usize>,
+    /// Values in the moving average of valence and arousal. Default 9.
+    pub smoothing_window: Option<usize>,
+    /// Exponential moving average weight of each new value, 0 to 1, instead of smoothing_window
+    pub smoothing_alpha: Option<f32>,
+    /// A value equal to the one before, "skip" or "keep". Default "skip".
+    pub repeated_values: Option<String>,
+    /// Valence formula which drives the mandala, see affect_metric::BUILT_IN_METRICS. Default "asymmetry_over_theta".
+    pub valence_metric: Option<String>,
+    /// Arousal formula which drives the mandala. Default "theta_over_alpha".
+    pub arousal_metric: Option<String>,
+    /// Drowsiness formula for the drowsiness view. Default "slow_wave_ratio".
+    pub drowsiness_metric: Option<String>,
+    /// Metrics computed and logged to affect.csv beside the valence, arousal and drowsiness metrics. Default all.
+    pub affect_metrics: Option<Vec<String>>,
+    /// What opens and closes the breathing mandala, "paced" or "heart_rate". Default "paced".
+    pub breath_driver: Option<String>,
+    /// Where kinds of headset message are recorded, "kind:sink" with sink csv, other or off. Default see recorder::DEFAULT_SINKS.
+    pub message_sinks: Vec<String>,
+    /// Each session is written to its own directory in here. Default "sessions".
+    pub data_root: Option<PathBuf>,
+    /// Recorded in each session.json, to tell apart sessions from several rigs
+    pub rig_id: Option<String>,
+    /// Experiment protocol recorded in each session.json. Default "meme".
+    pub protocol: Option<String>,
+    /// Also write each participant's raw EEG and the session events as EDF+ when the session ends. Default false.
+    pub export_edf: Option<bool>,
+    /// Also record raw EEG, band powers, motion, valence, arousal and session events as streams of one session.xdf. Default false.
+    pub xdf: Option<bool>,
+}
+
+impl AppConfig {
+    /// Settings for this run from the config file, environment and command line
+    pub fn load() -> Result<Self, String> {
+        let args: Vec<String> = env::args().skip(1).collect();
+
+        Self::load_from(&args, |name| env::var(name).ok())
+    }
+
+    fn load_from(args: &[String], var: impl Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let args = parse_args(args)?;
+        let explicit_file = args
+            .iter()
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| value.clone())
+            .or_else(|| var("MEME_CONFIG"));
+
+        let mut config = match explicit_file {
+            Some(file) => Self::from_file(Path::new(&file))?,
+            None if Path::new(CONFIG_FILE).exists() => Self::from_file(Path::new(CONFIG_FILE))?,
+            None => Self::default(),
+        };
+
+        for (name, key) in ENV_VARS {
+            if let Some(value) = var(name) {
+                config
+                    .set(key, &value)
+                    .map_err(|e| format!("{}: {}", name, e))?;
+            }
+        }
+
+        for (key, value) in args.iter().filter(|(key, _)| key != "config") {
+            config
+                .set(key, value)
+                .map_err(|e| format!("--{}: {}", key.replace('_', "-"), e))?;
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self, String> {
+        info!("Reading settings from {:?}", path);
+        let text = fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+
+        Self::parse(&text).map_err(|e| format!("{:?}: {}", path, e))
+    }
+
+    fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Override one value given as text. Lists are comma separated.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "listen" => self.listen = split_list(value),
+            "record" => self.record = Some(PathBuf::from(value)),
+            "replay" => self.replay = Some(PathBuf::from(value)),
+            "replay_speed" => self.replay_speed = Some(parse_value(value)?),
+            "csv_replay" => self.csv_replay = Some(PathBuf::from(value)),
+            "simulator" => self.simulator = Some(value.to_string()),
+            "simulator_seed" => self.simulator_seed = Some(parse_value(value)?),
+            "relay_source" => self.relay_source = Some(value.to_string()),
+            "relay" if value.is_empty() => self.relay = Some(WEBSOCKET_PORT),
+            "relay" => self.relay = Some(parse_value(value)?),
+            "participants" => self.participants = split_list(value),
+            "participant_combination" => self.participant_combination = Some(value.to_string()),
+            "clock_offset" => self.clock_offset = Some(value.to_string()),
+            "band_edges" => self.band_edges = Some(value.to_string()),
+            "notch" => self.notch = Some(value.to_string()),
+            "high_pass" => self.high_pass = Some(value.to_string()),
+            "band_pass" => self.band_pass = Some(value.to_string()),
+            "blink_exclusion_ms" => self.blink_exclusion_ms = Some(parse_value(value)?),
+            "jaw_clench_exclusion_ms" => self.jaw_clench_exclusion_ms = Some(parse_value(value)?),
+            "motion_exclusion_ms" => self.motion_exclusion_ms = Some(parse_value(value)?),
+            "artifact_margin_ms" => self.artifact_margin_ms = Some(parse_value(value)?),
+            "max_horseshoe" => self.max_horseshoe = Some(value.to_string()),
+            "fit_check_seconds" => self.fit_check_seconds = Some(parse_value(value)?),
+            "participant_ids" => self.participant_ids = split_list(value),
+            "calibration_seconds" => self.calibration_seconds = Some(parse_value(value)?),
+            "calibration_dir" => self.calibration_dir = Some(PathBuf::from(value)),
+            "reuse_calibration" => self.reuse_calibration = Some(parse_value(value)?),
+            "normalization" => self.normalization = Some(value.to_string()),
+            "normalization_window" => self.normalization_window = Some(parse_value(value)?),
+            "smoothing_window" => self.smoothing_window = Some(parse_value(value)?),
+            "smoothing_alpha" => self.smoothing_alpha = Some(parse_value(value)?),
+            "repeated_values" => self.repeated_values = Some(value.to_string()),
+            "valence_metric" => self.valence_metric = Some(value.to_string()),
+            "arousal_metric" => self.arousal_metric = Some(value.to_string()),
+            "drowsiness_metric" => self.drowsiness_metric = Some(value.to_string()),
+            "affect_metrics" => self.affect_metrics = Some(split_list(value)),
+            "breath_driver" => self.breath_driver = Some(value.to_string()),
+            "message_sinks" => self.message_sinks = split_list(value),
+            "data_root" => self.data_root = Some(PathBuf::from(value)),
+            "rig_id" => self.rig_id = Some(value.to_string()),
+            "protocol" => self.protocol = Some(value.to_string()),
+            "export_edf" => self.export_edf = Some(parse_value(value)?),
+            "xdf" => self.xdf = Some(parse_value(value)?),
+            other => return Err(format!("unknown setting '{}'", other)),
+        }
+
+        Ok(())
+    }
+
+    /// Where EEG comes from. A replay, simulator or relay source replaces listening for OSC.
+    pub fn eeg_source(&self) -> Result<EegSourceConfig, String> {
+        if let Some(path) = &self.replay {
+            return Ok(EegSourceConfig::Replay {
+                path: path.clone(),
+                speed: self.replay_speed()?,
+            });
+        }
+
+        if let Some(directory) = &self.csv_replay {
+            return Ok(EegSourceConfig::Csv {
+                directory: directory.clone(),
+                speed: self.replay_speed()?,
+            });
+        }
+
+        if let Some(scenario) = &self.simulator {
+            return Ok(EegSourceConfig::Simulator {
+                scenario: scenario.clone(),
+                seed: self.simulator_seed.unwrap_or(1),
+            });
+        }
+
+        if let Some(url) = &self.relay_source {
+            return Ok(EegSourceConfig::Relay { url: url.clone() });
+        }
+
+        let listen = self.listen_addresses()?;
+        match &self.record {
+            Some(path) => Ok(EegSourceConfig::Record {
+                listen,
+                path: path.clone(),
+            }),
+            None => Ok(EegSourceConfig::Osc { listen }),
+        }
+    }
+
+    pub fn listen_addresses(&self) -> Result<Vec<SocketAddrV4>, String> {
+        if self.listen.is_empty() {
+            return Ok(vec![SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, OSC_PORT)]);
+        }
+
+        self.listen
+            .iter()
+            .map(|listen| match listen.parse::<u16>() {
+                Ok(port) => Ok(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)),
+                Err(_) => listen
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a port or ip:port to listen on", listen)),
+            })
+            .collect()
+    }
+
+    pub fn participant_addresses(&self) -> Result<Vec<IpAddr>, String> {
+        self.participants
+            .iter()
+            .map(|source| {
+                source
+                    .parse()
+                    .map_err(|_| format!("participant '{}' is not an IP address", source))
+            })
+            .collect()
+    }
+
+    pub fn participant_combination(&self) -> Result<ParticipantCombination, String> {
+        match self.participant_combination.as_deref() {
+            None | Some("average") => Ok(ParticipantCombination::Average),
+            Some("split") => Ok(ParticipantCombination::Split),
+            Some(other) => Err(format!("unknown participant combination '{}'", other)),
+        }
+    }
+
+    pub fn breath_driver(&self) -> Result<BreathDriver, String> {
+        match self.breath_driver.as_deref() {
+            None | Some("paced") => Ok(BreathDriver::Paced),
+            Some("heart_rate") => Ok(BreathDriver::HeartRate),
+            Some(other) => Err(format!("unknown breath driver '{}'", other)),
+        }
+    }
+
+    pub fn clock_offset_mode(&self) -> Result<ClockOffsetMode, String> {
+        match self.clock_offset.as_deref() {
+            None | Some("minimum_delay") => Ok(ClockOffsetMode::MinimumDelay {
+                window: DEFAULT_CLOCK_OFFSET_WINDOW,
+            }),
+            Some("receive_time") => Ok(ClockOffsetMode::ReceiveTime),
+            Some(offset_ms) => offset_ms
+                .parse()
+                .map(|offset_ms| ClockOffsetMode::Fixed { offset_ms })
+                .map_err(|_| format!("unknown clock offset '{}'", offset_ms)),
+        }
+    }
+
+    pub fn band_edges(&self) -> Result<BandEdges, String> {
+        match &self.band_edges {
+            Some(band_edges) => BandEdges::parse(band_edges),
+            None => Ok(BandEdges::default()),
+        }
+    }
+
+    /// Multiple of real time for a capture or CSV replay
+    fn replay_speed(&self) -> Result<f64, String> {
+        match self.replay_speed.unwrap_or(1.0) {
+            speed if speed > 0.0 && speed.is_finite() => Ok(speed),
+            speed => Err(format!("replay speed '{}' is not a positive number", speed)),
+        }
+    }
+
+    pub fn filter_settings(&self) -> Result<FilterSettings, String> {
+        let mut filter_settings = FilterSettings::default();
+
+        if let Some(notch) = &self.notch {
+            filter_settings.notch = match notch.as_str() {
+                "off" => None,
+                "50" => Some(50.0),
+                "60" => Some(60.0),
+                other => return Err(format!("notch '{}' is not 50, 60 or off", other)),
+            };
+        }
+
+        let nyquist = EEG_SAMPLE_RATE / 2.0;
+        if let Some(high_pass) = &self.high_pass {
+            filter_settings.high_pass = match high_pass.as_str() {
+                "off" => None,
+                cut_off => match parse_value::<f32>(cut_off)? {
+                    cut_off if cut_off > 0.0 && cut_off < nyquist => Some(cut_off),
+                    _ => {
+                        return Err(format!(
+                            "high pass '{}' is not between 0 and {}Hz",
+                            high_pass, nyquist
+                        ))
+                    }
+                },
+            };
+        }
+
+        if let Some(band_pass) = &self.band_pass {
+            filter_settings.band_pass = match band_pass.as_str() {
+                "off" => None,
+                range => {
+                    let mut edges = range.splitn(2, '-').map(parse_value::<f32>);
+                    match (edges.next(), edges.next()) {
+                        (Some(Ok(low)), Some(Ok(high)))
+                            if low > 0.0 && low < high && high < nyquist =>
+                        {
+                            Some((low, high))
+                        }
+                        _ => {
+                            return Err(format!(
+                                "band pass '{}' is not a low-high range between 0 and {}Hz",
+                                range, nyquist
+                            ))
+                        }
+                    }
+                }
+            };
+        }
+
+        Ok(filter_settings)
+    }
+
+    pub fn artifact_policy(&self) -> Result<ArtifactPolicy, String> {
+        let mut artifact_policy = ArtifactPolicy::default();
+        let exclusion = |name: &str, ms: i64| match ms {
+            ms if ms >= 0 => Ok(Duration::milliseconds(ms)),
+            _ => Err(format!("{} '{}' is negative", name, ms)),
+        };
+
+        if let Some(ms) = self.blink_exclusion_ms {
+            artifact_policy.blink_exclusion = exclusion("blink exclusion", ms)?;
+        }
+
+        if let Some(ms) = self.jaw_clench_exclusion_ms {
+            artifact_policy.jaw_clench_exclusion = exclusion("jaw clench exclusion", ms)?;
+        }
+
+        if let Some(ms) = self.motion_exclusion_ms {
+            artifact_policy.motion_exclusion = exclusion("motion exclusion", ms)?;
+        }
+
+        if let Some(ms) = self.artifact_margin_ms {
+            artifact_policy.pre_event_margin = exclusion("artifact margin", ms)?;
+        }
+
+        if let Some(max_horseshoe) = &self.max_horseshoe {
+            let values = split_list(max_horseshoe)
+                .iter()
+                .map(|value| parse_value::<f32>(value))
+                .collect::<Result<Vec<f32>, String>>()?;
+            artifact_policy.max_horseshoe = match values.as_slice() {
+                [all] => [*all; 4],
+                [tp9, af7, af8, tp10] => [*tp9, *af7, *af8, *tp10],
+                _ => {
+                    return Err(format!(
+                        "max horseshoe '{}' needs one value or four",
+                        max_horseshoe
+                    ))
+                }
+            };
+        }
+
+        Ok(artifact_policy)
+    }
+
+    pub fn normalization_settings(&self) -> Result<NormalizationSettings, String> {
+        let mut normalization_settings = NormalizationSettings::default();
+
+        if let Some(normalization) = &self.normalization {
+            normalization_settings.statistic = match normalization.as_str() {
+                "mean" => BaselineStatistic::MeanDeviation,
+                "median" => BaselineStatistic::MedianMad,
+                other => return Err(format!("normalization '{}' is not mean or median", other)),
+            };
+        }
+
+        if let Some(window) = self.normalization_window {
+            if window == 0 {
+                return Err("normalization window must be at least 1 value".to_string());
+            }
+            normalization_settings.window = Some(window);
+        }
+
+        normalization_settings.smoothing = match (self.smoothing_window, self.smoothing_alpha) {
+            (Some(_), Some(_)) => {
+                return Err("set smoothing window or smoothing alpha, not both".to_string())
+            }
+            (Some(window), None) if window > 0 => Smoothing::MovingAverage { window },
+            (None, Some(alpha)) if alpha > 0.0 && alpha <= 1.0 => Smoothing::Exponential { alpha },
+            (None, None) => normalization_settings.smoothing,
+            _ => {
+                return Err(
+                    "smoothing window must be at least 1, smoothing alpha from 0 to 1".to_string(),
+                )
+            }
+        };
+
+        if let Some(repeated_values) = &self.repeated_values {
+            normalization_settings.repeated_values = match repeated_values.as_str() {
+                "skip" => RepeatedValues::Skip,
+                "keep" => RepeatedValues::Keep,
+                other => return Err(format!("repeated values '{}' is not skip or keep", other)),
+            };
+        }
+
+        Ok(normalization_settings)
+    }
+
+    pub fn affect_settings(&self) -> Result<AffectSettings, String> {
+        let all: Vec<String> = BUILT_IN_METRICS
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+
+        AffectSettings::new(
+            self.valence_metric
+                .as_deref()
+                .unwrap_or("asymmetry_over_theta"),
+            self.arousal_metric.as_deref().unwrap_or("theta_over_alpha"),
+            self.drowsiness_metric
+                .as_deref()
+                .unwrap_or("slow_wave_ratio"),
+            self.affect_metrics.as_ref().unwrap_or(&all),
+        )
+    }
+
+    pub fn recorder_settings(&self) -> Result<RecorderSettings, String> {
+        let mut recorder_settings = RecorderSettings::default();
+        for setting in &self.message_sinks {
+            recorder_settings.set(setting)?;
+        }
+
+        Ok(recorder_settings)
+    }
+
+    pub fn session_settings(&self) -> SessionSettings {
+        let mut session_settings = SessionSettings {
+            rig_id: self.rig_id.clone(),
+            export_edf: self.export_edf.unwrap_or(false),
+            xdf: self.xdf.unwrap_or(false),
+            ..SessionSettings::default()
+        };
+        if let Some(data_root) = &self.data_root {
+            session_settings.data_root = data_root.clone();
+        }
+        if let Some(protocol) = &self.protocol {
+            session_settings.protocol = protocol.clone();
+        }
+
+        session_settings
+    }
+
+    pub fn calibration_settings(&self) -> Result<CalibrationSettings, String> {
+        let mut calibration_settings = CalibrationSettings {
+            participant_ids: self.participant_ids.clone(),
+            reuse: self.reuse_calibration.unwrap_or(false),
+            ..CalibrationSettings::default()
+        };
+
+        // Each id names a file in the calibration directory, so it must stay inside it
+        for participant_id in &calibration_settings.participant_ids {
+            if participant_id.is_empty()
+                || participant_id.contains(&['/', '\\'][..])
+                || participant_id.contains("..")
+            {
+                return Err(format!(
+                    "participant id '{}' can not be used as a file name",
+                    participant_id
+                ));
+            }
+        }
+
+        if let Some(seconds) = self.calibration_seconds {
+            if seconds <= 0 {
+                return Err(format!("calibration seconds {} must be positive", seconds));
+            }
+            calibration_settings.duration = Duration::seconds(seconds);
+        }
+
+        if let Some(directory) = &self.calibration_dir {
+            calibration_settings.directory = directory.clone();
+        }
+
+        Ok(calibration_settings)
+    }
+
+    /// How long the headbands must fit well before the session starts
+    pub fn fit_check_hold(&self) -> Result<Duration, String> {
+        match self.fit_check_seconds.unwrap_or(DEFAULT_FIT_CHECK_SECONDS) {
+            seconds if seconds >= 0 => Ok(Duration::seconds(seconds)),
+            seconds => Err(format!("fit check '{}' seconds is negative", seconds)),
+        }
+    }
+}
+
+/// "--listen 34254 --record=a.osc" -> [("listen", "34254"), ("record", "a.osc")]
+fn parse_args(args: &[String]) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            return Err(format!("unexpected argument '{}'", arg));
+        }
+        let option = &arg[2..];
+        let (key, value) = match option.find('=') {
+            Some(i) => (&option[..i], option[i + 1..].to_string()),
+            None => match args.next() {
+                Some(value) => (option, value.clone()),
+                None => return Err(format!("--{} needs a value", option)),
+            },
+        };
+        pairs.push((key.replace('-', "_"), value));
+    }
+
+    Ok(pairs)
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn parse_value<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a valid value", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recorder::Sink;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_default_listens_on_osc_port() {
+        let config = AppConfig::load_from(&[], |_| None).unwrap();
+
+        assert_eq!(Ok(EegSourceConfig::default()), config.eeg_source());
+    }
+
+    #[test]
+    fn test_several_listen_addresses() {
+        let mut config = AppConfig::default();
+        config.set("listen", "34254, 127.0.0.1:34264").unwrap();
+
+        assert_eq!(
+            Ok(vec![
+                SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 34254),
+                SocketAddrV4::new(Ipv4Addr::LOCALHOST, 34264)
+            ]),
+            config.listen_addresses()
+        );
+    }
+
+    #[test]
+    fn test_bad_listen_address() {
+        let mut config = AppConfig::default();
+        config.set("listen", "localhost:http").unwrap();
+
+        assert!(config.eeg_source().is_err());
+    }
+
+    #[test]
+    fn test_csv_replay() {
+        let mut config = AppConfig::default();
+        config.set("csv_replay", "sessions/earlier").unwrap();
+        config.set("replay_speed", "4").unwrap();
+
+        assert_eq!(
+            Ok(EegSourceConfig::Csv {
+                directory: PathBuf::from("sessions/earlier"),
+                speed: 4.0
+            }),
+            config.eeg_source()
+        );
+
+        for speed in ["0", "-2", "NaN", "inf"].iter() {
+            config.set("replay_speed", speed).unwrap();
+            assert!(config.eeg_source().is_err());
+        }
+    }
+
+    #[test]
+    fn test_file_settings() {
+        let config = AppConfig::parse(
+            "listen = [\"34254\", \"34264\"]\nrelay = 34255\nclock_offset = \"-40\"\n",
+        )
+        .unwrap();
+
+        assert_eq!(2, config.listen_addresses().unwrap().len());
+        assert_eq!(Some(34255), config.relay);
+        assert_eq!(
+            Ok(ClockOffsetMode::Fixed { offset_ms: -40 }),
+            config.clock_offset_mode()
+        );
+        assert!(AppConfig::parse("lisen = [\"34254\"]").is_err());
+    }
+
+    #[test]
+    fn test_filter_settings() {
+        let mut config = AppConfig::default();
+        assert_eq!(Ok(FilterSettings::default()), config.filter_settings());
+
+        config.set("notch", "60").unwrap();
+        config.set("high_pass", "off").unwrap();
+        config.set("band_pass", "1-45").unwrap();
+        assert_eq!(
+            Ok(FilterSettings {
+                notch: Some(60.0),
+                high_pass: None,
+                band_pass: Some((1.0, 45.0)),
+            }),
+            config.filter_settings()
+        );
+
+        config.set("band_pass", "45-1").unwrap();
+        assert!(config.filter_settings().is_err());
+        config.set("band_pass", "1-128").unwrap();
+        assert!(config.filter_settings().is_err());
+
+        config.set("band_pass", "off").unwrap();
+        for cut_off in ["0", "-1", "128", "NaN"].iter() {
+            config.set("high_pass", cut_off).unwrap();
+            assert!(config.filter_settings().is_err());
+        }
+    }
+
+    #[test]
+    fn test_artifact_policy() {
+        let mut config = AppConfig::default();
+        config.set("blink_exclusion_ms", "500").unwrap();
+        config.set("motion_exclusion_ms", "0").unwrap();
+        config.set("artifact_margin_ms", "100").unwrap();
+        config.set("max_horseshoe", "2").unwrap();
+        let artifact_policy = config.artifact_policy().unwrap();
+
+        assert_eq!(Duration::milliseconds(500), artifact_policy.blink_exclusion);
+        assert_eq!(Duration::zero(), artifact_policy.motion_exclusion);
+        assert_eq!(
+            Duration::milliseconds(100),
+            artifact_policy.pre_event_margin
+        );
+        assert_eq!([2.0; 4], artifact_policy.max_horseshoe);
+
+        config.set("max_horseshoe", "1,2").unwrap();
+        assert!(config.artifact_policy().is_err());
+
+        config.set("max_horseshoe", "2").unwrap();
+        for key in [
+            "blink_exclusion_ms",
+            "jaw_clench_exclusion_ms",
+            "motion_exclusion_ms",
+            "artifact_margin_ms",
+        ]
+        .iter()
+        {
+            let mut config = config.clone();
+            config.set(key, "-1").unwrap();
+            assert!(config.artifact_policy().is_err());
+        }
+    }
+
+    #[test]
+    fn test_calibration_settings() {
+        let mut config = AppConfig::default();
+        config.set("participant_ids", "alice, bob").unwrap();
+        config.set("reuse_calibration", "true").unwrap();
+        let calibration_settings = config.calibration_settings().unwrap();
+
+        assert_eq!("bob", calibration_settings.participant_id(1));
+        assert_eq!("P3", calibration_settings.participant_id(2));
+        assert!(calibration_settings.reuse);
+
+        config.set("calibration_seconds", "0").unwrap();
+        assert!(config.calibration_settings().is_err());
+
+        config.set("calibration_seconds", "30").unwrap();
+        for participant_ids in &["../alice, bob", "alice, a/b", "alice, a\\b", "alice, .."] {
+            config.set("participant_ids", participant_ids).unwrap();
+            assert!(config.calibration_settings().is_err());
+        }
+    }
+
+    #[test]
+    fn test_normalization_settings() {
+        let mut config = AppConfig::default();
+        assert_eq!(
+            Ok(NormalizationSettings::default()),
+            config.normalization_settings()
+        );
+
+        config.set("normalization", "median").unwrap();
+        config.set("smoothing_alpha", "0.25").unwrap();
+        config.set("repeated_values", "keep").unwrap();
+        let normalization_settings = config.normalization_settings().unwrap();
+        assert_eq!(
+            BaselineStatistic::MedianMad,
+            normalization_settings.statistic
+        );
+        assert_eq!(
+            Smoothing::Exponential { alpha: 0.25 },
+            normalization_settings.smoothing
+        );
+        assert_eq!(RepeatedValues::Keep, normalization_settings.repeated_values);
+
+        config.set("smoothing_window", "5").unwrap();
+        assert!(config.normalization_settings().is_err());
+    }
+
+    #[test]
+    fn test_affect_settings() {
+        let mut config = AppConfig::default();
+        assert_eq!(Ok(AffectSettings::default()), config.affect_settings());
+
+        config.set("valence_metric", "log_alpha_asymmetry").unwrap();
+        config.set("affect_metrics", "engagement").unwrap();
+        config
+            .set("drowsiness_metric", "theta_alpha_power")
+            .unwrap();
+        assert!(config.affect_settings().is_ok());
+
+        config.set("arousal_metric", "log_alpha_asymmetry").unwrap();
+        assert!(config.affect_settings().is_err());
+    }
+
+    #[test]
+    fn test_breath_driver() {
+        let mut config = AppConfig::default();
+        assert_eq!(Ok(BreathDriver::Paced), config.breath_driver());
+
+        config.set("breath_driver", "heart_rate").unwrap();
+        assert_eq!(Ok(BreathDriver::HeartRate), config.breath_driver());
+
+        config.set("breath_driver", "pulse").unwrap();
+        assert!(config.breath_driver().is_err());
+    }
+
+    #[test]
+    fn test_recorder_settings() {
+        let mut config = AppConfig::default();
+        assert_eq!(Ok(RecorderSettings::default()), config.recorder_settings());
+
+        config
+            .set("message_sinks", "raw_fft:off, gyro:csv")
+            .unwrap();
+        let recorder_settings = config.recorder_settings().unwrap();
+        assert_eq!(Sink::Off, recorder_settings.sink("raw_fft"));
+        assert_eq!(Sink::Csv, recorder_settings.sink("gyro"));
+
+        config.set("message_sinks", "raw_fft").unwrap();
+        assert!(config.recorder_settings().is_err());
+    }
+
+    #[test]
+    fn test_session_settings() {
+        let mut config = AppConfig::default();
+        assert_eq!(SessionSettings::default(), config.session_settings());
+
+        config.set("data_root", "/data/meme").unwrap();
+        config.set("rig_id", "rig 2").unwrap();
+        let session_settings = config.session_settings();
+        assert_eq!(PathBuf::from("/data/meme"), session_settings.data_root);
+        assert_eq!(Some("rig 2".to_string()), session_settings.rig_id);
+        assert_eq!("meme", session_settings.protocol);
+        assert!(!session_settings.export_edf);
+
+        config.set("export_edf", "true").unwrap();
+        config.set("xdf", "true").unwrap();
+        assert!(config.session_settings().export_edf);
+        assert!(config.session_settings().xdf);
+    }
+
+    #[test]
+    fn test_fit_check_hold() {
+        let mut config = AppConfig::default();
+        assert_eq!(Ok(Duration::seconds(5)), config.fit_check_hold());
+
+        config.set("fit_check_seconds", "0").unwrap();
+        assert_eq!(Ok(Duration::zero()), config.fit_check_hold());
+
+        config.set("fit_check_seconds", "-5").unwrap();
+        assert!(config.fit_check_hold().is_err());
+    }
+
+    #[test]
+    fn test_command_line_overrides_environment() {
+        let var = |name: &str| match name {
+            "MEME_OSC_LISTEN" => Some("34264".to_string()),
+            "MEME_SIMULATOR_SEED" => Some("7".to_string()),
+            _ => None,
+        };
+        let config = AppConfig::load_from(&args(&["--listen", "34274", "--relay="]), var).unwrap();
+
+        assert_eq!(vec!["34274".to_string()], config.listen);
+        assert_eq!(Some(7), config.simulator_seed);
+        assert_eq!(Some(WEBSOCKET_PORT), config.relay);
+    }
+
+    #[test]
+    fn test_bad_arguments() {
+        assert!(AppConfig::load_from(&args(&["--listen"]), |_| None).is_err());
+        assert!(AppConfig::load_from(&args(&["34254"]), |_| None).is_err());
+        assert!(AppConfig::load_from(&args(&["--no-such-setting", "1"]), |_| None).is_err());
+        assert!(AppConfig::load_from(&args(&["--replay-speed", "fast"]), |_| None).is_err());
+    }
+}
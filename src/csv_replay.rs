@@ -0,0 +1,279 @@
+/// Replay the EEG and band power CSV files of an earlier session, as if the headsets were live.
+///
+/// Each "<label> eeg.csv" in the directory is one headset, with its "<label> alpha.csv", "beta", "gamma",
+/// "delta" and "theta" files beside it where they were recorded. Rows from every file are merged in time
+/// order and delivered `speed` times faster than real time, the same as an OSC capture replay. The
+/// headsets are reported as 127.0.0.1, 127.0.0.2 and so on in label order, so each keeps its own model.
+use crate::edf::{read_rows, Row};
+use crate::eeg_source::OSC_PORT;
+use crate::muse_model::{EegMessageReceiver, MuseMessage, MuseMessageType, MusePacketError};
+use chrono::{DateTime, Local};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Instant;
+
+/// The recorder's file for each kind of message which can be replayed, "<label> eeg.csv" first
+const KINDS: [&str; 6] = ["eeg", "alpha", "beta", "gamma", "delta", "theta"];
+
+/// One CSV file, read a row ahead so the files can be merged by time
+struct CsvStream {
+    name: String, // File name, reported as the address of a row which can not be read
+    kind: &'static str,
+    addr: SocketAddr,
+    rows: Box<dyn Iterator<Item = Row>>,
+    next: Option<(DateTime<Local>, Vec<String>)>,
+}
+
+impl CsvStream {
+    fn open(path: &Path, kind: &'static str, addr: SocketAddr) -> io::Result<Self> {
+        let (_, rows) = read_rows(path).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let mut stream = Self {
+            name: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+            kind,
+            addr,
+            rows: Box::new(rows),
+            next: None,
+        };
+        stream.advance();
+
+        Ok(stream)
+    }
+
+    /// Read the next row. A file which can not be read further ends there.
+    fn advance(&mut self) {
+        self.next = match self.rows.next() {
+            Some(Ok(row)) => Some(row),
+            Some(Err(e)) => {
+                error!("Can not read CSV, replay of {} ended: {}", self.name, e);
+                None
+            }
+            None => None,
+        };
+    }
+
+    fn message(
+        &self,
+        time: DateTime<Local>,
+        values: &[String],
+    ) -> Result<MuseMessage, MusePacketError> {
+        let undecodable = |reason: String| MusePacketError::Undecodable {
+            addr: self.name.clone(),
+            reason,
+        };
+        let mut electrodes = [0.0; 4];
+        if values.len() < electrodes.len() {
+            return Err(undecodable("too few electrodes".to_string()));
+        }
+        for (electrode, value) in electrodes.iter_mut().zip(values.iter()) {
+            *electrode = value
+                .parse()
+                .map_err(|_| undecodable(format!("'{}' is not a number", value)))?;
+        }
+        let [a, b, c, d] = electrodes;
+        let muse_message_type = match self.kind {
+            "eeg" => MuseMessageType::Eeg { eeg: electrodes },
+            "alpha" => MuseMessageType::Alpha { alpha: electrodes },
+            "beta" => MuseMessageType::Beta { beta: electrodes },
+            "gamma" => MuseMessageType::Gamma { gamma: electrodes },
+            "delta" => MuseMessageType::Delta { a, b, c, d },
+            _ => MuseMessageType::Theta { a, b, c, d },
+        };
+
+        Ok(MuseMessage {
+            message_time: time,
+            receive_time: time,
+            timetag: None,
+            ip_address: self.addr,
+            muse_message_type,
+        })
+    }
+}
+
+/// The CSV files of one session directory, replayed in real time or faster
+pub struct CsvMessageReceiver {
+    streams: RefCell<Vec<CsvStream>>,
+    first_time: Option<DateTime<Local>>,
+    replay_start: Instant,
+    speed: f64, // 1.0 is real time, 10.0 replays ten times faster
+}
+
+impl CsvMessageReceiver {
+    /// Fails if the directory can not be read or has no eeg.csv
+    pub fn new(directory: &Path, speed: f64) -> io::Result<Self> {
+        info!(
+            "Replaying session CSV files from {:?} at {}x",
+            directory, speed
+        );
+        let suffix = format!(" {}.csv", KINDS[0]);
+        let mut labels = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.ends_with(&suffix) {
+                labels.push(name[..name.len() - suffix.len()].to_string());
+            }
+        }
+        if labels.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("No '<label>{}' in {:?}", suffix, directory),
+            ));
+        }
+        labels.sort();
+
+        let mut streams = Vec::new();
+        for (n, label) in labels.iter().enumerate() {
+            let addr = SocketAddr::from((Ipv4Addr::new(127, 0, 0, n as u8 + 1), OSC_PORT));
+            info!("Replaying {} as {}", label, addr);
+            for kind in KINDS.iter() {
+                let path = directory.join(format!("{} {}.csv", label, kind));
+                if path.exists() {
+                    streams.push(CsvStream::open(&path, kind, addr)?);
+                }
+            }
+        }
+        let first_time = streams
+            .iter()
+            .filter_map(|stream| stream.next.as_ref().map(|(time, _)| *time))
+            .min();
+
+        Ok(Self {
+            streams: RefCell::new(streams),
+            first_time,
+            replay_start: Instant::now(),
+            speed: speed.max(f64::MIN_POSITIVE),
+        })
+    }
+
+    /// Every row of every file has been delivered
+    pub fn is_finished(&self) -> bool {
+        self.streams
+            .borrow()
+            .iter()
+            .all(|stream| stream.next.is_none())
+    }
+
+    /// Rows which were recorded up to this many milliseconds after the first, in time order
+    fn replay_until(&self, session_elapsed_ms: i64) -> Vec<Result<MuseMessage, MusePacketError>> {
+        let first = match self.first_time {
+            Some(first) => first,
+            None => return Vec::new(),
+        };
+        let mut streams = self.streams.borrow_mut();
+        let mut due = Vec::new();
+
+        loop {
+            let earliest = streams
+                .iter_mut()
+                .filter(|stream| stream.next.is_some())
+                .min_by_key(|stream| stream.next.as_ref().map(|(time, _)| *time));
+            let stream = match earliest {
+                Some(stream) => stream,
+                None => break,
+            };
+            let (time, values) = stream.next.take().unwrap();
+            if (time - first).num_milliseconds() > session_elapsed_ms {
+                stream.next = Some((time, values));
+                break;
+            }
+            due.push(stream.message(time, &values));
+            stream.advance();
+        }
+
+        due
+    }
+}
+
+impl EegMessageReceiver for CsvMessageReceiver {
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        let real_elapsed_ms = self.replay_start.elapsed().as_micros() as f64 / 1000.0;
+        let due = self.replay_until((real_elapsed_ms * self.speed) as i64);
+        if !due.is_empty() && self.is_finished() {
+            info!("Session CSV replay finished");
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(directory: &Path, name: &str, text: &str) {
+        fs::write(directory.join(name), text).unwrap();
+    }
+
+    #[test]
+    fn test_session_files_merge_in_time_order() {
+        let directory = std::env::temp_dir().join("meme_test_csv_replay");
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        write(
+            &directory,
+            "P1 eeg.csv",
+            "Time,TP9,AF7,AF8,TP10\n\
+             2020-02-25 09:35:49.000,1.0,2.0,3.0,4.0\n\
+             2020-02-25 09:35:50.000,5.0,6.0,7.0,NaN\n",
+        );
+        write(
+            &directory,
+            "P1 theta.csv",
+            "Time,Theta TP9,Theta AF7,Theta AF8,Theta TP10\n\
+             2020-02-25 09:35:49.500,0.1,0.2,0.3,0.4\n",
+        );
+        write(
+            &directory,
+            "P2 alpha.csv",
+            "Time,Alpha TP9,Alpha AF7,Alpha AF8,Alpha TP10\n\
+             2020-02-25 09:35:49.200,0.5,x,0.5,0.5\n",
+        );
+        write(&directory, "P2 eeg.csv", "Time,TP9,AF7,AF8,TP10\n");
+        write(&directory, "P1 other.csv", "Time,Record\n");
+
+        let replay = CsvMessageReceiver::new(&directory, 1.0).unwrap();
+        let due = replay.replay_until(600);
+        assert_eq!(3, due.len());
+        let first = due[0].as_ref().unwrap();
+        match first.muse_message_type {
+            MuseMessageType::Eeg { eeg } => assert_eq!([1.0, 2.0, 3.0, 4.0], eeg),
+            ref other => panic!("Expected EEG, got {:?}", other),
+        }
+        assert_eq!(
+            SocketAddr::from(([127, 0, 0, 1], OSC_PORT)),
+            first.ip_address
+        );
+        assert_eq!(
+            &MusePacketError::Undecodable {
+                addr: "P2 alpha.csv".to_string(),
+                reason: "'x' is not a number".to_string()
+            },
+            due[1].as_ref().unwrap_err()
+        );
+        match due[2].as_ref().unwrap().muse_message_type {
+            MuseMessageType::Theta { a, d, .. } => assert_eq!((0.1, 0.4), (a, d)),
+            ref other => panic!("Expected theta, got {:?}", other),
+        }
+        assert!(!replay.is_finished());
+
+        let last = replay.replay_until(10_000);
+        assert_eq!(1, last.len());
+        assert!(replay.is_finished());
+
+        let _ = fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn test_directory_without_eeg() {
+        assert!(
+            CsvMessageReceiver::new(&std::env::temp_dir().join("meme_no_such_session"), 1.0)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,445 @@
+/// EDF+ export of raw EEG, for clinical tools which expect European Data Format.
+///
+/// The four Muse electrodes are written as 16 bit signals of 256 samples per one second data record,
+/// scaled over the 0 to 1682.815 microvolt range of the Muse raw EEG, and protocol events become EDF+
+/// annotations. Packets are sometimes lost, so the file is discontinuous EDF+D: samples are taken as
+/// evenly spaced within each run, a gap of more than half a second starts a new run, and every data
+/// record carries its own onset. The last record of a run is padded by repeating its last sample.
+///
+/// This module stands alone so the csv_to_edf converter can use it for earlier sessions.
+use chrono::{DateTime, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const SAMPLE_RATE: usize = 256; // Muse raw EEG samples per second per electrode
+const ELECTRODES: [&str; 4] = ["TP9", "AF7", "AF8", "TP10"];
+const PHYSICAL_MIN: f32 = 0.0; // microVolts
+const PHYSICAL_MAX: f32 = 1682.815;
+const DIGITAL_MIN: i32 = -32768;
+const DIGITAL_MAX: i32 = 32767;
+const MAX_GAP_MS: i64 = 500; // A longer pause between samples starts a new run of data records
+const CSV_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f"; // As written by muse_model::date_time_csv_format
+const LEGACY_EVENT_PREFIXES: [&str; 3] = ["Image:", "LocalFrame:", "Sound:"]; // Protocol rows of other.csv, among headset values and notes
+
+/// One second of samples, in digital values
+struct DataRecord {
+    onset: f64, // Seconds after the file start time
+    samples: [Vec<i16>; 4],
+    annotations: Vec<(f64, String)>,
+}
+
+/// Raw EEG and events of one participant, ready to write as EDF+
+pub struct EdfRecording {
+    patient: String,
+    samples: Vec<(DateTime<Local>, [f32; 4])>,
+    annotations: Vec<(DateTime<Local>, String)>,
+}
+
+impl EdfRecording {
+    /// The patient code identifies the participant in the file header, for example their participant ID
+    pub fn new(patient: &str) -> Self {
+        EdfRecording {
+            patient: patient.to_string(),
+            samples: Vec::new(),
+            annotations: Vec::new(),
+        }
+    }
+
+    /// Samples must be added in time order
+    pub fn push_sample(&mut self, time: DateTime<Local>, eeg: [f32; 4]) {
+        self.samples.push((time, eeg));
+    }
+
+    pub fn annotate(&mut self, time: DateTime<Local>, text: &str) {
+        self.annotations.push((time, text.to_string()));
+    }
+
+    /// Read a headset's eeg.csv, and the session's events.csv as annotations such as
+    /// "StimulusOnset:NEGATIVE:3". Sessions from before events.csv have their events in other.csv,
+    /// of which only the protocol rows such as "Sound:NEGATIVE_A:OK" become annotations.
+    pub fn read_csv(
+        patient: &str,
+        eeg_csv: &Path,
+        events_csv: Option<&Path>,
+    ) -> Result<Self, String> {
+        let mut recording = EdfRecording::new(patient);
+        let (_, rows) = read_rows(eeg_csv)?;
+        for row in rows {
+            let (time, values) = row?;
+            let mut eeg = [0.0; 4];
+            if values.len() < eeg.len() {
+                return Err(format!("{}: too few electrodes", eeg_csv.display()));
+            }
+            for (electrode, value) in eeg.iter_mut().zip(values.iter()) {
+                *electrode = value
+                    .parse()
+                    .map_err(|_| format!("{}: '{}' is not a number", eeg_csv.display(), value))?;
+            }
+            recording.push_sample(time, eeg);
+        }
+        if let Some(events_csv) = events_csv {
+            let (columns, rows) = read_rows(events_csv)?;
+            let legacy = columns == ["Record"];
+            for row in rows {
+                let (time, values) = row?;
+                let text: Vec<String> = values
+                    .into_iter()
+                    .zip(columns.iter())
+                    .filter(|(value, column)| !value.is_empty() && *column != "Seconds")
+                    .map(|(value, _)| value)
+                    .collect();
+                let text = text.join(":");
+                if legacy
+                    && !LEGACY_EVENT_PREFIXES
+                        .iter()
+                        .any(|prefix| text.starts_with(prefix))
+                {
+                    continue;
+                }
+                recording.annotate(time, &text);
+            }
+        }
+
+        Ok(recording)
+    }
+
+    /// Write the EDF+ file. Returns the number of one second data records.
+    pub fn write(&self, path: &Path) -> Result<usize, String> {
+        let first = self
+            .samples
+            .first()
+            .map(|(time, _)| *time)
+            .ok_or_else(|| "No EEG samples to export".to_string())?;
+        let start = first.with_nanosecond(0).unwrap_or(first);
+        let records = self.data_records(start);
+        let annotation_bytes = records
+            .iter()
+            .map(|record| annotation_tals(record).len())
+            .max()
+            .unwrap_or(0);
+        let annotation_samples = annotation_bytes / 2 + annotation_bytes % 2;
+
+        let write = || -> std::io::Result<()> {
+            let mut file = BufWriter::new(File::create(path)?);
+            file.write_all(&self.header(start, records.len(), annotation_samples))?;
+            for record in records.iter() {
+                for signal in record.samples.iter() {
+                    for sample in signal {
+                        file.write_all(&sample.to_le_bytes())?;
+                    }
+                }
+                let mut tals = annotation_tals(record);
+                tals.resize(annotation_samples * 2, 0);
+                file.write_all(&tals)?;
+            }
+            file.flush()
+        };
+        write().map_err(|e| format!("Can not write {}: {}", path.display(), e))?;
+
+        Ok(records.len())
+    }
+
+    fn data_records(&self, start: DateTime<Local>) -> Vec<DataRecord> {
+        let seconds = |time: DateTime<Local>| {
+            (time - start).num_microseconds().unwrap_or(0) as f64 / 1_000_000.0
+        };
+        let mut records: Vec<DataRecord> = Vec::new();
+        let mut run_start = start;
+        let mut run_samples = 0;
+        let mut previous: Option<DateTime<Local>> = None;
+
+        for (time, eeg) in self.samples.iter() {
+            let is_gap = match previous {
+                Some(previous) => *time - previous > Duration::milliseconds(MAX_GAP_MS),
+                None => true,
+            };
+            if is_gap {
+                pad(records.last_mut());
+                run_start = *time;
+                run_samples = 0;
+            }
+            if run_samples % SAMPLE_RATE == 0 {
+                records.push(DataRecord {
+                    onset: seconds(run_start) + (run_samples / SAMPLE_RATE) as f64,
+                    samples: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+                    annotations: Vec::new(),
+                });
+            }
+            let record = records.last_mut().expect("Record was just added");
+            for (signal, value) in record.samples.iter_mut().zip(eeg.iter()) {
+                signal.push(digital(*value));
+            }
+            run_samples += 1;
+            previous = Some(*time);
+        }
+        pad(records.last_mut());
+
+        for (time, text) in self.annotations.iter() {
+            let onset = seconds(*time);
+            let index = records
+                .iter()
+                .rposition(|record| record.onset <= onset)
+                .unwrap_or(0);
+            if let Some(record) = records.get_mut(index) {
+                record.annotations.push((onset, text.clone()));
+            }
+        }
+
+        records
+    }
+
+    fn header(&self, start: DateTime<Local>, records: usize, annotation_samples: usize) -> Vec<u8> {
+        let signals = ELECTRODES.len() + 1;
+        let mut header = String::new();
+        let each = |header: &mut String, eeg: &str, annotations: &str, width: usize| {
+            for _ in ELECTRODES.iter() {
+                field(header, eeg, width);
+            }
+            field(header, annotations, width);
+        };
+
+        field(&mut header, "0", 8);
+        field(
+            &mut header,
+            &format!("{} X X X", subfield(&self.patient)),
+            80,
+        );
+        let startdate = start.format("%d-%b-%Y").to_string().to_uppercase();
+        field(
+            &mut header,
+            &format!("Startdate {} X X Muse", startdate),
+            80,
+        );
+        field(&mut header, &start.format("%d.%m.%y").to_string(), 8);
+        field(&mut header, &start.format("%H.%M.%S").to_string(), 8);
+        field(&mut header, &(256 * (signals + 1)).to_string(), 8);
+        field(&mut header, "EDF+D", 44);
+        field(&mut header, &records.to_string(), 8);
+        field(&mut header, "1", 8); // Seconds per data record
+        field(&mut header, &signals.to_string(), 4);
+        for electrode in ELECTRODES.iter() {
+            field(&mut header, &format!("EEG {}", electrode), 16);
+        }
+        field(&mut header, "EDF Annotations", 16);
+        each(&mut header, "Dry electrode", "", 80);
+        each(&mut header, "uV", "", 8);
+        each(&mut header, &PHYSICAL_MIN.to_string(), "-1", 8);
+        each(&mut header, &PHYSICAL_MAX.to_string(), "1", 8);
+        each(
+            &mut header,
+            &DIGITAL_MIN.to_string(),
+            &DIGITAL_MIN.to_string(),
+            8,
+        );
+        each(
+            &mut header,
+            &DIGITAL_MAX.to_string(),
+            &DIGITAL_MAX.to_string(),
+            8,
+        );
+        each(&mut header, "", "", 80); // Prefiltering, none for raw EEG
+        each(
+            &mut header,
+            &SAMPLE_RATE.to_string(),
+            &annotation_samples.to_string(),
+            8,
+        );
+        each(&mut header, "", "", 32);
+
+        header.into_bytes()
+    }
+}
+
+/// Time and the remaining values of one CSV row
+pub type Row = Result<(DateTime<Local>, Vec<String>), String>;
+
+/// The column names after Time, and the rows of a CSV file with a Time column first
+pub fn read_rows(path: &Path) -> Result<(Vec<String>, impl Iterator<Item = Row>), String> {
+    let name = path.display().to_string();
+    let mut reader = csv::ReaderBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| format!("Can not read {}: {}", name, e))?;
+    let columns = reader
+        .headers()
+        .map_err(|e| format!("Can not read {}: {}", name, e))?
+        .iter()
+        .skip(1)
+        .map(String::from)
+        .collect();
+
+    let rows = reader.into_records().map(move |record| {
+        let record = record.map_err(|e| format!("Can not read {}: {}", name, e))?;
+        let time = record.get(0).unwrap_or("");
+        let time = NaiveDateTime::parse_from_str(time, CSV_TIME_FORMAT)
+            .ok()
+            .and_then(|time| Local.from_local_datetime(&time).earliest())
+            .ok_or_else(|| format!("{}: '{}' is not a time", name, time))?;
+
+        Ok((time, record.iter().skip(1).map(String::from).collect()))
+    });
+
+    Ok((columns, rows))
+}
+
+/// Microvolts to the 16 bit range, clipped. Missing values are the lowest digital value.
+fn digital(value: f32) -> i16 {
+    if !value.is_finite() {
+        return DIGITAL_MIN as i16;
+    }
+    let scale = (DIGITAL_MAX - DIGITAL_MIN) as f32 / (PHYSICAL_MAX - PHYSICAL_MIN);
+    let clipped = PHYSICAL_MIN.max(value.min(PHYSICAL_MAX));
+
+    ((clipped - PHYSICAL_MIN) * scale + DIGITAL_MIN as f32).round() as i16
+}
+
+/// Complete a short record by repeating its last sample
+fn pad(record: Option<&mut DataRecord>) {
+    if let Some(record) = record {
+        for signal in record.samples.iter_mut() {
+            let last = signal.last().cloned().unwrap_or(DIGITAL_MIN as i16);
+            signal.resize(SAMPLE_RATE, last);
+        }
+    }
+}
+
+/// The record's time-keeping TAL, then one TAL for each annotation
+fn annotation_tals(record: &DataRecord) -> Vec<u8> {
+    let mut tals = format!("{}\x14\x14\x00", onset(record.onset));
+    for (time, text) in record.annotations.iter() {
+        let text: String = text.chars().filter(|c| !c.is_control()).collect();
+        tals.push_str(&format!("{}\x14{}\x14\x00", onset(*time), text));
+    }
+
+    tals.into_bytes()
+}
+
+/// "+12.345"
+fn onset(seconds: f64) -> String {
+    let formatted = format!("{:+.3}", seconds);
+
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Spaces separate the subfields of the patient and recording identification
+fn subfield(value: &str) -> String {
+    value.replace(' ', "_")
+}
+
+/// Left aligned, padded with spaces and cut to the width, printable ASCII only
+fn field(header: &mut String, value: &str, width: usize) {
+    let value: String = value
+        .chars()
+        .map(|c| {
+            if c.is_ascii_graphic() || c == ' ' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(width)
+        .collect();
+    header.push_str(&format!("{:width$}", value, width = width));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn at(ms: i64) -> DateTime<Local> {
+        Local.timestamp_millis(1_582_616_149_250 + ms)
+    }
+
+    #[test]
+    fn test_digital() {
+        assert_eq!(-32768, digital(0.0));
+        assert_eq!(32767, digital(PHYSICAL_MAX));
+        assert_eq!(32767, digital(5000.0));
+        assert!(digital(PHYSICAL_MAX / 2.0).abs() <= 1);
+        assert_eq!(-32768, digital(f32::NAN));
+    }
+
+    #[test]
+    fn test_gap_starts_new_record() {
+        let mut recording = EdfRecording::new("alice");
+        for i in 0..300 {
+            recording.push_sample(at(i * 1000 / 256), [800.0; 4]);
+        }
+        for i in 0..10 {
+            recording.push_sample(at(5000 + i * 4), [800.0; 4]);
+        }
+        recording.annotate(at(5010), "Image:NEGATIVE_B");
+        let records = recording.data_records(at(-250));
+
+        let onsets: Vec<f64> = records.iter().map(|record| record.onset).collect();
+        assert_eq!(vec![0.25, 1.25, 5.25], onsets);
+        assert!(records.iter().all(|record| record.samples[3].len() == 256));
+        assert_eq!("+5.26", onset(records[2].annotations[0].0));
+    }
+
+    #[test]
+    fn test_legacy_other_csv_events() {
+        let directory = std::env::temp_dir().join("meme edf legacy test");
+        fs::create_dir_all(&directory).unwrap();
+        let eeg_csv = directory.join("eeg.csv");
+        let other_csv = directory.join("other.csv");
+        fs::write(
+            &eeg_csv,
+            "Time,TP9,AF7,AF8,TP10\n2020-02-25 09:35:49.250,800.1,801.3,799.0,802.2\n",
+        )
+        .unwrap();
+        fs::write(
+            &other_csv,
+            "Time,Record\n\
+             2020-02-25 09:35:49.250,Sound:NEGATIVE_A:OK\n\
+             2020-02-25 09:35:49.260,\"Accel, 0.1, 0.2, 0.9\"\n\
+             2020-02-25 09:35:49.270,\"Battery, 87\"\n\
+             2020-02-25 09:35:49.280,Image:NEGATIVE:3\n\
+             2020-02-25 09:35:49.290,Application shutdown by ESC key\n",
+        )
+        .unwrap();
+
+        let recording = EdfRecording::read_csv("alice", &eeg_csv, Some(&other_csv)).unwrap();
+        let annotations: Vec<&str> = recording
+            .annotations
+            .iter()
+            .map(|(_, text)| text.as_str())
+            .collect();
+        assert_eq!(vec!["Sound:NEGATIVE_A:OK", "Image:NEGATIVE:3"], annotations);
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_write_edf() {
+        let mut recording = EdfRecording::new("alice smith");
+        for i in 0..512 {
+            recording.push_sample(at(i * 1000 / 256), [100.0, 200.0, 300.0, 400.0]);
+        }
+        recording.annotate(at(1500), "LocalFrame:POSITIVE");
+        let path = std::env::temp_dir().join("meme edf test.edf");
+
+        assert_eq!(Ok(2), recording.write(&path));
+        let bytes = fs::read(&path).unwrap();
+        let header = String::from_utf8_lossy(&bytes[..256 * 6]).to_string();
+        assert!(header.starts_with("0       alice_smith X X X"));
+        assert_eq!("EDF+D", header[192..197].to_string());
+        assert_eq!("2       1       5   EEG TP9", header[236..263].to_string());
+
+        let annotation_samples: usize = header[256 + 216 * 5 + 8 * 4..256 + 216 * 5 + 8 * 5]
+            .trim()
+            .parse()
+            .unwrap();
+        let record_bytes = 2 * (4 * 256 + annotation_samples);
+        assert_eq!(256 * 6 + 2 * record_bytes, bytes.len());
+        let second_record = String::from_utf8_lossy(&bytes[256 * 6 + record_bytes..]);
+        assert!(second_record.contains("+1.25\x14\x14\x00+1.75\x14LocalFrame:POSITIVE\x14"));
+
+        fs::remove_file(path).unwrap();
+    }
+}
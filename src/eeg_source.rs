@@ -0,0 +1,269 @@
+/// Where EEG messages come from. Each source implements muse_model::EegMessageReceiver so MuseModel
+/// does not need to know if it is listening to a headset, a capture file, session CSV files or the simulator.
+use crate::csv_replay::CsvMessageReceiver;
+use crate::muse_model::{EegMessageReceiver, MuseMessage, MusePacketError};
+use crate::muse_packet::parse_muse_packet;
+use crate::muse_simulator::{Scenario, SimulatedHeadset, DEFAULT_OSC_PORT};
+use crate::osc_capture::{RecordingMessageReceiver, ReplayMessageReceiver};
+use crate::wire_format::receive_frame;
+use nannou_osc::Packet;
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Instant;
+
+pub const OSC_PORT: u16 = 34254; // Default port Mind Monitor sends to
+
+/// Settings for one EEG input source
+#[derive(Clone, Debug, PartialEq)]
+pub enum EegSourceConfig {
+    /// Live OSC packets from Mind Monitor or the muse_simulator binary, on one or more addresses
+    Osc { listen: Vec<SocketAddrV4> },
+    /// Live OSC, also writing every packet to a capture file
+    Record {
+        listen: Vec<SocketAddrV4>,
+        path: PathBuf,
+    },
+    /// An earlier capture file, `speed` times faster than real time
+    Replay { path: PathBuf, speed: f64 },
+    /// The eeg.csv and band power CSV files of an earlier session directory, `speed` times faster than real time
+    Csv { directory: PathBuf, speed: f64 },
+    /// The built in synthetic headset. `scenario` is a built in name or the path of a script file
+    Simulator { scenario: String, seed: u64 },
+    /// Another copy of the app relaying its headset over WebSocket, for example "ws://10.0.0.5:34255"
+    Relay { url: String },
+}
+
+impl Default for EegSourceConfig {
+    fn default() -> Self {
+        EegSourceConfig::Osc {
+            listen: vec![SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, OSC_PORT)],
+        }
+    }
+}
+
+impl EegSourceConfig {
+    /// Open the source. Fails if a port is already in use or a file can not be opened.
+    pub fn create_receiver(&self) -> io::Result<Box<dyn EegMessageReceiver>> {
+        let receiver: Box<dyn EegMessageReceiver> = match self {
+            EegSourceConfig::Osc { listen } => Box::new(OscMessageReceiver::new(listen)?),
+            EegSourceConfig::Record { listen, path } => {
+                Box::new(RecordingMessageReceiver::new(listen, path)?)
+            }
+            EegSourceConfig::Replay { path, speed } => {
+                Box::new(ReplayMessageReceiver::new(path, *speed)?)
+            }
+            EegSourceConfig::Csv { directory, speed } => {
+                Box::new(CsvMessageReceiver::new(directory, *speed)?)
+            }
+            EegSourceConfig::Simulator { scenario, seed } => {
+                let scenario = match Scenario::named(scenario) {
+                    Some(scenario) => scenario,
+                    None => Scenario::parse(&fs::read_to_string(scenario)?)
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
+                };
+
+                Box::new(SimulatedMessageReceiver::new(scenario, *seed))
+            }
+            EegSourceConfig::Relay { url } => Box::new(RelayMessageReceiver::new(url)),
+        };
+
+        Ok(receiver)
+    }
+}
+
+/// UDP sockets receiving OSC, one for each listen address
+pub struct OscSockets {
+    receivers: Vec<nannou_osc::Receiver>,
+}
+
+impl OscSockets {
+    /// Bind every address, or fail naming the first address which can not be bound
+    pub fn bind(listen: &[SocketAddrV4]) -> io::Result<Self> {
+        let mut receivers = Vec::with_capacity(listen.len());
+
+        for addr in listen {
+            info!("Listening for EEG on {}", addr);
+            let receiver = nannou_osc::Receiver::bind_to(*addr).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Can not listen for OSC on {}: {}", addr, e),
+                )
+            })?;
+            receivers.push(receiver);
+        }
+
+        Ok(Self { receivers })
+    }
+
+    /// Packets which have arrived on any socket since the last call
+    pub fn try_recv_all(&self) -> Vec<(Packet, SocketAddr)> {
+        self.receivers
+            .iter()
+            .flat_map(|receiver| receiver.try_iter())
+            .collect()
+    }
+}
+
+/// Live OSC packets arriving on one or more UDP ports
+pub struct OscMessageReceiver {
+    sockets: OscSockets,
+}
+
+impl OscMessageReceiver {
+    pub fn new(listen: &[SocketAddrV4]) -> io::Result<Self> {
+        let sockets = OscSockets::bind(listen)?;
+
+        Ok(Self { sockets })
+    }
+}
+
+impl EegMessageReceiver for OscMessageReceiver {
+    /// Receive any pending osc packets.
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        let receivables: Vec<(Packet, SocketAddr)> = self.sockets.try_recv_all();
+
+        let mut muse_messages: Vec<Result<MuseMessage, MusePacketError>> = Vec::new();
+
+        for (packet, addr) in receivables {
+            let mut additional_messages: Vec<Result<MuseMessage, MusePacketError>> =
+                parse_muse_packet(addr, &packet);
+            muse_messages.append(&mut additional_messages);
+        }
+
+        muse_messages
+    }
+}
+
+/// Used in place of a source which could not be opened, so the app can start and show the error
+pub struct DisconnectedMessageReceiver {}
+
+impl EegMessageReceiver for DisconnectedMessageReceiver {
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        Vec::new()
+    }
+}
+
+/// The synthetic headset running inside the app in real time, without a network
+pub struct SimulatedMessageReceiver {
+    headset: RefCell<SimulatedHeadset>,
+    start: Instant,
+    addr: SocketAddr, // Reported as the source of every message
+}
+
+impl SimulatedMessageReceiver {
+    pub fn new(scenario: Scenario, seed: u64) -> Self {
+        info!("Simulating EEG");
+
+        Self {
+            headset: RefCell::new(SimulatedHeadset::new(scenario, seed)),
+            start: Instant::now(),
+            addr: SocketAddr::from(([127, 0, 0, 1], DEFAULT_OSC_PORT)),
+        }
+    }
+}
+
+impl EegMessageReceiver for SimulatedMessageReceiver {
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        let elapsed = self.start.elapsed();
+        let elapsed_seconds = elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1e9;
+        let mut headset = self.headset.borrow_mut();
+        let mut muse_messages = Vec::new();
+
+        while headset.time() < elapsed_seconds {
+            for message in headset.step() {
+                if !headset.drop_packet() {
+                    muse_messages
+                        .append(&mut parse_muse_packet(self.addr, &Packet::Message(message)));
+                }
+            }
+        }
+
+        muse_messages
+    }
+}
+
+/// Frames from another app's WebSocket relay, decoded as on the web build. The connection runs on its own thread.
+pub struct RelayMessageReceiver {
+    url: String,
+    frames: Receiver<String>,
+}
+
+impl RelayMessageReceiver {
+    pub fn new(url: &str) -> Self {
+        info!("Connecting to EEG relay {}", url);
+        let (tx_frames, frames) = mpsc::channel();
+        let connect_url = url.to_string();
+
+        thread::spawn(move || {
+            let result = ws::connect(connect_url.clone(), |_out: ws::Sender| RelayClient {
+                frames: tx_frames.clone(),
+            });
+            if let Err(e) = result {
+                error!("EEG relay {} connection ended: {}", connect_url, e);
+            }
+        });
+
+        Self {
+            url: url.to_string(),
+            frames,
+        }
+    }
+}
+
+/// Passes each text frame from the relay to the receiver
+struct RelayClient {
+    frames: Sender<String>,
+}
+
+impl ws::Handler for RelayClient {
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        if let Ok(text) = message.into_text() {
+            let _ = self.frames.send(text);
+        }
+
+        Ok(())
+    }
+}
+
+impl EegMessageReceiver for RelayMessageReceiver {
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        self.frames
+            .try_iter()
+            .filter_map(|frame| receive_frame(&frame, &self.url))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simulated_messages_parse() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], DEFAULT_OSC_PORT));
+        let mut headset = SimulatedHeadset::new(Scenario::named("demo").unwrap(), 3);
+
+        for _ in 0..2560 {
+            for message in headset.step() {
+                for result in parse_muse_packet(addr, &Packet::Message(message)) {
+                    assert!(result.is_ok(), "Simulator sent {:?}", result);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_scenario_file() {
+        let config = EegSourceConfig::Simulator {
+            scenario: "no such scenario or file".to_string(),
+            seed: 1,
+        };
+
+        assert!(config.create_receiver().is_err());
+    }
+}
@@ -1,4 +1,5 @@
-use crate::muse_model::MuseModel;
+use crate::muse_model::{Estimate, HeadsetModel, MuseModel};
+use crate::signal_quality::{FitCheck, GOOD_SIGNAL_QUALITY};
 use crate::*;
 use core::f32::consts::PI;
 
@@ -75,6 +76,21 @@ const SPIDER_GRAPH_LABEL_OFFSET: Vector = Vector { x: -160., y: -160. }; // Shif
 const FREQUENCY_LABEL_OFFSET: Vector = Vector { x: 0.5, y: -1.5 }; // Shift letters up slightly to center in the circle
 const SPIDER_SCALE: f32 = 150.0; // Make alpha etc larger for display purposes
 
+const STR_FIT_CHECK: &str = "Adjust the headband until every circle is green";
+const FIT_CHECK_ELECTRODE_SPACING: f32 = 250.0; // Between circles in a row, and between participant rows
+const FIT_CHECK_RADIUS: f32 = 90.0;
+const FIT_CHECK_BAR_SIZE: Vector = Vector { x: 800.0, y: 20.0 }; // Fills as the good fit is held
+const COLOR_FIT_CHECK_BAR: Color = Color {
+    r: 0.25,
+    g: 0.25,
+    b: 0.25,
+    a: 1.0,
+};
+
+const ESTIMATE_RANGE: f32 = 3.0; // Standard deviations either side of the baseline shown by the F2 and F3 views
+const ESTIMATE_SIZE: f32 = 0.3; // Largest circle of the F2 and F3 views, before the display scale
+const MIN_ESTIMATE_OPACITY: f32 = 0.25; // Circle opacity at zero confidence
+
 const IMAGE_SET_SIZE: usize = 25;
 pub struct ImageSet {
     _images: [Asset<Image>; IMAGE_SET_SIZE],
@@ -121,6 +137,7 @@ pub struct EegViewState {
     clench_box: LabeledBox,
     graph_label_images: [Asset<Image>; N_EEG_CHANNELS],
     frequency_label_images: [Asset<Image>; N_EEG_DERIVED_VALUES],
+    fit_check_text: Asset<Image>,
     _calm_ext: ImageSet,
     _pos_neg: ImageSet,
     _valence_index: usize,
@@ -219,6 +236,12 @@ impl EegViewState {
             ),
             graph_label_images,
             frequency_label_images,
+            fit_check_text: Asset::new(Font::load(FONT_MULI).and_then(|font| {
+                result(font.render(
+                    STR_FIT_CHECK,
+                    &FontStyle::new(FONT_GRAPH_LABEL_SIZE, Color::WHITE),
+                ))
+            })),
             _calm_ext: ImageSet::new("calm_ex"),
             _pos_neg: ImageSet::new("pos_neg"),
             _valence_index: 5,
@@ -229,35 +252,97 @@ impl EegViewState {
 
 /// Render concenctric circules associated with alpha, beta, gamma..
 pub fn draw_view(muse_model: &MuseModel, window: &mut Window, eeg_view_state: &mut EegViewState) {
+    let headset = match muse_model.primary() {
+        Some(headset) => headset,
+        None => return, // The first participant's headset has not connected yet
+    };
+    let scale = muse_model.scale;
+
     match muse_model.display_type {
-        DisplayType::Mandala => draw_mandala_view(muse_model, window, eeg_view_state),
-        DisplayType::Dowsiness => draw_drowsiness_view(muse_model, window),
-        DisplayType::Emotion => draw_emotion_sun_view(muse_model, window),
-        DisplayType::EegValues => draw_eeg_values_view(muse_model, window, eeg_view_state),
+        DisplayType::Mandala => draw_mandala_view(headset, window, eeg_view_state),
+        DisplayType::Dowsiness => draw_drowsiness_view(headset, scale, window),
+        DisplayType::Emotion => draw_emotion_sun_view(headset, scale, window),
+        DisplayType::EegValues => draw_eeg_values_view(headset, window, eeg_view_state),
     }
 }
 
-/// A bigger yellow circle indiates greater happiness. Maybe.
-fn draw_emotion_sun_view(model: &MuseModel, window: &mut Window) {
-    let asymm = model.calc_absolute_valence();
+/// One row of electrode circles for each participant, coloured by signal quality, and a bar showing how long the good fit has been held
+pub fn draw_fit_check(
+    muse_model: &MuseModel,
+    fit_check: &FitCheck,
+    current_time: DateTime<Local>,
+    window: &mut Window,
+    eeg_view_state: &mut EegViewState,
+) {
+    let center = Vector::new(SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 / 2.0);
 
-    draw_circle(&COLOR_EMOTION, asymm / 5.0, window, model.scale, (0.0, 0.0));
+    let _result = eeg_view_state.fit_check_text.execute(|image| {
+        window.draw(
+            &image.area().with_center((center.x, SCREEN_SIZE.1 / 6.0)),
+            Img(&image),
+        );
+        Ok(())
+    });
+
+    let headsets = muse_model.headsets();
+    let first_row = center.y - (headsets.len() as f32 - 1.0) * FIT_CHECK_ELECTRODE_SPACING / 2.0;
+    for (row, headset) in headsets.iter().enumerate() {
+        let y = first_row + row as f32 * FIT_CHECK_ELECTRODE_SPACING;
+        for (chan, quality) in headset.signal_quality().iter().enumerate() {
+            let x = center.x + (chan as f32 - 1.5) * FIT_CHECK_ELECTRODE_SPACING;
+            let color = if *quality >= GOOD_SIGNAL_QUALITY {
+                Color::GREEN
+            } else if *quality >= 0.5 {
+                Color::YELLOW
+            } else {
+                Color::RED
+            };
+            window.draw(&Circle::new((x, y), FIT_CHECK_RADIUS), Col(color));
+            let _result = eeg_view_state.graph_label_images[chan].execute(|image| {
+                window.draw(&image.area().with_center((x, y)), Img(&image));
+                Ok(())
+            });
+        }
+    }
+
+    let bar_position = Vector::new(
+        center.x - FIT_CHECK_BAR_SIZE.x / 2.0,
+        SCREEN_SIZE.1 * 5.0 / 6.0,
+    );
+    let held = Vector::new(
+        FIT_CHECK_BAR_SIZE.x * fit_check.progress(current_time),
+        FIT_CHECK_BAR_SIZE.y,
+    );
+    window.draw(
+        &Rectangle::new(bar_position, FIT_CHECK_BAR_SIZE),
+        Col(COLOR_FIT_CHECK_BAR),
+    );
+    window.draw(&Rectangle::new(bar_position, held), Col(Color::GREEN));
 }
 
-fn draw_drowsiness_view(model: &MuseModel, window: &mut Window) {
-    let lizard_mind = (muse_model::average_from_front_electrodes(&model.theta)
-        + muse_model::average_from_front_electrodes(&model.delta))
-        / 2.0;
+/// A bigger yellow circle indicates more positive emotion than during calibration
+fn draw_emotion_sun_view(model: &HeadsetModel, scale: f32, window: &mut Window) {
+    draw_estimate(&COLOR_EMOTION, model.emotion_estimate, window, scale);
+}
 
-    draw_circle(&COLOR_THETA, lizard_mind, window, model.scale, (0.0, 0.0));
+/// A bigger circle indicates a drowsier participant than during calibration
+fn draw_drowsiness_view(model: &HeadsetModel, scale: f32, window: &mut Window) {
+    draw_estimate(&COLOR_THETA, model.drowsiness_estimate, window, scale);
+}
 
-    draw_circle(
-        &COLOR_ALPHA,
-        muse_model::average_from_front_electrodes(&model.alpha),
-        window,
-        model.scale,
-        (0.0, 0.0),
-    );
+/// Full size at ESTIMATE_RANGE standard deviations above the baseline, nothing at ESTIMATE_RANGE below. Faint when the estimate has little confidence.
+fn draw_estimate(color: &Color, estimate: Estimate, window: &mut Window, scale: f32) {
+    if let Some(value) = estimate.value {
+        let size = ((value + ESTIMATE_RANGE) / (2.0 * ESTIMATE_RANGE))
+            .max(0.0)
+            .min(1.0);
+        let color = Color {
+            a: MIN_ESTIMATE_OPACITY + (1.0 - MIN_ESTIMATE_OPACITY) * estimate.confidence,
+            ..*color
+        };
+
+        draw_circle(&color, size * ESTIMATE_SIZE, window, scale, (0.0, 0.0));
+    }
 }
 
 // TODO Add maximum slew rate to visualized value for mandala to change "smoothly"
@@ -266,7 +351,11 @@ fn _range_raw_values_to_0_to_9(val: f32) -> usize {
     ((val + 3.0) / 0.6).max(0.0).min(9.0) as usize
 }
 
-fn draw_mandala_view(_model: &MuseModel, _window: &mut Window, _eeg_view_state: &mut EegViewState) {
+fn draw_mandala_view(
+    _model: &HeadsetModel,
+    _window: &mut Window,
+    _eeg_view_state: &mut EegViewState,
+) {
     // match (
     //     model.valence.moving_average(),
     //     model.arousal.moving_average(),
@@ -313,7 +402,7 @@ fn draw_circle(line_color: &Color, value: f32, window: &mut Window, scale: f32,
 
 /// A set of all EEG values displayed for diagnostic purposes
 fn draw_eeg_values_view(
-    muse_model: &MuseModel,
+    muse_model: &HeadsetModel,
     window: &mut Window,
     eeg_view_state: &mut EegViewState,
 ) {
@@ -0,0 +1,230 @@
+/// Streaming IIR filters for raw EEG, applied to each electrode separately before any derived value
+/// is computed. Each filter is a biquad section from the Audio EQ Cookbook (R. Bristow-Johnson),
+/// run in transposed direct form II with f64 state so low cut-off frequencies stay stable.
+use crate::spectrum::{EEG_SAMPLE_RATE, N_EEG_CHANNELS};
+use std::f64::consts::{FRAC_1_SQRT_2, PI};
+
+const NOTCH_Q: f64 = 30.0; // About 1.7Hz wide at 50Hz
+
+/// Which filters run, in the order notch, high-pass, band-pass. Frequencies are in Hz.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterSettings {
+    /// Mains frequency, 50 or 60. Harmonics below half the sample rate are removed too.
+    pub notch: Option<f32>,
+    /// High-pass cut-off to remove the electrode DC offset and slow drift
+    pub high_pass: Option<f32>,
+    /// Low and high cut-off of a band-pass
+    pub band_pass: Option<(f32, f32)>,
+}
+
+impl Default for FilterSettings {
+    /// Only DC removal. Mains frequency depends on the site, so there is no default notch.
+    fn default() -> Self {
+        FilterSettings {
+            notch: None,
+            high_pass: Some(0.5),
+            band_pass: None,
+        }
+    }
+}
+
+/// One second order section
+#[derive(Clone, Debug)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    s1: f64,
+    s2: f64,
+}
+
+impl Biquad {
+    /// Coefficients are normalized by a0
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Biquad {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            s1: 0.0,
+            s2: 0.0,
+        }
+    }
+
+    /// (cos w0, alpha) for a cut-off or centre frequency
+    fn prewarp(frequency: f32, q: f64) -> (f64, f64) {
+        let w0 = 2.0 * PI * frequency as f64 / EEG_SAMPLE_RATE as f64;
+
+        (w0.cos(), w0.sin() / (2.0 * q))
+    }
+
+    fn notch(frequency: f32, q: f64) -> Self {
+        let (cos, alpha) = Self::prewarp(frequency, q);
+
+        Self::new(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn high_pass(frequency: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(frequency, FRAC_1_SQRT_2);
+
+        Self::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn low_pass(frequency: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(frequency, FRAC_1_SQRT_2);
+
+        Self::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.s1;
+        self.s1 = self.b1 * x - self.a1 * y + self.s2;
+        self.s2 = self.b2 * x - self.a2 * y;
+
+        y
+    }
+
+    /// Set the state as if `x` had always been the input, so a large DC offset does not ring at startup. Returns the steady output.
+    fn settle(&mut self, x: f64) -> f64 {
+        let gain = (self.b0 + self.b1 + self.b2) / (1.0 + self.a1 + self.a2);
+        let y = gain * x;
+        self.s2 = self.b2 * x - self.a2 * y;
+        self.s1 = self.b1 * x - self.a1 * y + self.s2;
+
+        y
+    }
+}
+
+/// The same chain of filters for each electrode
+pub struct FilterBank {
+    channels: Vec<Vec<Biquad>>,
+    settled: bool, // The first sample sets the starting state
+}
+
+impl FilterBank {
+    pub fn new(settings: FilterSettings) -> Self {
+        let nyquist = EEG_SAMPLE_RATE / 2.0;
+        let mut chain = Vec::new();
+
+        if let Some(mains) = settings.notch {
+            let mut harmonic = mains;
+            while harmonic < nyquist {
+                chain.push(Biquad::notch(harmonic, NOTCH_Q));
+                harmonic += mains;
+            }
+        }
+        if let Some(cut_off) = settings.high_pass {
+            chain.push(Biquad::high_pass(cut_off));
+        }
+        if let Some((low, high)) = settings.band_pass {
+            chain.push(Biquad::high_pass(low));
+            chain.push(Biquad::low_pass(high));
+        }
+
+        FilterBank {
+            channels: vec![chain; N_EEG_CHANNELS],
+            settled: false,
+        }
+    }
+
+    /// Filter one sample from each electrode. A missing (NaN) sample passes through without disturbing the filter state.
+    pub fn process(&mut self, eeg: [f32; N_EEG_CHANNELS]) -> [f32; N_EEG_CHANNELS] {
+        let mut filtered = eeg;
+        let settle = !self.settled && eeg.iter().all(|value| value.is_finite());
+
+        for (channel, chain) in self.channels.iter_mut().enumerate() {
+            if !eeg[channel].is_finite() {
+                continue;
+            }
+            let mut value = eeg[channel] as f64;
+            for section in chain.iter_mut() {
+                value = if settle {
+                    section.settle(value)
+                } else {
+                    section.process(value)
+                };
+            }
+            filtered[channel] = value as f32;
+        }
+        self.settled |= settle;
+
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Peak output amplitude for a sine input after the filter has settled
+    fn response(settings: FilterSettings, frequency: f32) -> f32 {
+        let mut bank = FilterBank::new(settings);
+        let mut peak: f32 = 0.0;
+
+        for i in 0..(20.0 * EEG_SAMPLE_RATE) as usize {
+            let t = i as f32 / EEG_SAMPLE_RATE;
+            let value = 800.0 + 10.0 * (2.0 * std::f32::consts::PI * frequency * t).sin();
+            let filtered = bank.process([value; N_EEG_CHANNELS]);
+            if t > 10.0 {
+                peak = peak.max(filtered[0].abs());
+            }
+        }
+
+        peak / 10.0
+    }
+
+    #[test]
+    fn test_dc_removed_without_ringing() {
+        let mut bank = FilterBank::new(FilterSettings::default());
+
+        for _ in 0..256 {
+            let filtered = bank.process([800.0, -20.0, 0.0, 1500.0]);
+            assert!(filtered.iter().all(|value| value.abs() < 1e-3));
+        }
+    }
+
+    #[test]
+    fn test_notch_removes_mains() {
+        let settings = FilterSettings {
+            notch: Some(50.0),
+            ..FilterSettings::default()
+        };
+
+        assert!(response(settings, 50.0) < 0.01);
+        assert!(response(settings, 100.0) < 0.01);
+        assert!(response(settings, 10.0) > 0.95);
+    }
+
+    #[test]
+    fn test_band_pass() {
+        let settings = FilterSettings {
+            band_pass: Some((1.0, 40.0)),
+            ..FilterSettings::default()
+        };
+
+        assert!(response(settings, 10.0) > 0.9);
+        assert!(response(settings, 100.0) < 0.25);
+    }
+
+    #[test]
+    fn test_missing_sample_passes_through() {
+        let mut bank = FilterBank::new(FilterSettings::default());
+        bank.process([800.0; N_EEG_CHANNELS]);
+        let filtered = bank.process([std::f32::NAN, 800.0, 800.0, 800.0]);
+
+        assert!(filtered[0].is_nan());
+        assert!(bank.process([800.0; N_EEG_CHANNELS])[0].abs() < 1e-3);
+    }
+}
@@ -0,0 +1,298 @@
+/// Heart rate and heart rate variability from the Muse 2/S photoplethysmograph.
+///
+/// The infrared PPG is band limited by subtracting a one second moving average from a short one, then
+/// each pulse is found as a local peak above half the recent pulse amplitude. Inter-beat intervals
+/// which are outside a plausible heart rate, or jump too far from the recent median as a missed or
+/// extra beat would, are dropped. Heart rate is the mean of the most recent intervals and HRV is their
+/// RMSSD, the root mean square of successive differences.
+use crate::running_stats::WindowedStats;
+use chrono::{DateTime, Duration, Local};
+use std::collections::VecDeque;
+
+const PPG_SAMPLE_RATE: f32 = 64.0;
+const PPG_CHANNEL: usize = 1; // Infrared, the strongest pulse of ambient, infrared and red
+const SMOOTHING_SAMPLES: usize = 4; // Short moving average, removes sensor noise
+const BASELINE_SAMPLES: usize = 64; // Long moving average, removes drift and breathing
+const AMPLITUDE_DECAY: f32 = 0.99; // Per sample, so the pulse amplitude estimate halves in about a second
+const PEAK_THRESHOLD: f32 = 0.5; // Fraction of the recent pulse amplitude a peak must reach
+const MIN_IBI_MS: f32 = 333.0; // 180 beats per minute
+const MAX_IBI_MS: f32 = 1500.0; // 40 beats per minute
+const MAX_IBI_CHANGE: f32 = 0.3; // Fraction of the recent median interval
+const MAX_REJECTED_BEATS: usize = 3; // In a row, after which the heart rate is assumed to have really changed
+const HEART_RATE_BEATS: usize = 8; // Intervals averaged for heart rate
+const HRV_BEATS: usize = 30; // Intervals kept for RMSSD, about half a minute
+const MIN_HRV_BEATS: usize = 5;
+const SWING_BEATS: usize = 10; // Intervals over which heart rate rises and falls with breathing
+const MAX_BEAT_AGE_MS: i64 = 4000; // Without a beat for longer the PPG has dropped out
+
+/// Most recent cardiac values of one participant
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HeartRate {
+    /// Beats per minute
+    pub bpm: Option<f32>,
+    /// Most recent inter-beat interval, milliseconds
+    pub ibi: Option<f32>,
+    /// Short window HRV, milliseconds
+    pub rmssd: Option<f32>,
+    /// Instantaneous heart rate from 0 at the lowest to 1 at the highest of the last few beats. With
+    /// slow breathing this follows the breath, and follows it more closely the more coherent it is.
+    pub swing: Option<f32>,
+    /// When the most recent beat was accepted
+    pub beat_time: Option<DateTime<Local>>,
+}
+
+impl HeartRate {
+    /// These values, or None if no beat has been accepted in the few seconds before `time`, as when the PPG drops out
+    pub fn at(self, time: DateTime<Local>) -> Option<HeartRate> {
+        let beat_time = self.beat_time?;
+
+        match time - beat_time > Duration::milliseconds(MAX_BEAT_AGE_MS) {
+            true => None,
+            false => Some(self),
+        }
+    }
+}
+
+/// What opens and closes the breathing mandala
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreathDriver {
+    /// A 10 second breath to follow
+    Paced,
+    /// The participant's heart rate swing, falling back to Paced until beats are found in the PPG and whenever
+    /// none has been found for a few seconds
+    HeartRate,
+}
+
+/// Beat detection for one headset
+pub struct HeartRateDetector {
+    smoothing: WindowedStats<f32>,
+    baseline: WindowedStats<f32>,
+    previous: [f32; 2], // The two previous band limited values, most recent first
+    amplitude: f32,
+    sample: u64,              // Count of samples received
+    last_peak: Option<u64>,   // Sample of the most recent pulse peak
+    intervals: VecDeque<f32>, // Accepted inter-beat intervals, oldest first, milliseconds
+    rejected: usize,          // Intervals dropped in a row
+}
+
+impl Default for HeartRateDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeartRateDetector {
+    pub fn new() -> Self {
+        HeartRateDetector {
+            smoothing: WindowedStats::new(Some(SMOOTHING_SAMPLES)),
+            baseline: WindowedStats::new(Some(BASELINE_SAMPLES)),
+            previous: [0.0; 2],
+            amplitude: 0.0,
+            sample: 0,
+            last_peak: None,
+            intervals: VecDeque::with_capacity(HRV_BEATS),
+            rejected: 0,
+        }
+    }
+
+    /// Add one PPG sample. Returns the inter-beat interval in milliseconds when a beat is accepted.
+    pub fn push(&mut self, ppg: [f32; 3]) -> Option<f32> {
+        let value = ppg[PPG_CHANNEL];
+        if !value.is_finite() {
+            return None;
+        }
+        self.sample += 1;
+        self.smoothing.push(value);
+        self.baseline.push(value);
+        if self.baseline.len() < BASELINE_SAMPLES {
+            return None;
+        }
+        let pulse = self.smoothing.mean()? - self.baseline.mean()?;
+
+        self.amplitude = (self.amplitude * AMPLITUDE_DECAY).max(pulse.abs());
+        let [previous, before] = self.previous;
+        self.previous = [pulse, previous];
+        let is_peak =
+            previous > before && previous >= pulse && previous > PEAK_THRESHOLD * self.amplitude;
+        if !is_peak {
+            return None;
+        }
+
+        let peak = self.sample - 1;
+        let last_peak = self.last_peak.replace(peak)?;
+        let ibi = (peak - last_peak) as f32 * 1000.0 / PPG_SAMPLE_RATE;
+        if ibi < MIN_IBI_MS {
+            self.last_peak = Some(last_peak); // A notch in the same pulse
+            return None;
+        }
+
+        self.accept(ibi)
+    }
+
+    fn accept(&mut self, ibi: f32) -> Option<f32> {
+        let plausible = ibi <= MAX_IBI_MS
+            && match median(&self.intervals) {
+                Some(median) => (ibi - median).abs() <= MAX_IBI_CHANGE * median,
+                None => true,
+            };
+        if !plausible {
+            self.rejected += 1;
+            if self.rejected >= MAX_REJECTED_BEATS {
+                self.intervals.clear();
+                self.rejected = 0;
+            }
+            return None;
+        }
+
+        self.rejected = 0;
+        if self.intervals.len() == HRV_BEATS {
+            self.intervals.pop_front();
+        }
+        self.intervals.push_back(ibi);
+
+        Some(ibi)
+    }
+
+    /// Values from the intervals accepted so far. The caller knows the time of the beat.
+    pub fn heart_rate(&self) -> HeartRate {
+        HeartRate {
+            bpm: self.bpm(),
+            ibi: self.intervals.back().cloned(),
+            rmssd: self.rmssd(),
+            swing: self.swing(),
+            beat_time: None,
+        }
+    }
+
+    fn bpm(&self) -> Option<f32> {
+        let recent: Vec<f32> = self.recent(HEART_RATE_BEATS).collect();
+        if recent.is_empty() {
+            return None;
+        }
+
+        Some(60_000.0 * recent.len() as f32 / recent.iter().sum::<f32>())
+    }
+
+    fn rmssd(&self) -> Option<f32> {
+        if self.intervals.len() < MIN_HRV_BEATS {
+            return None;
+        }
+        let intervals: Vec<f32> = self.intervals.iter().cloned().collect();
+        let squares: Vec<f32> = intervals
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).powi(2))
+            .collect();
+
+        Some((squares.iter().sum::<f32>() / squares.len() as f32).sqrt())
+    }
+
+    fn swing(&self) -> Option<f32> {
+        let rates: Vec<f32> = self.recent(SWING_BEATS).map(|ibi| 60_000.0 / ibi).collect();
+        if rates.len() < MIN_HRV_BEATS {
+            return None;
+        }
+        let low = rates.iter().cloned().fold(f32::INFINITY, f32::min);
+        let high = rates.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let current = rates[0];
+
+        if high > low {
+            Some((current - low) / (high - low))
+        } else {
+            Some(0.5)
+        }
+    }
+
+    /// Up to `n` intervals, most recent first
+    fn recent(&self, n: usize) -> impl Iterator<Item = f32> + '_ {
+        self.intervals.iter().rev().take(n).cloned()
+    }
+}
+
+fn median(intervals: &VecDeque<f32>) -> Option<f32> {
+    let mut sorted: Vec<f32> = intervals.iter().cloned().collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(|a, b| a.partial_cmp(b).expect("Intervals are finite"));
+
+    Some(sorted[sorted.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::f32::consts::PI;
+
+    /// A pulse wave with the given beat intervals in samples, on a drifting infrared level
+    fn pulses(detector: &mut HeartRateDetector, beats: &[u64]) -> Vec<f32> {
+        let mut intervals = Vec::new();
+        let mut sample = 0;
+        for beat in beats {
+            for i in 0..*beat {
+                let phase = i as f32 / *beat as f32;
+                let drift = 0.02 * sample as f32;
+                let value = 5000.0 + drift - 100.0 * (2.0 * PI * phase).cos();
+                sample += 1;
+                if let Some(ibi) = detector.push([0.0, value, 0.0]) {
+                    intervals.push(ibi);
+                }
+            }
+        }
+
+        intervals
+    }
+
+    #[test]
+    fn test_steady_heart_rate() {
+        let mut detector = HeartRateDetector::new();
+        let intervals = pulses(&mut detector, &[64; 20]);
+        let heart_rate = detector.heart_rate();
+
+        assert!(intervals.len() > 10);
+        assert!(intervals.iter().all(|ibi| *ibi == 1000.0));
+        assert_eq!(Some(60.0), heart_rate.bpm);
+        assert_eq!(Some(0.0), heart_rate.rmssd);
+    }
+
+    #[test]
+    fn test_variable_heart_rate() {
+        let mut detector = HeartRateDetector::new();
+        let beats: Vec<u64> = (0..30).map(|i| if i % 2 == 0 { 56 } else { 64 }).collect();
+        pulses(&mut detector, &beats);
+        let heart_rate = detector.heart_rate();
+
+        assert!(heart_rate.rmssd.unwrap() > 20.0);
+        assert!(heart_rate.bpm.unwrap() > 60.0 && heart_rate.bpm.unwrap() < 69.0);
+        assert!(heart_rate.swing.is_some());
+    }
+
+    #[test]
+    fn test_heart_rate_expires() {
+        let beat_time = Local.timestamp(1_582_616_149, 0);
+        let heart_rate = HeartRate {
+            bpm: Some(60.0),
+            ibi: Some(1000.0),
+            swing: Some(0.5),
+            beat_time: Some(beat_time),
+            ..HeartRate::default()
+        };
+
+        assert_eq!(
+            Some(heart_rate),
+            heart_rate.at(beat_time + Duration::seconds(4))
+        );
+        assert_eq!(None, heart_rate.at(beat_time + Duration::seconds(5)));
+        assert_eq!(None, HeartRate::default().at(beat_time));
+    }
+
+    #[test]
+    fn test_implausible_interval_dropped() {
+        let mut detector = HeartRateDetector::new();
+        pulses(&mut detector, &[64; 10]);
+
+        assert_eq!(None, detector.accept(2000.0));
+        assert_eq!(None, detector.accept(500.0));
+        assert_eq!(Some(1100.0), detector.accept(1100.0));
+    }
+}
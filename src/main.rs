@@ -26,6 +26,7 @@ use crate::eeg_view::ImageSet;
 use arr_macro::arr;
 use chrono::{DateTime, Local};
 use eeg_view::EegViewState;
+use heart_rate::BreathDriver;
 use log::{error, info};
 use mandala::{Mandala, MandalaState};
 use muse_model::{DisplayType, MuseModel};
@@ -38,14 +39,53 @@ use quicksilver::{
     sound::Sound,
     Future, Result,
 };
+use session_event::{ImageCategory, SessionEvent};
+use signal_quality::FitCheck;
 use std::f32::consts::PI;
 
+mod affect_metric;
+mod artifact;
+mod calibration;
+mod clock_offset;
+mod edf;
 mod eeg_view;
+mod filter;
+mod heart_rate;
+mod motion;
 mod muse_model;
+mod recorder;
+mod running_stats;
+mod session;
+mod session_event;
+mod signal_quality;
+mod spectrum;
+mod wire_format;
+mod xdf;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod config;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod csv_replay;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod eeg_source;
 
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 mod muse_packet;
 
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod muse_simulator;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod osc_capture;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod relay;
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+mod websocket_receiver;
+
 const MULTISAMPLING: u16 = 8; // Graphics rendering oversampling
 
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
@@ -73,6 +113,22 @@ const POSITIVE_B: u64 = POSITIVE_A + 119 * FPS; // TASK 4
 const FREE_RIDE_A: u64 = POSITIVE_B + 19 * FPS;
 const FREE_RIDE_B: u64 = FREE_RIDE_A + 70 * FPS; // (same image)
 const THANK_YOU: u64 = FREE_RIDE_B + 9 * FPS; // THANK YOU
+/// First frame and name of each stage of the protocol, in order
+const STAGES: [(u64, &str); 13] = [
+    (TITLE, "TITLE"),
+    (INTRO_A, "INTRO_A"),
+    (INTRO_B, "INTRO_B"),
+    (INTRO_C, "INTRO_C"),
+    (NEGATIVE_A, "NEGATIVE_A"),
+    (NEGATIVE_B, "NEGATIVE_B"),
+    (BREATHING_A, "BREATHING_A"),
+    (BREATHING_B, "BREATHING_B"),
+    (POSITIVE_A, "POSITIVE_A"),
+    (POSITIVE_B, "POSITIVE_B"),
+    (FREE_RIDE_A, "FREE_RIDE_A"),
+    (FREE_RIDE_B, "FREE_RIDE_B"),
+    (THANK_YOU, "THANK_YOU"),
+];
 
 const IMAGE_LOGO: &str = "0_nof1_logo.png";
 const MANDALA_VALENCE_PETAL_SVG_NAME: &str = "mandala_valence_petal.svg";
@@ -87,6 +143,7 @@ const _FONT_EXTRA_BOLD_SIZE: f32 = 72.0;
 const _FONT_MULI_SIZE: f32 = 40.0;
 const FONT_GRAPH_LABEL_SIZE: f32 = 40.0;
 const FONT_EEG_LABEL_SIZE: f32 = 30.0;
+const FONT_ERROR_SIZE: f32 = 36.0;
 
 const SOUND_CLICK: &str = "click.ogg";
 const _SOUND_GUIDANCE: &str = "Meet Your Mind Leo's voice 200224.mp3";
@@ -131,6 +188,7 @@ const COLOR_TEXT: Color = Color::BLACK;
 const _COLOR_BUTTON: Color = COLOR_NOF1_DARK_BLUE;
 const COLOR_BUTTON_PRESSED: Color = COLOR_NOF1_LIGHT_BLUE;
 const COLOR_EMOTION: Color = Color::YELLOW;
+const COLOR_ERROR: Color = Color::RED;
 const COLOR_VALENCE_MANDALA_CLOSED: Color = Color {
     // Purple, positive
     r: 0.415,
@@ -243,6 +301,8 @@ struct AppState {
     image_index_negative: usize,
     local_frame: u64,
     mandala_on: bool,
+    error_text: Option<Asset<Image>>, // Setup problems, shown until the app is restarted
+    fit_check: FitCheck,              // The session waits until all electrodes are good
 }
 
 fn breathing_sinusoid_10sec(current_time: f32) -> f32 {
@@ -260,6 +320,7 @@ impl AppState {
     }
 
     fn left_action(&mut self, _window: &mut Window) -> Result<()> {
+        self.log_event(Local::now(), SessionEvent::KeyPress { key: "LEFT" });
         self.left_button_color = COLOR_BUTTON_PRESSED;
         self.sound_click
             .execute(|sound| sound.play())
@@ -268,6 +329,7 @@ impl AppState {
     }
 
     fn right_action(&mut self, _window: &mut Window) -> Result<()> {
+        self.log_event(Local::now(), SessionEvent::KeyPress { key: "RIGHT" });
         self.right_button_color = COLOR_BUTTON_PRESSED;
         self.sound_click.execute(|sound| sound.play())
     }
@@ -281,6 +343,23 @@ impl AppState {
         std_duration.as_nanos() as f32 / 1000000000.0
     }
 
+    /// Draw setup problems near the bottom of the window
+    fn draw_error_text(&mut self, window: &mut Window) -> Result<()> {
+        if let Some(error_text) = &mut self.error_text {
+            error_text.execute(|image| {
+                window.draw(
+                    &image
+                        .area()
+                        .with_center((SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 * 7.0 / 8.0)),
+                    Img(&image),
+                );
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
     /// Draw the current animated state of a flower-like object to the window
     fn draw_mandala(&mut self, seconds_since_start: f32, mandala_on: bool, window: &mut Window) {
         if !mandala_on {
@@ -296,27 +375,43 @@ impl AppState {
         window.mesh().extend(&mesh);
     }
 
+    /// Follows the paced breath, or the participant's heart rate as it rises and falls with their breathing
     fn draw_breath_mandala(&mut self, current_time: DateTime<Local>, window: &mut Window) {
         let mut mesh = Mesh::new();
         let seconds_since_start = self.seconds_since_start(current_time);
-        let breath_state = breathing_sinusoid_10sec(seconds_since_start);
+        let heart_rate_swing = match self.muse_model.breath_driver {
+            BreathDriver::HeartRate => self
+                .muse_model
+                .heart_rate()
+                .and_then(|heart_rate| heart_rate.swing),
+            BreathDriver::Paced => None,
+        };
+        let (breath_state, transition) = match heart_rate_swing {
+            Some(swing) => (swing, MANDALA_TRANSITION_DURATION), // Changes once a beat
+            None => (breathing_sinusoid_10sec(seconds_since_start), 0.01),
+        };
         let mut shape_renderer = ShapeRenderer::new(&mut mesh, Color::RED);
         self.mandala_breath
-            .start_transition(seconds_since_start, 0.01, breath_state);
+            .start_transition(seconds_since_start, transition, breath_state);
         self.mandala_breath
             .draw(seconds_since_start, &mut shape_renderer);
         window.mesh().extend(&mesh);
     }
 
-    /// Add a tag to the output CSV file indicating what happened at runtime
-    fn log_result(&mut self, date_time: DateTime<Local>, tag: &str, result: Result<()>) {
-        if result.is_ok() {
-            let s: &str = &format!("{}:OK", tag);
-            self.muse_model.log_other(date_time, s);
-        } else {
-            let s: &str = &format!("{}:ERROR", tag);
-            self.muse_model.log_other(date_time, s);
-        }
+    /// Add a protocol event to events.csv
+    fn log_event(&mut self, date_time: DateTime<Local>, event: SessionEvent) {
+        self.muse_model.log_event(date_time, &event);
+    }
+
+    /// The sound of a stage started, or why it could not be played
+    fn log_sound(&mut self, date_time: DateTime<Local>, stage: &'static str, result: Result<()>) {
+        let event = match result {
+            Ok(()) => SessionEvent::SoundCue { stage },
+            Err(e) => SessionEvent::Error {
+                message: format!("Can not play the {} sound: {:?}", stage, e),
+            },
+        };
+        self.log_event(date_time, event);
     }
 }
 
@@ -347,6 +442,123 @@ mod max_thread_priority {
     }
 }
 
+/// EEG source, relay, participants and fit check from config::AppConfig. Anything which can not be
+/// set up is reported in the returned message for display, and the app starts without it.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn create_muse_model(start_time: DateTime<Local>) -> (MuseModel, FitCheck, Option<String>) {
+    let mut errors: Vec<String> = Vec::new();
+    let config = config::AppConfig::load().unwrap_or_else(|e| {
+        errors.push(e);
+        config::AppConfig::default()
+    });
+
+    let receiver = config
+        .eeg_source()
+        .and_then(|source| source.create_receiver().map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| -> Box<dyn muse_model::EegMessageReceiver> {
+            errors.push(e);
+            Box::new(eeg_source::DisconnectedMessageReceiver {})
+        });
+    let mut muse_model = MuseModel::new(start_time, receiver, config.session_settings());
+
+    if let Some(port) = config.relay {
+        match relay::WebSocketRelay::start(port) {
+            Ok(relay) => muse_model.add_sink(Box::new(relay)),
+            Err(e) => errors.push(format!("Can not relay on port {}: {}", port, e)),
+        }
+    }
+
+    match config.participant_addresses() {
+        Ok(sources) => {
+            for (participant, source) in sources.into_iter().enumerate() {
+                muse_model.assign_participant(participant, source);
+            }
+        }
+        Err(e) => errors.push(e),
+    }
+
+    match config.participant_combination() {
+        Ok(combination) => muse_model.combination = combination,
+        Err(e) => errors.push(e),
+    }
+
+    match config.breath_driver() {
+        Ok(breath_driver) => muse_model.breath_driver = breath_driver,
+        Err(e) => errors.push(e),
+    }
+
+    match config.clock_offset_mode() {
+        Ok(mode) => muse_model.set_clock_offset_mode(mode),
+        Err(e) => errors.push(e),
+    }
+
+    match config.band_edges() {
+        Ok(band_edges) => muse_model.set_band_edges(band_edges),
+        Err(e) => errors.push(e),
+    }
+
+    match config.filter_settings() {
+        Ok(filter_settings) => muse_model.set_filter_settings(filter_settings),
+        Err(e) => errors.push(e),
+    }
+
+    match config.artifact_policy() {
+        Ok(artifact_policy) => muse_model.set_artifact_policy(artifact_policy),
+        Err(e) => errors.push(e),
+    }
+
+    match config.affect_settings() {
+        Ok(affect_settings) => muse_model.set_affect_settings(affect_settings),
+        Err(e) => errors.push(e),
+    }
+
+    match config.normalization_settings() {
+        Ok(normalization_settings) => muse_model.set_normalization_settings(normalization_settings),
+        Err(e) => errors.push(e),
+    }
+
+    match config.recorder_settings() {
+        Ok(recorder_settings) => muse_model.set_recorder_settings(recorder_settings),
+        Err(e) => errors.push(e),
+    }
+
+    match config.calibration_settings() {
+        Ok(calibration_settings) => muse_model.set_calibration_settings(calibration_settings),
+        Err(e) => errors.push(e),
+    }
+
+    let fit_check_hold = config.fit_check_hold().unwrap_or_else(|e| {
+        errors.push(e);
+        chrono::Duration::seconds(signal_quality::DEFAULT_FIT_CHECK_SECONDS)
+    });
+    let fit_check = FitCheck::new(fit_check_hold);
+
+    for e in &errors {
+        error!("{}", e);
+    }
+
+    if errors.is_empty() {
+        (muse_model, fit_check, None)
+    } else {
+        (muse_model, fit_check, Some(errors.join("\n")))
+    }
+}
+
+/// EEG relayed from a native app, see websocket_receiver::WebSocketMessageReceiver::new()
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn create_muse_model(start_time: DateTime<Local>) -> (MuseModel, FitCheck, Option<String>) {
+    let receiver = Box::new(websocket_receiver::WebSocketMessageReceiver::new());
+    let fit_check = FitCheck::new(chrono::Duration::seconds(
+        signal_quality::DEFAULT_FIT_CHECK_SECONDS,
+    ));
+
+    (
+        MuseModel::new(start_time, receiver, session::SessionSettings::default()),
+        fit_check,
+        None,
+    )
+}
+
 impl State for AppState {
     fn new() -> Result<AppState> {
         let start_date_time = Local::now();
@@ -382,7 +594,12 @@ impl State for AppState {
         let help_7 = Asset::new(Image::load("7fi.png"));
         let help_8 = Asset::new(Image::load("8fi.png"));
 
-        let muse_model = muse_model::MuseModel::new(start_date_time);
+        let (muse_model, fit_check, error_message) = create_muse_model(start_date_time);
+        let error_text = error_message.map(|message| {
+            Asset::new(Font::load(FONT_MULI).and_then(move |font| {
+                result(font.render(&message, &FontStyle::new(FONT_ERROR_SIZE, COLOR_ERROR)))
+            }))
+        });
         let mandala_valence_state_open = MandalaState::new(
             COLOR_VALENCE_MANDALA_OPEN,
             Transform::rotate(90),
@@ -494,6 +711,8 @@ impl State for AppState {
             image_index_negative,
             local_frame,
             mandala_on,
+            error_text,
+            fit_check,
         })
     }
 
@@ -509,11 +728,30 @@ impl State for AppState {
                     .iter()
                     .any(|pad| pad[GamepadButton::FaceLeft].is_down())
             {
+                self.log_event(current_time, SessionEvent::KeyPress { key: "ESC" });
+                let frame_count = self.frame_count;
+                if let Some((_, stage)) =
+                    STAGES.iter().rev().find(|(frame, _)| *frame <= frame_count)
+                {
+                    self.log_event(current_time, SessionEvent::StageEnd { stage: *stage });
+                }
                 self.muse_model
                     .log_other(current_time, "Application shutdown by ESC key");
-                self.muse_model
-                    .flush_all()
-                    .expect("Can not flush logs on orderly shutdown");
+                self.muse_model.log_headset_summary(current_time);
+                self.muse_model.log_packet_error_summary(current_time);
+                if let Err(e) = self.muse_model.flush_all() {
+                    error!("Can not flush logs on orderly shutdown: {}", e);
+                }
+                for export in self.muse_model.export_edf() {
+                    match export {
+                        Ok(path) => info!("EEG exported to {}", path.display()),
+                        Err(e) => error!("Can not export EDF+: {}", e),
+                    }
+                }
+                match self.muse_model.write_manifest(current_time) {
+                    Ok(path) => info!("Session saved, see {}", path.display()),
+                    Err(e) => error!("Can not write session manifest: {}", e),
+                }
                 window.close();
             }
         }
@@ -564,26 +802,55 @@ impl State for AppState {
 
         // F1
         if window.keyboard()[Key::F1] == ButtonState::Pressed {
+            self.log_event(current_time, SessionEvent::KeyPress { key: "F1" });
             self.muse_model.display_type = DisplayType::Mandala;
         }
 
         // F2
         if window.keyboard()[Key::F2] == ButtonState::Pressed {
+            self.log_event(current_time, SessionEvent::KeyPress { key: "F2" });
             self.muse_model.display_type = DisplayType::Dowsiness;
         }
 
         // F3
         if window.keyboard()[Key::F3] == ButtonState::Pressed {
+            self.log_event(current_time, SessionEvent::KeyPress { key: "F3" });
             self.muse_model.display_type = DisplayType::Emotion;
         }
 
         // F4
         if window.keyboard()[Key::F4] == ButtonState::Pressed {
+            self.log_event(current_time, SessionEvent::KeyPress { key: "F4" });
             self.muse_model.display_type = DisplayType::EegValues;
         }
 
         let (normalized_valence_option, normalized_arousal_option) =
             self.muse_model.receive_packets();
+        if self
+            .fit_check
+            .update(current_time, self.muse_model.is_well_fitted())
+        {
+            self.log_event(current_time, SessionEvent::FitCheckPassed);
+            self.muse_model.start_calibration(current_time);
+        }
+        // A nod or shake answers yes or no to the image on screen
+        if let Some(gesture) = self.muse_model.take_gesture() {
+            let image = if (NEGATIVE_A..NEGATIVE_B).contains(&self.frame_count) {
+                Some((ImageCategory::Negative, self.image_index_negative))
+            } else if (POSITIVE_A..POSITIVE_B).contains(&self.frame_count) {
+                Some((ImageCategory::Positive, self.image_index_positive))
+            } else {
+                None
+            };
+            if let Some((category, image)) = image {
+                let response = SessionEvent::Response {
+                    category,
+                    image,
+                    answer: gesture.answer(),
+                };
+                self.log_event(current_time, response);
+            }
+        }
         if self.frame_count > TITLE {
             let current_time = self.seconds_since_start(current_time);
             if let Some(normalized_valence) = normalized_valence_option {
@@ -625,89 +892,87 @@ impl State for AppState {
         let background_color = COLOR_BACKGROUND;
         window.clear(background_color)?;
 
+        if !self.fit_check.is_passed() {
+            eeg_view::draw_fit_check(
+                &self.muse_model,
+                &self.fit_check,
+                current_time,
+                window,
+                &mut self.eeg_view_state,
+            );
+            return self.draw_error_text(window);
+        }
+
         if self.muse_model.is_receiving_data() {
+            if let Some(stage) = STAGES
+                .iter()
+                .position(|(frame, _)| *frame == self.frame_count)
+            {
+                if stage > 0 {
+                    let previous = STAGES[stage - 1].1;
+                    self.log_event(current_time, SessionEvent::StageEnd { stage: previous });
+                }
+                let stage = STAGES[stage].1;
+                self.log_event(current_time, SessionEvent::StageStart { stage });
+            }
             // THE NAME AT THE TOP OF THE IF STATEMENT IS THE NAME OF THE PREVIOUS STAGE
             if self.frame_count == TITLE {
                 let result = self.sound_e1.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:TITLE", result);
+                self.log_sound(current_time, "TITLE", result);
             }
             if self.frame_count == INTRO_C {
                 let result = self.sound_e2.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:INTRO_C", result);
+                self.log_sound(current_time, "INTRO_C", result);
             }
             if self.frame_count == NEGATIVE_A {
                 let result = self.sound_e3.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:NEGATIVE_A", result);
+                self.log_sound(current_time, "NEGATIVE_A", result);
             }
             if self.frame_count == NEGATIVE_B {
                 let result = self.sound_e4.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:NEGATIVE_B", result);
+                self.log_sound(current_time, "NEGATIVE_B", result);
             }
             if self.frame_count == BREATHING_B {
                 let result = self.sound_e5.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:BREATHING_B", result);
+                self.log_sound(current_time, "BREATHING_B", result);
             }
             if self.frame_count == POSITIVE_A {
                 let result = self.sound_e6.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:POSITIVE_A", result);
+                self.log_sound(current_time, "POSITIVE_A", result);
             }
             if self.frame_count == POSITIVE_B {
                 let result = self.sound_e7.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:POSITIVE_B", result);
+                self.log_sound(current_time, "POSITIVE_B", result);
             }
             if self.frame_count == THANK_YOU {
                 let result = self.sound_e8.execute(|sound| sound.play());
-                self.log_result(current_time, "Sound:THANK_YOU", result);
+                self.log_sound(current_time, "THANK_YOU", result);
             }
 
             let optional_image: Option<&mut Asset<Image>> =
                 if self.frame_count >= TITLE && self.frame_count < INTRO_A {
                     // TITLE SLIDE
-                    if self.frame_count == TITLE {
-                        self.log_result(current_time, "Image:TITLE", Ok(()));
-                    }
                     Some(&mut self.help_1)
                 } else if self.frame_count >= INTRO_A && self.frame_count < INTRO_B {
                     // MENTAL STATES VISUALIZED 1/2
-                    if self.frame_count == INTRO_A {
-                        self.log_result(current_time, "Image:INTRO_A", Ok(()));
-                    }
                     Some(&mut self.help_2)
                 } else if self.frame_count >= INTRO_B && self.frame_count < INTRO_C {
                     // MENTAL STATES VISUALIZED 2/2
-                    if self.frame_count == INTRO_B {
-                        self.log_result(current_time, "Image:INTRO_B", Ok(()));
-                    }
                     Some(&mut self.help_3)
                 } else if self.frame_count >= INTRO_C && self.frame_count < NEGATIVE_A {
                     // TASK 1 SLIDE
-                    if self.frame_count == INTRO_C {
-                        self.log_result(current_time, "Image:INTRO_C", Ok(()));
-                    }
                     Some(&mut self.help_4)
                 } else if self.frame_count >= NEGATIVE_B && self.frame_count < BREATHING_A {
                     // TASK 2 SLIDE
-                    if self.frame_count == NEGATIVE_B {
-                        self.log_result(current_time, "Image:NEGATIVE_B", Ok(()));
-                    }
                     Some(&mut self.help_5)
                 } else if self.frame_count >= BREATHING_B && self.frame_count < POSITIVE_A {
                     // TASK 3 SLIDE
-                    if self.frame_count == BREATHING_B {
-                        self.log_result(current_time, "Image:BREATHING_B", Ok(()));
-                    }
                     Some(&mut self.help_6)
                 } else if self.frame_count >= POSITIVE_B && self.frame_count < FREE_RIDE_A {
                     // TASK 4 SLIDE
-                    if self.frame_count == FREE_RIDE_A {
-                        self.log_result(current_time, "Image:FREE_RIDE_A", Ok(()));
-                    }
                     Some(&mut self.help_7)
                 } else if self.frame_count >= THANK_YOU {
                     // SLIDE THANK YOU
-                    if self.frame_count == THANK_YOU {
-                        self.log_result(current_time, "Image:THANK_YOU", Ok(()));
-                    }
                     Some(&mut self.help_8)
                 } else {
                     None
@@ -757,13 +1022,25 @@ impl State for AppState {
                         self.draw_mandala(seconds_since_start, self.mandala_on, window);
                         if self.local_frame < IMAGE_DURATION_FRAMES {
                             if self.local_frame == 0 {
-                                self.log_result(current_time, "LocalFrame:NEGATIVE", Ok(()));
+                                self.log_event(
+                                    current_time,
+                                    SessionEvent::StimulusOnset {
+                                        category: ImageCategory::Negative,
+                                        image: self.image_index_negative,
+                                    },
+                                );
                             }
                             self.negative_images.draw(self.image_index_negative, window);
                             self.local_frame += 1;
                         } else if self.local_frame < IMAGE_DURATION_FRAMES + INTER_IMAGE_INTERVAL {
                             if self.local_frame == IMAGE_DURATION_FRAMES {
-                                self.log_result(current_time, "LocalFrame:END_NEGATIVE", Ok(()));
+                                self.log_event(
+                                    current_time,
+                                    SessionEvent::StimulusOffset {
+                                        category: ImageCategory::Negative,
+                                        image: self.image_index_negative,
+                                    },
+                                );
                             }
                             self.local_frame += 1;
                         } else {
@@ -799,13 +1076,25 @@ impl State for AppState {
                         self.draw_mandala(seconds_since_start, self.mandala_on, window);
                         if self.local_frame < IMAGE_DURATION_FRAMES {
                             if self.local_frame == 0 {
-                                self.log_result(current_time, "LocalFrame:POSITIVE", Ok(()));
+                                self.log_event(
+                                    current_time,
+                                    SessionEvent::StimulusOnset {
+                                        category: ImageCategory::Positive,
+                                        image: self.image_index_positive,
+                                    },
+                                );
                             }
                             self.positive_images.draw(self.image_index_positive, window);
                             self.local_frame += 1;
                         } else if self.local_frame < IMAGE_DURATION_FRAMES + INTER_IMAGE_INTERVAL {
                             if self.local_frame == IMAGE_DURATION_FRAMES {
-                                self.log_result(current_time, "LocalFrame:END_POSITIVE", Ok(()));
+                                self.log_event(
+                                    current_time,
+                                    SessionEvent::StimulusOffset {
+                                        category: ImageCategory::Positive,
+                                        image: self.image_index_positive,
+                                    },
+                                );
                             }
                             self.local_frame += 1;
                         } else {
@@ -859,6 +1148,8 @@ impl State for AppState {
             //     }
         }
 
+        self.draw_error_text(window)?;
+
         if self.muse_model.is_receiving_data() || self.frame_count < TITLE {
             self.frame_count = self.frame_count + 1;
             if self.frame_count == std::u64::MAX {
@@ -0,0 +1,276 @@
+/// Head orientation, movement and nod or shake gestures from the Muse accelerometer and gyro.
+///
+/// Pitch and roll come from a complementary filter. Integrating the gyro follows quick turns
+/// smoothly, and the direction of gravity in the accelerometer pulls the angles back so gyro drift
+/// does not build up. Axes are taken as x forward, y to the left and z up, with the accelerometer in
+/// g and the gyro in degrees per second about each axis. Pitch is positive looking down and roll
+/// positive tilting towards the right shoulder.
+use crate::running_stats::WindowedStats;
+use chrono::{DateTime, Duration, Local};
+use std::fmt;
+
+const GYRO_WEIGHT: f32 = 0.98; // Complementary filter weight of the integrated gyro, the rest is accelerometer tilt
+const MAX_GYRO_INTERVAL_MS: i64 = 100; // Longer gaps between gyro samples restart from accelerometer tilt
+const INTENSITY_SAMPLES: usize = 52; // Gyro samples in the movement intensity window, about 1 second
+const MOVING_INTENSITY: f32 = 20.0; // Degrees per second RMS, above which movement spoils the EEG
+const GESTURE_RATE: f32 = 60.0; // Degrees per second for one swing of a nod or shake
+const GESTURE_DOMINANCE: f32 = 2.0; // A swing must be this much faster than the other gesture axis
+const GESTURE_SWINGS: usize = 3; // Alternating swings in one gesture, for example down, up, down
+const GESTURE_GAP_MS: i64 = 600; // Longest pause between the swings of one gesture
+const GESTURE_HOLD_OFF_MS: i64 = 1000; // No new gesture this soon after one is recognised
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Orientation {
+    /// Degrees, positive looking down
+    pub pitch: f32,
+    /// Degrees, positive tilting right
+    pub roll: f32,
+}
+
+/// A deliberate head movement, which can answer a yes or no question hands free
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeadGesture {
+    /// Yes
+    Nod,
+    /// No
+    Shake,
+}
+
+impl HeadGesture {
+    pub fn answer(self) -> &'static str {
+        match self {
+            HeadGesture::Nod => "YES",
+            HeadGesture::Shake => "NO",
+        }
+    }
+}
+
+impl fmt::Display for HeadGesture {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeadGesture::Nod => write!(f, "Nod"),
+            HeadGesture::Shake => write!(f, "Shake"),
+        }
+    }
+}
+
+/// Alternating fast rotations about one axis
+#[derive(Default)]
+struct Swings {
+    direction: f32, // Sign of the most recent swing, 0 before any
+    count: usize,
+    last: Option<DateTime<Local>>,
+}
+
+impl Swings {
+    /// Returns true when enough swings have alternated quickly enough
+    fn update(&mut self, time: DateTime<Local>, rate: f32) -> bool {
+        let direction = rate.signum();
+        if direction == self.direction {
+            self.last = Some(time); // Still the same swing
+            return false;
+        }
+
+        let continues = match self.last {
+            Some(last) => time - last <= Duration::milliseconds(GESTURE_GAP_MS),
+            None => false,
+        };
+        self.count = if continues { self.count + 1 } else { 1 };
+        self.direction = direction;
+        self.last = Some(time);
+
+        self.count >= GESTURE_SWINGS
+    }
+}
+
+/// Motion state of one headset
+pub struct MotionTracker {
+    accelerometer: Option<[f32; 3]>,
+    gyro: Option<(DateTime<Local>, [f32; 3])>, // Most recent sample and its time
+    orientation: Option<Orientation>,
+    speed: WindowedStats<f32>, // Squared angular speed over the intensity window
+    nod: Swings,
+    shake: Swings,
+    hold_off_until: Option<DateTime<Local>>,
+}
+
+impl Default for MotionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MotionTracker {
+    pub fn new() -> Self {
+        MotionTracker {
+            accelerometer: None,
+            gyro: None,
+            orientation: None,
+            speed: WindowedStats::new(Some(INTENSITY_SAMPLES)),
+            nod: Swings::default(),
+            shake: Swings::default(),
+            hold_off_until: None,
+        }
+    }
+
+    /// Without a gyro, orientation is the accelerometer tilt alone
+    pub fn accelerometer(&mut self, accelerometer: [f32; 3]) {
+        self.accelerometer = Some(accelerometer);
+        if self.gyro.is_none() {
+            self.orientation = Some(tilt(accelerometer));
+        }
+    }
+
+    /// Update orientation, movement intensity and gestures. Returns a gesture when one is recognised.
+    pub fn gyro(&mut self, time: DateTime<Local>, gyro: [f32; 3]) -> Option<HeadGesture> {
+        let [roll_rate, pitch_rate, yaw_rate] = gyro;
+        let interval = self
+            .gyro
+            .replace((time, gyro))
+            .map(|(previous, _)| time - previous)
+            .filter(|interval| {
+                *interval > Duration::zero()
+                    && *interval <= Duration::milliseconds(MAX_GYRO_INTERVAL_MS)
+            });
+
+        if let Some(accelerometer) = self.accelerometer {
+            let tilt = tilt(accelerometer);
+            self.orientation = Some(match (self.orientation, interval) {
+                (Some(orientation), Some(interval)) => {
+                    let seconds = interval.num_microseconds().unwrap_or(0) as f32 / 1_000_000.0;
+                    let blend = |angle: f32, rate: f32, tilt: f32| {
+                        GYRO_WEIGHT * (angle + rate * seconds) + (1.0 - GYRO_WEIGHT) * tilt
+                    };
+                    Orientation {
+                        pitch: blend(orientation.pitch, pitch_rate, tilt.pitch),
+                        roll: blend(orientation.roll, roll_rate, tilt.roll),
+                    }
+                }
+                _ => tilt,
+            });
+        }
+
+        self.speed.push(gyro.iter().map(|rate| rate * rate).sum());
+
+        self.gesture(time, pitch_rate, yaw_rate)
+    }
+
+    fn gesture(
+        &mut self,
+        time: DateTime<Local>,
+        pitch_rate: f32,
+        yaw_rate: f32,
+    ) -> Option<HeadGesture> {
+        if let Some(hold_off_until) = self.hold_off_until {
+            if time < hold_off_until {
+                return None;
+            }
+        }
+
+        let is_swing = |rate: f32, other: f32| {
+            rate.abs() >= GESTURE_RATE && rate.abs() >= GESTURE_DOMINANCE * other.abs()
+        };
+        let gesture = if is_swing(pitch_rate, yaw_rate) && self.nod.update(time, pitch_rate) {
+            HeadGesture::Nod
+        } else if is_swing(yaw_rate, pitch_rate) && self.shake.update(time, yaw_rate) {
+            HeadGesture::Shake
+        } else {
+            return None;
+        };
+
+        self.nod = Swings::default();
+        self.shake = Swings::default();
+        self.hold_off_until = Some(time + Duration::milliseconds(GESTURE_HOLD_OFF_MS));
+
+        Some(gesture)
+    }
+
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.orientation
+    }
+
+    /// RMS angular speed over about the last second, degrees per second. None without a gyro.
+    pub fn intensity(&self) -> Option<f32> {
+        self.speed.mean().map(|mean_square| mean_square.sqrt())
+    }
+
+    /// Moving enough to spoil the EEG
+    pub fn is_moving(&self) -> bool {
+        self.intensity().unwrap_or(0.0) > MOVING_INTENSITY
+    }
+}
+
+/// Pitch and roll from the direction of gravity alone
+fn tilt(accelerometer: [f32; 3]) -> Orientation {
+    let [x, y, z] = accelerometer;
+
+    Orientation {
+        pitch: (-x).atan2((y * y + z * z).sqrt()).to_degrees(),
+        roll: y.atan2(z).to_degrees(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(ms: i64) -> DateTime<Local> {
+        Local.timestamp_millis(1_582_616_149_000 + ms)
+    }
+
+    #[test]
+    fn test_tilt() {
+        let level = tilt([0.0, 0.0, 1.0]);
+        assert_eq!(0.0, level.pitch);
+        assert_eq!(0.0, level.roll);
+
+        let down = tilt([-0.5, 0.0, 0.75f32.sqrt()]);
+        assert!((down.pitch - 30.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_gyro_turn_settles_on_tilt() {
+        let mut motion = MotionTracker::new();
+        motion.accelerometer([0.0, 0.0, 1.0]);
+        motion.gyro(at(0), [0.0, 0.0, 0.0]);
+        for i in 1..=50 {
+            motion.gyro(at(i * 20), [0.0, 45.0, 0.0]); // Looking down at 45 degrees per second for a second
+        }
+        let turned = motion.orientation().unwrap().pitch;
+        assert!(turned > 20.0 && turned < 45.0);
+        assert!(motion.is_moving());
+
+        for i in 51..=500 {
+            motion.gyro(at(i * 20), [0.0, 0.0, 0.0]); // The accelerometer still says level
+        }
+        assert!(motion.orientation().unwrap().pitch.abs() < 0.1);
+        assert!(!motion.is_moving());
+    }
+
+    #[test]
+    fn test_nod_and_shake() {
+        let mut motion = MotionTracker::new();
+        let swing = |motion: &mut MotionTracker, start: i64, rates: [f32; 3]| {
+            (0..5)
+                .filter_map(|i| motion.gyro(at(start + i * 20), rates))
+                .last()
+        };
+
+        assert_eq!(None, swing(&mut motion, 0, [0.0, 90.0, 0.0]));
+        assert_eq!(None, swing(&mut motion, 200, [0.0, -90.0, 0.0]));
+        assert_eq!(
+            Some(HeadGesture::Nod),
+            swing(&mut motion, 400, [0.0, 90.0, 10.0])
+        );
+
+        assert_eq!(None, swing(&mut motion, 3000, [0.0, 0.0, 90.0]));
+        assert_eq!(None, swing(&mut motion, 3200, [0.0, 0.0, -90.0]));
+        assert_eq!(None, swing(&mut motion, 5000, [0.0, 0.0, 90.0])); // Too slow
+        assert_eq!(None, swing(&mut motion, 5200, [0.0, 0.0, -90.0]));
+        assert_eq!(
+            Some(HeadGesture::Shake),
+            swing(&mut motion, 5400, [0.0, 0.0, 90.0])
+        );
+    }
+}
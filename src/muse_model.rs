@@ -1,36 +1,48 @@
-use crate::muse_packet::*;
+use crate::affect_metric::{AffectMetrics, AffectSettings};
+use crate::artifact::{Artifact, ArtifactGate, ArtifactPolicy};
+use crate::calibration::{Baseline, Calibration, CalibrationSettings, ParticipantBaseline};
+use crate::clock_offset::{
+    ClockOffsetEstimator, ClockOffsetMode, OscTimetag, DEFAULT_CLOCK_OFFSET_WINDOW,
+};
+use crate::edf::EdfRecording;
+use crate::filter::{FilterBank, FilterSettings};
+use crate::heart_rate::{BreathDriver, HeartRate, HeartRateDetector};
+use crate::motion::{HeadGesture, MotionTracker};
+use crate::recorder::{
+    format_values, sha256, CsvFile, MessageLog, RecordedFile, Recorder, RecorderHandle,
+    RecorderSettings,
+};
+use crate::running_stats::{ExponentialAverage, WindowedStats};
+use crate::session::{SessionManifest, SessionParticipant, SessionSettings};
+use crate::session_event::{self, SessionEvent, EVENTS_FILE};
+use crate::signal_quality::SignalQuality;
+use crate::spectrum::{BandEdges, BandPowers, SpectralPipeline, WelchSettings};
+use crate::wire_format::nan_as_null;
+use crate::xdf::XdfRecorder;
 
 //#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 
 /// Muse data model and associated message handling from muse_packet
 // #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
-use std::sync::mpsc::SendError;
-
 // use log::*;
 use chrono::{DateTime, Local};
-use csv::Writer;
 use num_traits::float::Float;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::From;
 use std::f32::consts::E;
-use std::net::SocketAddr;
-use std::sync::mpsc;
-use std::sync::mpsc::{Receiver, Sender};
-use std::{convert::From, fs::File, slice::Iter, thread};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 
 const FOREHEAD_COUNTDOWN: i32 = 5; // 60th of a second counts
 const BLINK_COUNTDOWN: i32 = 5;
 const CLENCH_COUNTDOWN: i32 = 5;
-const HISTORY_LENGTH: usize = 120; // Used to trunacte ArousalHistory and ValenceHistory length - this is the number of samples in the normalization phase
-const TP9: usize = 0; // Muse measurment array index for first electrode
-const AF7: usize = 1; // Muse measurment array index for second electrode
-const AF8: usize = 2; // Muse measurment array index for third electrode
-const TP10: usize = 3; // Muse measurment array index for fourth electrode
-
 const WINDOW_LENGTH: usize = 10; // Current values is smoothed by most recent X values
 
-const OSC_PORT: u16 = 34254;
-
 const TIME_FORMAT_FOR_FILENAMES: &str = "%Y-%m-%d %H-%M-%S%.3f"; // 2020-02-25 09-35-49
 const TIME_FORMAT_FOR_CSV: &str = "%Y-%m-%d %H:%M:%S%.3f"; // 2020-02-25 09:35:49
+const XDF_FILE: &str = "session.xdf";
 
 /// Make it easier to print out the message receiver object for debug purposes
 // struct ReceiverDebug<T> {
@@ -51,7 +63,7 @@ pub fn date_time_filename_format(date_time: DateTime<Local>) -> String {
 }
 
 /// Format a Duration (from packet receive time etc) to a string date nominally accurate down to milliseconds in a format sutable for parsing from CSV / Spreadsheets
-fn date_time_csv_format(date_time: DateTime<Local>) -> String {
+pub fn date_time_csv_format(date_time: DateTime<Local>) -> String {
     let s: String = format!("{}", date_time.format(TIME_FORMAT_FOR_CSV));
 
     s
@@ -73,106 +85,347 @@ pub enum DisplayType {
     EegValues,
 }
 
-#[derive(Clone, Debug)]
-pub enum MuseMessageType {
-    Eeg { eeg: [f32; 4] }, // microVolts
-    Accelerometer { x: f32, y: f32, z: f32 },
-    Gyro { x: f32, y: f32, z: f32 },
-    Alpha { alpha: [f32; 4] },                // microVolts
-    Beta { beta: [f32; 4] },                  // microVolts
-    Gamma { gamma: [f32; 4] },                // microVolts
-    Delta { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Theta { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Batt { batt: i32 },
-    Horseshoe { a: f32, b: f32, c: f32, d: f32 },
-    TouchingForehead { touch: bool },
-    Blink { blink: bool },
-    JawClench { clench: bool },
+/// EEG frequency bands, in the order the Muse names them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EegBand {
+    Delta, // 1-4Hz
+    Theta, // 4-8Hz
+    Alpha, // 7.5-13Hz
+    Beta,  // 13-30Hz
+    Gamma, // 30-44Hz
 }
 
-type TimedMuseMessage = (DateTime<Local>, MuseMessageType);
+pub const N_EEG_BANDS: usize = 5;
+
+impl EegBand {
+    pub const ALL: [EegBand; N_EEG_BANDS] = [
+        EegBand::Delta,
+        EegBand::Theta,
+        EegBand::Alpha,
+        EegBand::Beta,
+        EegBand::Gamma,
+    ];
+
+    /// Band from the lower case name used in OSC addresses such as "/muse/elements/alpha_relative"
+    pub fn from_name(name: &str) -> Option<EegBand> {
+        match name {
+            "delta" => Some(EegBand::Delta),
+            "theta" => Some(EegBand::Theta),
+            "alpha" => Some(EegBand::Alpha),
+            "beta" => Some(EegBand::Beta),
+            "gamma" => Some(EegBand::Gamma),
+            _ => None,
+        }
+    }
+
+    /// Name for CSV headers and log rows
+    pub fn label(self) -> &'static str {
+        match self {
+            EegBand::Delta => "Delta",
+            EegBand::Theta => "Theta",
+            EegBand::Alpha => "Alpha",
+            EegBand::Beta => "Beta",
+            EegBand::Gamma => "Gamma",
+        }
+    }
 
-#[derive(Clone, Debug)]
+    /// Position in EegBand::ALL and in per-band arrays
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Float fields are serialized with nan_as_null, as the wire format is JSON which has no NaN
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum MuseMessageType {
+    Eeg {
+        #[serde(with = "nan_as_null")]
+        eeg: [f32; 4], // microVolts
+    },
+    Accelerometer {
+        #[serde(with = "nan_as_null")]
+        x: f32,
+        #[serde(with = "nan_as_null")]
+        y: f32,
+        #[serde(with = "nan_as_null")]
+        z: f32,
+    },
+    Gyro {
+        #[serde(with = "nan_as_null")]
+        x: f32,
+        #[serde(with = "nan_as_null")]
+        y: f32,
+        #[serde(with = "nan_as_null")]
+        z: f32,
+    },
+    Alpha {
+        #[serde(with = "nan_as_null")]
+        alpha: [f32; 4], // microVolts
+    },
+    Beta {
+        #[serde(with = "nan_as_null")]
+        beta: [f32; 4], // microVolts
+    },
+    Gamma {
+        #[serde(with = "nan_as_null")]
+        gamma: [f32; 4], // microVolts
+    },
+    Delta {
+        #[serde(with = "nan_as_null")]
+        a: f32,
+        #[serde(with = "nan_as_null")]
+        b: f32,
+        #[serde(with = "nan_as_null")]
+        c: f32,
+        #[serde(with = "nan_as_null")]
+        d: f32,
+    }, // microVolts
+    Theta {
+        #[serde(with = "nan_as_null")]
+        a: f32,
+        #[serde(with = "nan_as_null")]
+        b: f32,
+        #[serde(with = "nan_as_null")]
+        c: f32,
+        #[serde(with = "nan_as_null")]
+        d: f32,
+    }, // microVolts
+    Batt {
+        batt: i32,
+    },
+    Horseshoe {
+        #[serde(with = "nan_as_null")]
+        a: f32,
+        #[serde(with = "nan_as_null")]
+        b: f32,
+        #[serde(with = "nan_as_null")]
+        c: f32,
+        #[serde(with = "nan_as_null")]
+        d: f32,
+    },
+    TouchingForehead {
+        touch: bool,
+    },
+    Blink {
+        blink: bool,
+    },
+    JawClench {
+        clench: bool,
+    },
+    Relative {
+        band: EegBand,
+        #[serde(with = "nan_as_null")]
+        values: [f32; 4], // Fraction of total power, 0..1
+    },
+    SessionScore {
+        band: EegBand,
+        #[serde(with = "nan_as_null")]
+        values: [f32; 4], // 0..1 relative to this session's history
+    },
+    LowFrequencies {
+        #[serde(with = "nan_as_null")]
+        low_freqs: [f32; 4], // microVolts, 2.5-6.1Hz
+    },
+    RawFft {
+        channel: usize,
+        #[serde(with = "nan_as_null")]
+        fft: Vec<f32>, // 129 bins of 0.86Hz for one electrode
+    },
+    IsGood {
+        is_good: [bool; 4],
+    },
+    HsiPrecision {
+        #[serde(with = "nan_as_null")]
+        hsi: [f32; 4],
+    },
+    Ppg {
+        #[serde(with = "nan_as_null")]
+        ppg: [f32; 3], // Muse 2/S photoplethysmograph: ambient, infrared, red
+    },
+    DrlRef {
+        #[serde(with = "nan_as_null")]
+        drl: f32,
+        #[serde(with = "nan_as_null")]
+        reference: f32,
+    }, // microVolts
+    Aux {
+        #[serde(with = "nan_as_null")]
+        aux: Vec<f32>, // microVolts
+    },
+    Concentration {
+        #[serde(with = "nan_as_null")]
+        concentration: f32,
+    },
+    Mellow {
+        #[serde(with = "nan_as_null")]
+        mellow: f32,
+    },
+    Annotation {
+        annotation: String,
+    },
+    Marker {
+        marker: i32, // Mind Monitor marker buttons, /Marker/1 to /Marker/5
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MuseMessage {
-    pub message_time: DateTime<Local>, // Since UNIX_EPOCH, the beginning of 1970
+    pub message_time: DateTime<Local>, // Since UNIX_EPOCH, the beginning of 1970. Best estimate of sample time on the local clock
+    pub receive_time: DateTime<Local>, // When the packet arrived on this machine
+    pub timetag: Option<OscTimetag>,   // Sender clock time from the enclosing OSC bundle, if any
     pub ip_address: SocketAddr,
     pub muse_message_type: MuseMessageType,
 }
 
-/// Receive messages of EEG data from some source (OSC or websockets)
-trait EegMessageReceiver {
-    fn new() -> inner_receiver::InnerMessageReceiver;
-    fn receive_packets(&self) -> Vec<MuseMessage>;
+/// Reasons an incoming OSC message or relay frame can not be converted to a MuseMessage. The packet
+/// is dropped, not the session.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MusePacketError {
+    /// The message arrived with no argument list at all
+    MissingArgs { addr: String },
+    /// Fewer arguments than the address requires
+    WrongArity {
+        addr: String,
+        expected: usize,
+        actual: usize,
+    },
+    /// An argument was present but not of the expected OSC type
+    TypeMismatch {
+        addr: String,
+        index: usize,
+        expected: &'static str,
+    },
+    /// The OSC address is not one sent by the Muse or Mind Monitor
+    UnknownAddress { addr: String },
+    /// A frame from a non-OSC source (WebSocket relay, ...) could not be decoded. `addr` names the source.
+    Undecodable { addr: String, reason: String },
 }
 
-/// An OSC USB packet receiver for all platforms except WASM
-#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
-mod inner_receiver {
-    use super::{EegMessageReceiver, MuseMessage};
-    use nannou_osc;
-
-    pub struct InnerMessageReceiver {
-        receiver: nannou_osc::Receiver,
+impl MusePacketError {
+    /// The OSC address of the message which failed to parse, used to count errors per address
+    pub fn addr(&self) -> &str {
+        match self {
+            MusePacketError::MissingArgs { addr }
+            | MusePacketError::WrongArity { addr, .. }
+            | MusePacketError::TypeMismatch { addr, .. }
+            | MusePacketError::UnknownAddress { addr }
+            | MusePacketError::Undecodable { addr, .. } => addr,
+        }
     }
+}
 
-    impl EegMessageReceiver for InnerMessageReceiver {
-        fn new() -> InnerMessageReceiver {
-            info!("Connecting to EEG");
-
-            let receiver = nannou_osc::receiver(super::OSC_PORT)
-                .expect("Can not bind to port- is another copy of this app already running?");
-
-            InnerMessageReceiver { receiver }
+impl fmt::Display for MusePacketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MusePacketError::MissingArgs { addr } => write!(f, "{}: no arguments", addr),
+            MusePacketError::WrongArity {
+                addr,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}: expected {} arguments, received {}",
+                addr, expected, actual
+            ),
+            MusePacketError::TypeMismatch {
+                addr,
+                index,
+                expected,
+            } => write!(f, "{}: argument {} is not {}", addr, index, expected),
+            MusePacketError::UnknownAddress { addr } => write!(f, "{}: unknown address", addr),
+            MusePacketError::Undecodable { addr, reason } => {
+                write!(f, "{}: can not decode, {}", addr, reason)
+            }
         }
+    }
+}
 
-        /// Receive any pending osc packets.
-        fn receive_packets(&self) -> Vec<MuseMessage> {
-            let receivables: Vec<(nannou_osc::Packet, std::net::SocketAddr)> =
-                self.receiver.try_iter().collect();
-
-            let mut muse_messages: Vec<MuseMessage> = Vec::new();
+impl std::error::Error for MusePacketError {}
 
-            for (packet, addr) in receivables {
-                let mut additional_messages: Vec<MuseMessage> =
-                    super::parse_muse_packet(addr, &packet);
-                muse_messages.append(&mut additional_messages);
-            }
+/// Receive messages of EEG data from some source (OSC, capture replay, simulator, websockets, ...).
+/// MuseModel polls its receiver once per frame, so implementations must not block.
+pub trait EegMessageReceiver {
+    /// Everything which has arrived since the previous call, including packets which could not be parsed
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>>;
+}
 
-            muse_messages
-        }
-    }
+/// Somewhere to pass on each message after it is received and timed, such as a network relay
+pub trait MuseMessageSink {
+    fn send_message(&self, muse_message: &MuseMessage);
+
+    /// Normalized valence and arousal of one participant, sent each time they are recalculated
+    fn send_affect(
+        &self,
+        participant: usize,
+        time: DateTime<Local>,
+        valence: Option<f32>,
+        arousal: Option<f32>,
+    );
+
+    /// A protocol event such as a change of stage, as written to events.csv
+    fn send_event(&self, _time: DateTime<Local>, _event: &SessionEvent) {}
 }
 
-/// A placeholder structure for WASM to avoid dependency on non-existing package issues
-#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
-mod inner_receiver {
-    use super::{EegMessageReceiver, MuseMessage};
+/// Smoothing of the current value before it is normalized
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    /// Mean of the most recent `window` values
+    MovingAverage { window: usize },
+    /// Each new value has weight `alpha`, 0 to 1
+    Exponential { alpha: f32 },
+}
 
-    /// TODO Receive messages from the server in the web implementation
-    pub struct InnerMessageReceiver {}
+/// Centre and spread of the baseline which values are normalized against
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BaselineStatistic {
+    MeanDeviation,
+    /// Median and scaled median absolute deviation, which outliers such as movement barely shift.
+    /// Each update sorts the baseline window.
+    MedianMad,
+}
 
-    impl EegMessageReceiver for InnerMessageReceiver {
-        fn new() -> InnerMessageReceiver {
-            info!("PLACEHOLDER: Will be indirectly connecting to EEG");
+/// What to do with a value equal to the previous one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RepeatedValues {
+    /// Leave it out, as the headset resends unchanged band powers when it has nothing new
+    Skip,
+    Keep,
+}
 
-            InnerMessageReceiver {}
-        }
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NormalizationSettings {
+    /// Most recent values in the baseline until calibration freezes it. None uses every value since
+    /// calibration started.
+    pub window: Option<usize>,
+    pub smoothing: Smoothing,
+    pub statistic: BaselineStatistic,
+    pub repeated_values: RepeatedValues,
+}
 
-        /// Receive any pending osc packets.
-        fn receive_packets(&self) -> Vec<MuseMessage> {
-            Vec::new()
+impl Default for NormalizationSettings {
+    fn default() -> Self {
+        NormalizationSettings {
+            window: None,
+            smoothing: Smoothing::MovingAverage {
+                window: WINDOW_LENGTH - 1, // The window has always dropped its oldest value on reaching WINDOW_LENGTH
+            },
+            statistic: BaselineStatistic::MeanDeviation,
+            repeated_values: RepeatedValues::Skip,
         }
     }
 }
 
 pub struct NormalizedValue<T: Float + From<i16>> {
+    settings: NormalizationSettings,
     current: Option<T>,
     min: Option<T>,
     max: Option<T>,
-    mean: Option<T>,
-    deviation: Option<T>,
-    history: Vec<T>,
-    moving_average_history: Vec<T>,
+    mean: Option<T>, // Baseline centre, the median for BaselineStatistic::MedianMad
+    deviation: Option<T>, // Baseline spread
+    history: WindowedStats<T>, // Baseline values since the start of calibration
+    baseline: Option<(T, T)>, // Mean and deviation frozen at the end of calibration
+    moving_average_history: WindowedStats<T>,
+    exponential_average: ExponentialAverage<T>,
 }
 
 impl<T> NormalizedValue<T>
@@ -180,35 +433,45 @@ where
     T: Float + From<i16>,
 {
     pub fn new() -> Self {
+        Self::with_settings(NormalizationSettings::default())
+    }
+
+    pub fn with_settings(settings: NormalizationSettings) -> Self {
+        let (moving_average_window, alpha) = match settings.smoothing {
+            Smoothing::MovingAverage { window } => (window, 1.0),
+            Smoothing::Exponential { alpha } => (1, alpha),
+        };
+
         Self {
+            settings,
             current: None,
             min: None,
             max: None,
             mean: None,
             deviation: None,
-            history: Vec::new(),
-            moving_average_history: Vec::new(),
+            history: WindowedStats::new(settings.window),
+            baseline: None,
+            moving_average_history: WindowedStats::new(Some(moving_average_window)),
+            exponential_average: ExponentialAverage::new(alpha),
         }
     }
 
+    /// The current value smoothed as set in NormalizationSettings
     pub fn moving_average(&self) -> Option<T>
     where
         T: Float + From<i16>,
     {
-        let count = self.moving_average_history.len() as i16;
-        let sum = sum(&self.moving_average_history);
-
-        match count {
-            positive if positive > 0 => Some(sum / count.into()),
-            _ => None,
+        match self.settings.smoothing {
+            Smoothing::MovingAverage { .. } => self.moving_average_history.mean(),
+            Smoothing::Exponential { .. } => self.exponential_average.value(),
         }
     }
 
-    // Set the value if it is a change and a rational number. Returns true if the value is accepted as finite and a change from the previous value
+    // Set the value if it is a rational number, and by the RepeatedValues policy a change. Returns true if the value is accepted.
     pub fn set(&mut self, val: T) -> bool {
-        let acceptable_new_value = match self.current {
-            Some(current_value) => val.is_finite() && val != current_value,
-            None => val.is_finite(),
+        let acceptable_new_value = match (self.current, self.settings.repeated_values) {
+            (Some(current_value), RepeatedValues::Skip) => val.is_finite() && val != current_value,
+            _ => val.is_finite(),
         };
 
         if acceptable_new_value {
@@ -219,23 +482,54 @@ where
             if !self.min.is_some() || self.min.unwrap() > val {
                 self.min = Some(val);
             }
-            self.history.push(val);
-            if self.history.len() > HISTORY_LENGTH {
-                self.history.remove(0);
+            if self.baseline.is_none() {
+                self.history.push(val);
+                match self.settings.statistic {
+                    BaselineStatistic::MeanDeviation => {
+                        self.mean = self.history.mean();
+                        self.deviation = self.history.deviation();
+                    }
+                    BaselineStatistic::MedianMad => {
+                        self.mean = self.history.median();
+                        self.deviation = self.history.median_absolute_deviation();
+                    }
+                }
             }
-            self.mean = mean(&self.history); //TODO never call this anywhere else
-            self.deviation = std_deviation(&self.history, self.mean); //TODO never call this anywhere else
             self.moving_average_history.push(val);
-            if self.moving_average_history.len() >= WINDOW_LENGTH {
-                self.moving_average_history.remove(0);
-            }
+            self.exponential_average.push(val);
         }
 
         acceptable_new_value
     }
 
-    pub fn _percent_normalization_complete(&self) -> f32 {
-        self.history.len() as f32 / HISTORY_LENGTH as f32
+    /// There is a mean and deviation to freeze
+    pub fn can_freeze(&self) -> bool {
+        self.mean.is_some() && self.deviation.is_some()
+    }
+
+    /// Forget the baseline values so far, to calibrate again
+    pub fn restart_baseline(&mut self) {
+        self.mean = None;
+        self.deviation = None;
+        self.history.clear();
+    }
+
+    /// Stop calibrating and keep the current mean and deviation from now on. Returns them, or None
+    /// if no values have arrived yet.
+    pub fn freeze(&mut self) -> Option<(T, T)> {
+        if let (Some(mean), Some(deviation)) = (self.mean, self.deviation) {
+            self.set_baseline(mean, deviation);
+        }
+
+        self.baseline
+    }
+
+    /// Use a mean and deviation from an earlier calibration
+    pub fn set_baseline(&mut self, mean: T, deviation: T) {
+        self.baseline = Some((mean, deviation));
+        self.mean = Some(mean);
+        self.deviation = Some(deviation);
+        self.history.clear();
     }
 
     pub fn mean(&self) -> Option<T> {
@@ -311,266 +605,144 @@ pub fn average_from_front_electrodes(x: &[f32; 4]) -> f32 {
     //(x[1] + x[2]) / 2.0
 }
 
-/// Create a log of values and events collected during a session
-fn create_log_writer(start_date_time: DateTime<Local>, filename: &str) -> Writer<File> {
-    let formatted_date_time = date_time_filename_format(start_date_time);
-
-    let filename_with_date_time = format!("{} {}", formatted_date_time, filename);
-    let writer: Writer<File> =
-        Writer::from_path(filename_with_date_time).expect("Could not open CSV file for writing");
-
-    writer
-}
+/// Format values as "1.0, 2.0, 3.0" for a single other.csv record
+fn comma_list(values: &[f32]) -> String {
+    let strings: Vec<String> = values.iter().map(|val| format!("{:?}", val)).collect();
 
-fn write_record(
-    time: DateTime<Local>,
-    iter: Iter<f32>,
-    writer: &mut Writer<File>,
-) -> Result<(), String> {
-    let mut vec: Vec<String> = Vec::new();
-    vec.push(date_time_csv_format(time));
-    for val in iter {
-        vec.push(val.to_string());
-    }
-    writer
-        .write_record(vec)
-        .or(Err("Can not write record".to_string()))
+    strings.join(", ")
 }
 
-fn create_async_eeg_log_writer(
-    start_date_time: DateTime<Local>,
-    filename: &str,
-    header: Iter<&str>,
-) -> Sender<MuseMessage> {
-    let (tx_log, rx_log): (Sender<MuseMessage>, Receiver<MuseMessage>) = mpsc::channel();
-    let filename: String = filename.into();
-    let mut header_vec: Vec<String> = Vec::new();
-    for val in header {
-        header_vec.push(val.to_string());
-    }
-
-    thread::spawn(move || {
-        let mut writer = create_log_writer(start_date_time, &filename);
-        let mut iter: mpsc::Iter<MuseMessage> = rx_log.iter();
-        let mut stream_open = true;
-
-        writer
-            .write_record(header_vec)
-            .expect("Could not write eeg header");
-        while stream_open {
-            match iter.next() {
-                Some(MuseMessage {
-                    message_time,
-                    muse_message_type,
-                    ..
-                }) => match muse_message_type {
-                    MuseMessageType::Eeg { eeg } => {
-                        write_record(message_time, eeg.iter(), &mut writer)
-                            .expect(&format!("Could not write record to {}", filename));
-                    }
-                    _ => {
-                        panic!(format!(
-                            "Unexpected message type, should be for {}",
-                            filename
-                        ));
-                    }
-                },
-                None => {
-                    writer
-                        .flush()
-                        .expect(&format!("Can not flush writer: {}", filename));
-                    stream_open = false;
-                }
-            }
-        }
-    });
-
-    tx_log
+/// A state of the participant relative to their calibration baseline, and how far it can be trusted
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Estimate {
+    /// Smoothed standard deviations from the baseline, None until values arrive
+    pub value: Option<f32>,
+    /// 0 while calibrating or after an artifact, otherwise the mean electrode signal quality, 0 to 1
+    pub confidence: f32,
 }
 
-fn create_async_alpha_log_writer(
-    start_date_time: DateTime<Local>,
-    filename: &str,
-    header: Iter<&str>,
-) -> Sender<MuseMessage> {
-    let (tx_log, rx_log): (Sender<MuseMessage>, Receiver<MuseMessage>) = mpsc::channel();
-    let filename: String = filename.into();
-    let mut header_vec: Vec<String> = Vec::new();
-    for val in header {
-        header_vec.push(val.to_string());
-    }
-
-    thread::spawn(move || {
-        let mut writer = create_log_writer(start_date_time, &filename);
-        let mut iter: mpsc::Iter<MuseMessage> = rx_log.iter();
-        let mut stream_open = true;
-
-        writer
-            .write_record(header_vec)
-            .expect("Could not write alpha header");
-        while stream_open {
-            match iter.next() {
-                Some(MuseMessage {
-                    message_time,
-                    muse_message_type,
-                    ..
-                }) => match muse_message_type {
-                    MuseMessageType::Alpha { alpha } => {
-                        write_record(message_time, alpha.iter(), &mut writer)
-                            .expect(&format!("Could not write record to {}", filename));
-                    }
-                    _ => {
-                        panic!(format!(
-                            "Unexpected message type, should be for {}",
-                            filename
-                        ));
-                    }
-                },
-                None => {
-                    writer
-                        .flush()
-                        .expect(&format!("Can not flush writer: {}", filename));
-                    stream_open = false;
-                }
-            }
-        }
-    });
-
-    tx_log
-}
-
-fn create_async_beta_log_writer(
-    start_date_time: DateTime<Local>,
-    filename: &str,
-    header: Iter<&str>,
-) -> Sender<MuseMessage> {
-    let (tx_log, rx_log): (Sender<MuseMessage>, Receiver<MuseMessage>) = mpsc::channel();
-    let filename: String = filename.into();
-    let mut header_vec: Vec<String> = Vec::new();
-    for val in header {
-        header_vec.push(val.to_string());
-    }
-
-    thread::spawn(move || {
-        let mut writer = create_log_writer(start_date_time, &filename);
-        let mut iter: mpsc::Iter<MuseMessage> = rx_log.iter();
-        let mut stream_open = true;
-
-        writer
-            .write_record(header_vec)
-            .expect("Could not write beta header");
-        while stream_open {
-            match iter.next() {
-                Some(MuseMessage {
-                    message_time,
-                    muse_message_type,
-                    ..
-                }) => match muse_message_type {
-                    MuseMessageType::Beta { beta } => {
-                        write_record(message_time, beta.iter(), &mut writer)
-                            .expect(&format!("Could not write record to {}", filename));
-                    }
-                    _ => {
-                        panic!(format!(
-                            "Unexpected message type, should be for {}",
-                            filename
-                        ));
-                    }
-                },
-                None => {
-                    writer
-                        .flush()
-                        .expect(&format!("Can not flush writer: {}", filename));
-                    stream_open = false;
-                }
-            }
-        }
-    });
-
-    tx_log
-}
-
-fn create_async_gamma_log_writer(
-    start_date_time: DateTime<Local>,
-    filename: &str,
-    header: Iter<&str>,
-) -> Sender<MuseMessage> {
-    let (tx_log, rx_log): (Sender<MuseMessage>, Receiver<MuseMessage>) = mpsc::channel();
-    let filename: String = filename.into();
-    let mut header_vec: Vec<String> = Vec::new();
-    for val in header {
-        header_vec.push(val.to_string());
-    }
-
-    thread::spawn(move || {
-        let mut writer = create_log_writer(start_date_time, &filename);
-        let mut iter: mpsc::Iter<MuseMessage> = rx_log.iter();
-        let mut stream_open = true;
-
-        writer
-            .write_record(header_vec)
-            .expect("Could not write gamma header");
-        while stream_open {
-            match iter.next() {
-                Some(MuseMessage {
-                    message_time,
-                    muse_message_type,
-                    ..
-                }) => match muse_message_type {
-                    MuseMessageType::Gamma { gamma } => {
-                        write_record(message_time, gamma.iter(), &mut writer)
-                            .expect(&format!("Could not write record to {}", filename));
-                    }
-                    _ => {
-                        panic!(format!(
-                            "Unexpected message type, should be for {}",
-                            filename
-                        ));
-                    }
-                },
-                None => {
-                    writer
-                        .flush()
-                        .expect(&format!("Can not flush writer: {}", filename));
-                    stream_open = false;
-                }
-            }
-        }
-    });
-
-    tx_log
-}
-
-/// Snapshot of the most recently collected values from Muse EEG headset
-pub struct MuseModel {
+/// Snapshot of the most recently collected values from one Muse EEG headset
+pub struct HeadsetModel {
+    source: IpAddr,     // Packets from this address belong to this headset
+    participant: usize, // Participant slot, 0 is the first participant
     most_recent_message_receive_time: DateTime<Local>,
-    pub inner_receiver: inner_receiver::InnerMessageReceiver,
     receiving_data: bool,
     accelerometer: [f32; 3],
     gyro: [f32; 3],
+    motion: MotionTracker, // Orientation, movement and gestures from accelerometer and gyro
+    gesture: Option<HeadGesture>, // Recognised but not yet taken
+    pub eeg: [f32; 4],     // Most recent raw sample for each electrode, microVolts
+    pub filtered_eeg: [f32; 4], // The same sample after the filter bank, microVolts
     pub alpha: [f32; 4],
     pub beta: [f32; 4],
     pub gamma: [f32; 4],
     pub delta: [f32; 4],
     pub theta: [f32; 4],
+    pub relative: [[f32; 4]; N_EEG_BANDS], // Indexed by EegBand::index()
+    pub session_score: [[f32; 4]; N_EEG_BANDS], // Indexed by EegBand::index()
+    pub low_freqs: [f32; 4],
+    pub raw_fft: [Vec<f32>; 4], // Most recent spectrum for each electrode
+    pub local_absolute: BandPowers, // Computed here from raw EEG, indexed by EegBand::index()
+    pub is_good: [bool; 4],
+    pub hsi_precision: [f32; 4],
+    pub ppg: [f32; 3],
+    pub heart_rate: HeartRate, // From the PPG, updated every accepted beat
+    pub drl_ref: [f32; 2],
+    pub aux: Vec<f32>,
+    pub concentration: f32,
+    pub mellow: f32,
     batt: i32,
     horseshoe: [f32; 4],
     blink_countdown: i32,
     touching_forehead_countdown: i32,
     jaw_clench_countdown: i32,
-    pub scale: f32,
-    pub display_type: DisplayType,
     pub arousal: NormalizedValue<f32>,
     pub valence: NormalizedValue<f32>,
-    eeg_log_sender: Sender<MuseMessage>, // Raw EEG values every time they arrive, CSV
-    alpha_log_sender: Sender<MuseMessage>, // Processed EEG values every time they arrive, CSV
-    beta_log_sender: Sender<MuseMessage>, // Processed EEG values every time they arrive, CSV
-    gamma_log_sender: Sender<MuseMessage>, // Processed EEG values every time they arrive, CSV
-    delta_log_writer: Writer<File>,      // Processed EEG values every time they arrive, CSV
-    theta_log_writer: Writer<File>,      // Processed EEG values every time they arrive, CSV
-    other_log_writer: Writer<File>,      // Other values every time they arrive, CSV
+    pub drowsiness: NormalizedValue<f32>,
+    pub emotion_estimate: Estimate, // Normalized valence, for DisplayType::Emotion
+    pub drowsiness_estimate: Estimate, // Normalized drowsiness, for DisplayType::Dowsiness
+    pub artifact: Option<Artifact>, // Why the most recent band powers were left out of valence and arousal
+    artifact_gate: ArtifactGate,
+    pending_band_powers: VecDeque<(DateTime<Local>, BandPowers)>, // Held back for the artifact gate's pre-event margin
+    calibration: Calibration, // Valence, arousal and drowsiness baseline for this participant
+    signal_quality: SignalQuality, // Per electrode fit from horseshoe, is_good and the raw signal
+    heart_rate_detector: HeartRateDetector, // Beats in the PPG
+    clock_offset: ClockOffsetEstimator, // Maps headset timetags to local sample times
+    filter_bank: FilterBank,  // Notch, high-pass and band-pass for raw EEG
+    spectrum: SpectralPipeline, // Band powers from filtered EEG
+    message_log: MessageLog,  // Every message as it arrives, each kind to its own CSV or other.csv
+    other_log: CsvFile,       // Events and other values every time they arrive, CSV
+    filtered_eeg_log: CsvFile, // Filtered EEG values every time they arrive, CSV
+    heart_rate_log: CsvFile,  // Heart rate and HRV every accepted beat, CSV
+    motion_log: CsvFile,      // Accelerometer, gyro, orientation and movement, CSV
+    local_band_log: CsvFile,  // Band powers computed from raw EEG, CSV
+    affect_metrics: AffectMetrics, // Valence and arousal formulas, and others logged beside them
+    affect_log: CsvFile,      // Every active affect metric, CSV
+    affect_log_columns: Vec<&'static str>, // Metric names in the most recent affect.csv header
+    estimate_log: CsvFile,    // Emotion and drowsiness estimates with their confidence, CSV
 }
 
+/// How the valence and arousal of several participants drive one display
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParticipantCombination {
+    /// Mean of all participants with a current value
+    Average,
+    /// Valence from the first participant, arousal from the second
+    Split,
+}
+
+/// What each new headset starts with. The MuseModel setters change these and the headsets
+/// already connected.
+#[derive(Clone)]
+struct HeadsetSettings {
+    recorder: RecorderSettings,
+    clock_offset_mode: ClockOffsetMode,
+    welch: WelchSettings,
+    filter: FilterSettings,
+    artifact_policy: ArtifactPolicy,
+}
+
+impl Default for HeadsetSettings {
+    fn default() -> Self {
+        HeadsetSettings {
+            recorder: RecorderSettings::default(),
+            clock_offset_mode: ClockOffsetMode::MinimumDelay {
+                window: DEFAULT_CLOCK_OFFSET_WINDOW,
+            },
+            welch: WelchSettings::default(),
+            filter: FilterSettings::default(),
+            artifact_policy: ArtifactPolicy::default(),
+        }
+    }
+}
+
+/// All headsets in the session, each assigned to a participant slot by source IP address
+pub struct MuseModel {
+    start_time: DateTime<Local>,
+    inner_receiver: Box<dyn EegMessageReceiver>,
+    sinks: Vec<Box<dyn MuseMessageSink>>,
+    headsets: HashMap<IpAddr, HeadsetModel>,
+    participants: Vec<Option<IpAddr>>, // Source in each participant slot, assigned or in order of first packet
+    pub combination: ParticipantCombination,
+    pub scale: f32,
+    pub display_type: DisplayType,
+    pub breath_driver: BreathDriver,
+    headset_settings: HeadsetSettings, // Used for each new headset
+    calibration_settings: CalibrationSettings, // Used for each new headset
+    calibration_started: bool, // The fit check has passed, so each headset measures its baseline as soon as it connects
+    normalization_settings: NormalizationSettings, // Used for each new headset
+    affect_settings: AffectSettings, // Used for each new headset
+    packet_error_counts: HashMap<String, u64>, // Dropped OSC messages, by OSC address
+    session_settings: SessionSettings, // Written to the manifest
+    other_log: CsvFile,        // Session notes such as recording errors, CSV
+    events_log: CsvFile,       // Protocol events, CSV
+    exported_files: Vec<RecordedFile>, // EDF+ and XDF files, listed in the manifest after the CSV files
+    xdf: Option<XdfRecorder>,          // Closed when the manifest is written
+    recorder: Recorder, // Last, so every headset's rows are sent before it closes the files
+}
+
+/// Batch reference for the running deviation in NormalizedValue
+#[cfg(test)]
 fn std_deviation<T>(data: &Vec<T>, mean: Option<T>) -> Option<T>
 where
     T: Float + From<i16>,
@@ -594,120 +766,216 @@ where
     }
 }
 
-impl MuseModel {
-    /// Create a new model for storing received values
-    pub fn new(start_time: DateTime<Local>) -> MuseModel {
-        let inner_receiver = inner_receiver::InnerMessageReceiver::new();
+impl HeadsetModel {
+    /// Create a new model for one headset. Its CSV file names start with the participant label.
+    fn new(
+        start_time: DateTime<Local>,
+        recorder: &RecorderHandle,
+        source: IpAddr,
+        participant: usize,
+        settings: &HeadsetSettings,
+    ) -> HeadsetModel {
+        info!(
+            "New headset {} is {}",
+            source,
+            participant_label(participant)
+        );
+        let prefixed = |filename: &str| format!("{} {}", participant_label(participant), filename);
         let receiving_data = false;
-        let eeg_log_sender = create_async_eeg_log_writer(
-            start_time,
-            "eeg.csv",
-            ["Time", "TP9", "AF7", "AF8", "TP10"].iter(),
+        let other_log = recorder.create(&prefixed("other.csv"), &["Time", "Record"]);
+        let message_log = MessageLog::new(
+            recorder.clone(),
+            settings.recorder.clone(),
+            &participant_label(participant),
+            other_log.clone(),
         );
-        let alpha_log_sender = create_async_alpha_log_writer(
-            start_time,
-            "alpha.csv",
-            ["Time", "Alpha TP9", "Alpha AF7", "Alpha AF8", "Alpha TP10"].iter(),
+        let filtered_eeg_log = recorder.create(
+            &prefixed("eeg_filtered.csv"),
+            &["Time", "TP9", "AF7", "AF8", "TP10"],
         );
-        let beta_log_sender = create_async_beta_log_writer(
-            start_time,
-            "beta.csv",
-            ["Time", "Beta TP9", "Beta AF7", "Beta AF8", "Beta TP10"].iter(),
+        let heart_rate_log = recorder.create(
+            &prefixed("heart_rate.csv"),
+            &["Time", "IBI", "Heart rate", "RMSSD"],
         );
-        let gamma_log_sender = create_async_gamma_log_writer(
-            start_time,
-            "gamma.csv",
-            ["Time", "Gamma TP9", "Gamma AF7", "Gamma AF8", "Gamma TP10"].iter(),
+        let motion_log = recorder.create(
+            &prefixed("motion.csv"),
+            &[
+                "Time",
+                "Accel X",
+                "Accel Y",
+                "Accel Z",
+                "Gyro X",
+                "Gyro Y",
+                "Gyro Z",
+                "Pitch",
+                "Roll",
+                "Intensity",
+                "Moving",
+            ],
+        );
+        let affect_log = recorder.create(&prefixed("affect.csv"), &[]);
+        let estimate_log = recorder.create(
+            &prefixed("estimates.csv"),
+            &[
+                "Time",
+                "Emotion",
+                "Emotion confidence",
+                "Drowsiness",
+                "Drowsiness confidence",
+            ],
+        );
+        let local_band_log = recorder.create(
+            &prefixed("local_bands.csv"),
+            &["Time", "Band", "TP9", "AF7", "AF8", "TP10"],
         );
-        let mut delta_log_writer = create_log_writer(start_time, "delta.csv");
-        delta_log_writer
-            .write_record(&["Time", "Delta TP9", "Delta AF7", "Delta AF8", "Delta TP10"])
-            .expect("Can not write delta.csv header");
-        let mut theta_log_writer = create_log_writer(start_time, "theta.csv");
-        theta_log_writer
-            .write_record(&["Time", "Theta TP9", "Theta AF7", "Theta AF8", "Theta TP10"])
-            .expect("Can not write theta.csv header");
-        let mut other_log_writer = create_log_writer(start_time, "other.csv");
-        other_log_writer
-            .write_record(&["Time", "Record"])
-            .expect("Can not write other.csv header");
 
-        MuseModel {
+        HeadsetModel {
+            source,
+            participant,
             most_recent_message_receive_time: start_time,
-            inner_receiver,
             receiving_data,
             accelerometer: [0.0, 0.0, 0.0],
             gyro: [0.0, 0.0, 0.0],
+            motion: MotionTracker::new(),
+            gesture: None,
+            eeg: [0.0; 4],
+            filtered_eeg: [0.0; 4],
             alpha: [0.0, 0.0, 0.0, 0.0], // 7.5-13Hz
             beta: [0.0, 0.0, 0.0, 0.0],  // 13-30Hz
             gamma: [0.0, 0.0, 0.0, 0.0], // 30-44Hz
             delta: [0.0, 0.0, 0.0, 0.0], // 1-4Hz
             theta: [0.0, 0.0, 0.0, 0.0], // 4-8Hz
+            relative: [[0.0; 4]; N_EEG_BANDS],
+            session_score: [[0.0; 4]; N_EEG_BANDS],
+            low_freqs: [0.0; 4],
+            raw_fft: [Vec::new(), Vec::new(), Vec::new(), Vec::new()],
+            local_absolute: [[0.0; 4]; N_EEG_BANDS],
+            is_good: [false; 4],
+            hsi_precision: [0.0; 4],
+            ppg: [0.0; 3],
+            heart_rate: HeartRate::default(),
+            drl_ref: [0.0; 2],
+            aux: Vec::new(),
+            concentration: 0.0,
+            mellow: 0.0,
             batt: 0,
             horseshoe: [0.0, 0.0, 0.0, 0.0],
             blink_countdown: 0,
             touching_forehead_countdown: 0,
             jaw_clench_countdown: 0,
-            scale: 1.5, // Make the circles relatively larger or smaller
-            display_type: DisplayType::Mandala, // Current drawing mode
             arousal: NormalizedValue::new(),
             valence: NormalizedValue::new(),
-            eeg_log_sender,
-            alpha_log_sender,
-            beta_log_sender,
-            gamma_log_sender,
-            delta_log_writer,
-            theta_log_writer,
-            other_log_writer,
+            drowsiness: NormalizedValue::new(),
+            emotion_estimate: Estimate::default(),
+            drowsiness_estimate: Estimate::default(),
+            artifact: None,
+            artifact_gate: ArtifactGate::new(settings.artifact_policy),
+            pending_band_powers: VecDeque::new(),
+            calibration: Calibration::new(&CalibrationSettings::default(), participant),
+            signal_quality: SignalQuality::new(),
+            heart_rate_detector: HeartRateDetector::new(),
+            clock_offset: ClockOffsetEstimator::new(settings.clock_offset_mode),
+            filter_bank: FilterBank::new(settings.filter),
+            spectrum: SpectralPipeline::new(settings.welch),
+            message_log,
+            other_log,
+            filtered_eeg_log,
+            heart_rate_log,
+            motion_log,
+            local_band_log,
+            affect_metrics: AffectMetrics::new(&AffectSettings::default()),
+            affect_log,
+            affect_log_columns: Vec::new(),
+            estimate_log,
         }
     }
 
+    /// Packets from this address belong to this headset
+    pub fn source(&self) -> IpAddr {
+        self.source
+    }
+
+    /// Participant slot, 0 is the first participant
+    pub fn participant(&self) -> usize {
+        self.participant
+    }
+
     /// Valid measurements are flowing from the EEG headset
     pub fn is_receiving_data(&self) -> bool {
         self.receiving_data
     }
 
-    /// Write any pending activity to disk
-    pub fn flush_all(&mut self) -> Result<(), std::io::Error> {
-        self.theta_log_writer
-            .flush()
-            .and(self.delta_log_writer.flush())
-            .and(self.other_log_writer.flush())
+    /// Current estimate of local clock minus headset clock, if timetags are being received
+    pub fn clock_offset(&self) -> Option<chrono::Duration> {
+        self.clock_offset.offset()
     }
 
-    fn log_delta(&mut self, receive_time: DateTime<Local>) {
-        let receive_time_csv_format = date_time_csv_format(receive_time);
-        let time = format!("{}", receive_time_csv_format);
-        let tp9 = format!("{:?}", self.delta[TP9]);
-        let af7 = format!("{:?}", self.delta[AF7]);
-        let af8 = format!("{:?}", self.delta[AF8]);
-        let tp10 = format!("{:?}", self.delta[TP10]);
+    fn log_local_bands(&mut self, receive_time: DateTime<Local>) {
+        for band in EegBand::ALL.iter() {
+            let mut row = vec![band.label().to_string()];
+            row.extend(format_values(&self.local_absolute[band.index()]));
+            self.local_band_log.write(receive_time, row);
+        }
+    }
+
+    /// One row of every active affect metric. A header row comes first, and again if the metrics change.
+    fn log_affect(&mut self, receive_time: DateTime<Local>, values: &[f32]) {
+        let names = self.affect_metrics.names();
+        if self.affect_log_columns != names {
+            let mut header = vec!["Time".to_string()];
+            header.extend(names.iter().map(|name| name.to_string()));
+            self.affect_log.write_header(header);
+            self.affect_log_columns = names;
+        }
 
-        self.delta_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
-            .expect("Can not add row to delta.csv");
+        self.affect_log.write(receive_time, format_values(values));
     }
 
-    fn log_theta(&mut self, receive_time: DateTime<Local>) {
-        let receive_time_csv_format = date_time_csv_format(receive_time);
-        let time = format!("{}", receive_time_csv_format);
-        let tp9 = format!("{:?}", self.theta[TP9]);
-        let af7 = format!("{:?}", self.theta[AF7]);
-        let af8 = format!("{:?}", self.theta[AF8]);
-        let tp10 = format!("{:?}", self.theta[TP10]);
+    fn log_estimates(&mut self, receive_time: DateTime<Local>) {
+        let value = |estimate: Estimate| {
+            estimate
+                .value
+                .map(|value| format!("{:?}", value))
+                .unwrap_or_default()
+        };
+        let row = vec![
+            value(self.emotion_estimate),
+            format!("{:?}", self.emotion_estimate.confidence),
+            value(self.drowsiness_estimate),
+            format!("{:?}", self.drowsiness_estimate.confidence),
+        ];
+
+        self.estimate_log.write(receive_time, row);
+    }
 
-        self.theta_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
-            .expect("Can not add row to theta.csv");
+    /// One row each beat. Heart rate and RMSSD are empty until enough beats have arrived.
+    fn log_heart_rate(&mut self, receive_time: DateTime<Local>) {
+        let value = |value: Option<f32>| value.map(|v| format!("{:?}", v)).unwrap_or_default();
+        let row = vec![
+            value(self.heart_rate.ibi),
+            value(self.heart_rate.bpm),
+            value(self.heart_rate.rmssd),
+        ];
+
+        self.heart_rate_log.write(receive_time, row);
     }
 
-    pub fn log_other(&mut self, receive_time: DateTime<Local>, other: &str) {
-        let receive_time_csv_format = date_time_csv_format(receive_time);
-        let time = format!("{}", receive_time_csv_format);
+    /// One row each accelerometer sample, with the most recent gyro sample and what is derived from them
+    fn log_motion(&mut self, receive_time: DateTime<Local>) {
+        let value = |value: Option<f32>| value.map(|v| format!("{:?}", v)).unwrap_or_default();
+        let orientation = self.motion.orientation();
+        let mut row = format_values(&self.accelerometer);
+        row.extend(format_values(&self.gyro));
+        row.push(value(orientation.map(|orientation| orientation.pitch)));
+        row.push(value(orientation.map(|orientation| orientation.roll)));
+        row.push(value(self.motion.intensity()));
+        row.push(self.motion.is_moving().to_string());
+
+        self.motion_log.write(receive_time, row);
+    }
 
-        self.other_log_writer
-            .write_record(&[&time, other])
-            .expect("Can not add row to other.csv");
+    fn log_other(&mut self, receive_time: DateTime<Local>, other: &str) {
+        self.other_log.write(receive_time, vec![other.to_string()]);
     }
 
     // pub fn log_other_now(&mut self, other: &str) {
@@ -732,6 +1000,21 @@ impl MuseModel {
         self.touching_forehead_countdown > 0
     }
 
+    /// The most recent nod or shake, if it has not been taken yet
+    pub fn take_gesture(&mut self) -> Option<HeadGesture> {
+        self.gesture.take()
+    }
+
+    /// Signal quality from 0 (unusable) to 1 (good) for each electrode
+    pub fn signal_quality(&self) -> [f32; 4] {
+        self.signal_quality.scores()
+    }
+
+    /// Every electrode has good signal quality
+    pub fn is_well_fitted(&self) -> bool {
+        self.signal_quality.all_good()
+    }
+
     /// This is called 60x/sec and allows various temporary display states to time out
     pub fn count_down(&mut self) {
         if self.blink_countdown > 0 {
@@ -747,162 +1030,856 @@ impl MuseModel {
         }
     }
 
-    pub fn receive_packets(&mut self) -> (Option<f32>, Option<f32>) {
-        let muse_messages = self.inner_receiver.receive_packets();
-        let mut updated_numeric_values = false;
-        let mut normalized_valence_option = None;
-        let mut normalized_arousal_option = None;
-
-        for muse_message in muse_messages {
-            self.most_recent_message_receive_time = muse_message.message_time.clone();
-            updated_numeric_values = updated_numeric_values
-                || self
-                    .handle_muse_message(muse_message)
-                    .expect("Could not receive OSC message");
+    /// Map the headset clock onto the local clock, if the message has a bundle timetag
+    fn correct_message_time(&mut self, muse_message: &mut MuseMessage) {
+        if let Some(timetag) = muse_message.timetag {
+            self.clock_offset.update(timetag, muse_message.receive_time);
+            muse_message.message_time = self
+                .clock_offset
+                .to_local(Some(timetag), muse_message.receive_time);
+        }
+        self.most_recent_message_receive_time = muse_message.message_time.clone();
+    }
+
+    /// Recalculate valence, arousal and drowsiness after new band powers, returning normalized valence
+    /// and arousal. Band powers are held back for the pre-event margin of the artifact gate, so this
+    /// uses the latest ones which are due. Band powers spoiled by an artifact are left out and logged,
+    /// the estimates lose their confidence, and nothing is returned.
+    fn update_affect(&mut self) -> (Option<f32>, Option<f32>) {
+        self.receiving_data = true;
+        let latest = self.most_recent_message_receive_time;
+        self.pending_band_powers
+            .push_back((latest, self.band_powers()));
+        let mut affect = (None, None);
+
+        while let Some((time, band_powers)) = self.pending_band_powers.pop_front() {
+            if latest - time < self.artifact_gate.policy.pre_event_margin {
+                self.pending_band_powers.push_front((time, band_powers));
+                break;
+            }
+            affect = self.update_affect_at(time, band_powers);
         }
 
-        if updated_numeric_values {
-            self.receiving_data = true;
-            let _valence_updated = self.update_valence();
-            let _arousal_updated = self.update_arousal();
-            let vma = self.valence.moving_average();
-            let ama = self.arousal.moving_average();
+        affect
+    }
 
-            normalized_valence_option = self.valence.normalize(vma);
-            normalized_arousal_option = self.arousal.normalize(ama);
+    /// update_affect() for band powers which arrived at `time`, once any artifact which follows
+    /// them has arrived
+    fn update_affect_at(
+        &mut self,
+        time: DateTime<Local>,
+        band_powers: BandPowers,
+    ) -> (Option<f32>, Option<f32>) {
+        self.artifact = self.artifact_gate.check(time);
+        if let Some(artifact) = self.artifact {
+            self.log_other(time, &format!("Artifact, {}", artifact));
+            self.emotion_estimate.confidence = 0.0;
+            self.drowsiness_estimate.confidence = 0.0;
+            return (None, None);
         }
+        let values = self.affect_metrics.compute(&band_powers);
+        self.log_affect(time, &values);
+        self.valence.set(self.affect_metrics.valence(&values));
+        self.arousal.set(self.affect_metrics.arousal(&values));
+        self.drowsiness.set(self.affect_metrics.drowsiness(&values));
+        if self.calibration.update(time) {
+            self.finish_calibration(time);
+        }
+        let vma = self.valence.moving_average();
+        let ama = self.arousal.moving_average();
+        let dma = self.drowsiness.moving_average();
+        let confidence = self.estimate_confidence();
+        self.emotion_estimate = Estimate {
+            value: self.valence.normalize(vma),
+            confidence,
+        };
+        self.drowsiness_estimate = Estimate {
+            value: self.drowsiness.normalize(dma),
+            confidence,
+        };
+        self.log_estimates(time);
 
-        (normalized_valence_option, normalized_arousal_option)
+        (self.emotion_estimate.value, self.arousal.normalize(ama))
     }
 
-    /// Front assymetry- higher values mean more positive mood
-    fn front_assymetry(&self) -> f32 {
-        E.powf(self.alpha[AF8] - self.alpha[AF7])
+    /// Values compared with a baseline still being measured mean little, so confidence is 0 until
+    /// calibration is complete
+    fn estimate_confidence(&self) -> f32 {
+        if !self.calibration.is_complete() {
+            return 0.0;
+        }
+        let scores = self.signal_quality.scores();
+
+        scores.iter().sum::<f32>() / scores.len() as f32
     }
 
-    /// Positive-negative balance of emotion
-    pub fn calc_absolute_valence(&self) -> f32 {
-        self.front_assymetry() / average_from_front_electrodes(&self.theta)
+    /// Prepare to measure a new valence, arousal and drowsiness baseline once start_calibration() is
+    /// called, or load the saved one if settings.reuse is set
+    pub fn reset_calibration(
+        &mut self,
+        normalization_settings: NormalizationSettings,
+        settings: &CalibrationSettings,
+    ) {
+        self.calibration = Calibration::new(settings, self.participant);
+        self.valence = NormalizedValue::with_settings(normalization_settings);
+        self.arousal = NormalizedValue::with_settings(normalization_settings);
+        self.drowsiness = NormalizedValue::with_settings(normalization_settings);
+        self.emotion_estimate = Estimate::default();
+        self.drowsiness_estimate = Estimate::default();
+        let time = self.most_recent_message_receive_time;
+
+        if settings.reuse {
+            let valence_metric = self.affect_metrics.valence_name();
+            let arousal_metric = self.affect_metrics.arousal_name();
+            let drowsiness_metric = self.affect_metrics.drowsiness_name();
+            match self
+                .calibration
+                .load(valence_metric, arousal_metric, drowsiness_metric)
+            {
+                Ok(baseline) => {
+                    self.valence
+                        .set_baseline(baseline.valence.mean, baseline.valence.deviation);
+                    self.arousal
+                        .set_baseline(baseline.arousal.mean, baseline.arousal.deviation);
+                    self.drowsiness
+                        .set_baseline(baseline.drowsiness.mean, baseline.drowsiness.deviation);
+                    self.log_other(
+                        time,
+                        &format!(
+                            "Calibration, loaded, {}, {}",
+                            baseline.participant_id, baseline.calibrated_at
+                        ),
+                    );
+                }
+                Err(e) => {
+                    warn!("{}, calibrating again", e);
+                    self.log_other(time, &format!("Calibration, not loaded, {}", e));
+                }
+            }
+        }
     }
 
-    /// Level of emotional intensity based on other, more primitive values
-    pub fn calc_abolute_arousal(&self) -> f32 {
-        let frontal_apha = (E.powf(self.alpha[AF7]) + E.powf(self.alpha[AF8])) / 2.0;
-        let frontal_theta = (E.powf(self.theta[AF7]) + E.powf(self.theta[AF8])) / 2.0;
-        frontal_theta / (frontal_apha + 1e-6)
+    /// Measure the baseline from the next band powers on, unless it was loaded or is already being
+    /// measured. Values before this, such as during the fit check, are not part of the baseline.
+    pub fn start_calibration(&mut self, time: DateTime<Local>) {
+        if self.calibration.is_started() {
+            return;
+        }
+        self.valence.restart_baseline();
+        self.arousal.restart_baseline();
+        self.drowsiness.restart_baseline();
+        self.calibration.start();
+        self.log_other(
+            time,
+            &format!(
+                "Calibration, started, {}",
+                self.calibration.participant_id()
+            ),
+        );
     }
 
-    /// Calculate the current arousal value and add it to the length-limited history
-    pub fn update_arousal(&mut self) -> bool {
-        let abs_arousal = self.calc_abolute_arousal();
-        self.arousal.set(abs_arousal)
+    /// Freeze the valence, arousal and drowsiness baseline and save it for later sessions. All three are
+    /// frozen or none, and calibration starts again if any has no values yet.
+    fn finish_calibration(&mut self, time: DateTime<Local>) {
+        if !(self.valence.can_freeze() && self.arousal.can_freeze() && self.drowsiness.can_freeze())
+        {
+            self.log_other(time, "Calibration, no values, restarted");
+            self.valence.restart_baseline();
+            self.arousal.restart_baseline();
+            self.drowsiness.restart_baseline();
+            self.calibration.restart();
+            return;
+        }
+        let values = self.valence.history.len();
+        let (valence, arousal, drowsiness) = match (
+            self.valence.freeze(),
+            self.arousal.freeze(),
+            self.drowsiness.freeze(),
+        ) {
+            (Some(valence), Some(arousal), Some(drowsiness)) => (valence, arousal, drowsiness),
+            _ => return, // Each can freeze, checked above
+        };
+        let baseline = ParticipantBaseline {
+            participant_id: self.calibration.participant_id().to_string(),
+            calibrated_at: time,
+            valence_metric: self.affect_metrics.valence_name().to_string(),
+            valence: Baseline {
+                mean: valence.0,
+                deviation: valence.1,
+            },
+            arousal_metric: self.affect_metrics.arousal_name().to_string(),
+            arousal: Baseline {
+                mean: arousal.0,
+                deviation: arousal.1,
+            },
+            drowsiness_metric: self.affect_metrics.drowsiness_name().to_string(),
+            drowsiness: Baseline {
+                mean: drowsiness.0,
+                deviation: drowsiness.1,
+            },
+        };
+        self.log_other(
+            time,
+            &format!(
+                "Calibration, complete, {} values, {}",
+                values,
+                comma_list(&[
+                    valence.0,
+                    valence.1,
+                    arousal.0,
+                    arousal.1,
+                    drowsiness.0,
+                    drowsiness.1
+                ])
+            ),
+        );
+
+        if let Err(e) = self.calibration.save(&baseline) {
+            error!("{}", e);
+            self.log_other(time, &format!("Calibration, not saved, {}", e));
+        }
     }
 
-    /// Calculate the current valence value and add it to the length-limited history
-    pub fn update_valence(&mut self) -> bool {
-        let abs_valence = self.calc_absolute_valence();
-        self.valence.set(abs_valence)
+    /// The most recent absolute band powers from the headset, indexed by EegBand::index() then electrode
+    fn band_powers(&self) -> BandPowers {
+        [self.delta, self.theta, self.alpha, self.beta, self.gamma]
     }
 
-    /// Update state based on an incoming message
-    fn handle_muse_message(
-        &mut self,
-        muse_message: MuseMessage,
-    ) -> Result<bool, SendError<TimedMuseMessage>> {
+    /// Update state based on an incoming message. Returns true if it was a band power, so valence
+    /// and arousal need to be recalculated.
+    fn handle_muse_message(&mut self, muse_message: MuseMessage) -> bool {
         let message_time = muse_message.message_time;
+        self.message_log
+            .record(message_time, &muse_message.muse_message_type);
 
         match muse_message.muse_message_type {
             MuseMessageType::Accelerometer { x, y, z } => {
                 self.accelerometer = [x, y, z];
-                self.log_other(message_time, &format!("Accel, {:?}, {:?}, {:?}", x, y, z));
-                Ok(false)
+                self.motion.accelerometer(self.accelerometer);
+                self.log_motion(message_time);
+                false
             }
             MuseMessageType::Gyro { x, y, z } => {
                 self.gyro = [x, y, z];
-                self.log_other(message_time, &format!("Gyro, {:?}, {:?}, {:?}", x, y, z));
-                Ok(false)
+                let gesture = self.motion.gyro(message_time, self.gyro);
+                if self.motion.is_moving() {
+                    self.artifact_gate.motion(message_time);
+                }
+                if let Some(gesture) = gesture {
+                    self.log_other(message_time, &format!("Gesture, {}", gesture));
+                    self.gesture = Some(gesture);
+                }
+                false
             }
             MuseMessageType::Horseshoe { a, b, c, d } => {
                 self.horseshoe = [a, b, c, d];
-                self.log_other(
-                    message_time,
-                    &format!("Horseshoe, {:?}, {:?}, {:?}, {:?}", a, b, c, d),
-                );
-                // self.send((time, MuseMessageType::Horseshoe { a, b, c, d }));
-                Ok(false)
+                self.artifact_gate.horseshoe(self.horseshoe);
+                self.signal_quality.set_horseshoe(self.horseshoe);
+                false
             }
-            MuseMessageType::Eeg { .. } => {
-                self.eeg_log_sender
-                    .send(muse_message)
-                    .expect("Unable to log eeg");
-                Ok(false)
+            MuseMessageType::Eeg { eeg } => {
+                self.eeg = eeg;
+                if self.signal_quality.push(eeg) {
+                    let scores = self.signal_quality.scores();
+                    self.log_other(
+                        message_time,
+                        &format!("SignalQuality, {}", comma_list(&scores)),
+                    );
+                }
+                self.filtered_eeg = self.filter_bank.process(eeg);
+                if let Some(band_powers) = self.spectrum.push(self.filtered_eeg) {
+                    self.local_absolute = band_powers;
+                    self.log_local_bands(message_time);
+                }
+                self.filtered_eeg_log
+                    .write(message_time, format_values(&self.filtered_eeg));
+                false
             }
             MuseMessageType::Alpha { alpha } => {
                 self.alpha = alpha;
-                self.alpha_log_sender
-                    .send(muse_message)
-                    .expect("Unable to log alpha");
-                Ok(true)
+                true
             }
             MuseMessageType::Beta { beta } => {
                 self.beta = beta;
-                self.beta_log_sender
-                    .send(muse_message)
-                    .expect("Unable to log beta");
-                Ok(true)
+                true
             }
             MuseMessageType::Gamma { gamma } => {
                 self.gamma = gamma;
-                self.gamma_log_sender
-                    .send(muse_message)
-                    .expect("Unable to log gamma");
-                Ok(true)
+                true
             }
             MuseMessageType::Delta { a, b, c, d } => {
                 self.delta = [a, b, c, d];
-                self.log_delta(message_time);
-                Ok(true)
+                true
             }
             MuseMessageType::Theta { a, b, c, d } => {
                 self.theta = [a, b, c, d];
-                self.log_theta(message_time);
-                Ok(true)
+                true
             }
             MuseMessageType::Batt { batt } => {
                 self.batt = batt;
-                self.log_other(message_time, &format!("Battery, {:?}", batt));
-                Ok(false)
+                false
             }
             MuseMessageType::TouchingForehead { touch } => {
-                let mut i = 0;
                 if touch {
-                    i = 1;
-                } else {
                     self.touching_forehead_countdown = FOREHEAD_COUNTDOWN;
                 };
-                self.log_other(message_time, &format!("Battery, {:?}", i));
-                Ok(false)
+                false
             }
             MuseMessageType::Blink { blink } => {
-                let mut i = 0;
                 if blink {
                     self.blink_countdown = BLINK_COUNTDOWN;
-                    i = 1;
+                    self.artifact_gate.blink(message_time);
                 };
-                self.log_other(message_time, &format!("Blink, {:?}", i));
-                //                self.send((time, MuseMessageType::Blink { blink }));
-                Ok(false)
+                false
             }
             MuseMessageType::JawClench { clench } => {
-                let mut i = 0;
                 if clench {
                     self.jaw_clench_countdown = CLENCH_COUNTDOWN;
-                    i = 1;
+                    self.artifact_gate.jaw_clench(message_time);
+                };
+                false
+            }
+            MuseMessageType::Relative { band, values } => {
+                self.relative[band.index()] = values;
+                false
+            }
+            MuseMessageType::SessionScore { band, values } => {
+                self.session_score[band.index()] = values;
+                false
+            }
+            MuseMessageType::LowFrequencies { low_freqs } => {
+                self.low_freqs = low_freqs;
+                false
+            }
+            MuseMessageType::RawFft { channel, fft } => {
+                if let Some(slot) = self.raw_fft.get_mut(channel) {
+                    *slot = fft;
+                }
+                false
+            }
+            MuseMessageType::IsGood { is_good } => {
+                self.is_good = is_good;
+                self.signal_quality.set_is_good(is_good);
+                false
+            }
+            MuseMessageType::HsiPrecision { hsi } => {
+                self.hsi_precision = hsi;
+                false
+            }
+            MuseMessageType::Ppg { ppg } => {
+                self.ppg = ppg;
+                if self.heart_rate_detector.push(ppg).is_some() {
+                    self.heart_rate = HeartRate {
+                        beat_time: Some(message_time),
+                        ..self.heart_rate_detector.heart_rate()
+                    };
+                    self.log_heart_rate(message_time);
+                }
+                false
+            }
+            MuseMessageType::DrlRef { drl, reference } => {
+                self.drl_ref = [drl, reference];
+                false
+            }
+            MuseMessageType::Aux { aux } => {
+                self.aux = aux;
+                false
+            }
+            MuseMessageType::Concentration { concentration } => {
+                self.concentration = concentration;
+                false
+            }
+            MuseMessageType::Mellow { mellow } => {
+                self.mellow = mellow;
+                false
+            }
+            MuseMessageType::Annotation { .. } | MuseMessageType::Marker { .. } => false,
+        }
+    }
+}
+
+impl MuseModel {
+    /// Create a new model for storing values received from any EEG source. Files are written to a
+    /// new session directory.
+    pub fn new(
+        start_time: DateTime<Local>,
+        inner_receiver: Box<dyn EegMessageReceiver>,
+        session_settings: SessionSettings,
+    ) -> MuseModel {
+        let recorder = Recorder::start(&session_settings.directory(start_time));
+        info!("Recording to {}", recorder.directory().display());
+        let other_log = recorder.handle().create("other.csv", &["Time", "Record"]);
+        let events_log = recorder
+            .handle()
+            .create(EVENTS_FILE, &session_event::COLUMNS);
+        let mut sinks: Vec<Box<dyn MuseMessageSink>> = Vec::new();
+        let mut xdf = None; // Never on the web, which has no filesystem
+        if session_settings.xdf && cfg!(not(all(target_arch = "wasm32", target_os = "unknown"))) {
+            let path = recorder.directory().join(XDF_FILE);
+            match XdfRecorder::start(&path) {
+                Ok(xdf_recorder) => {
+                    sinks.push(Box::new(xdf_recorder.sink()));
+                    xdf = Some(xdf_recorder);
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    other_log.write(start_time, vec![format!("Recording error, {}", e)]);
+                }
+            }
+        }
+
+        MuseModel {
+            start_time,
+            inner_receiver,
+            sinks,
+            headsets: HashMap::new(),
+            participants: Vec::new(),
+            combination: ParticipantCombination::Average,
+            scale: 1.5, // Make the circles relatively larger or smaller
+            display_type: DisplayType::Mandala, // Current drawing mode
+            breath_driver: BreathDriver::Paced,
+            headset_settings: HeadsetSettings::default(),
+            calibration_settings: CalibrationSettings::default(),
+            calibration_started: false,
+            normalization_settings: NormalizationSettings::default(),
+            affect_settings: AffectSettings::default(),
+            packet_error_counts: HashMap::new(),
+            session_settings,
+            other_log,
+            events_log,
+            exported_files: Vec::new(),
+            xdf,
+            recorder,
+        }
+    }
+
+    /// Reserve a participant slot for the headset streaming from this address. Assign before the
+    /// headset connects so its CSV files carry the right participant label.
+    pub fn assign_participant(&mut self, participant: usize, source: IpAddr) {
+        for slot in self.participants.iter_mut() {
+            if *slot == Some(source) {
+                *slot = None;
+            }
+        }
+        if self.participants.len() <= participant {
+            self.participants.resize(participant + 1, None);
+        }
+        self.participants[participant] = Some(source);
+        if let Some(headset) = self.headsets.get_mut(&source) {
+            headset.participant = participant;
+        }
+    }
+
+    /// The headset in this participant slot, if it has sent anything
+    pub fn participant(&self, participant: usize) -> Option<&HeadsetModel> {
+        self.participants
+            .get(participant)
+            .and_then(|source| source.as_ref())
+            .and_then(|source| self.headsets.get(source))
+    }
+
+    /// The first participant's headset, which drives the single person views
+    pub fn primary(&self) -> Option<&HeadsetModel> {
+        self.participant(0)
+    }
+
+    /// The first participant's heart rate, while beats are being found in their PPG
+    pub fn heart_rate(&self) -> Option<HeartRate> {
+        self.primary().and_then(|headset| {
+            headset
+                .heart_rate
+                .at(headset.most_recent_message_receive_time)
+        })
+    }
+
+    /// The first participant's most recent nod or shake, if it has not been taken yet
+    pub fn take_gesture(&mut self) -> Option<HeadGesture> {
+        let source = self.participants.get(0).and_then(|source| *source)?;
+
+        self.headsets.get_mut(&source)?.take_gesture()
+    }
+
+    /// Valid measurements are flowing from at least one EEG headset
+    pub fn is_receiving_data(&self) -> bool {
+        self.headsets
+            .values()
+            .any(|headset| headset.is_receiving_data())
+    }
+
+    /// Choose how OSC bundle timetags are mapped onto local sample times, for all headsets
+    pub fn set_clock_offset_mode(&mut self, mode: ClockOffsetMode) {
+        self.headset_settings.clock_offset_mode = mode;
+        for headset in self.headsets.values_mut() {
+            headset.clock_offset.set_mode(mode);
+        }
+    }
+
+    /// Headsets in participant slot order
+    pub fn headsets(&self) -> Vec<&HeadsetModel> {
+        self.participants
+            .iter()
+            .filter_map(|source| source.and_then(|source| self.headsets.get(&source)))
+            .collect()
+    }
+
+    /// Every reserved participant has a headset connected, and every electrode of every headset has
+    /// good signal quality
+    pub fn is_well_fitted(&self) -> bool {
+        let headsets = self.headsets();
+
+        !headsets.is_empty()
+            && headsets.len() == self.participants.len()
+            && headsets.iter().all(|headset| headset.is_well_fitted())
+    }
+
+    /// Frequency ranges of the band powers computed from raw EEG, for all headsets
+    pub fn set_band_edges(&mut self, band_edges: BandEdges) {
+        self.headset_settings.welch.band_edges = band_edges;
+        for headset in self.headsets.values_mut() {
+            headset.spectrum.set_band_edges(band_edges);
+        }
+    }
+
+    /// Filters applied to raw EEG before band powers are computed, for all headsets. Existing
+    /// headsets restart their filters.
+    pub fn set_filter_settings(&mut self, filter_settings: FilterSettings) {
+        self.headset_settings.filter = filter_settings;
+        for headset in self.headsets.values_mut() {
+            headset.filter_bank = FilterBank::new(filter_settings);
+        }
+    }
+
+    /// When band powers are left out of valence and arousal, for all headsets
+    pub fn set_artifact_policy(&mut self, artifact_policy: ArtifactPolicy) {
+        self.headset_settings.artifact_policy = artifact_policy;
+        for headset in self.headsets.values_mut() {
+            headset.artifact_gate.policy = artifact_policy;
+        }
+    }
+
+    /// Measure each participant's baseline from now on, once the fit check has passed. Headsets which
+    /// connect later start measuring straight away.
+    pub fn start_calibration(&mut self, time: DateTime<Local>) {
+        self.calibration_started = true;
+        for headset in self.headsets.values_mut() {
+            headset.start_calibration(time);
+        }
+    }
+
+    /// Calibration duration and saved baselines. Headsets already connected start calibrating again.
+    pub fn set_calibration_settings(&mut self, calibration_settings: CalibrationSettings) {
+        for headset in self.headsets.values_mut() {
+            headset.reset_calibration(self.normalization_settings, &calibration_settings);
+            if self.calibration_started {
+                headset.start_calibration(headset.most_recent_message_receive_time);
+            }
+        }
+        self.calibration_settings = calibration_settings;
+    }
+
+    /// Valence and arousal formulas, and the other metrics logged beside them. Headsets already
+    /// connected start calibrating again.
+    pub fn set_affect_settings(&mut self, affect_settings: AffectSettings) {
+        for headset in self.headsets.values_mut() {
+            headset.affect_metrics = AffectMetrics::new(&affect_settings);
+            headset.reset_calibration(self.normalization_settings, &self.calibration_settings);
+            if self.calibration_started {
+                headset.start_calibration(headset.most_recent_message_receive_time);
+            }
+        }
+        self.affect_settings = affect_settings;
+    }
+
+    /// Where each kind of headset message is recorded, for headsets which connect from now on
+    pub fn set_recorder_settings(&mut self, recorder_settings: RecorderSettings) {
+        self.headset_settings.recorder = recorder_settings;
+    }
+
+    /// Smoothing and baseline statistics of valence and arousal. Headsets already connected start
+    /// calibrating again.
+    pub fn set_normalization_settings(&mut self, normalization_settings: NormalizationSettings) {
+        for headset in self.headsets.values_mut() {
+            headset.reset_calibration(normalization_settings, &self.calibration_settings);
+            if self.calibration_started {
+                headset.start_calibration(headset.most_recent_message_receive_time);
+            }
+        }
+        self.normalization_settings = normalization_settings;
+    }
+
+    /// Total number of OSC messages dropped so far across all addresses
+    pub fn dropped_packet_count(&self) -> u64 {
+        self.packet_error_counts.values().sum()
+    }
+
+    /// Add the dropped message counts to other.csv so they are part of the session record
+    pub fn log_packet_error_summary(&mut self, receive_time: DateTime<Local>) {
+        let mut counts: Vec<(String, u64)> = self
+            .packet_error_counts
+            .iter()
+            .map(|(addr, count)| (addr.clone(), *count))
+            .collect();
+        counts.sort();
+
+        for (addr, count) in counts {
+            self.log_other(receive_time, &format!("Dropped, {}, {}", addr, count));
+        }
+        let total = self.dropped_packet_count();
+        self.log_other(receive_time, &format!("Dropped total, {}", total));
+    }
+
+    /// Add each headset's participant slot, address and clock offset to other.csv
+    pub fn log_headset_summary(&mut self, receive_time: DateTime<Local>) {
+        let mut rows: Vec<(usize, String)> = self
+            .headsets
+            .values()
+            .map(|headset| {
+                let offset = match headset.clock_offset() {
+                    Some(offset) => format!("{}", offset.num_milliseconds()),
+                    None => "".to_string(),
                 };
-                self.log_other(message_time, &format!("Clench, {:?}", i));
-                // self.send((time, MuseMessageType::JawClench { clench }));
-                Ok(false)
+                let row = format!(
+                    "Headset, {}, {}, {}",
+                    participant_label(headset.participant()),
+                    headset.source(),
+                    offset
+                );
+
+                (headset.participant(), row)
+            })
+            .collect();
+        rows.sort();
+
+        for (_, row) in rows {
+            self.log_other(receive_time, &row);
+        }
+    }
+
+    fn count_packet_error(&mut self, error: &MusePacketError) {
+        *self
+            .packet_error_counts
+            .entry(error.addr().to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Write any pending activity to disk. The error lists files which have lost rows.
+    pub fn flush_all(&mut self) -> Result<(), String> {
+        self.recorder.flush()
+    }
+
+    /// When enabled in SessionSettings, convert each participant's eeg.csv to EDF+ with the protocol events
+    /// in events.csv as annotations. Call after flush_all(). Returns the path or error of each export.
+    pub fn export_edf(&mut self) -> Vec<Result<PathBuf, String>> {
+        if !self.session_settings.export_edf {
+            return Vec::new();
+        }
+        let directory = self.recorder.directory().to_path_buf();
+        let mut results = Vec::new();
+
+        for participant in 0..self.participants.len() {
+            let label = participant_label(participant);
+            let eeg_csv = directory.join(format!("{} eeg.csv", label));
+            if !eeg_csv.exists() {
+                continue; // Never connected, or EEG not recorded as CSV
+            }
+            let name = format!("{} eeg.edf", label);
+            let path = directory.join(&name);
+            let result = EdfRecording::read_csv(
+                &self.calibration_settings.participant_id(participant),
+                &eeg_csv,
+                Some(&directory.join(EVENTS_FILE)),
+            )
+            .and_then(|recording| recording.write(&path))
+            .map(|records| {
+                self.exported_files.push(RecordedFile {
+                    name,
+                    rows: records as u64,
+                    sha256: sha256(&path).ok(),
+                });
+                path
+            });
+            results.push(result);
+        }
+
+        results
+    }
+
+    /// Close the XDF file, flush every file and describe the session in session.json beside them.
+    /// Returns the manifest path.
+    pub fn write_manifest(&mut self, end_time: DateTime<Local>) -> Result<PathBuf, String> {
+        if let Some(xdf) = self.xdf.take() {
+            match xdf.close() {
+                Ok(file) => self.exported_files.push(file),
+                Err(e) => error!("{}", e),
+            }
+        }
+        let participants = self
+            .participants
+            .iter()
+            .enumerate()
+            .map(|(participant, source)| SessionParticipant {
+                label: participant_label(participant),
+                participant_id: self.calibration_settings.participant_id(participant),
+                source: *source,
+            })
+            .collect();
+        let manifest = SessionManifest {
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            rig_id: self.session_settings.rig_id.clone(),
+            protocol: self.session_settings.protocol.clone(),
+            start_time: self.start_time,
+            end_time,
+            participants,
+            files: self
+                .recorder
+                .files()?
+                .into_iter()
+                .chain(self.exported_files.iter().cloned())
+                .collect(),
+        };
+
+        manifest.save(self.recorder.directory())
+    }
+
+    /// Add a note about the session, such as a shutdown summary, to other.csv
+    pub fn log_other(&mut self, receive_time: DateTime<Local>, other: &str) {
+        self.other_log.write(receive_time, vec![other.to_string()]);
+    }
+
+    /// Add a protocol event to events.csv, and pass it to every sink
+    pub fn log_event(&mut self, time: DateTime<Local>, event: &SessionEvent) {
+        self.events_log
+            .write(time, event.values(time, self.start_time));
+        for sink in self.sinks.iter() {
+            sink.send_event(time, event);
+        }
+    }
+
+    /// Log files which could not be created or written, and note them in other.csv in case it still can be
+    fn report_recorder_errors(&mut self) {
+        for e in self.recorder.take_errors() {
+            error!("{}", e);
+            self.log_other(Local::now(), &format!("Recording error, {}", e));
+        }
+    }
+
+    /// This is called 60x/sec and allows various temporary display states to time out
+    pub fn count_down(&mut self) {
+        for headset in self.headsets.values_mut() {
+            headset.count_down();
+        }
+    }
+
+    /// Also pass every received message, and the resulting valence and arousal, to this sink
+    pub fn add_sink(&mut self, sink: Box<dyn MuseMessageSink>) {
+        self.sinks.push(sink);
+    }
+
+    /// The headset for this address, created in the reserved or next free participant slot
+    fn headset_for(&mut self, source: IpAddr) -> &mut HeadsetModel {
+        if !self.headsets.contains_key(&source) {
+            let participant = match self.participants.iter().position(|s| *s == Some(source)) {
+                Some(participant) => participant,
+                None => {
+                    let free = self
+                        .participants
+                        .iter()
+                        .position(|s| s.is_none())
+                        .unwrap_or(self.participants.len());
+                    self.assign_participant(free, source);
+                    free
+                }
+            };
+            let mut headset = HeadsetModel::new(
+                self.start_time,
+                &self.recorder.handle(),
+                source,
+                participant,
+                &self.headset_settings,
+            );
+            headset.affect_metrics = AffectMetrics::new(&self.affect_settings);
+            headset.reset_calibration(self.normalization_settings, &self.calibration_settings);
+            if self.calibration_started {
+                headset.start_calibration(headset.most_recent_message_receive_time);
             }
+            self.headsets.insert(source, headset);
+        }
+
+        self.headsets
+            .get_mut(&source)
+            .expect("Headset was just created")
+    }
+
+    /// Handle all pending messages from every headset. Returns the combined normalized valence and
+    /// arousal of the participants if they changed.
+    pub fn receive_packets(&mut self) -> (Option<f32>, Option<f32>) {
+        let muse_messages = self.inner_receiver.receive_packets();
+        let mut updated_sources: Vec<IpAddr> = Vec::new();
+
+        for muse_message_result in muse_messages {
+            match muse_message_result {
+                Ok(mut muse_message) => {
+                    let source = muse_message.ip_address.ip();
+                    let headset = self.headset_for(source);
+                    headset.correct_message_time(&mut muse_message);
+                    for sink in self.sinks.iter() {
+                        sink.send_message(&muse_message);
+                    }
+                    let headset = self.headset_for(source);
+                    let updated_numeric_values = headset.handle_muse_message(muse_message);
+                    if updated_numeric_values && !updated_sources.contains(&source) {
+                        updated_sources.push(source);
+                    }
+                }
+                Err(e) => self.count_packet_error(&e),
+            }
+        }
+
+        let mut affect: Vec<(usize, Option<f32>, Option<f32>)> = Vec::new();
+        for source in updated_sources {
+            let headset = self.headset_for(source);
+            let (valence, arousal) = headset.update_affect();
+            let participant = headset.participant;
+            let time = headset.most_recent_message_receive_time;
+            for sink in self.sinks.iter() {
+                sink.send_affect(participant, time, valence, arousal);
+            }
+            affect.push((participant, valence, arousal));
+        }
+        self.report_recorder_errors();
+
+        combine_participants(self.combination, &affect)
+    }
+}
+
+/// "P1" for participant slot 0
+pub fn participant_label(participant: usize) -> String {
+    format!("P{}", participant + 1)
+}
+
+/// One display value from the (participant, valence, arousal) updates of this frame
+fn combine_participants(
+    combination: ParticipantCombination,
+    affect: &[(usize, Option<f32>, Option<f32>)],
+) -> (Option<f32>, Option<f32>) {
+    match combination {
+        ParticipantCombination::Average => {
+            let valences: Vec<f32> = affect.iter().filter_map(|(_, v, _)| *v).collect();
+            let arousals: Vec<f32> = affect.iter().filter_map(|(_, _, a)| *a).collect();
+
+            (mean(&valences), mean(&arousals))
+        }
+        ParticipantCombination::Split => {
+            let valence = affect
+                .iter()
+                .find(|(p, _, _)| *p == 0)
+                .and_then(|(_, v, _)| *v);
+            let arousal = affect
+                .iter()
+                .find(|(p, _, _)| *p == 1)
+                .and_then(|(_, _, a)| *a);
+
+            (valence, arousal)
         }
     }
 }
@@ -1014,27 +1991,135 @@ mod tests {
         assert_eq!(nv.normalize(nv.min), Some(-1.7176766934264711));
         assert_eq!(nv.normalize(nv.max), Some(1.7176766934264711));
         assert_eq!(nv.history.len(), 120);
-        assert_eq!(nv._percent_normalization_complete(), 1.0);
     }
 
     #[test]
     fn test_normalized_value_history_with_negative_values() {
         let mut nv: NormalizedValue<f32> = NormalizedValue::new();
 
-        // Twice as many values as the initial normalization stage. All normlalization values are negative
-        for i in -100..101 {
+        // All calibration values are negative, then the baseline is frozen
+        for i in -100..1 {
+            nv.set(i as f32);
+        }
+        assert_eq!(Some((-50.0, 29.15476)), nv.freeze());
+        for i in 1..101 {
             nv.set(i as f32);
         }
 
         assert_eq!(nv.min, Some(-100.0));
         assert_eq!(nv.max, Some(100.0));
         assert_eq!(nv.moving_average(), Some(96.0));
-        assert_eq!(nv.mean(), Some(40.5));
-        assert_eq!(nv.deviation(), Some(34.63981331743384));
-        assert_eq!(nv.normalize(nv.moving_average()), Some(1.6022027));
-        assert_eq!(nv.normalize(nv.min), Some(-4.0560265));
-        assert_eq!(nv.normalize(nv.max), Some(1.7176768));
-        assert_eq!(nv.history.len(), 120);
+        assert_eq!(nv.mean(), Some(-50.0));
+        assert_eq!(nv.deviation(), Some(29.15476));
+        assert_eq!(nv.normalize(nv.moving_average()), Some(5.0077586));
+        assert_eq!(nv.normalize(nv.min), Some(-1.7149858));
+        assert_eq!(nv.normalize(nv.max), Some(5.1449575));
+        assert_eq!(nv.history.len(), 0);
+    }
+
+    #[test]
+    fn test_restart_baseline() {
+        let mut nv: NormalizedValue<f32> = NormalizedValue::new();
+        assert!(!nv.can_freeze());
+
+        for i in 0..10 {
+            nv.set(i as f32);
+        }
+        assert!(nv.can_freeze());
+        nv.restart_baseline();
+
+        assert!(!nv.can_freeze());
+        assert_eq!(nv.history.len(), 0);
+        assert_eq!(None, nv.freeze());
+    }
+
+    #[test]
+    fn test_repeated_values_policy() {
+        let mut skip: NormalizedValue<f64> = NormalizedValue::new();
+        let mut keep: NormalizedValue<f64> =
+            NormalizedValue::with_settings(NormalizationSettings {
+                repeated_values: RepeatedValues::Keep,
+                ..NormalizationSettings::default()
+            });
+
+        for value in &[1.0, 1.0, 4.0] {
+            skip.set(*value);
+            keep.set(*value);
+        }
+        assert_eq!(skip.mean(), Some(2.5));
+        assert_eq!(keep.mean(), Some(2.0));
+        assert!(!skip.set(std::f64::NAN));
+        assert!(!keep.set(std::f64::NAN));
+    }
+
+    #[test]
+    fn test_robust_windowed_normalization() {
+        let mut nv: NormalizedValue<f64> = NormalizedValue::with_settings(NormalizationSettings {
+            window: Some(5),
+            smoothing: Smoothing::Exponential { alpha: 0.5 },
+            statistic: BaselineStatistic::MedianMad,
+            repeated_values: RepeatedValues::Skip,
+        });
+
+        for value in &[50.0, 1.0, 2.0, 3.0, 4.0, 1000.0] {
+            nv.set(*value);
+        }
+        assert_eq!(nv.history.len(), 5);
+        assert_eq!(nv.mean(), Some(3.0));
+        assert_eq!(nv.deviation(), Some(1.4826));
+        assert_eq!(nv.moving_average(), Some(503.09375));
+    }
+
+    #[test]
+    fn test_comma_list() {
+        assert_eq!("1.0, 2.5", comma_list(&[1.0, 2.5]));
+    }
+
+    #[test]
+    fn test_band_index_matches_all() {
+        for (i, band) in EegBand::ALL.iter().enumerate() {
+            assert_eq!(i, band.index());
+        }
+    }
+
+    #[test]
+    fn test_packet_error_addr() {
+        let e = MusePacketError::TypeMismatch {
+            addr: "/muse/batt".to_string(),
+            index: 1,
+            expected: "an int",
+        };
+
+        assert_eq!("/muse/batt", e.addr());
+    }
+
+    #[test]
+    fn test_combine_participants_average() {
+        let affect = [(0, Some(1.0), None), (1, Some(3.0), Some(-1.0))];
+
+        assert_eq!(
+            (Some(2.0), Some(-1.0)),
+            combine_participants(ParticipantCombination::Average, &affect)
+        );
+    }
+
+    #[test]
+    fn test_combine_participants_split() {
+        let affect = [(1, Some(3.0), Some(-1.0)), (0, Some(1.0), Some(5.0))];
+
+        assert_eq!(
+            (Some(1.0), Some(-1.0)),
+            combine_participants(ParticipantCombination::Split, &affect)
+        );
+        assert_eq!(
+            (None, None),
+            combine_participants(ParticipantCombination::Split, &[])
+        );
+    }
+
+    #[test]
+    fn test_participant_label() {
+        assert_eq!("P1", participant_label(0));
     }
 
     #[test]
@@ -1,260 +1,395 @@
-use crate::muse_model::{MuseMessage, MuseMessageType};
+use crate::clock_offset::OscTimetag;
+use crate::muse_model::{EegBand, MuseMessage, MuseMessageType, MusePacketError};
 /// Muse packets are received over an OSC protol USP socket from MindMonitor app
 /// running on Android on the same WIFI
 use log::*;
 use std::net::SocketAddr;
 
-use chrono::Local;
+use chrono::{DateTime, Local};
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 use nannou_osc::*;
 
-pub fn parse_muse_packet(addr: SocketAddr, packet: &Packet) -> Vec<MuseMessage> {
+const ELEMENTS_PREFIX: &str = "/muse/elements/";
+const RAW_FFT_PREFIX: &str = "/muse/elements/raw_fft";
+const MARKER_PREFIX: &str = "/Marker/";
+
+pub fn parse_muse_packet(
+    addr: SocketAddr,
+    packet: &Packet,
+) -> Vec<Result<MuseMessage, MusePacketError>> {
+    parse_muse_packet_at(addr, packet, Local::now())
+}
+
+/// Parse a packet which arrived at a known time, for example when replaying a capture file
+pub fn parse_muse_packet_at(
+    addr: SocketAddr,
+    packet: &Packet,
+    receive_time: DateTime<Local>,
+) -> Vec<Result<MuseMessage, MusePacketError>> {
     let mut raw_messages = Vec::new();
-    let message_time = Local::now();
 
-    packet.clone().unfold(&mut raw_messages);
+    unfold_with_timetag(packet.clone(), None, &mut raw_messages);
     let mut muse_messages = Vec::with_capacity(raw_messages.len());
 
-    for raw_message in raw_messages {
-        if let Some(muse_message_type) = parse_muse_message_type(raw_message) {
-            muse_messages.push(MuseMessage {
-                message_time,
-                ip_address: addr,
-                muse_message_type,
-            });
+    for (raw_message, timetag) in raw_messages {
+        let parse_result = parse_muse_message_type(raw_message);
+
+        if let Err(e) = &parse_result {
+            warn!("Dropped OSC message: {}", e);
         }
+
+        muse_messages.push(parse_result.map(|muse_message_type| MuseMessage {
+            message_time: receive_time, // Corrected to the sample time later if there is a timetag
+            receive_time,
+            timetag,
+            ip_address: addr,
+            muse_message_type,
+        }));
     }
 
     muse_messages
 }
 
-pub fn parse_muse_message_type(raw_message: Message) -> Option<MuseMessageType> {
-    let service = raw_message.addr.as_ref();
-    let args = raw_message
-        .clone()
-        .args
-        .expect("Expected value was not sent by Muse");
+/// Like Packet::unfold, but each message keeps the timetag of the innermost bundle which contained it
+fn unfold_with_timetag(
+    packet: Packet,
+    timetag: Option<OscTimetag>,
+    messages: &mut Vec<(Message, Option<OscTimetag>)>,
+) {
+    match packet {
+        Packet::Message(message) => messages.push((message, timetag)),
+        Packet::Bundle(bundle) => {
+            let bundle_timetag = match bundle.timetag {
+                Type::Time(seconds, fraction) => {
+                    let t = OscTimetag::new(seconds, fraction);
+                    match t.is_immediate() {
+                        true => timetag,
+                        false => Some(t),
+                    }
+                }
+                _ => timetag,
+            };
+
+            for inner in bundle.content {
+                unfold_with_timetag(inner.into(), bundle_timetag, messages);
+            }
+        }
+    }
+}
 
-    match (match service {
+pub fn parse_muse_message_type(raw_message: Message) -> Result<MuseMessageType, MusePacketError> {
+    let service: &str = raw_message.addr.as_ref();
+
+    // Mind Monitor marker buttons may be sent without any arguments
+    if service.starts_with(MARKER_PREFIX) {
+        return match service[MARKER_PREFIX.len()..].parse::<i32>() {
+            Ok(marker) => Ok(MuseMessageType::Marker { marker }),
+            Err(_) => Err(MusePacketError::UnknownAddress {
+                addr: service.to_string(),
+            }),
+        };
+    }
+
+    let args = match &raw_message.args {
+        Some(args) => args,
+        None => {
+            return Err(MusePacketError::MissingArgs {
+                addr: service.to_string(),
+            })
+        }
+    };
+
+    let muse_message_type = match service {
         "/muse/eeg" => {
+            check_arity(service, args, 4)?;
             let eeg = [
-                get_float_from_args(0, &args),
-                get_float_from_args(0, &args),
-                get_float_from_args(0, &args),
-                get_float_from_args(0, &args),
+                get_float_from_args(service, 0, args)?,
+                get_float_from_args(service, 1, args)?,
+                get_float_from_args(service, 2, args)?,
+                get_float_from_args(service, 3, args)?,
             ];
 
-            Some(MuseMessageType::Eeg { eeg })
+            MuseMessageType::Eeg { eeg }
         }
 
-        "/muse/acc" => Some(MuseMessageType::Accelerometer {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
+        "/muse/acc" => {
+            check_arity(service, args, 3)?;
+            MuseMessageType::Accelerometer {
+                x: get_float_from_args(service, 0, args)?,
+                y: get_float_from_args(service, 1, args)?,
+                z: get_float_from_args(service, 2, args)?,
+            }
+        }
 
-        "/muse/gyro" => Some(MuseMessageType::Gyro {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
+        "/muse/gyro" => {
+            check_arity(service, args, 3)?;
+            MuseMessageType::Gyro {
+                x: get_float_from_args(service, 0, args)?,
+                y: get_float_from_args(service, 1, args)?,
+                z: get_float_from_args(service, 2, args)?,
+            }
+        }
 
-        "/muse/elements/touching_forehead" => Some(MuseMessageType::TouchingForehead {
-            touch: get_int_from_args(0, &args) != 0,
-        }),
+        "/muse/elements/touching_forehead" => {
+            check_arity(service, args, 1)?;
+            MuseMessageType::TouchingForehead {
+                touch: get_int_from_args(service, 0, args)? != 0,
+            }
+        }
 
-        "/muse/elements/horseshoe" => Some(MuseMessageType::Horseshoe {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/horseshoe" => {
+            check_arity(service, args, 4)?;
+            MuseMessageType::Horseshoe {
+                a: get_float_from_args(service, 0, args)?,
+                b: get_float_from_args(service, 1, args)?,
+                c: get_float_from_args(service, 2, args)?,
+                d: get_float_from_args(service, 3, args)?,
+            }
+        }
 
-        "/muse/elements/alpha_absolute" => Some(MuseMessageType::Alpha {
-            alpha: [
-                get_float_from_args(0, &args),
-                get_float_from_args(1, &args),
-                get_float_from_args(2, &args),
-                get_float_from_args(3, &args),
-            ],
-        }),
+        "/muse/elements/alpha_absolute" => MuseMessageType::Alpha {
+            alpha: get_four_floats_from_args(service, args)?,
+        },
 
-        "/muse/elements/beta_absolute" => Some(MuseMessageType::Beta {
-            beta: [
-                get_float_from_args(0, &args),
-                get_float_from_args(1, &args),
-                get_float_from_args(2, &args),
-                get_float_from_args(3, &args),
-            ],
-        }),
+        "/muse/elements/beta_absolute" => MuseMessageType::Beta {
+            beta: get_four_floats_from_args(service, args)?,
+        },
 
-        "/muse/elements/gamma_absolute" => Some(MuseMessageType::Gamma {
-            gamma: [
-                get_float_from_args(0, &args),
-                get_float_from_args(1, &args),
-                get_float_from_args(2, &args),
-                get_float_from_args(3, &args),
-            ],
-        }),
+        "/muse/elements/gamma_absolute" => MuseMessageType::Gamma {
+            gamma: get_four_floats_from_args(service, args)?,
+        },
 
-        "/muse/elements/delta_absolute" => Some(MuseMessageType::Delta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/delta_absolute" => {
+            let [a, b, c, d] = get_four_floats_from_args(service, args)?;
+            MuseMessageType::Delta { a, b, c, d }
+        }
 
-        "/muse/elements/theta_absolute" => Some(MuseMessageType::Theta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/theta_absolute" => {
+            let [a, b, c, d] = get_four_floats_from_args(service, args)?;
+            MuseMessageType::Theta { a, b, c, d }
+        }
 
         "/muse/elements/blink" => {
-            let blink = get_int_from_args(0, &args);
+            check_arity(service, args, 1)?;
+            let blink = get_int_from_args(service, 0, args)?;
             info!("Blink: {:#?}", blink);
 
-            Some(MuseMessageType::Blink { blink: blink != 0 })
+            MuseMessageType::Blink { blink: blink != 0 }
         }
 
-        "/muse/batt" => Some(MuseMessageType::Batt {
-            batt: (get_int_from_args(1, &args) as f32 / get_int_from_args(0, &args) as f32) as i32,
-        }),
-
-        "/muse/elements/jaw_clench" => Some(MuseMessageType::JawClench {
-            clench: get_int_from_args(0, &args) != 0,
-        }),
-
-        _ => {
-            error!("Unparsed message type: {:#?} {:#?}", service, raw_message);
-            None
+        "/muse/batt" => {
+            check_arity(service, args, 2)?;
+            MuseMessageType::Batt {
+                batt: (get_int_from_args(service, 1, args)? as f32
+                    / get_int_from_args(service, 0, args)? as f32) as i32,
+            }
         }
-    })
-    .clone()
-    {
-        Some(m) => warn!("OSC message: {:?}", m),
-        _ => warn!("Unparsed OSC message"),
-    }
-
-    match service {
-        "/muse/eeg" => {
-            let eeg = [
-                get_float_from_args(0, &args),
-                get_float_from_args(0, &args),
-                get_float_from_args(0, &args),
-                get_float_from_args(0, &args),
-            ];
 
-            Some(MuseMessageType::Eeg { eeg })
+        "/muse/elements/jaw_clench" => {
+            check_arity(service, args, 1)?;
+            MuseMessageType::JawClench {
+                clench: get_int_from_args(service, 0, args)? != 0,
+            }
         }
 
-        "/muse/acc" => Some(MuseMessageType::Accelerometer {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
-
-        "/muse/gyro" => Some(MuseMessageType::Gyro {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
+        "/muse/elements/low_freqs_absolute" => MuseMessageType::LowFrequencies {
+            low_freqs: get_four_floats_from_args(service, args)?,
+        },
+
+        "/muse/elements/is_good" => {
+            check_arity(service, args, 4)?;
+            MuseMessageType::IsGood {
+                is_good: [
+                    get_int_from_args(service, 0, args)? != 0,
+                    get_int_from_args(service, 1, args)? != 0,
+                    get_int_from_args(service, 2, args)? != 0,
+                    get_int_from_args(service, 3, args)? != 0,
+                ],
+            }
+        }
 
-        "/muse/elements/touching_forehead" => Some(MuseMessageType::TouchingForehead {
-            touch: get_int_from_args(0, &args) != 0,
-        }),
+        "/muse/elements/hsi_precision" => MuseMessageType::HsiPrecision {
+            hsi: get_four_floats_from_args(service, args)?,
+        },
+
+        "/muse/ppg" => {
+            check_arity(service, args, 3)?;
+            MuseMessageType::Ppg {
+                ppg: [
+                    get_float_from_args(service, 0, args)?,
+                    get_float_from_args(service, 1, args)?,
+                    get_float_from_args(service, 2, args)?,
+                ],
+            }
+        }
 
-        "/muse/elements/horseshoe" => Some(MuseMessageType::Horseshoe {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/drlref" => {
+            check_arity(service, args, 2)?;
+            MuseMessageType::DrlRef {
+                drl: get_float_from_args(service, 0, args)?,
+                reference: get_float_from_args(service, 1, args)?,
+            }
+        }
 
-        "/muse/elements/alpha_absolute" => {
-            let alpha = [
-                get_float_from_args(0, &args),
-                get_float_from_args(1, &args),
-                get_float_from_args(2, &args),
-                get_float_from_args(3, &args),
-            ];
+        "/muse/aux" => {
+            check_arity(service, args, 1)?;
+            MuseMessageType::Aux {
+                aux: get_all_floats_from_args(service, args)?,
+            }
+        }
 
-            // println!("Raw Alpha: [{:#?}, {:#?}, {:#?}, {:#?}]", a, b, c, d);
+        "/muse/elements/experimental/concentration" => {
+            check_arity(service, args, 1)?;
+            MuseMessageType::Concentration {
+                concentration: get_float_from_args(service, 0, args)?,
+            }
+        }
 
-            Some(MuseMessageType::Alpha { alpha })
+        "/muse/elements/experimental/mellow" => {
+            check_arity(service, args, 1)?;
+            MuseMessageType::Mellow {
+                mellow: get_float_from_args(service, 0, args)?,
+            }
         }
 
-        "/muse/elements/beta_absolute" => {
-            let beta = [
-                get_float_from_args(0, &args),
-                get_float_from_args(1, &args),
-                get_float_from_args(2, &args),
-                get_float_from_args(3, &args),
-            ];
-            Some(MuseMessageType::Beta { beta })
+        "/muse/annotation" => {
+            check_arity(service, args, 1)?;
+            MuseMessageType::Annotation {
+                annotation: get_string_from_args(service, 0, args)?,
+            }
         }
 
-        "/muse/elements/gamma_absolute" => {
-            let gamma = [
-                get_float_from_args(0, &args),
-                get_float_from_args(1, &args),
-                get_float_from_args(2, &args),
-                get_float_from_args(3, &args),
-            ];
-            Some(MuseMessageType::Gamma { gamma })
+        _ if service.starts_with(RAW_FFT_PREFIX) => {
+            match service[RAW_FFT_PREFIX.len()..].parse::<usize>() {
+                Ok(channel) if channel < 4 => {
+                    check_arity(service, args, 1)?;
+                    MuseMessageType::RawFft {
+                        channel,
+                        fft: get_all_floats_from_args(service, args)?,
+                    }
+                }
+                _ => {
+                    return Err(MusePacketError::UnknownAddress {
+                        addr: service.to_string(),
+                    })
+                }
+            }
         }
 
-        "/muse/elements/delta_absolute" => Some(MuseMessageType::Delta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        _ => match split_band_element(service) {
+            Some((band, "relative")) => MuseMessageType::Relative {
+                band,
+                values: get_four_floats_from_args(service, args)?,
+            },
+            Some((band, "session_score")) => MuseMessageType::SessionScore {
+                band,
+                values: get_four_floats_from_args(service, args)?,
+            },
+            _ => {
+                return Err(MusePacketError::UnknownAddress {
+                    addr: service.to_string(),
+                })
+            }
+        },
+    };
+    trace!("OSC message: {:?}", muse_message_type);
+
+    Ok(muse_message_type)
+}
 
-        "/muse/elements/theta_absolute" => Some(MuseMessageType::Theta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+/// Fail if fewer arguments arrived than the address requires. Additional trailing values are ignored.
+fn check_arity(addr: &str, args: &[Type], expected: usize) -> Result<(), MusePacketError> {
+    if args.len() < expected {
+        return Err(MusePacketError::WrongArity {
+            addr: addr.to_string(),
+            expected,
+            actual: args.len(),
+        });
+    }
 
-        "/muse/elements/blink" => {
-            let blink = get_int_from_args(0, &args);
-            info!("Blink: {:#?}", blink);
+    Ok(())
+}
 
-            Some(MuseMessageType::Blink { blink: blink != 0 })
-        }
+/// Split "/muse/elements/alpha_relative" into (EegBand::Alpha, "relative")
+fn split_band_element(service: &str) -> Option<(EegBand, &str)> {
+    if !service.starts_with(ELEMENTS_PREFIX) {
+        return None;
+    }
+    let element = &service[ELEMENTS_PREFIX.len()..];
+    let underscore = element.find('_')?;
+    let band = EegBand::from_name(&element[..underscore])?;
 
-        "/muse/batt" => Some(MuseMessageType::Batt {
-            batt: (get_int_from_args(1, &args) as f32 / get_int_from_args(0, &args) as f32) as i32,
-        }),
+    Some((band, &element[underscore + 1..]))
+}
 
-        "/muse/elements/jaw_clench" => Some(MuseMessageType::JawClench {
-            clench: get_int_from_args(0, &args) != 0,
-        }),
+/// Band power values for the four electrodes. Mind Monitor can be set to send one value averaged over all electrodes, in which case all four get that value.
+fn get_four_floats_from_args(addr: &str, args: &[Type]) -> Result<[f32; 4], MusePacketError> {
+    if args.len() == 1 {
+        let average = get_float_from_args(addr, 0, args)?;
 
-        _ => {
-            error!("Unparsed message type: {:#?} {:#?}", service, raw_message);
-            None
-        }
+        return Ok([average; 4]);
     }
+    check_arity(addr, args, 4)?;
+
+    Ok([
+        get_float_from_args(addr, 0, args)?,
+        get_float_from_args(addr, 1, args)?,
+        get_float_from_args(addr, 2, args)?,
+        get_float_from_args(addr, 3, args)?,
+    ])
 }
 
-fn get_float_from_args(i: usize, args: &Vec<Type>) -> f32 {
-    let f = args.get(i).expect("Float was not provided");
+fn get_float_from_args(addr: &str, i: usize, args: &[Type]) -> Result<f32, MusePacketError> {
+    match args.get(i) {
+        Some(Type::Float(value)) => Ok(*value),
+        Some(_) => Err(MusePacketError::TypeMismatch {
+            addr: addr.to_string(),
+            index: i,
+            expected: "a float",
+        }),
+        None => Err(MusePacketError::WrongArity {
+            addr: addr.to_string(),
+            expected: i + 1,
+            actual: args.len(),
+        }),
+    }
+}
 
-    match f {
-        Type::Float(value) => *value,
-        _ => panic!("Muse value was not a float"),
+fn get_all_floats_from_args(addr: &str, args: &[Type]) -> Result<Vec<f32>, MusePacketError> {
+    (0..args.len())
+        .map(|i| get_float_from_args(addr, i, args))
+        .collect()
+}
+
+fn get_string_from_args(addr: &str, i: usize, args: &[Type]) -> Result<String, MusePacketError> {
+    match args.get(i) {
+        Some(Type::String(value)) => Ok(value.clone()),
+        Some(_) => Err(MusePacketError::TypeMismatch {
+            addr: addr.to_string(),
+            index: i,
+            expected: "a string",
+        }),
+        None => Err(MusePacketError::WrongArity {
+            addr: addr.to_string(),
+            expected: i + 1,
+            actual: args.len(),
+        }),
     }
 }
 
-fn get_int_from_args(i: usize, args: &Vec<Type>) -> i32 {
-    let j = args.get(i).expect("Int was not provided");
-    match j {
-        Type::Int(value) => *value,
-        _ => panic!("Muse value was not an int"),
+fn get_int_from_args(addr: &str, i: usize, args: &[Type]) -> Result<i32, MusePacketError> {
+    match args.get(i) {
+        Some(Type::Int(value)) => Ok(*value),
+        Some(_) => Err(MusePacketError::TypeMismatch {
+            addr: addr.to_string(),
+            index: i,
+            expected: "an int",
+        }),
+        None => Err(MusePacketError::WrongArity {
+            addr: addr.to_string(),
+            expected: i + 1,
+            actual: args.len(),
+        }),
     }
 }
 
@@ -262,13 +397,15 @@ fn get_int_from_args(i: usize, args: &Vec<Type>) -> i32 {
 mod tests {
     use crate::muse_packet::*;
 
+    const ADDR: &str = "/muse/test";
+
     #[test]
     fn test_int_from_args() {
         let i = 32;
         let mut args: Vec<Type> = Vec::new();
         args.push(Type::Int(i));
 
-        assert_eq!(i, get_int_from_args(0, &args));
+        assert_eq!(Ok(i), get_int_from_args(ADDR, 0, &args));
     }
 
     #[test]
@@ -277,6 +414,193 @@ mod tests {
         let mut args: Vec<Type> = Vec::new();
         args.push(Type::Float(f));
 
-        assert_eq!(f, get_float_from_args(0, &args));
+        assert_eq!(Ok(f), get_float_from_args(ADDR, 0, &args));
+    }
+
+    #[test]
+    fn test_float_from_args_type_mismatch() {
+        let args: Vec<Type> = vec![Type::Int(1)];
+
+        assert_eq!(
+            Err(MusePacketError::TypeMismatch {
+                addr: ADDR.to_string(),
+                index: 0,
+                expected: "a float"
+            }),
+            get_float_from_args(ADDR, 0, &args)
+        );
+    }
+
+    #[test]
+    fn test_int_from_args_missing() {
+        let args: Vec<Type> = Vec::new();
+
+        assert_eq!(
+            Err(MusePacketError::WrongArity {
+                addr: ADDR.to_string(),
+                expected: 1,
+                actual: 0
+            }),
+            get_int_from_args(ADDR, 0, &args)
+        );
+    }
+
+    #[test]
+    fn test_bundle_timetag_applies_to_contents() {
+        let message = Message {
+            addr: "/muse/elements/blink".to_string(),
+            args: Some(vec![Type::Int(1)]),
+        };
+        let packet = Packet::Bundle(Bundle {
+            timetag: Type::Time(3_791_604_949, 0),
+            content: vec![Packet::Message(message.clone()).into()],
+        });
+        let addr: SocketAddr = "127.0.0.1:34254".parse().unwrap();
+
+        let muse_messages = parse_muse_packet(addr, &packet);
+
+        assert_eq!(1, muse_messages.len());
+        assert_eq!(
+            Some(OscTimetag::new(3_791_604_949, 0)),
+            muse_messages[0].as_ref().unwrap().timetag
+        );
+
+        let unbundled = parse_muse_packet(addr, &Packet::Message(message));
+        assert_eq!(None, unbundled[0].as_ref().unwrap().timetag);
+    }
+
+    #[test]
+    fn test_parse_missing_args() {
+        let message = Message {
+            addr: "/muse/eeg".to_string(),
+            args: None,
+        };
+
+        let error = parse_muse_message_type(message).unwrap_err();
+
+        assert_eq!("/muse/eeg", error.addr());
+    }
+
+    #[test]
+    fn test_parse_short_eeg() {
+        let message = Message {
+            addr: "/muse/eeg".to_string(),
+            args: Some(vec![Type::Float(1.0), Type::Float(2.0)]),
+        };
+
+        assert_eq!(
+            Err(MusePacketError::WrongArity {
+                addr: "/muse/eeg".to_string(),
+                expected: 4,
+                actual: 2
+            }),
+            parse_muse_message_type(message).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_parse_unknown_address() {
+        let message = Message {
+            addr: "/not/muse".to_string(),
+            args: Some(Vec::new()),
+        };
+
+        assert_eq!(
+            Err(MusePacketError::UnknownAddress {
+                addr: "/not/muse".to_string()
+            }),
+            parse_muse_message_type(message).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_parse_eeg_keeps_channel_order() {
+        let message = Message {
+            addr: "/muse/eeg".to_string(),
+            args: Some(vec![
+                Type::Float(1.0),
+                Type::Float(2.0),
+                Type::Float(3.0),
+                Type::Float(4.0),
+            ]),
+        };
+
+        match parse_muse_message_type(message) {
+            Ok(MuseMessageType::Eeg { eeg }) => assert_eq!([1.0, 2.0, 3.0, 4.0], eeg),
+            other => panic!("Unexpected parse result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_relative_band() {
+        let message = Message {
+            addr: "/muse/elements/theta_relative".to_string(),
+            args: Some(vec![
+                Type::Float(0.1),
+                Type::Float(0.2),
+                Type::Float(0.3),
+                Type::Float(0.4),
+            ]),
+        };
+
+        match parse_muse_message_type(message) {
+            Ok(MuseMessageType::Relative { band, values }) => {
+                assert_eq!(EegBand::Theta, band);
+                assert_eq!([0.1, 0.2, 0.3, 0.4], values);
+            }
+            other => panic!("Unexpected parse result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_averaged_band() {
+        let message = Message {
+            addr: "/muse/elements/alpha_absolute".to_string(),
+            args: Some(vec![Type::Float(0.5)]),
+        };
+
+        match parse_muse_message_type(message) {
+            Ok(MuseMessageType::Alpha { alpha }) => assert_eq!([0.5; 4], alpha),
+            other => panic!("Unexpected parse result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_raw_fft() {
+        let message = Message {
+            addr: "/muse/elements/raw_fft2".to_string(),
+            args: Some(vec![Type::Float(1.0); 129]),
+        };
+
+        match parse_muse_message_type(message) {
+            Ok(MuseMessageType::RawFft { channel, fft }) => {
+                assert_eq!(2, channel);
+                assert_eq!(129, fft.len());
+            }
+            other => panic!("Unexpected parse result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_raw_fft_bad_channel() {
+        let message = Message {
+            addr: "/muse/elements/raw_fft7".to_string(),
+            args: Some(vec![Type::Float(1.0)]),
+        };
+
+        assert!(parse_muse_message_type(message).is_err());
+    }
+
+    #[test]
+    fn test_parse_marker_without_args() {
+        let message = Message {
+            addr: "/Marker/3".to_string(),
+            args: None,
+        };
+
+        match parse_muse_message_type(message) {
+            Ok(MuseMessageType::Marker { marker }) => assert_eq!(3, marker),
+            other => panic!("Unexpected parse result {:?}", other),
+        }
     }
 }
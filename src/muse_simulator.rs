@@ -0,0 +1,482 @@
+/// Synthetic Muse headset which produces Mind Monitor style OSC messages, so the app can be
+/// developed and tested without hardware. Used by the muse_simulator binary.
+use nannou_osc::{Message, Type};
+use std::f32::consts::PI;
+
+pub const DEFAULT_OSC_PORT: u16 = 34254; // Same port the app listens on, see muse_model::OSC_PORT
+pub const SAMPLE_RATE: u32 = 256; // Raw EEG samples per second per electrode
+
+const ACCELEROMETER_INTERVAL: u64 = 5; // ~52Hz, in EEG samples
+const BAND_INTERVAL: u64 = 26; // ~10Hz, in EEG samples
+const BATTERY_INTERVAL: u64 = 10 * SAMPLE_RATE as u64;
+const EEG_DC_OFFSET: f32 = 800.0; // Muse raw EEG is centered near 800 microVolts
+const EEG_NOISE: f32 = 3.0; // microVolts standard deviation
+const DROPOUT_NOISE: f32 = 120.0; // An electrode off the skin picks up large noise
+const ELECTRODE_NAMES: [&str; 4] = ["TP9", "AF7", "AF8", "TP10"];
+const AF7: usize = 1;
+const AF8: usize = 2;
+
+/// Frequency (Hz) and amplitude (microVolts) of the rhythm generating each band, in the order delta, theta, alpha, beta, gamma
+const BANDS: [(&str, f32, f32); 5] = [
+    ("delta", 2.0, 10.0),
+    ("theta", 6.0, 6.0),
+    ("alpha", 10.0, 8.0),
+    ("beta", 20.0, 4.0),
+    ("gamma", 40.0, 2.0),
+];
+const ALPHA: usize = 2;
+
+/// Something that happens to the simulated wearer for a while
+#[derive(Clone, Debug, PartialEq)]
+pub enum SimulatedEvent {
+    /// Alpha amplitude on all electrodes is multiplied by gain, as when relaxing with eyes closed
+    AlphaSurge { gain: f32 },
+    /// Shift alpha from AF7 toward AF8 (positive) or the reverse (negative), -1.0 to 1.0
+    Asymmetry { shift: f32 },
+    /// The electrode loses skin contact
+    Dropout { electrode: usize },
+    /// This fraction of OSC packets are never sent, 0.0 to 1.0
+    PacketLoss { fraction: f32 },
+}
+
+/// An event active from `start` for `duration` seconds after the simulation begins
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScriptedEvent {
+    pub start: f32,
+    pub duration: f32,
+    pub event: SimulatedEvent,
+}
+
+/// A timeline of events. Outside of any event the wearer is calm with good contact.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scenario {
+    pub events: Vec<ScriptedEvent>,
+}
+
+impl Scenario {
+    /// Built in scenarios: baseline, alpha_surge, asymmetry, dropout, packet_loss, demo (all of them in turn)
+    pub fn named(name: &str) -> Option<Scenario> {
+        let script = match name {
+            "baseline" => "",
+            "alpha_surge" => "10 20 alpha_surge 3.0",
+            "asymmetry" => "10 20 asymmetry 0.8\n40 20 asymmetry -0.8",
+            "dropout" => "10 10 dropout AF7\n30 10 dropout TP10",
+            "packet_loss" => "10 30 packet_loss 0.3",
+            "demo" => {
+                "10 20 alpha_surge 3.0\n40 20 asymmetry 0.8\n70 20 asymmetry -0.8\n100 10 dropout AF8\n120 20 packet_loss 0.3"
+            }
+            _ => return None,
+        };
+
+        Scenario::parse(script).ok()
+    }
+
+    /// One event per line: start seconds, duration seconds, event name, parameter. Blank lines and lines starting with # are ignored.
+    ///
+    ///   # Relax, then a strong right frontal shift
+    ///   10 20 alpha_surge 3.0
+    ///   40 10 asymmetry 0.8
+    ///   60 5 dropout AF7
+    ///   70 10 packet_loss 0.25
+    pub fn parse(script: &str) -> Result<Scenario, String> {
+        let mut events = Vec::new();
+
+        for (line_number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 {
+                return Err(format!(
+                    "Line {}: expected 'start duration event parameter'",
+                    line_number + 1
+                ));
+            }
+            let number = |s: &str| {
+                s.parse::<f32>()
+                    .map_err(|_| format!("Line {}: '{}' is not a number", line_number + 1, s))
+            };
+            let start = number(fields[0])?;
+            let duration = number(fields[1])?;
+            let event = match fields[2] {
+                "alpha_surge" => SimulatedEvent::AlphaSurge {
+                    gain: number(fields[3])?,
+                },
+                "asymmetry" => SimulatedEvent::Asymmetry {
+                    shift: number(fields[3])?.clamp(-1.0, 1.0),
+                },
+                "dropout" => match ELECTRODE_NAMES.iter().position(|e| *e == fields[3]) {
+                    Some(electrode) => SimulatedEvent::Dropout { electrode },
+                    None => {
+                        return Err(format!(
+                            "Line {}: unknown electrode '{}'",
+                            line_number + 1,
+                            fields[3]
+                        ))
+                    }
+                },
+                "packet_loss" => SimulatedEvent::PacketLoss {
+                    fraction: number(fields[3])?.clamp(0.0, 1.0),
+                },
+                other => {
+                    return Err(format!(
+                        "Line {}: unknown event '{}'",
+                        line_number + 1,
+                        other
+                    ))
+                }
+            };
+            events.push(ScriptedEvent {
+                start,
+                duration,
+                event,
+            });
+        }
+
+        Ok(Scenario { events })
+    }
+
+    fn active(&self, time: f32) -> impl Iterator<Item = &SimulatedEvent> {
+        self.events
+            .iter()
+            .filter(move |e| time >= e.start && time < e.start + e.duration)
+            .map(|e| &e.event)
+    }
+}
+
+/// Small deterministic random number generator (xorshift64*) so simulated sessions are repeatable
+struct Random {
+    state: u64,
+}
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    /// Uniform in 0.0..1.0
+    fn next(&mut self) -> f32 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let r = self.state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+
+        (r >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Approximately normal, mean 0.0 and standard deviation 1.0
+    fn gaussian(&mut self) -> f32 {
+        let sum: f32 = (0..12).map(|_| self.next()).sum();
+
+        sum - 6.0
+    }
+}
+
+/// Current effect of all active events on the wearer
+struct Condition {
+    alpha_gain: [f32; 4],
+    dropout: [bool; 4],
+    packet_loss: f32,
+}
+
+/// Generates the OSC messages a Muse and Mind Monitor would send, one EEG sample at a time
+pub struct SimulatedHeadset {
+    scenario: Scenario,
+    random: Random,
+    sample_index: u64,
+    phase: [[f32; 4]; 5], // Random start phase of each band on each electrode
+    next_blink: f32,
+    next_clench: f32,
+}
+
+impl SimulatedHeadset {
+    pub fn new(scenario: Scenario, seed: u64) -> Self {
+        let mut random = Random::new(seed);
+        let mut phase = [[0.0; 4]; 5];
+        for band in phase.iter_mut() {
+            for p in band.iter_mut() {
+                *p = random.next() * 2.0 * PI;
+            }
+        }
+        let next_blink = 2.0 + random.next() * 4.0;
+        let next_clench = 15.0 + random.next() * 15.0;
+
+        Self {
+            scenario,
+            random,
+            sample_index: 0,
+            phase,
+            next_blink,
+            next_clench,
+        }
+    }
+
+    /// Seconds of simulated time generated so far
+    pub fn time(&self) -> f32 {
+        self.sample_index as f32 / SAMPLE_RATE as f32
+    }
+
+    fn condition(&self, time: f32) -> Condition {
+        let mut condition = Condition {
+            alpha_gain: [1.0; 4],
+            dropout: [false; 4],
+            packet_loss: 0.0,
+        };
+
+        for event in self.scenario.active(time) {
+            match event {
+                SimulatedEvent::AlphaSurge { gain } => {
+                    for g in condition.alpha_gain.iter_mut() {
+                        *g *= gain;
+                    }
+                }
+                SimulatedEvent::Asymmetry { shift } => {
+                    condition.alpha_gain[AF7] *= 1.0 - shift / 2.0;
+                    condition.alpha_gain[AF8] *= 1.0 + shift / 2.0;
+                }
+                SimulatedEvent::Dropout { electrode } => condition.dropout[*electrode] = true,
+                SimulatedEvent::PacketLoss { fraction } => {
+                    condition.packet_loss = condition.packet_loss.max(*fraction)
+                }
+            }
+        }
+
+        condition
+    }
+
+    /// Decide if the next packet is lost on the network at the current simulated time
+    pub fn drop_packet(&mut self) -> bool {
+        let loss = self.condition(self.time()).packet_loss;
+
+        loss > 0.0 && self.random.next() < loss
+    }
+
+    /// Advance one EEG sample (1/256 second) and return all messages due at that moment
+    pub fn step(&mut self) -> Vec<Message> {
+        let time = self.time();
+        let condition = self.condition(time);
+        let mut messages = Vec::new();
+
+        let mut eeg = [EEG_DC_OFFSET; 4];
+        for (channel, value) in eeg.iter_mut().enumerate() {
+            if condition.dropout[channel] {
+                *value += DROPOUT_NOISE * self.random.gaussian();
+                continue;
+            }
+            for (band, (_, frequency, amplitude)) in BANDS.iter().enumerate() {
+                let gain = match band {
+                    ALPHA => condition.alpha_gain[channel],
+                    _ => 1.0,
+                };
+                *value += amplitude
+                    * gain
+                    * (2.0 * PI * frequency * time + self.phase[band][channel]).sin();
+            }
+            *value += EEG_NOISE * self.random.gaussian();
+        }
+        messages.push(float_message("/muse/eeg", &eeg));
+
+        if self.sample_index % ACCELEROMETER_INTERVAL == 0 {
+            let acc = [
+                0.01 * self.random.gaussian(),
+                0.01 * self.random.gaussian(),
+                1.0 + 0.01 * self.random.gaussian(),
+            ];
+            let gyro = [
+                0.5 * self.random.gaussian(),
+                0.5 * self.random.gaussian(),
+                0.5 * self.random.gaussian(),
+            ];
+            messages.push(float_message("/muse/acc", &acc));
+            messages.push(float_message("/muse/gyro", &gyro));
+        }
+
+        if self.sample_index % BAND_INTERVAL == 0 {
+            for (band, (name, _, amplitude)) in BANDS.iter().enumerate() {
+                let mut values = [0.0; 4];
+                for (channel, value) in values.iter_mut().enumerate() {
+                    let gain = match (condition.dropout[channel], band) {
+                        (true, _) => DROPOUT_NOISE / amplitude,
+                        (false, ALPHA) => condition.alpha_gain[channel],
+                        _ => 1.0,
+                    };
+                    // Mind Monitor absolute band power is log10 of the power spectral density
+                    let power = (amplitude * gain).powi(2) / 2.0;
+                    *value = power.log10() - 0.8 + 0.05 * self.random.gaussian();
+                }
+                messages.push(float_message(
+                    &format!("/muse/elements/{}_absolute", name),
+                    &values,
+                ));
+            }
+
+            let mut horseshoe = [1.0; 4];
+            for (channel, h) in horseshoe.iter_mut().enumerate() {
+                if condition.dropout[channel] {
+                    *h = 4.0;
+                }
+            }
+            messages.push(float_message("/muse/elements/horseshoe", &horseshoe));
+            let touching = !condition.dropout.iter().all(|d| *d);
+            messages.push(int_message(
+                "/muse/elements/touching_forehead",
+                &[touching as i32],
+            ));
+        }
+
+        if time >= self.next_blink {
+            messages.push(int_message("/muse/elements/blink", &[1]));
+            self.next_blink = time + 2.0 + self.random.next() * 4.0;
+        }
+
+        if time >= self.next_clench {
+            messages.push(int_message("/muse/elements/jaw_clench", &[1]));
+            self.next_clench = time + 15.0 + self.random.next() * 15.0;
+        }
+
+        if self.sample_index % BATTERY_INTERVAL == 0 {
+            let charge = (9500 - (time as i32) * 2).max(0); // Percent * 100
+            messages.push(int_message("/muse/batt", &[charge, 3900, 3850, 30]));
+        }
+
+        self.sample_index += 1;
+
+        messages
+    }
+}
+
+fn float_message(addr: &str, values: &[f32]) -> Message {
+    Message {
+        addr: addr.to_string(),
+        args: Some(values.iter().map(|v| Type::Float(*v)).collect()),
+    }
+}
+
+fn int_message(addr: &str, values: &[i32]) -> Message {
+    Message {
+        addr: addr.to_string(),
+        args: Some(values.iter().map(|v| Type::Int(*v)).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn band_value(messages: &[Message], addr: &str, channel: usize) -> Option<f32> {
+        messages.iter().find(|m| m.addr == addr).and_then(|m| {
+            match &m.args.as_ref().unwrap()[channel] {
+                Type::Float(f) => Some(*f),
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let scenario = Scenario::parse("# comment\n\n1 2 alpha_surge 3\n4 5 dropout AF8").unwrap();
+
+        assert_eq!(
+            vec![
+                ScriptedEvent {
+                    start: 1.0,
+                    duration: 2.0,
+                    event: SimulatedEvent::AlphaSurge { gain: 3.0 }
+                },
+                ScriptedEvent {
+                    start: 4.0,
+                    duration: 5.0,
+                    event: SimulatedEvent::Dropout { electrode: 2 }
+                }
+            ],
+            scenario.events
+        );
+    }
+
+    #[test]
+    fn test_parse_script_errors() {
+        assert!(Scenario::parse("1 2 alpha_surge").is_err());
+        assert!(Scenario::parse("1 2 dropout FP1").is_err());
+        assert!(Scenario::parse("x 2 alpha_surge 3").is_err());
+        assert!(Scenario::parse("1 2 sneeze 3").is_err());
+    }
+
+    #[test]
+    fn test_named_scenarios_exist() {
+        for name in [
+            "baseline",
+            "alpha_surge",
+            "asymmetry",
+            "dropout",
+            "packet_loss",
+            "demo",
+        ]
+        .iter()
+        {
+            assert!(Scenario::named(name).is_some(), "{}", name);
+        }
+        assert!(Scenario::named("nonexistent").is_none());
+    }
+
+    #[test]
+    fn test_one_second_of_messages() {
+        let mut headset = SimulatedHeadset::new(Scenario::default(), 1);
+        let messages: Vec<Message> = (0..SAMPLE_RATE).flat_map(|_| headset.step()).collect();
+        let eeg_count = messages.iter().filter(|m| m.addr == "/muse/eeg").count();
+        let alpha_count = messages
+            .iter()
+            .filter(|m| m.addr == "/muse/elements/alpha_absolute")
+            .count();
+
+        assert_eq!(SAMPLE_RATE as usize, eeg_count);
+        assert_eq!(10, alpha_count);
+        assert_eq!(1.0, headset.time());
+    }
+
+    #[test]
+    fn test_alpha_surge_raises_alpha() {
+        let scenario = Scenario::parse("0 10 alpha_surge 4.0").unwrap();
+        let surge = SimulatedHeadset::new(scenario, 7).step();
+        let calm = SimulatedHeadset::new(Scenario::default(), 7).step();
+        let addr = "/muse/elements/alpha_absolute";
+
+        assert!(band_value(&surge, addr, AF7).unwrap() > band_value(&calm, addr, AF7).unwrap());
+    }
+
+    #[test]
+    fn test_asymmetry_shifts_alpha_right() {
+        let scenario = Scenario::parse("0 10 asymmetry 1.0").unwrap();
+        let messages = SimulatedHeadset::new(scenario, 3).step();
+        let addr = "/muse/elements/alpha_absolute";
+
+        assert!(
+            band_value(&messages, addr, AF8).unwrap() > band_value(&messages, addr, AF7).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dropout_marks_horseshoe() {
+        let scenario = Scenario::parse("0 10 dropout TP10").unwrap();
+        let messages = SimulatedHeadset::new(scenario, 3).step();
+
+        assert_eq!(
+            Some(4.0),
+            band_value(&messages, "/muse/elements/horseshoe", 3)
+        );
+        assert_eq!(
+            Some(1.0),
+            band_value(&messages, "/muse/elements/horseshoe", 0)
+        );
+    }
+
+    #[test]
+    fn test_packet_loss_fraction() {
+        let scenario = Scenario::parse("0 100 packet_loss 0.5").unwrap();
+        let mut headset = SimulatedHeadset::new(scenario, 11);
+        let dropped = (0..1000).filter(|_| headset.drop_packet()).count();
+
+        assert!(dropped > 400 && dropped < 600, "{}", dropped);
+        assert!(!SimulatedHeadset::new(Scenario::default(), 11).drop_packet());
+    }
+}
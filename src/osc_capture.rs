@@ -0,0 +1,698 @@
+/// Record raw OSC packets from the Muse to a compact session capture file, and replay them later
+/// through the same parser so a whole session can be re-run without a headset.
+///
+/// File format, all numbers little endian:
+///   header: CAPTURE_MAGIC, u16 version
+///   record: i64 arrival microseconds since UNIX_EPOCH, source address, u32 packet length, packet
+///   address: u8 4 or 6, IP bytes, u16 port
+///   packet: u8 0 then message, or u8 1 then u32 seconds, u32 fraction, u16 count, packets
+///   message: u16 length, address bytes, u8 1 if there are args, u16 count, args
+///   arg: u8 OSC type tag character, value
+use crate::eeg_source::OscSockets;
+use crate::muse_model::{EegMessageReceiver, MuseMessage, MusePacketError};
+use crate::muse_packet::parse_muse_packet_at;
+use chrono::{DateTime, Local, TimeZone};
+use nannou_osc::rosc::{OscColor, OscMidiMessage};
+use nannou_osc::{Bundle, Message, Packet, Type};
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::time::Instant;
+
+const CAPTURE_MAGIC: &[u8; 8] = b"MEMEOSC\0";
+const CAPTURE_VERSION: u16 = 1;
+const MAX_PACKET_LENGTH: usize = 65_536; // One UDP datagram, so only a corrupt file has longer packets
+
+/// One packet as it arrived from the network
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedPacket {
+    pub arrival_time: DateTime<Local>,
+    pub addr: SocketAddr,
+    pub packet: Packet,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+/// Lengths and counts stored as u16. A packet with more is not recorded rather than written wrongly.
+fn write_u16_len(out: &mut Vec<u8>, len: usize) -> io::Result<()> {
+    let len = u16::try_from(len).map_err(|_| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("{} is too many to record", len),
+        )
+    })?;
+    write_u16(out, len);
+
+    Ok(())
+}
+
+fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u32(out, bytes.len() as u32);
+    out.extend_from_slice(bytes);
+}
+
+fn encode_arg(arg: &Type, out: &mut Vec<u8>) {
+    match arg {
+        Type::Int(i) => {
+            out.push(b'i');
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        Type::Float(f) => {
+            out.push(b'f');
+            out.extend_from_slice(&f.to_bits().to_le_bytes());
+        }
+        Type::String(s) => {
+            out.push(b's');
+            write_bytes(out, s.as_bytes());
+        }
+        Type::Blob(b) => {
+            out.push(b'b');
+            write_bytes(out, b);
+        }
+        Type::Time(seconds, fraction) => {
+            out.push(b't');
+            write_u32(out, *seconds);
+            write_u32(out, *fraction);
+        }
+        Type::Long(h) => {
+            out.push(b'h');
+            out.extend_from_slice(&h.to_le_bytes());
+        }
+        Type::Double(d) => {
+            out.push(b'd');
+            out.extend_from_slice(&d.to_bits().to_le_bytes());
+        }
+        Type::Char(c) => {
+            out.push(b'c');
+            write_u32(out, *c as u32);
+        }
+        Type::Color(color) => {
+            out.push(b'r');
+            out.extend_from_slice(&[color.red, color.green, color.blue, color.alpha]);
+        }
+        Type::Midi(midi) => {
+            out.push(b'm');
+            out.extend_from_slice(&[midi.port, midi.status, midi.data1, midi.data2]);
+        }
+        Type::Bool(true) => out.push(b'T'),
+        Type::Bool(false) => out.push(b'F'),
+        Type::Nil => out.push(b'N'),
+        Type::Inf => out.push(b'I'),
+    }
+}
+
+/// Serialize an OSC packet in the capture file format
+pub fn encode_packet(packet: &Packet, out: &mut Vec<u8>) -> io::Result<()> {
+    match packet {
+        Packet::Message(message) => {
+            out.push(0);
+            write_u16_len(out, message.addr.len())?;
+            out.extend_from_slice(message.addr.as_bytes());
+            match &message.args {
+                Some(args) => {
+                    out.push(1);
+                    write_u16_len(out, args.len())?;
+                    for arg in args {
+                        encode_arg(arg, out);
+                    }
+                }
+                None => out.push(0),
+            }
+        }
+        Packet::Bundle(bundle) => {
+            out.push(1);
+            let (seconds, fraction) = match bundle.timetag {
+                Type::Time(seconds, fraction) => (seconds, fraction),
+                _ => (0, 1),
+            };
+            write_u32(out, seconds);
+            write_u32(out, fraction);
+            write_u16_len(out, bundle.content.len())?;
+            for inner in &bundle.content {
+                encode_packet(&inner.clone().into(), out)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read position within an encoded packet
+struct Cursor<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.position + n > self.bytes.len() {
+            return Err(invalid_data("Capture packet is truncated"));
+        }
+        let slice = &self.bytes[self.position..self.position + n];
+        self.position += n;
+
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(b))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?).map_err(|_| invalid_data("Capture string is not UTF-8"))
+    }
+}
+
+fn decode_arg(cursor: &mut Cursor) -> io::Result<Type> {
+    let arg = match cursor.u8()? {
+        b'i' => Type::Int(cursor.u32()? as i32),
+        b'f' => Type::Float(f32::from_bits(cursor.u32()?)),
+        b's' => Type::String(cursor.string()?),
+        b'b' => Type::Blob(cursor.bytes()?),
+        b't' => Type::Time(cursor.u32()?, cursor.u32()?),
+        b'h' => Type::Long(cursor.u64()? as i64),
+        b'd' => Type::Double(f64::from_bits(cursor.u64()?)),
+        b'c' => match std::char::from_u32(cursor.u32()?) {
+            Some(c) => Type::Char(c),
+            None => return Err(invalid_data("Capture char is not valid")),
+        },
+        b'r' => {
+            let c = cursor.take(4)?;
+            Type::Color(OscColor {
+                red: c[0],
+                green: c[1],
+                blue: c[2],
+                alpha: c[3],
+            })
+        }
+        b'm' => {
+            let m = cursor.take(4)?;
+            Type::Midi(OscMidiMessage {
+                port: m[0],
+                status: m[1],
+                data1: m[2],
+                data2: m[3],
+            })
+        }
+        b'T' => Type::Bool(true),
+        b'F' => Type::Bool(false),
+        b'N' => Type::Nil,
+        b'I' => Type::Inf,
+        _ => return Err(invalid_data("Unknown OSC type in capture")),
+    };
+
+    Ok(arg)
+}
+
+fn decode_packet_from(cursor: &mut Cursor) -> io::Result<Packet> {
+    match cursor.u8()? {
+        0 => {
+            let addr_len = cursor.u16()? as usize;
+            let addr = String::from_utf8(cursor.take(addr_len)?.to_vec())
+                .map_err(|_| invalid_data("Capture OSC address is not UTF-8"))?;
+            let args = match cursor.u8()? {
+                0 => None,
+                _ => {
+                    let count = cursor.u16()?;
+                    let mut args = Vec::with_capacity(count as usize);
+                    for _ in 0..count {
+                        args.push(decode_arg(cursor)?);
+                    }
+                    Some(args)
+                }
+            };
+
+            Ok(Packet::Message(Message { addr, args }))
+        }
+        1 => {
+            let timetag = Type::Time(cursor.u32()?, cursor.u32()?);
+            let count = cursor.u16()?;
+            let mut content = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                content.push(decode_packet_from(cursor)?.into());
+            }
+
+            Ok(Packet::Bundle(Bundle { timetag, content }))
+        }
+        _ => Err(invalid_data("Unknown packet kind in capture")),
+    }
+}
+
+/// Deserialize an OSC packet written by encode_packet
+pub fn decode_packet(bytes: &[u8]) -> io::Result<Packet> {
+    let mut cursor = Cursor { bytes, position: 0 };
+
+    decode_packet_from(&mut cursor)
+}
+
+fn date_time_to_micros(date_time: DateTime<Local>) -> i64 {
+    date_time.timestamp() * 1_000_000 + date_time.timestamp_subsec_micros() as i64
+}
+
+fn micros_to_date_time(micros: i64) -> io::Result<DateTime<Local>> {
+    Local
+        .timestamp_opt(
+            micros.div_euclid(1_000_000),
+            (micros.rem_euclid(1_000_000) * 1_000) as u32,
+        )
+        .single()
+        .ok_or_else(|| invalid_data("Arrival time in capture is out of range"))
+}
+
+/// Writes the capture file header then one record per packet
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(CAPTURE_MAGIC)?;
+        writer.write_all(&CAPTURE_VERSION.to_le_bytes())?;
+
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, captured: &CapturedPacket) -> io::Result<()> {
+        let mut record = Vec::new();
+        record.extend_from_slice(&date_time_to_micros(captured.arrival_time).to_le_bytes());
+        match captured.addr.ip() {
+            IpAddr::V4(ip) => {
+                record.push(4);
+                record.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                record.push(6);
+                record.extend_from_slice(&ip.octets());
+            }
+        }
+        write_u16(&mut record, captured.addr.port());
+        let mut packet = Vec::new();
+        encode_packet(&captured.packet, &mut packet)?;
+        if packet.len() > MAX_PACKET_LENGTH {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} byte packet is too long to record", packet.len()),
+            ));
+        }
+        write_bytes(&mut record, &packet);
+
+        self.writer.write_all(&record)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads records from a capture file in arrival order
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != CAPTURE_MAGIC {
+            return Err(invalid_data("Not a meme OSC capture file"));
+        }
+        let mut version = [0; 2];
+        reader.read_exact(&mut version)?;
+        if u16::from_le_bytes(version) != CAPTURE_VERSION {
+            return Err(invalid_data("Unsupported OSC capture file version"));
+        }
+
+        Ok(Self { reader })
+    }
+
+    /// The next record, or None at the end of the file
+    pub fn next_packet(&mut self) -> io::Result<Option<CapturedPacket>> {
+        let mut time = [0; 8];
+        match self.reader.read_exact(&mut time) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let arrival_time = micros_to_date_time(i64::from_le_bytes(time))?;
+
+        let mut kind = [0; 1];
+        self.reader.read_exact(&mut kind)?;
+        let ip = match kind[0] {
+            4 => {
+                let mut octets = [0; 4];
+                self.reader.read_exact(&mut octets)?;
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            6 => {
+                let mut octets = [0; 16];
+                self.reader.read_exact(&mut octets)?;
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return Err(invalid_data("Unknown address kind in capture")),
+        };
+        let mut port = [0; 2];
+        self.reader.read_exact(&mut port)?;
+        let addr = SocketAddr::new(ip, u16::from_le_bytes(port));
+
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_PACKET_LENGTH {
+            return Err(invalid_data("Packet length in capture is too long"));
+        }
+        let mut packet_bytes = vec![0; len];
+        self.reader.read_exact(&mut packet_bytes)?;
+        let packet = decode_packet(&packet_bytes)?;
+
+        Ok(Some(CapturedPacket {
+            arrival_time,
+            addr,
+            packet,
+        }))
+    }
+}
+
+/// Live OSC receiver which also writes every packet to a capture file
+pub struct RecordingMessageReceiver {
+    sockets: OscSockets,
+    capture: RefCell<Option<CaptureWriter<BufWriter<File>>>>, // None after a write error, so the session continues unrecorded
+}
+
+impl RecordingMessageReceiver {
+    pub fn new(listen: &[SocketAddrV4], path: &Path) -> io::Result<Self> {
+        info!("Connecting to EEG and recording to {:?}", path);
+        let sockets = OscSockets::bind(listen)?;
+        let capture = CaptureWriter::new(BufWriter::new(File::create(path)?))?;
+
+        Ok(Self {
+            sockets,
+            capture: RefCell::new(Some(capture)),
+        })
+    }
+
+    fn record(&self, captured: &CapturedPacket) {
+        let mut capture = self.capture.borrow_mut();
+        let failed = match capture.as_mut().map(|writer| writer.write(captured)) {
+            Some(Err(ref e)) if e.kind() == ErrorKind::InvalidInput => {
+                warn!("OSC packet from {} not recorded: {}", captured.addr, e);
+                false
+            }
+            Some(Err(_)) => true,
+            _ => false,
+        };
+
+        if failed {
+            error!("Can not write OSC capture file, recording stopped");
+            *capture = None;
+        }
+    }
+}
+
+impl EegMessageReceiver for RecordingMessageReceiver {
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        let receivables: Vec<(Packet, SocketAddr)> = self.sockets.try_recv_all();
+        let mut muse_messages = Vec::new();
+
+        for (packet, addr) in receivables {
+            let captured = CapturedPacket {
+                arrival_time: Local::now(),
+                addr,
+                packet,
+            };
+            self.record(&captured);
+            muse_messages.append(&mut parse_muse_packet_at(
+                captured.addr,
+                &captured.packet,
+                captured.arrival_time,
+            ));
+        }
+
+        if let Some(writer) = self.capture.borrow_mut().as_mut() {
+            if writer.flush().is_err() {
+                error!("Can not flush OSC capture file");
+            }
+        }
+
+        muse_messages
+    }
+}
+
+/// Feed a capture file back through the parser with the original packet timing, optionally speeded up
+pub struct ReplayMessageReceiver {
+    reader: RefCell<CaptureReader<BufReader<File>>>,
+    next: RefCell<Option<CapturedPacket>>,
+    first_arrival_time: Option<DateTime<Local>>,
+    replay_start: Instant,
+    speed: f64, // 1.0 is real time, 10.0 replays ten times faster
+}
+
+impl ReplayMessageReceiver {
+    pub fn new(path: &Path, speed: f64) -> io::Result<Self> {
+        info!("Replaying EEG from {:?} at {}x", path, speed);
+        let mut reader = CaptureReader::new(BufReader::new(File::open(path)?))?;
+        let next = reader.next_packet()?;
+        let first_arrival_time = next.as_ref().map(|captured| captured.arrival_time);
+
+        Ok(Self {
+            reader: RefCell::new(reader),
+            next: RefCell::new(next),
+            first_arrival_time,
+            replay_start: Instant::now(),
+            speed: speed.max(std::f64::MIN_POSITIVE),
+        })
+    }
+
+    /// All packets in the capture have been delivered
+    pub fn is_finished(&self) -> bool {
+        self.next.borrow().is_none()
+    }
+
+    /// Packets which were captured up to this many microseconds after the first, on the original clock
+    fn replay_until(&self, capture_elapsed_micros: i64) -> Vec<CapturedPacket> {
+        let first = match self.first_arrival_time {
+            Some(first) => date_time_to_micros(first),
+            None => return Vec::new(),
+        };
+        let mut due = Vec::new();
+        let mut next = self.next.borrow_mut();
+
+        while let Some(captured) = next.take() {
+            if date_time_to_micros(captured.arrival_time) - first > capture_elapsed_micros {
+                *next = Some(captured);
+                break;
+            }
+            due.push(captured);
+            *next = match self.reader.borrow_mut().next_packet() {
+                Ok(n) => n,
+                Err(e) => {
+                    error!("Can not read OSC capture, replay ended: {}", e);
+                    None
+                }
+            };
+        }
+
+        due
+    }
+}
+
+impl EegMessageReceiver for ReplayMessageReceiver {
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        let real_elapsed_micros = self.replay_start.elapsed().as_micros() as f64;
+        let capture_elapsed_micros = (real_elapsed_micros * self.speed) as i64;
+        let mut muse_messages = Vec::new();
+
+        let due = self.replay_until(capture_elapsed_micros);
+        if !due.is_empty() && self.is_finished() {
+            info!("OSC capture replay finished");
+        }
+
+        for captured in due {
+            muse_messages.append(&mut parse_muse_packet_at(
+                captured.addr,
+                &captured.packet,
+                captured.arrival_time,
+            ));
+        }
+
+        muse_messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blink_message() -> Message {
+        Message {
+            addr: "/muse/elements/blink".to_string(),
+            args: Some(vec![Type::Int(1)]),
+        }
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let packet = Packet::Bundle(Bundle {
+            timetag: Type::Time(3_791_604_949, 12345),
+            content: vec![
+                Packet::Message(blink_message()).into(),
+                Packet::Message(Message {
+                    addr: "/muse/annotation".to_string(),
+                    args: Some(vec![
+                        Type::String("start".to_string()),
+                        Type::Float(1.5),
+                        Type::Double(-2.0),
+                        Type::Long(1 << 40),
+                        Type::Bool(true),
+                        Type::Nil,
+                    ]),
+                })
+                .into(),
+                Packet::Message(Message {
+                    addr: "/Marker/1".to_string(),
+                    args: None,
+                })
+                .into(),
+            ],
+        });
+        let mut bytes = Vec::new();
+        encode_packet(&packet, &mut bytes).unwrap();
+
+        assert_eq!(packet, decode_packet(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_truncated_packet_is_an_error() {
+        let mut bytes = Vec::new();
+        encode_packet(&Packet::Message(blink_message()), &mut bytes).unwrap();
+        bytes.pop();
+
+        assert!(decode_packet(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_capture_file_round_trip() {
+        let arrival_time = Local.timestamp(1_582_616_149, 123_456_000);
+        let captured = vec![
+            CapturedPacket {
+                arrival_time,
+                addr: "192.168.1.20:5000".parse().unwrap(),
+                packet: Packet::Message(blink_message()),
+            },
+            CapturedPacket {
+                arrival_time: arrival_time + chrono::Duration::milliseconds(4),
+                addr: "[::1]:5000".parse().unwrap(),
+                packet: Packet::Message(blink_message()),
+            },
+        ];
+
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        for c in &captured {
+            writer.write(c).unwrap();
+        }
+        let bytes = writer.writer;
+
+        let mut reader = CaptureReader::new(&bytes[..]).unwrap();
+        assert_eq!(Some(captured[0].clone()), reader.next_packet().unwrap());
+        assert_eq!(Some(captured[1].clone()), reader.next_packet().unwrap());
+        assert_eq!(None, reader.next_packet().unwrap());
+    }
+
+    #[test]
+    fn test_lengths_are_bounded() {
+        let too_many_args = Packet::Message(Message {
+            addr: "/muse/aux".to_string(),
+            args: Some(vec![Type::Nil; 70_000]),
+        });
+        assert!(encode_packet(&too_many_args, &mut Vec::new()).is_err());
+
+        let mut bytes = CAPTURE_MAGIC.to_vec();
+        bytes.extend_from_slice(&CAPTURE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0i64.to_le_bytes());
+        bytes.extend_from_slice(&[4, 127, 0, 0, 1, 0, 0]);
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = CaptureReader::new(&bytes[..]).unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            reader.next_packet().unwrap_err().kind()
+        );
+
+        let mut bytes = CAPTURE_MAGIC.to_vec();
+        bytes.extend_from_slice(&CAPTURE_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&i64::MAX.to_le_bytes());
+        let mut reader = CaptureReader::new(&bytes[..]).unwrap();
+        assert_eq!(
+            ErrorKind::InvalidData,
+            reader.next_packet().unwrap_err().kind()
+        );
+    }
+
+    #[test]
+    fn test_reject_other_files() {
+        let bytes = b"Time,TP9,AF7,AF8,TP10\n";
+
+        assert!(CaptureReader::new(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_replay_respects_capture_timing() {
+        let path = std::env::temp_dir().join("meme_test_replay_timing.osc");
+        let start = Local.timestamp(1_582_616_149, 0);
+        {
+            let mut writer = CaptureWriter::new(File::create(&path).unwrap()).unwrap();
+            for i in 0..3 {
+                writer
+                    .write(&CapturedPacket {
+                        arrival_time: start + chrono::Duration::seconds(i),
+                        addr: "127.0.0.1:5000".parse().unwrap(),
+                        packet: Packet::Message(blink_message()),
+                    })
+                    .unwrap();
+            }
+        }
+
+        let replay = ReplayMessageReceiver::new(&path, 1.0).unwrap();
+        assert_eq!(1, replay.replay_until(500_000).len());
+        assert_eq!(1, replay.replay_until(1_500_000).len());
+        assert!(!replay.is_finished());
+        assert_eq!(1, replay.replay_until(10_000_000).len());
+        assert!(replay.is_finished());
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
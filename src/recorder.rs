@@ -0,0 +1,762 @@
+/// Session CSV files, all written on one background thread.
+///
+/// Rows are formatted where the values are and sent to the recorder thread, so file I/O never holds
+/// up drawing. Each kind of headset message is routed by MessageLog to a Sink: a CSV file of its
+/// own, a row in the headset's other.csv, or nowhere. A file which can not be created or written
+/// is reported once through Recorder::take_errors() and its later rows are counted and dropped, so
+/// a full disk does not stop the session. Recorder::flush() writes everything so far to disk, and
+/// dropping the Recorder flushes and closes every file before the thread ends. Every file goes in
+/// one directory, and Recorder::files() lists them with their row counts and checksums.
+/// The web build has no threads or filesystem, so there every row is dropped as it is sent.
+use crate::muse_model::{date_time_csv_format, MuseMessageType};
+use chrono::{DateTime, Local};
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// Each kind of headset message and where it is recorded unless configured otherwise
+const DEFAULT_SINKS: [(&str, Sink); 26] = [
+    ("eeg", Sink::Csv),
+    ("accelerometer", Sink::Off), // Already in motion.csv
+    ("gyro", Sink::Off),          // Already in motion.csv
+    ("alpha", Sink::Csv),
+    ("beta", Sink::Csv),
+    ("gamma", Sink::Csv),
+    ("delta", Sink::Csv),
+    ("theta", Sink::Csv),
+    ("battery", Sink::Other),
+    ("horseshoe", Sink::Other),
+    ("touching_forehead", Sink::Other),
+    ("blink", Sink::Other),
+    ("jaw_clench", Sink::Other),
+    ("relative", Sink::Csv),
+    ("session_score", Sink::Other),
+    ("low_frequencies", Sink::Other),
+    ("raw_fft", Sink::Csv),
+    ("is_good", Sink::Other),
+    ("hsi_precision", Sink::Other),
+    ("ppg", Sink::Csv),
+    ("drl_ref", Sink::Other),
+    ("aux", Sink::Other),
+    ("concentration", Sink::Other),
+    ("mellow", Sink::Other),
+    ("annotation", Sink::Other),
+    ("marker", Sink::Other),
+];
+
+const ELECTRODES: [&str; 4] = ["TP9", "AF7", "AF8", "TP10"];
+
+/// Where one kind of headset message is recorded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sink {
+    /// A CSV file of its own named for the message kind, for example "P1 alpha.csv"
+    Csv,
+    /// One row in the headset's other.csv
+    Other,
+    /// Not recorded
+    Off,
+}
+
+impl Sink {
+    fn parse(name: &str) -> Result<Self, String> {
+        match name {
+            "csv" => Ok(Sink::Csv),
+            "other" => Ok(Sink::Other),
+            "off" => Ok(Sink::Off),
+            other => Err(format!(
+                "unknown sink '{}', expected csv, other or off",
+                other
+            )),
+        }
+    }
+}
+
+/// The sink of every kind of headset message
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecorderSettings {
+    sinks: HashMap<&'static str, Sink>,
+}
+
+impl Default for RecorderSettings {
+    fn default() -> Self {
+        RecorderSettings {
+            sinks: DEFAULT_SINKS.iter().cloned().collect(),
+        }
+    }
+}
+
+impl RecorderSettings {
+    /// Change the sink of one kind of message, "raw_fft:off"
+    pub fn set(&mut self, setting: &str) -> Result<(), String> {
+        let mut parts = setting.splitn(2, ':');
+        let kind = parts.next().unwrap_or("").trim();
+        let sink = match parts.next() {
+            Some(sink) => Sink::parse(sink.trim())?,
+            None => return Err(format!("'{}' should be kind:sink", setting)),
+        };
+        let kind = DEFAULT_SINKS
+            .iter()
+            .map(|(kind, _)| *kind)
+            .find(|known| *known == kind)
+            .ok_or_else(|| format!("unknown message kind '{}'", kind))?;
+        self.sinks.insert(kind, sink);
+
+        Ok(())
+    }
+
+    pub fn sink(&self, kind: &str) -> Sink {
+        self.sinks.get(kind).cloned().unwrap_or(Sink::Other)
+    }
+}
+
+enum Command {
+    Create {
+        filename: Arc<str>,
+        header: Vec<String>,
+    },
+    Write {
+        filename: Arc<str>,
+        row: Vec<String>,
+        is_header: bool,
+    },
+    Flush(Sender<Result<(), String>>),
+    Files(Sender<Vec<RecordedFile>>),
+    Close,
+}
+
+/// One file as it is listed in the session manifest
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedFile {
+    /// File name within the session directory
+    pub name: String,
+    /// Not counting header rows. For an EDF+ file, its one second data records.
+    pub rows: u64,
+    /// Hex SHA-256 of the file as written, None if it could not be read
+    pub sha256: Option<String>,
+}
+
+/// The recorder thread. Owned by MuseModel, which hands a RecorderHandle to each headset.
+pub struct Recorder {
+    directory: PathBuf,
+    handle: RecorderHandle,
+    errors: Receiver<String>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Recorder {
+    /// Every file is written in this directory, which is created if need be
+    pub fn start(directory: &Path) -> Self {
+        let (sender, commands) = mpsc::channel();
+        let (error_sender, errors) = mpsc::channel();
+        let mut files = CsvFiles {
+            directory: directory.to_path_buf(),
+            files: BTreeMap::new(),
+            errors: error_sender,
+        };
+        let run = move || {
+            if let Err(e) = fs::create_dir_all(&files.directory) {
+                let _ = files.errors.send(format!(
+                    "Can not create {}: {}",
+                    files.directory.display(),
+                    e
+                ));
+            }
+            for command in commands.iter() {
+                match command {
+                    Command::Create { filename, header } => files.create(filename, header),
+                    Command::Write {
+                        filename,
+                        row,
+                        is_header,
+                    } => files.write(filename, row, is_header),
+                    Command::Flush(reply) => {
+                        let _ = reply.send(files.flush());
+                    }
+                    Command::Files(reply) => {
+                        let _ = files.flush();
+                        let _ = reply.send(files.recorded());
+                    }
+                    Command::Close => break,
+                }
+            }
+            if let Err(e) = files.flush() {
+                error!("{}", e);
+            }
+        };
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        let thread = Some(std::thread::spawn(run));
+        // Dropping `run` closes the channel, so rows are dropped as they are sent
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        let thread = {
+            drop(run);
+            None
+        };
+
+        Recorder {
+            directory: directory.to_path_buf(),
+            handle: RecorderHandle { sender },
+            errors,
+            thread,
+        }
+    }
+
+    /// Where the files are written
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn handle(&self) -> RecorderHandle {
+        self.handle.clone()
+    }
+
+    /// Write every row sent so far to disk. The error lists files which have lost rows.
+    pub fn flush(&self) -> Result<(), String> {
+        let (reply, result) = mpsc::channel();
+        self.handle
+            .sender
+            .send(Command::Flush(reply))
+            .map_err(|_| "Recorder has stopped".to_string())?;
+
+        result
+            .recv()
+            .unwrap_or_else(|_| Err("Recorder has stopped".to_string()))
+    }
+
+    /// Flush, then list every file created so far in name order
+    pub fn files(&self) -> Result<Vec<RecordedFile>, String> {
+        let (reply, result) = mpsc::channel();
+        self.handle
+            .sender
+            .send(Command::Files(reply))
+            .map_err(|_| "Recorder has stopped".to_string())?;
+
+        result
+            .recv()
+            .map_err(|_| "Recorder has stopped".to_string())
+    }
+
+    /// Files which could not be created or written since the previous call
+    pub fn take_errors(&self) -> Vec<String> {
+        self.errors.try_iter().collect()
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let _ = self.handle.sender.send(Command::Close);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("Recorder thread panicked, some rows may be lost");
+            }
+        }
+    }
+}
+
+/// Creates files on the recorder thread
+#[derive(Clone)]
+pub struct RecorderHandle {
+    sender: Sender<Command>,
+}
+
+impl RecorderHandle {
+    /// A new CSV file in the recorder's directory. The header is written first unless it is empty.
+    pub fn create(&self, filename: &str, header: &[&str]) -> CsvFile {
+        let filename: Arc<str> = filename.into();
+        let header = header.iter().map(|column| column.to_string()).collect();
+        self.send(Command::Create {
+            filename: filename.clone(),
+            header,
+        });
+
+        CsvFile {
+            filename,
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Rows sent after the recorder stops are dropped, it has already reported why
+    fn send(&self, command: Command) {
+        let _ = self.sender.send(command);
+    }
+}
+
+/// One file on the recorder thread
+#[derive(Clone)]
+pub struct CsvFile {
+    filename: Arc<str>,
+    sender: Sender<Command>,
+}
+
+impl CsvFile {
+    /// One row starting with the time
+    pub fn write(&self, time: DateTime<Local>, values: Vec<String>) {
+        let mut row = Vec::with_capacity(values.len() + 1);
+        row.push(date_time_csv_format(time));
+        row.extend(values);
+        self.send(row, false);
+    }
+
+    /// A header row, for files whose columns change
+    pub fn write_header(&self, header: Vec<String>) {
+        self.send(header, true);
+    }
+
+    fn send(&self, row: Vec<String>, is_header: bool) {
+        let _ = self.sender.send(Command::Write {
+            filename: self.filename.clone(),
+            row,
+            is_header,
+        });
+    }
+}
+
+/// A file on the recorder thread
+struct OpenFile {
+    writer: Option<Writer<File>>, // None once it has failed
+    rows: u64,
+    dropped_rows: u64,
+}
+
+/// Every file on the recorder thread, by name
+struct CsvFiles {
+    directory: PathBuf,
+    files: BTreeMap<Arc<str>, OpenFile>,
+    errors: Sender<String>,
+}
+
+impl CsvFiles {
+    fn create(&mut self, filename: Arc<str>, header: Vec<String>) {
+        let created = Writer::from_path(self.directory.join(&*filename)).and_then(|mut writer| {
+            if !header.is_empty() {
+                writer.write_record(&header)?;
+            }
+            Ok(writer)
+        });
+        let writer = match created {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                let _ = self
+                    .errors
+                    .send(format!("Can not create {}: {}", filename, e));
+                None
+            }
+        };
+        self.files.insert(
+            filename,
+            OpenFile {
+                writer,
+                rows: 0,
+                dropped_rows: 0,
+            },
+        );
+    }
+
+    fn write(&mut self, filename: Arc<str>, row: Vec<String>, is_header: bool) {
+        let file = match self.files.get_mut(&filename) {
+            Some(file) => file,
+            None => return,
+        };
+        let result = match &mut file.writer {
+            Some(writer) => writer.write_record(&row),
+            None => {
+                file.dropped_rows += 1;
+                return;
+            }
+        };
+        match result {
+            Ok(()) if is_header => (),
+            Ok(()) => file.rows += 1,
+            Err(e) => {
+                file.writer = None;
+                file.dropped_rows += 1;
+                let _ = self
+                    .errors
+                    .send(format!("Can not write {}: {}", filename, e));
+            }
+        }
+    }
+
+    /// The error lists files which have failed
+    fn flush(&mut self) -> Result<(), String> {
+        let mut lost = Vec::new();
+        for (filename, file) in self.files.iter_mut() {
+            if let Some(writer) = &mut file.writer {
+                if let Err(e) = writer.flush() {
+                    file.writer = None;
+                    let _ = self
+                        .errors
+                        .send(format!("Can not flush {}: {}", filename, e));
+                }
+            }
+            if file.writer.is_none() {
+                lost.push(format!("{} ({} rows)", filename, file.dropped_rows));
+            }
+        }
+        if lost.is_empty() {
+            return Ok(());
+        }
+
+        Err(format!("Rows not written to {}", lost.join(", ")))
+    }
+
+    fn recorded(&self) -> Vec<RecordedFile> {
+        self.files
+            .iter()
+            .map(|(filename, file)| RecordedFile {
+                name: filename.to_string(),
+                rows: file.rows,
+                sha256: sha256(&self.directory.join(&**filename)).ok(),
+            })
+            .collect()
+    }
+}
+
+/// Hex SHA-256 of a file, as listed in session.json
+pub fn sha256(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 65536];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.input(&buffer[..count]);
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Records every message from one headset according to RecorderSettings
+pub struct MessageLog {
+    recorder: RecorderHandle,
+    settings: RecorderSettings,
+    file_prefix: String,
+    files: HashMap<&'static str, CsvFile>, // Created when the first message of each kind arrives
+    other: CsvFile,
+}
+
+impl MessageLog {
+    /// Own files are named "{file_prefix} {kind}.csv", other rows go to `other`
+    pub fn new(
+        recorder: RecorderHandle,
+        settings: RecorderSettings,
+        file_prefix: &str,
+        other: CsvFile,
+    ) -> Self {
+        MessageLog {
+            recorder,
+            settings,
+            file_prefix: file_prefix.to_string(),
+            files: HashMap::new(),
+            other,
+        }
+    }
+
+    pub fn record(&mut self, time: DateTime<Local>, muse_message_type: &MuseMessageType) {
+        let record = message_record(muse_message_type);
+        match self.settings.sink(record.kind) {
+            Sink::Csv => {
+                if !self.files.contains_key(record.kind) {
+                    let mut header = vec!["Time".to_string()];
+                    header.extend(record.columns);
+                    let header: Vec<&str> = header.iter().map(String::as_str).collect();
+                    let filename = format!("{} {}.csv", self.file_prefix, record.kind);
+                    let file = self.recorder.create(&filename, &header);
+                    self.files.insert(record.kind, file);
+                }
+                self.files[record.kind].write(time, record.values);
+            }
+            Sink::Other => {
+                let mut fields = vec![record.label.to_string()];
+                fields.extend(record.values);
+                self.other.write(time, vec![fields.join(", ")]);
+            }
+            Sink::Off => (),
+        }
+    }
+}
+
+/// Values as "1.0", the same as other.csv
+pub fn format_values(values: &[f32]) -> Vec<String> {
+    values.iter().map(|value| format!("{:?}", value)).collect()
+}
+
+/// A message as recorded
+struct MessageRecord {
+    kind: &'static str,   // Routing key and CSV file name
+    label: &'static str,  // First field of the other.csv record
+    columns: Vec<String>, // CSV header after the time
+    values: Vec<String>,
+}
+
+fn message_record(muse_message_type: &MuseMessageType) -> MessageRecord {
+    let electrodes = |prefix: &str| -> Vec<String> {
+        ELECTRODES
+            .iter()
+            .map(|electrode| format!("{}{}", prefix, electrode))
+            .collect()
+    };
+    let named = |names: &[&str]| -> Vec<String> { names.iter().map(|n| n.to_string()).collect() };
+    let flag = |flag: bool| (flag as i32).to_string();
+    let record = |kind, label, columns, values| MessageRecord {
+        kind,
+        label,
+        columns,
+        values,
+    };
+
+    match muse_message_type {
+        MuseMessageType::Eeg { eeg } => record("eeg", "Eeg", electrodes(""), format_values(eeg)),
+        MuseMessageType::Accelerometer { x, y, z } => record(
+            "accelerometer",
+            "Accelerometer",
+            named(&["X", "Y", "Z"]),
+            format_values(&[*x, *y, *z]),
+        ),
+        MuseMessageType::Gyro { x, y, z } => record(
+            "gyro",
+            "Gyro",
+            named(&["X", "Y", "Z"]),
+            format_values(&[*x, *y, *z]),
+        ),
+        MuseMessageType::Alpha { alpha } => {
+            record("alpha", "Alpha", electrodes("Alpha "), format_values(alpha))
+        }
+        MuseMessageType::Beta { beta } => {
+            record("beta", "Beta", electrodes("Beta "), format_values(beta))
+        }
+        MuseMessageType::Gamma { gamma } => {
+            record("gamma", "Gamma", electrodes("Gamma "), format_values(gamma))
+        }
+        MuseMessageType::Delta { a, b, c, d } => record(
+            "delta",
+            "Delta",
+            electrodes("Delta "),
+            format_values(&[*a, *b, *c, *d]),
+        ),
+        MuseMessageType::Theta { a, b, c, d } => record(
+            "theta",
+            "Theta",
+            electrodes("Theta "),
+            format_values(&[*a, *b, *c, *d]),
+        ),
+        MuseMessageType::Batt { batt } => record(
+            "battery",
+            "Battery",
+            named(&["Battery"]),
+            vec![batt.to_string()],
+        ),
+        MuseMessageType::Horseshoe { a, b, c, d } => record(
+            "horseshoe",
+            "Horseshoe",
+            electrodes(""),
+            format_values(&[*a, *b, *c, *d]),
+        ),
+        MuseMessageType::TouchingForehead { touch } => record(
+            "touching_forehead",
+            "TouchingForehead",
+            named(&["Touching"]),
+            vec![flag(*touch)],
+        ),
+        MuseMessageType::Blink { blink } => {
+            record("blink", "Blink", named(&["Blink"]), vec![flag(*blink)])
+        }
+        MuseMessageType::JawClench { clench } => record(
+            "jaw_clench",
+            "Clench",
+            named(&["Clench"]),
+            vec![flag(*clench)],
+        ),
+        MuseMessageType::Relative { band, values }
+        | MuseMessageType::SessionScore { band, values } => {
+            let (kind, label) = match muse_message_type {
+                MuseMessageType::Relative { .. } => ("relative", "Relative"),
+                _ => ("session_score", "SessionScore"),
+            };
+            let mut columns = named(&["Band"]);
+            columns.extend(electrodes(""));
+            let mut row = vec![band.label().to_string()];
+            row.extend(format_values(values));
+            record(kind, label, columns, row)
+        }
+        MuseMessageType::LowFrequencies { low_freqs } => record(
+            "low_frequencies",
+            "LowFreqs",
+            electrodes(""),
+            format_values(low_freqs),
+        ),
+        MuseMessageType::RawFft { channel, fft } => {
+            let mut columns = named(&["Channel"]);
+            columns.extend((0..fft.len()).map(|bin| format!("Bin {}", bin)));
+            let mut row = vec![channel.to_string()];
+            row.extend(format_values(fft));
+            record("raw_fft", "RawFft", columns, row)
+        }
+        MuseMessageType::IsGood { is_good } => record(
+            "is_good",
+            "IsGood",
+            electrodes(""),
+            is_good.iter().map(|good| flag(*good)).collect(),
+        ),
+        MuseMessageType::HsiPrecision { hsi } => record(
+            "hsi_precision",
+            "HsiPrecision",
+            electrodes(""),
+            format_values(hsi),
+        ),
+        MuseMessageType::Ppg { ppg } => record(
+            "ppg",
+            "Ppg",
+            named(&["PPG1", "PPG2", "PPG3"]),
+            format_values(ppg),
+        ),
+        MuseMessageType::DrlRef { drl, reference } => record(
+            "drl_ref",
+            "DrlRef",
+            named(&["DRL", "Reference"]),
+            format_values(&[*drl, *reference]),
+        ),
+        MuseMessageType::Aux { aux } => record(
+            "aux",
+            "Aux",
+            (1..=aux.len()).map(|i| format!("Aux {}", i)).collect(),
+            format_values(aux),
+        ),
+        MuseMessageType::Concentration { concentration } => record(
+            "concentration",
+            "Concentration",
+            named(&["Concentration"]),
+            format_values(&[*concentration]),
+        ),
+        MuseMessageType::Mellow { mellow } => record(
+            "mellow",
+            "Mellow",
+            named(&["Mellow"]),
+            format_values(&[*mellow]),
+        ),
+        MuseMessageType::Annotation { annotation } => record(
+            "annotation",
+            "Annotation",
+            named(&["Annotation"]),
+            vec![annotation.clone()],
+        ),
+        MuseMessageType::Marker { marker } => record(
+            "marker",
+            "Marker",
+            named(&["Marker"]),
+            vec![marker.to_string()],
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_model::EegBand;
+    use chrono::TimeZone;
+    use std::fs;
+
+    fn at(ms: i64) -> DateTime<Local> {
+        Local.timestamp_millis(1_582_616_149_000 + ms)
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut settings = RecorderSettings::default();
+        assert_eq!(Sink::Csv, settings.sink("raw_fft"));
+        assert_eq!(Sink::Off, settings.sink("gyro"));
+
+        settings.set("raw_fft:off").unwrap();
+        settings.set(" gyro : csv ").unwrap();
+        assert_eq!(Sink::Off, settings.sink("raw_fft"));
+        assert_eq!(Sink::Csv, settings.sink("gyro"));
+
+        assert!(settings.set("raw_fft").is_err());
+        assert!(settings.set("raw_fft:json").is_err());
+        assert!(settings.set("pulse:csv").is_err());
+    }
+
+    #[test]
+    fn test_every_kind_has_a_default_sink() {
+        let settings = RecorderSettings::default();
+        let messages = [
+            MuseMessageType::Batt { batt: 80 },
+            MuseMessageType::SessionScore {
+                band: EegBand::Alpha,
+                values: [0.5; 4],
+            },
+            MuseMessageType::Aux { aux: vec![1.0] },
+        ];
+        for message in messages.iter() {
+            let kind = message_record(message).kind;
+            assert!(settings.sinks.contains_key(kind), "{}", kind);
+        }
+        assert_eq!(DEFAULT_SINKS.len(), settings.sinks.len());
+    }
+
+    #[test]
+    fn test_messages_routed_and_flushed() {
+        let directory = std::env::temp_dir().join("meme recorder test");
+        let recorder = Recorder::start(&directory);
+        let handle = recorder.handle();
+        let other = handle.create("P1 other.csv", &["Time", "Record"]);
+        let mut settings = RecorderSettings::default();
+        settings.set("ppg:off").unwrap();
+        let mut log = MessageLog::new(handle, settings, "P1", other);
+
+        log.record(at(0), &MuseMessageType::Alpha { alpha: [1.0; 4] });
+        log.record(at(4), &MuseMessageType::Alpha { alpha: [2.5; 4] });
+        log.record(at(8), &MuseMessageType::Blink { blink: true });
+        log.record(at(8), &MuseMessageType::Ppg { ppg: [1.0; 3] });
+        assert_eq!(Ok(()), recorder.flush());
+
+        let alpha = fs::read_to_string(directory.join("P1 alpha.csv")).unwrap();
+        let lines: Vec<&str> = alpha.lines().collect();
+        assert_eq!("Time,Alpha TP9,Alpha AF7,Alpha AF8,Alpha TP10", lines[0]);
+        assert_eq!(3, lines.len());
+        assert!(lines[2].ends_with(",2.5,2.5,2.5,2.5"));
+        let other = fs::read_to_string(directory.join("P1 other.csv")).unwrap();
+        assert!(other.lines().last().unwrap().ends_with(",\"Blink, 1\""));
+        assert!(!directory.join("P1 ppg.csv").exists());
+        assert!(recorder.take_errors().is_empty());
+
+        let files = recorder.files().unwrap();
+        assert_eq!(vec!["P1 alpha.csv", "P1 other.csv"], names(&files));
+        assert_eq!(2, files[0].rows);
+        assert_eq!(64, files[0].sha256.as_ref().unwrap().len());
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    fn names(files: &[RecordedFile]) -> Vec<&str> {
+        files.iter().map(|file| file.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_io_errors_reported() {
+        let not_a_directory = std::env::temp_dir().join("meme recorder not a directory");
+        fs::write(&not_a_directory, "").unwrap();
+        let recorder = Recorder::start(&not_a_directory.join("session"));
+        let file = recorder.handle().create("other.csv", &["Time", "Record"]);
+        file.write(at(0), vec!["Lost".to_string()]);
+        file.write(at(1), vec!["Lost".to_string()]);
+
+        let flushed = recorder.flush();
+        assert_eq!(
+            Err("Rows not written to other.csv (2 rows)".to_string()),
+            flushed
+        );
+        assert_eq!(2, recorder.take_errors().len()); // The directory and the file
+        assert_eq!(None, recorder.files().unwrap()[0].sha256);
+
+        fs::remove_file(not_a_directory).unwrap();
+    }
+}
@@ -0,0 +1,87 @@
+/// Re-broadcast everything the app receives, plus valence and arousal, as JSON over WebSocket so
+/// browsers, the web build and dashboards can share one headset. See wire_format for the frames.
+use crate::muse_model::{MuseMessage, MuseMessageSink};
+use crate::wire_format::WireMessage;
+use chrono::{DateTime, Local};
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const RELAY_QUEUE_SIZE: usize = 4096; // Frames waiting to be sent, EEG arrives in bursts of several per screen frame
+const FAILURE_LOG_INTERVAL: usize = 1000; // Frames which can not be sent are logged once, then counted
+
+/// A WebSocket server on its own thread. Clients only listen, anything they send is ignored.
+#[derive(Clone)]
+pub struct WebSocketRelay {
+    broadcaster: ws::Sender,
+    failures: Arc<AtomicUsize>, // Frames which could not be sent, shared by clones
+}
+
+impl WebSocketRelay {
+    /// Bind now so a port conflict is reported at startup, then serve clients in the background
+    pub fn start(port: u16) -> io::Result<Self> {
+        let socket = ws::Builder::new()
+            .with_settings(ws::Settings {
+                queue_size: RELAY_QUEUE_SIZE,
+                ..ws::Settings::default()
+            })
+            .build(|_out: ws::Sender| ListenOnly)
+            .map_err(in_use)?
+            .bind(("0.0.0.0", port))
+            .map_err(in_use)?;
+        let broadcaster = socket.broadcaster();
+
+        info!("Relaying EEG over WebSocket on port {}", port);
+        thread::spawn(move || {
+            if let Err(e) = socket.run() {
+                error!("WebSocket relay stopped: {}", e);
+            }
+        });
+
+        Ok(Self {
+            broadcaster,
+            failures: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    fn broadcast(&self, wire_message: &WireMessage) {
+        if let Err(e) = self.broadcaster.broadcast(wire_message.encode()) {
+            let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+            if failures % FAILURE_LOG_INTERVAL == 1 {
+                warn!("Can not relay message, {} dropped so far: {}", failures, e);
+            }
+        }
+    }
+}
+
+/// Most likely another app already relays on the port
+fn in_use(e: ws::Error) -> io::Error {
+    io::Error::new(ErrorKind::AddrInUse, e)
+}
+
+/// A relay client, whose messages are ignored by the default handler
+struct ListenOnly;
+
+impl ws::Handler for ListenOnly {}
+
+impl MuseMessageSink for WebSocketRelay {
+    fn send_message(&self, muse_message: &MuseMessage) {
+        self.broadcast(&WireMessage::Muse(muse_message.clone()));
+    }
+
+    fn send_affect(
+        &self,
+        participant: usize,
+        time: DateTime<Local>,
+        valence: Option<f32>,
+        arousal: Option<f32>,
+    ) {
+        self.broadcast(&WireMessage::Affect {
+            participant,
+            time,
+            valence,
+            arousal,
+        });
+    }
+}
@@ -0,0 +1,203 @@
+/// Streaming statistics for NormalizedValue. Each new value updates the mean and variance in
+/// constant time, however long the window, using Welford's algorithm extended to remove the value
+/// leaving the window.
+use num_traits::{cast::NumCast, float::Float};
+use std::collections::VecDeque;
+
+const MAD_TO_DEVIATION: f64 = 1.4826; // Scales median absolute deviation to standard deviation for normally distributed values
+
+/// Mean and variance of the most recent values in a ring buffer
+pub struct WindowedStats<T: Float + From<i16>> {
+    window: Option<usize>, // None keeps every value
+    values: VecDeque<T>,   // Oldest first
+    mean: T,
+    m2: T, // Sum of squared differences from the mean
+}
+
+impl<T> WindowedStats<T>
+where
+    T: Float + From<i16>,
+{
+    pub fn new(window: Option<usize>) -> Self {
+        WindowedStats {
+            window,
+            values: VecDeque::with_capacity(window.unwrap_or(0)),
+            mean: 0.into(),
+            m2: 0.into(),
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        if self.window == Some(self.values.len()) {
+            if let Some(oldest) = self.values.pop_front() {
+                self.remove(oldest);
+            }
+        }
+
+        self.values.push_back(value);
+        let delta = value - self.mean;
+        self.mean = self.mean + delta / count(self.values.len());
+        self.m2 = self.m2 + delta * (value - self.mean);
+    }
+
+    /// Undo the update of a value already popped from the buffer
+    fn remove(&mut self, value: T) {
+        if self.is_empty() {
+            self.clear();
+            return;
+        }
+
+        let previous_mean = self.mean;
+        self.mean = previous_mean - (value - previous_mean) / count(self.values.len());
+        self.m2 = (self.m2 - (value - previous_mean) * (value - self.mean)).max(0.into());
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+        self.mean = 0.into();
+        self.m2 = 0.into();
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn mean(&self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.mean)
+        }
+    }
+
+    /// Population standard deviation
+    pub fn deviation(&self) -> Option<T> {
+        if self.is_empty() {
+            None
+        } else {
+            Some((self.m2 / count(self.values.len())).sqrt())
+        }
+    }
+
+    /// Sorts a copy of the window, so O(n log n)
+    pub fn median(&self) -> Option<T> {
+        let values: Vec<T> = self.values.iter().cloned().collect();
+
+        median(values)
+    }
+
+    /// Median absolute deviation scaled to be comparable with the standard deviation
+    pub fn median_absolute_deviation(&self) -> Option<T> {
+        let median = self.median()?;
+        let deviations: Vec<T> = self
+            .values
+            .iter()
+            .map(|value| (*value - median).abs())
+            .collect();
+        let scale: T = NumCast::from(MAD_TO_DEVIATION).expect("MAD scale fits any float");
+
+        self::median(deviations).map(|mad| mad * scale)
+    }
+}
+
+/// Exponentially weighted moving average
+pub struct ExponentialAverage<T: Float> {
+    alpha: T, // Weight of each new value, 0 to 1
+    value: Option<T>,
+}
+
+impl<T> ExponentialAverage<T>
+where
+    T: Float,
+{
+    pub fn new(alpha: f32) -> Self {
+        ExponentialAverage {
+            alpha: NumCast::from(alpha).expect("Smoothing factor fits any float"),
+            value: None,
+        }
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.value = Some(match self.value {
+            Some(average) => average + self.alpha * (value - average),
+            None => value,
+        });
+    }
+
+    pub fn value(&self) -> Option<T> {
+        self.value
+    }
+}
+
+fn count<T: Float>(n: usize) -> T {
+    NumCast::from(n).expect("Value count fits any float")
+}
+
+fn median<T: Float>(mut values: Vec<T>) -> Option<T> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).expect("Only finite values are pushed"));
+    let middle = values.len() / 2;
+
+    if values.len() % 2 == 0 {
+        Some((values[middle - 1] + values[middle]) / (count::<T>(2)))
+    } else {
+        Some(values[middle])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_drops_oldest() {
+        let mut stats: WindowedStats<f64> = WindowedStats::new(Some(3));
+        for value in &[100.0, 1.0, 2.0, 3.0] {
+            stats.push(*value);
+        }
+
+        assert_eq!(3, stats.len());
+        assert!((stats.mean().unwrap() - 2.0).abs() < 1e-12);
+        assert!((stats.deviation().unwrap() - (2.0f64 / 3.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_long_window_matches_batch() {
+        let mut stats: WindowedStats<f64> = WindowedStats::new(Some(50));
+        let values: Vec<f64> = (0..1000).map(|i| (i as f64 * 0.37).sin() * 10.0).collect();
+        values.iter().for_each(|value| stats.push(*value));
+
+        let last = &values[950..];
+        let mean = last.iter().sum::<f64>() / 50.0;
+        let variance = last.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / 50.0;
+        assert!((stats.mean().unwrap() - mean).abs() < 1e-9);
+        assert!((stats.deviation().unwrap() - variance.sqrt()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_median_ignores_outlier() {
+        let mut stats: WindowedStats<f32> = WindowedStats::new(None);
+        for value in &[1.0, 2.0, 3.0, 4.0, 1000.0] {
+            stats.push(*value);
+        }
+
+        assert_eq!(Some(3.0), stats.median());
+        assert_eq!(Some(1.0 * 1.4826), stats.median_absolute_deviation());
+    }
+
+    #[test]
+    fn test_exponential_average() {
+        let mut average: ExponentialAverage<f32> = ExponentialAverage::new(0.5);
+        assert_eq!(None, average.value());
+
+        average.push(4.0);
+        average.push(2.0);
+        assert_eq!(Some(3.0), average.value());
+    }
+}
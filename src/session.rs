@@ -0,0 +1,124 @@
+/// Each session is written to its own directory under the data root, named for its start time, for
+/// example "sessions/2020-02-25 09-35-49.123/P1 alpha.csv". When the session ends a session.json
+/// manifest is written beside the CSV files with who, where and what was recorded, and the row count
+/// and checksum of every file, so a data pipeline can ingest sessions without guessing at file names.
+use crate::muse_model::date_time_filename_format;
+use crate::recorder::RecordedFile;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+const DEFAULT_DATA_ROOT: &str = "sessions";
+const DEFAULT_PROTOCOL: &str = "meme";
+const MANIFEST_FILE: &str = "session.json";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionSettings {
+    /// Each session is a directory in here
+    pub data_root: PathBuf,
+    /// Which recording setup this is, to tell apart sessions from several rigs
+    pub rig_id: Option<String>,
+    /// Name of the experiment protocol being run
+    pub protocol: String,
+    /// Also write each participant's raw EEG as EDF+ when the session ends
+    pub export_edf: bool,
+    /// Also record every stream into one session.xdf
+    pub xdf: bool,
+}
+
+impl Default for SessionSettings {
+    fn default() -> Self {
+        SessionSettings {
+            data_root: PathBuf::from(DEFAULT_DATA_ROOT),
+            rig_id: None,
+            protocol: DEFAULT_PROTOCOL.to_string(),
+            export_edf: false,
+            xdf: false,
+        }
+    }
+}
+
+impl SessionSettings {
+    /// Directory of the session which started at this time
+    pub fn directory(&self, start_time: DateTime<Local>) -> PathBuf {
+        self.data_root.join(date_time_filename_format(start_time))
+    }
+}
+
+/// One participant slot in the manifest
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SessionParticipant {
+    /// "P1", which starts the names of this participant's files
+    pub label: String,
+    pub participant_id: String,
+    /// Address the headset streamed from, None if it was reserved but never connected
+    pub source: Option<IpAddr>,
+}
+
+/// Contents of session.json
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SessionManifest {
+    pub app_version: String,
+    pub rig_id: Option<String>,
+    pub protocol: String,
+    pub start_time: DateTime<Local>,
+    pub end_time: DateTime<Local>,
+    pub participants: Vec<SessionParticipant>,
+    /// Every CSV file in the session directory in name order, then any EDF+ and XDF files
+    pub files: Vec<RecordedFile>,
+}
+
+impl SessionManifest {
+    pub fn save(&self, directory: &Path) -> Result<PathBuf, String> {
+        let path = directory.join(MANIFEST_FILE);
+        let write =
+            || -> std::io::Result<()> { fs::write(&path, serde_json::to_string_pretty(self)?) };
+        write().map_err(|e| format!("Can not write {}: {}", path.display(), e))?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_save_manifest() {
+        let start_time = Local.timestamp(1_582_616_149, 0);
+        let settings = SessionSettings {
+            data_root: std::env::temp_dir().join("meme session test"),
+            ..SessionSettings::default()
+        };
+        let directory = settings.directory(start_time);
+        assert!(directory.ends_with(date_time_filename_format(start_time)));
+        fs::create_dir_all(&directory).unwrap();
+
+        let manifest = SessionManifest {
+            app_version: "1.0.0".to_string(),
+            rig_id: Some("rig 2".to_string()),
+            protocol: settings.protocol.clone(),
+            start_time,
+            end_time: start_time + chrono::Duration::minutes(20),
+            participants: vec![SessionParticipant {
+                label: "P1".to_string(),
+                participant_id: "alice".to_string(),
+                source: Some("192.168.1.20".parse().unwrap()),
+            }],
+            files: vec![RecordedFile {
+                name: "P1 alpha.csv".to_string(),
+                rows: 12_000,
+                sha256: None,
+            }],
+        };
+        let path = manifest.save(&directory).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        assert_eq!(manifest, serde_json::from_str(&json).unwrap());
+        assert!(json.contains("\"source\": \"192.168.1.20\""));
+
+        fs::remove_dir_all(settings.data_root).unwrap();
+    }
+}
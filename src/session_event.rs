@@ -0,0 +1,165 @@
+/// Protocol events of a session, such as a stage starting or an image being shown, written one per
+/// row of events.csv with a column for each detail so epoching needs no text parsing.
+///
+/// Events are timed on the same local clock as the EEG samples. Seconds since the session started
+/// are given to the microsecond beside the millisecond Time of every other CSV file.
+use chrono::{DateTime, Local};
+use std::fmt;
+
+pub const EVENTS_FILE: &str = "events.csv";
+pub const COLUMNS: [&str; 7] = [
+    "Time", "Seconds", "Event", "Stage", "Category", "Image", "Detail",
+];
+
+/// Which block of images a stimulus belongs to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageCategory {
+    Negative,
+    Positive,
+}
+
+impl fmt::Display for ImageCategory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageCategory::Negative => write!(f, "NEGATIVE"),
+            ImageCategory::Positive => write!(f, "POSITIVE"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SessionEvent {
+    /// A stage of the protocol, named for its first frame such as "NEGATIVE_A", begins
+    StageStart {
+        stage: &'static str,
+    },
+    StageEnd {
+        stage: &'static str,
+    },
+    /// An image is shown. Images are numbered from 0 in the order shown within their category.
+    StimulusOnset {
+        category: ImageCategory,
+        image: usize,
+    },
+    StimulusOffset {
+        category: ImageCategory,
+        image: usize,
+    },
+    /// The sound of a stage starts to play
+    SoundCue {
+        stage: &'static str,
+    },
+    /// A nod or head shake answering yes or no to the image on screen
+    Response {
+        category: ImageCategory,
+        image: usize,
+        answer: &'static str,
+    },
+    /// "LEFT", "RIGHT", "F1" to "F4" or "ESC"
+    KeyPress {
+        key: &'static str,
+    },
+    /// Every electrode had good contact for long enough, so the protocol can start
+    FitCheckPassed,
+    /// Something in the protocol failed, for example a sound which could not be played
+    Error {
+        message: String,
+    },
+}
+
+impl SessionEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            SessionEvent::StageStart { .. } => "StageStart",
+            SessionEvent::StageEnd { .. } => "StageEnd",
+            SessionEvent::StimulusOnset { .. } => "StimulusOnset",
+            SessionEvent::StimulusOffset { .. } => "StimulusOffset",
+            SessionEvent::SoundCue { .. } => "SoundCue",
+            SessionEvent::Response { .. } => "Response",
+            SessionEvent::KeyPress { .. } => "KeyPress",
+            SessionEvent::FitCheckPassed => "FitCheckPassed",
+            SessionEvent::Error { .. } => "Error",
+        }
+    }
+
+    /// Stage, category, image and detail columns, empty where they do not apply
+    fn details(&self) -> [String; 4] {
+        let none = String::new;
+        match self {
+            SessionEvent::StageStart { stage }
+            | SessionEvent::StageEnd { stage }
+            | SessionEvent::SoundCue { stage } => [stage.to_string(), none(), none(), none()],
+            SessionEvent::StimulusOnset { category, image }
+            | SessionEvent::StimulusOffset { category, image } => {
+                [none(), category.to_string(), image.to_string(), none()]
+            }
+            SessionEvent::Response {
+                category,
+                image,
+                answer,
+            } => [
+                none(),
+                category.to_string(),
+                image.to_string(),
+                answer.to_string(),
+            ],
+            SessionEvent::KeyPress { key } => [none(), none(), none(), key.to_string()],
+            SessionEvent::FitCheckPassed => [none(), none(), none(), none()],
+            SessionEvent::Error { message } => [none(), none(), none(), message.clone()],
+        }
+    }
+
+    /// The row of events.csv after Time, for an event this long after the session started
+    pub fn values(&self, time: DateTime<Local>, start_time: DateTime<Local>) -> Vec<String> {
+        let micros = (time - start_time).num_microseconds().unwrap_or(0);
+        let mut values = vec![
+            format!("{:.6}", micros as f64 / 1_000_000.0),
+            self.name().to_string(),
+        ];
+        values.extend(self.details().iter().cloned());
+
+        values
+    }
+}
+
+/// "StimulusOnset:NEGATIVE:3", the name and then each detail which applies
+impl fmt::Display for SessionEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        for detail in self.details().iter().filter(|detail| !detail.is_empty()) {
+            write!(f, ":{}", detail)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_event_values() {
+        let start_time = Local.timestamp(1_582_616_149, 0);
+        let onset = SessionEvent::StimulusOnset {
+            category: ImageCategory::Negative,
+            image: 3,
+        };
+        let time = start_time + Duration::microseconds(83_250_125);
+        assert_eq!(
+            vec!["83.250125", "StimulusOnset", "", "NEGATIVE", "3", ""],
+            onset.values(time, start_time)
+        );
+        assert_eq!(COLUMNS.len(), onset.values(time, start_time).len() + 1);
+        assert_eq!("StimulusOnset:NEGATIVE:3", onset.to_string());
+
+        let response = SessionEvent::Response {
+            category: ImageCategory::Positive,
+            image: 0,
+            answer: "YES",
+        };
+        assert_eq!("Response:POSITIVE:0:YES", response.to_string());
+        assert_eq!("FitCheckPassed", SessionEvent::FitCheckPassed.to_string());
+    }
+}
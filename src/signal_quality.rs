@@ -0,0 +1,261 @@
+/// How well each electrode is picking up EEG, and the fit check which holds the session until the
+/// headband sits well.
+///
+/// Each electrode gets a score from 0 (unusable) to 1 (good), the worst of:
+///   - horseshoe fit reported by the headset, 1 good, 2 medium, 4 bad
+///   - the headset's own is_good flag
+///   - mains hum amplitude in the raw signal, high when the electrode is not touching skin
+///   - flat line, when the electrode is saturated or not sending
+use crate::spectrum::{EEG_SAMPLE_RATE, N_EEG_CHANNELS};
+use chrono::{DateTime, Duration, Local};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+pub const GOOD_SIGNAL_QUALITY: f32 = 0.75; // Lowest score counted as good by the fit check
+pub const DEFAULT_FIT_CHECK_SECONDS: i64 = 5; // How long all electrodes must stay good
+
+const QUALITY_WINDOW: usize = 256; // Raw samples analysed together, 1 second
+const MAINS_FREQUENCIES: [f32; 2] = [50.0, 60.0];
+const LINE_NOISE_GOOD: f32 = 10.0; // microVolts amplitude, no penalty below this
+const LINE_NOISE_BAD: f32 = 50.0; // microVolts amplitude, score 0 above this
+const FLAT_LINE_DEVIATION: f32 = 0.5; // microVolts, less variation than this is not EEG
+const NOT_GOOD_SCORE: f32 = 0.5; // Score limit while the headset says the electrode is not good
+
+/// Signal quality for the four electrodes of one headset
+pub struct SignalQuality {
+    horseshoe: Option<[f32; N_EEG_CHANNELS]>,
+    is_good: Option<[bool; N_EEG_CHANNELS]>,
+    samples: VecDeque<[f32; N_EEG_CHANNELS]>,
+    line_noise: Option<[f32; N_EEG_CHANNELS]>, // Mains amplitude over the last complete window
+    deviation: Option<[f32; N_EEG_CHANNELS]>,  // Standard deviation over the last complete window
+}
+
+impl Default for SignalQuality {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SignalQuality {
+    pub fn new() -> Self {
+        SignalQuality {
+            horseshoe: None,
+            is_good: None,
+            samples: VecDeque::with_capacity(QUALITY_WINDOW),
+            line_noise: None,
+            deviation: None,
+        }
+    }
+
+    pub fn set_horseshoe(&mut self, horseshoe: [f32; N_EEG_CHANNELS]) {
+        self.horseshoe = Some(horseshoe);
+    }
+
+    pub fn set_is_good(&mut self, is_good: [bool; N_EEG_CHANNELS]) {
+        self.is_good = Some(is_good);
+    }
+
+    /// Add one raw (unfiltered) sample. Returns true when a new window has been analysed.
+    pub fn push(&mut self, eeg: [f32; N_EEG_CHANNELS]) -> bool {
+        self.samples.push_back(eeg);
+        if self.samples.len() < QUALITY_WINDOW {
+            return false;
+        }
+
+        let mut line_noise = [0.0; N_EEG_CHANNELS];
+        let mut deviation = [0.0; N_EEG_CHANNELS];
+        for electrode in 0..N_EEG_CHANNELS {
+            let samples: Vec<f32> = self.samples.iter().map(|eeg| eeg[electrode]).collect();
+            let mean = samples.iter().sum::<f32>() / samples.len() as f32;
+            let variance =
+                samples.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / samples.len() as f32;
+            deviation[electrode] = variance.sqrt();
+            line_noise[electrode] = MAINS_FREQUENCIES
+                .iter()
+                .map(|frequency| amplitude_at(&samples, mean, *frequency))
+                .fold(0.0, f32::max);
+        }
+        self.samples.clear();
+        self.line_noise = Some(line_noise);
+        self.deviation = Some(deviation);
+
+        true
+    }
+
+    /// Score from 0 to 1 for each electrode. 0 until a full window of raw EEG has arrived.
+    pub fn scores(&self) -> [f32; N_EEG_CHANNELS] {
+        let mut scores = [0.0; N_EEG_CHANNELS];
+        let (line_noise, deviation) = match (self.line_noise, self.deviation) {
+            (Some(line_noise), Some(deviation)) => (line_noise, deviation),
+            _ => return scores,
+        };
+
+        for (electrode, score) in scores.iter_mut().enumerate() {
+            let mut worst: f32 = 1.0;
+            if let Some(horseshoe) = self.horseshoe {
+                worst = worst.min((4.0 - horseshoe[electrode]) / 3.0);
+            }
+            if let Some(is_good) = self.is_good {
+                if !is_good[electrode] {
+                    worst = worst.min(NOT_GOOD_SCORE);
+                }
+            }
+            worst = worst
+                .min((LINE_NOISE_BAD - line_noise[electrode]) / (LINE_NOISE_BAD - LINE_NOISE_GOOD));
+            if deviation[electrode].is_nan() || deviation[electrode] < FLAT_LINE_DEVIATION {
+                worst = 0.0; // NaN from missing samples
+            }
+            *score = worst.clamp(0.0, 1.0);
+        }
+
+        scores
+    }
+
+    pub fn all_good(&self) -> bool {
+        self.scores()
+            .iter()
+            .all(|score| *score >= GOOD_SIGNAL_QUALITY)
+    }
+}
+
+/// Amplitude of one frequency in the window after removing the mean (Goertzel algorithm)
+fn amplitude_at(samples: &[f32], mean: f32, frequency: f32) -> f32 {
+    let coefficient = 2.0 * (2.0 * PI * frequency / EEG_SAMPLE_RATE).cos();
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for sample in samples {
+        let s0 = sample - mean + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+
+    2.0 * power.max(0.0).sqrt() / samples.len() as f32
+}
+
+/// Holds the session until every electrode has been good for `hold` without a break
+pub struct FitCheck {
+    hold: Duration,
+    good_since: Option<DateTime<Local>>,
+    passed: bool,
+}
+
+impl FitCheck {
+    /// A zero `hold` skips the fit check
+    pub fn new(hold: Duration) -> Self {
+        FitCheck {
+            hold,
+            good_since: None,
+            passed: hold <= Duration::zero(),
+        }
+    }
+
+    pub fn is_passed(&self) -> bool {
+        self.passed
+    }
+
+    /// Returns true only at the moment the check passes
+    pub fn update(&mut self, time: DateTime<Local>, all_good: bool) -> bool {
+        if self.passed {
+            return false;
+        }
+
+        if !all_good {
+            self.good_since = None;
+            return false;
+        }
+
+        let good_since = *self.good_since.get_or_insert(time);
+        self.passed = time - good_since >= self.hold;
+
+        self.passed
+    }
+
+    /// How far through the hold time, 0 to 1
+    pub fn progress(&self, time: DateTime<Local>) -> f32 {
+        match self.good_since {
+            _ if self.passed => 1.0,
+            Some(good_since) => {
+                let held = (time - good_since).num_milliseconds() as f32;
+                (held / self.hold.num_milliseconds() as f32).min(1.0)
+            }
+            None => 0.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// One window of 10Hz EEG at 20 microVolts with `hum` microVolts of 50Hz on every electrode
+    fn window(quality: &mut SignalQuality, hum: f32) {
+        for i in 0..QUALITY_WINDOW {
+            let t = i as f32 / EEG_SAMPLE_RATE;
+            let value =
+                800.0 + 20.0 * (2.0 * PI * 10.0 * t).sin() + hum * (2.0 * PI * 50.0 * t).sin();
+            quality.push([value; N_EEG_CHANNELS]);
+        }
+    }
+
+    #[test]
+    fn test_no_data_is_not_good() {
+        let quality = SignalQuality::new();
+
+        assert_eq!([0.0; N_EEG_CHANNELS], quality.scores());
+        assert!(!quality.all_good());
+    }
+
+    #[test]
+    fn test_clean_signal_is_good() {
+        let mut quality = SignalQuality::new();
+        quality.set_horseshoe([1.0; N_EEG_CHANNELS]);
+        window(&mut quality, 0.0);
+
+        assert!(quality.all_good());
+    }
+
+    #[test]
+    fn test_mains_hum_and_horseshoe() {
+        let mut quality = SignalQuality::new();
+        window(&mut quality, 60.0);
+        assert_eq!([0.0; N_EEG_CHANNELS], quality.scores());
+
+        window(&mut quality, 0.0);
+        quality.set_horseshoe([1.0, 2.0, 4.0, 1.0]);
+        let scores = quality.scores();
+        assert_eq!(1.0, scores[0]);
+        assert!(scores[1] < GOOD_SIGNAL_QUALITY && scores[1] > 0.5);
+        assert_eq!(0.0, scores[2]);
+    }
+
+    #[test]
+    fn test_flat_line() {
+        let mut quality = SignalQuality::new();
+        for _ in 0..QUALITY_WINDOW {
+            quality.push([800.0, 800.0, 800.0, f32::NAN]);
+        }
+
+        assert_eq!([0.0; N_EEG_CHANNELS], quality.scores());
+    }
+
+    #[test]
+    fn test_fit_check_needs_unbroken_hold() {
+        let at = |s: i64| Local.timestamp(1_582_616_149 + s, 0);
+        let mut fit_check = FitCheck::new(Duration::seconds(5));
+
+        assert!(!fit_check.update(at(0), true));
+        assert!(!fit_check.update(at(3), false));
+        assert!(!fit_check.update(at(4), true));
+        assert!(!fit_check.update(at(8), true));
+        assert!((fit_check.progress(at(8)) - 0.8).abs() < 1e-6);
+        assert!(fit_check.update(at(9), true));
+        assert!(fit_check.is_passed());
+        assert!(!fit_check.update(at(10), false));
+    }
+
+    #[test]
+    fn test_fit_check_can_be_skipped() {
+        assert!(FitCheck::new(Duration::zero()).is_passed());
+    }
+}
@@ -0,0 +1,336 @@
+/// Band powers computed in the app from raw /muse/eeg samples, so they do not depend on the phone
+/// app's processing and can be checked against it.
+///
+/// Each update uses the most recent `segments` half-overlapping segments of `segment_length` samples
+/// per electrode. Each segment has its mean removed and a Hann window applied before the FFT, and the
+/// power spectra are averaged (Welch's method). Band power is log10 of the summed power spectral
+/// density over the band, the same scale as the Muse `*_absolute` messages.
+use crate::muse_model::{EegBand, N_EEG_BANDS};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+pub const EEG_SAMPLE_RATE: f32 = 256.0; // Raw EEG samples per second per electrode
+pub const N_EEG_CHANNELS: usize = 4; // TP9, AF7, AF8, TP10
+
+/// Log10 band power for each band and electrode, indexed by EegBand::index() then electrode
+pub type BandPowers = [[f32; N_EEG_CHANNELS]; N_EEG_BANDS];
+
+/// Lower and upper frequency of each band in Hz, indexed by EegBand::index(). The upper edge is not part of the band.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BandEdges(pub [(f32, f32); N_EEG_BANDS]);
+
+impl Default for BandEdges {
+    /// The Muse band definitions
+    fn default() -> Self {
+        BandEdges([
+            (1.0, 4.0),
+            (4.0, 8.0),
+            (7.5, 13.0),
+            (13.0, 30.0),
+            (30.0, 44.0),
+        ])
+    }
+}
+
+impl BandEdges {
+    pub fn edges(&self, band: EegBand) -> (f32, f32) {
+        self.0[band.index()]
+    }
+
+    /// "1-4,4-8,7.5-13,13-30,30-44", one range for each band in the order of EegBand::ALL
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let ranges: Vec<&str> = text.split(',').map(str::trim).collect();
+        if ranges.len() != N_EEG_BANDS {
+            return Err(format!(
+                "'{}' needs {} ranges, one for each band",
+                text, N_EEG_BANDS
+            ));
+        }
+
+        let mut edges = [(0.0, 0.0); N_EEG_BANDS];
+        for (band, range) in EegBand::ALL.iter().zip(ranges) {
+            let mut parts = range.splitn(2, '-').map(|edge| edge.trim().parse::<f32>());
+            edges[band.index()] = match (parts.next(), parts.next()) {
+                (Some(Ok(low)), Some(Ok(high))) if low >= 0.0 && low < high => (low, high),
+                _ => {
+                    return Err(format!(
+                        "{} band '{}' is not a low-high range",
+                        band.label(),
+                        range
+                    ))
+                }
+            };
+        }
+
+        Ok(BandEdges(edges))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WelchSettings {
+    pub segment_length: usize,  // Samples in each FFT, a power of two
+    pub segments: usize,        // Half overlapping segments averaged for each update
+    pub update_interval: usize, // New samples between updates
+    pub band_edges: BandEdges,
+}
+
+impl Default for WelchSettings {
+    /// 1Hz resolution over the last 2.5 seconds, updated about 10 times per second like the Muse
+    fn default() -> Self {
+        WelchSettings {
+            segment_length: 256,
+            segments: 4,
+            update_interval: 26,
+            band_edges: BandEdges::default(),
+        }
+    }
+}
+
+impl WelchSettings {
+    /// Samples needed for one update
+    fn history_length(&self) -> usize {
+        self.segment_length + (self.segments - 1) * self.segment_step()
+    }
+
+    fn segment_step(&self) -> usize {
+        self.segment_length / 2
+    }
+}
+
+/// Rolling raw EEG history for one headset, turned into band powers as samples arrive
+pub struct SpectralPipeline {
+    settings: WelchSettings,
+    window: Vec<f32>,                         // Hann
+    window_power: f32, // Sum of squared window values, to scale the spectrum to power spectral density
+    history: VecDeque<[f32; N_EEG_CHANNELS]>, // Most recent last
+    samples_since_update: usize,
+}
+
+impl SpectralPipeline {
+    pub fn new(settings: WelchSettings) -> Self {
+        assert!(
+            settings.segment_length.is_power_of_two() && settings.segment_length >= 4,
+            "FFT segment length must be a power of two"
+        );
+        assert!(settings.segments > 0 && settings.update_interval > 0);
+        let n = settings.segment_length;
+        let window: Vec<f32> = (0..n)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos())
+            .collect();
+        let window_power = window.iter().map(|w| w * w).sum();
+
+        SpectralPipeline {
+            settings,
+            window,
+            window_power,
+            history: VecDeque::with_capacity(settings.history_length()),
+            samples_since_update: 0,
+        }
+    }
+
+    /// Change the bands without losing the sample history
+    pub fn set_band_edges(&mut self, band_edges: BandEdges) {
+        self.settings.band_edges = band_edges;
+    }
+
+    /// Frequency resolution of the spectrum in Hz
+    pub fn bin_width(&self) -> f32 {
+        EEG_SAMPLE_RATE / self.settings.segment_length as f32
+    }
+
+    /// Add one raw sample for each electrode, returning new band powers when an update is due
+    pub fn push(&mut self, eeg: [f32; N_EEG_CHANNELS]) -> Option<BandPowers> {
+        if self.history.len() == self.settings.history_length() {
+            self.history.pop_front();
+        }
+        self.history.push_back(eeg);
+        self.samples_since_update += 1;
+
+        if self.history.len() < self.settings.history_length()
+            || self.samples_since_update < self.settings.update_interval
+        {
+            return None;
+        }
+        self.samples_since_update = 0;
+
+        let spectra: Vec<Vec<f32>> = (0..N_EEG_CHANNELS)
+            .map(|channel| self.power_spectral_density(channel))
+            .collect();
+        let mut band_powers = [[0.0; N_EEG_CHANNELS]; N_EEG_BANDS];
+        for band in EegBand::ALL.iter() {
+            for (power, spectrum) in band_powers[band.index()].iter_mut().zip(spectra.iter()) {
+                *power = self.band_power(spectrum, *band);
+            }
+        }
+
+        Some(band_powers)
+    }
+
+    /// Welch estimate for one electrode in microVolts²/Hz, one value per bin from 0Hz to half the sample rate
+    pub fn power_spectral_density(&self, channel: usize) -> Vec<f32> {
+        let n = self.settings.segment_length;
+        let mut density = vec![0.0; n / 2 + 1];
+        if self.history.len() < n {
+            return density;
+        }
+        let segments = (self.history.len() - n) / self.settings.segment_step() + 1;
+
+        for segment in 0..segments {
+            let start = self.history.len() - n - segment * self.settings.segment_step();
+            let samples: Vec<f32> = self
+                .history
+                .iter()
+                .skip(start)
+                .take(n)
+                .map(|sample| sample[channel])
+                .collect();
+            let mean = samples.iter().sum::<f32>() / n as f32;
+            let mut re: Vec<f32> = samples
+                .iter()
+                .zip(self.window.iter())
+                .map(|(sample, w)| (sample - mean) * w)
+                .collect();
+            let mut im = vec![0.0; n];
+            fft(&mut re, &mut im);
+
+            for (bin, value) in density.iter_mut().enumerate() {
+                let one_sided = if bin == 0 || bin == n / 2 { 1.0 } else { 2.0 };
+                *value += one_sided * (re[bin] * re[bin] + im[bin] * im[bin]);
+            }
+        }
+
+        let scale = 1.0 / (EEG_SAMPLE_RATE * self.window_power * segments as f32);
+        density.iter_mut().for_each(|value| *value *= scale);
+
+        density
+    }
+
+    fn band_power(&self, spectrum: &[f32], band: EegBand) -> f32 {
+        let (low, high) = self.settings.band_edges.edges(band);
+        let bin_width = self.bin_width();
+        let sum: f32 = spectrum
+            .iter()
+            .enumerate()
+            .filter(|(bin, _)| {
+                let frequency = *bin as f32 * bin_width;
+                frequency >= low && frequency < high
+            })
+            .map(|(_, value)| value)
+            .sum();
+
+        sum.max(std::f32::MIN_POSITIVE).log10()
+    }
+}
+
+/// In place iterative radix-2 FFT. The length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f32;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + length / 2);
+                let t_re = re[b] * cos - im[b] * sin;
+                let t_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AF7_CHANNEL: usize = 1;
+
+    fn sine(pipeline: &mut SpectralPipeline, frequency: f32, amplitude: f32) -> BandPowers {
+        let mut latest = None;
+        for i in 0..pipeline.settings.history_length() + 100 {
+            let t = i as f32 / EEG_SAMPLE_RATE;
+            let value = 800.0 + amplitude * (2.0 * PI * frequency * t).sin();
+            if let Some(band_powers) = pipeline.push([value; N_EEG_CHANNELS]) {
+                latest = Some(band_powers);
+            }
+        }
+
+        latest.expect("No band powers after a full history")
+    }
+
+    #[test]
+    fn test_fft_impulse() {
+        let mut re = vec![0.0; 8];
+        let mut im = vec![0.0; 8];
+        re[0] = 1.0;
+        fft(&mut re, &mut im);
+
+        assert!(re.iter().all(|value| (value - 1.0).abs() < 1e-6));
+        assert!(im.iter().all(|value| value.abs() < 1e-6));
+    }
+
+    #[test]
+    fn test_sine_power() {
+        let mut pipeline = SpectralPipeline::new(WelchSettings::default());
+        sine(&mut pipeline, 10.0, 10.0);
+        let total: f32 = pipeline
+            .power_spectral_density(AF7_CHANNEL)
+            .iter()
+            .sum::<f32>()
+            * pipeline.bin_width();
+
+        // Variance of a sine is half the amplitude squared
+        assert!((total - 50.0).abs() < 2.5, "Total power {}", total);
+    }
+
+    #[test]
+    fn test_alpha_sine_is_alpha() {
+        let mut pipeline = SpectralPipeline::new(WelchSettings::default());
+        let band_powers = sine(&mut pipeline, 10.0, 10.0);
+        let alpha = band_powers[EegBand::Alpha.index()][AF7_CHANNEL];
+
+        for band in EegBand::ALL.iter().filter(|band| **band != EegBand::Alpha) {
+            assert!(alpha > band_powers[band.index()][AF7_CHANNEL] + 2.0);
+        }
+    }
+
+    #[test]
+    fn test_custom_band_edges() {
+        let edges = BandEdges::parse("1-4, 4-8, 8-12, 12-30, 30-45").unwrap();
+        let mut pipeline = SpectralPipeline::new(WelchSettings {
+            band_edges: edges,
+            ..WelchSettings::default()
+        });
+        let band_powers = sine(&mut pipeline, 12.5, 10.0);
+
+        assert_eq!((8.0, 12.0), edges.edges(EegBand::Alpha));
+        assert!(band_powers[EegBand::Beta.index()][0] > band_powers[EegBand::Alpha.index()][0]);
+    }
+
+    #[test]
+    fn test_bad_band_edges() {
+        assert!(BandEdges::parse("1-4,4-8,8-12,12-30").is_err());
+        assert!(BandEdges::parse("1-4,4-8,12-8,12-30,30-45").is_err());
+        assert!(BandEdges::parse("1-4,4-8,alpha,12-30,30-45").is_err());
+    }
+}
@@ -0,0 +1,96 @@
+/// Receive EEG in the browser from a native relay over WebSocket, see wire_format for the frames
+use crate::muse_model::{EegMessageReceiver, MuseMessage, MusePacketError};
+use crate::wire_format::{receive_frame, WEBSOCKET_PORT};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use stdweb::traits::*;
+use stdweb::web::event::{SocketCloseEvent, SocketErrorEvent, SocketMessageEvent, SocketOpenEvent};
+use stdweb::web::{window, WebSocket};
+
+const RELAY_QUERY_PARAMETER: &str = "relay=";
+
+/// Text frames which have arrived since the last poll, filled by the WebSocket callbacks
+type FrameQueue = Rc<RefCell<VecDeque<String>>>;
+
+pub struct WebSocketMessageReceiver {
+    url: String,
+    frames: FrameQueue,
+    _socket: Option<WebSocket>, // Kept so the connection stays open
+}
+
+impl WebSocketMessageReceiver {
+    /// Connect to the relay given in the page URL (?relay=ws://host:port), or else the default port on the host which served the page
+    pub fn new() -> Self {
+        let location = window().location();
+        let from_query = location
+            .as_ref()
+            .and_then(|l| l.search().ok())
+            .and_then(|search| relay_from_query(&search));
+        let hostname = location
+            .and_then(|l| l.hostname().ok())
+            .unwrap_or_else(|| "localhost".to_string());
+        let url = from_query.unwrap_or_else(|| format!("ws://{}:{}", hostname, WEBSOCKET_PORT));
+
+        Self::connect(url)
+    }
+
+    pub fn connect(url: String) -> Self {
+        info!("Connecting to EEG relay {}", url);
+        let frames: FrameQueue = Rc::new(RefCell::new(VecDeque::new()));
+
+        let socket = match WebSocket::new(&url) {
+            Ok(socket) => {
+                let open_url = url.clone();
+                socket.add_event_listener(move |_: SocketOpenEvent| {
+                    info!("Connected to EEG relay {}", open_url);
+                });
+                socket.add_event_listener(|_: SocketErrorEvent| {
+                    error!("EEG relay connection error");
+                });
+                socket.add_event_listener(|event: SocketCloseEvent| {
+                    warn!("EEG relay closed the connection: {}", event.reason());
+                });
+                let message_frames = frames.clone();
+                socket.add_event_listener(move |event: SocketMessageEvent| {
+                    match event.data().into_text() {
+                        Some(text) => message_frames.borrow_mut().push_back(text),
+                        None => warn!("Ignoring binary frame from EEG relay"),
+                    }
+                });
+
+                Some(socket)
+            }
+            Err(e) => {
+                error!("Can not connect to EEG relay {}: {:?}", url, e);
+                None
+            }
+        };
+
+        Self {
+            url,
+            frames,
+            _socket: socket,
+        }
+    }
+}
+
+impl EegMessageReceiver for WebSocketMessageReceiver {
+    fn receive_packets(&self) -> Vec<Result<MuseMessage, MusePacketError>> {
+        self.frames
+            .borrow_mut()
+            .drain(..)
+            .filter_map(|frame| receive_frame(&frame, &self.url))
+            .collect()
+    }
+}
+
+/// "?relay=ws://10.0.0.5:34255&x=y" -> "ws://10.0.0.5:34255"
+fn relay_from_query(search: &str) -> Option<String> {
+    search
+        .trim_start_matches('?')
+        .split('&')
+        .find(|parameter| parameter.starts_with(RELAY_QUERY_PARAMETER))
+        .map(|parameter| parameter[RELAY_QUERY_PARAMETER.len()..].to_string())
+        .filter(|url| !url.is_empty())
+}
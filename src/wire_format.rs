@@ -0,0 +1,292 @@
+/// JSON messages sent from a native relay to browsers and other WebSocket clients.
+///
+/// Each WebSocket text frame is one JSON object with a "kind" field:
+///   {"kind":"muse","message_time":"2020-02-25T09:35:49.123+02:00","receive_time":...,"timetag":null,
+///    "ip_address":"192.168.1.20:50000","muse_message_type":{"Eeg":{"eeg":[800.1,801.3,799.0,802.2]}}}
+///
+///   {"kind":"affect","participant":0,"time":"2020-02-25T09:35:49.140+02:00","valence":0.4,"arousal":null}
+///
+/// Fields after "kind":"muse" are those of MuseMessage. JSON has no NaN, so a NaN value such as EEG
+/// from an electrode which has lost contact is null, see nan_as_null. Valence and arousal are
+/// normalized, null until there is enough history. Times are RFC 3339 with offset.
+use crate::muse_model::{MuseMessage, MuseMessageType, MusePacketError};
+use crate::spectrum::N_EEG_CHANNELS;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+pub const WEBSOCKET_PORT: u16 = 34255; // Default relay port, next to the OSC port
+const MAX_RAW_FFT_BINS: usize = 129; // 0-110Hz in 0.86Hz bins
+const MAX_AUX_CHANNELS: usize = 4;
+
+/// One WebSocket frame
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WireMessage {
+    /// A message from the headset, as parsed by the relay
+    Muse(MuseMessage),
+    /// Valence and arousal computed by the relay for one participant slot, 0 is the first participant
+    Affect {
+        participant: usize,
+        time: DateTime<Local>,
+        valence: Option<f32>,
+        arousal: Option<f32>,
+    },
+}
+
+impl WireMessage {
+    pub fn encode(&self) -> String {
+        serde_json::to_string(self).expect("Wire messages are always serializable")
+    }
+
+    pub fn decode(frame: &str) -> Result<WireMessage, serde_json::Error> {
+        serde_json::from_str(frame)
+    }
+}
+
+/// Float fields of MuseMessageType written as null when they are NaN, and read back as NaN. Use as
+/// `#[serde(with = "nan_as_null")]`.
+pub mod nan_as_null {
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// A float, or fixed or variable length list of floats, and the same with each NaN as None
+    pub trait NanAsNull: Sized {
+        type Wire: Serialize + DeserializeOwned;
+
+        fn to_wire(&self) -> Self::Wire;
+        fn from_wire(wire: Self::Wire) -> Self;
+    }
+
+    fn to_wire(value: f32) -> Option<f32> {
+        if value.is_nan() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    fn from_wire(wire: Option<f32>) -> f32 {
+        wire.unwrap_or(f32::NAN)
+    }
+
+    impl NanAsNull for f32 {
+        type Wire = Option<f32>;
+
+        fn to_wire(&self) -> Self::Wire {
+            to_wire(*self)
+        }
+
+        fn from_wire(wire: Self::Wire) -> Self {
+            from_wire(wire)
+        }
+    }
+
+    impl NanAsNull for [f32; 3] {
+        type Wire = [Option<f32>; 3];
+
+        fn to_wire(&self) -> Self::Wire {
+            [to_wire(self[0]), to_wire(self[1]), to_wire(self[2])]
+        }
+
+        fn from_wire(wire: Self::Wire) -> Self {
+            [from_wire(wire[0]), from_wire(wire[1]), from_wire(wire[2])]
+        }
+    }
+
+    impl NanAsNull for [f32; 4] {
+        type Wire = [Option<f32>; 4];
+
+        fn to_wire(&self) -> Self::Wire {
+            [
+                to_wire(self[0]),
+                to_wire(self[1]),
+                to_wire(self[2]),
+                to_wire(self[3]),
+            ]
+        }
+
+        fn from_wire(wire: Self::Wire) -> Self {
+            [
+                from_wire(wire[0]),
+                from_wire(wire[1]),
+                from_wire(wire[2]),
+                from_wire(wire[3]),
+            ]
+        }
+    }
+
+    impl NanAsNull for Vec<f32> {
+        type Wire = Vec<Option<f32>>;
+
+        fn to_wire(&self) -> Self::Wire {
+            self.iter().cloned().map(to_wire).collect()
+        }
+
+        fn from_wire(wire: Self::Wire) -> Self {
+            wire.into_iter().map(from_wire).collect()
+        }
+    }
+
+    pub fn serialize<T: NanAsNull, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.to_wire().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T: NanAsNull, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        T::Wire::deserialize(deserializer).map(T::from_wire)
+    }
+}
+
+/// Decode a frame from a relay as input for this app. Affect frames are skipped as valence and arousal are
+/// recalculated locally from the messages. `source` names the relay in errors.
+pub fn receive_frame(frame: &str, source: &str) -> Option<Result<MuseMessage, MusePacketError>> {
+    let undecodable = |reason: String| MusePacketError::Undecodable {
+        addr: source.to_string(),
+        reason,
+    };
+
+    match WireMessage::decode(frame) {
+        Ok(WireMessage::Muse(muse_message)) => Some(
+            check_muse_message(&muse_message.muse_message_type)
+                .map(|_| muse_message)
+                .map_err(undecodable),
+        ),
+        Ok(WireMessage::Affect { .. }) => None,
+        Err(e) => Some(Err(undecodable(e.to_string()))),
+    }
+}
+
+/// Serde checks the fixed size arrays, but the channel and variable length values of a frame must
+/// also be checked as they are used to index and size the model
+fn check_muse_message(muse_message_type: &MuseMessageType) -> Result<(), String> {
+    match muse_message_type {
+        MuseMessageType::RawFft { channel, .. } if *channel >= N_EEG_CHANNELS => {
+            Err(format!("raw FFT channel {} out of range", channel))
+        }
+        MuseMessageType::RawFft { fft, .. } if fft.is_empty() || fft.len() > MAX_RAW_FFT_BINS => {
+            Err(format!("raw FFT has {} bins", fft.len()))
+        }
+        MuseMessageType::Aux { aux } if aux.is_empty() || aux.len() > MAX_AUX_CHANNELS => {
+            Err(format!("{} aux channels", aux.len()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::muse_model::{EegBand, MuseMessageType};
+    use chrono::TimeZone;
+    use std::net::SocketAddr;
+
+    fn muse_message(muse_message_type: MuseMessageType) -> WireMessage {
+        let time = Local.timestamp(1_582_616_149, 123_000_000);
+
+        WireMessage::Muse(MuseMessage {
+            message_time: time,
+            receive_time: time,
+            timetag: None,
+            ip_address: SocketAddr::from(([192, 168, 1, 20], 50000)),
+            muse_message_type,
+        })
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let frame = muse_message(MuseMessageType::Relative {
+            band: EegBand::Alpha,
+            values: [0.1, 0.2, 0.3, 0.4],
+        })
+        .encode();
+
+        match WireMessage::decode(&frame) {
+            Ok(WireMessage::Muse(message)) => match message.muse_message_type {
+                MuseMessageType::Relative { band, values } => {
+                    assert_eq!(EegBand::Alpha, band);
+                    assert_eq!([0.1, 0.2, 0.3, 0.4], values);
+                }
+                other => panic!("Unexpected message type {:?}", other),
+            },
+            other => panic!("Unexpected decode result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_nan_round_trip() {
+        let frame = muse_message(MuseMessageType::Eeg {
+            eeg: [800.0, f32::NAN, 801.5, f32::NAN],
+        })
+        .encode();
+        assert!(frame.contains(r#"{"Eeg":{"eeg":[800.0,null,801.5,null]}}"#));
+
+        match WireMessage::decode(&frame) {
+            Ok(WireMessage::Muse(message)) => match message.muse_message_type {
+                MuseMessageType::Eeg { eeg } => {
+                    assert_eq!((800.0, 801.5), (eeg[0], eeg[2]));
+                    assert!(eeg[1].is_nan() && eeg[3].is_nan());
+                }
+                other => panic!("Unexpected message type {:?}", other),
+            },
+            other => panic!("Unexpected decode result {:?}", other),
+        }
+        let frame = muse_message(MuseMessageType::Mellow { mellow: f32::NAN }).encode();
+        assert!(receive_frame(&frame, "ws://10.0.0.5:34255")
+            .unwrap()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_frame_has_kind() {
+        let frame = muse_message(MuseMessageType::Blink { blink: true }).encode();
+
+        assert!(frame.starts_with(r#"{"kind":"muse","#));
+        assert!(frame.contains(r#""muse_message_type":{"Blink":{"blink":true}}"#));
+    }
+
+    #[test]
+    fn test_affect_frame() {
+        let frame = WireMessage::Affect {
+            participant: 1,
+            time: Local.timestamp(1_582_616_149, 0),
+            valence: Some(0.5),
+            arousal: None,
+        }
+        .encode();
+
+        assert!(frame.starts_with(r#"{"kind":"affect","participant":1,"#));
+        assert!(frame.ends_with(r#""valence":0.5,"arousal":null}"#));
+    }
+
+    #[test]
+    fn test_decode_garbage() {
+        assert!(WireMessage::decode("{\"kind\":\"nonsense\"}").is_err());
+    }
+
+    #[test]
+    fn test_receive_frame_checks_raw_fft() {
+        let source = "ws://10.0.0.5:34255";
+        let frame = |channel, bins| {
+            muse_message(MuseMessageType::RawFft {
+                channel,
+                fft: vec![1.0; bins],
+            })
+            .encode()
+        };
+
+        assert!(receive_frame(&frame(3, 129), source).unwrap().is_ok());
+        for bad_frame in [frame(4, 129), frame(0, 0), frame(0, 130)].iter() {
+            match receive_frame(bad_frame, source) {
+                Some(Err(MusePacketError::Undecodable { addr, .. })) => assert_eq!(source, addr),
+                other => panic!("Unexpected receive result {:?}", other),
+            }
+        }
+        assert!(receive_frame(r#"{"kind":"muse"}"#, source)
+            .unwrap()
+            .is_err());
+    }
+}
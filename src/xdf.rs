@@ -0,0 +1,612 @@
+/// The whole session as one XDF file, the multi-stream format of Lab Streaming Layer tools.
+///
+/// Each headset has its own streams of raw EEG, the five band powers, accelerometer and gyro, each
+/// participant a valence and arousal stream, and the protocol events such as stage changes are a
+/// string marker stream. Headset samples are stamped with the headset's (phone app's) clock from the
+/// OSC bundle timetags, and ClockOffset chunks every few seconds give the offset which maps them
+/// onto this machine's clock, as an XDF loader expects. Samples without a timetag, and the streams
+/// made here, are stamped with this machine's clock and an offset of 0. Times are seconds since
+/// UNIX_EPOCH.
+///
+/// The file is written on a background thread. Samples are gathered into one chunk per stream each
+/// second, and XdfRecorder::close() writes the stream footers.
+use crate::muse_model::{participant_label, MuseMessage, MuseMessageSink, MuseMessageType};
+use crate::recorder::{sha256, RecordedFile};
+use crate::session_event::SessionEvent;
+use chrono::{DateTime, Local};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const MAGIC: &[u8] = b"XDF:";
+const FILE_HEADER: u16 = 1;
+const STREAM_HEADER: u16 = 2;
+const SAMPLES: u16 = 3;
+const CLOCK_OFFSET: u16 = 4;
+const STREAM_FOOTER: u16 = 6;
+const CHUNK_INTERVAL: Duration = Duration::from_secs(1); // Pending samples are written this often
+const CLOCK_OFFSET_INTERVAL: f64 = 5.0; // Seconds between ClockOffset chunks of each stream
+const SOURCE: &str = "meme"; // Source of the marker stream
+
+/// Each stream recorded for a headset, participant or the session
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum StreamKind {
+    Eeg,
+    Alpha,
+    Beta,
+    Gamma,
+    Delta,
+    Theta,
+    Accelerometer,
+    Gyro,
+    Affect,
+    Markers,
+}
+
+impl StreamKind {
+    fn name(self) -> &'static str {
+        match self {
+            StreamKind::Eeg => "EEG",
+            StreamKind::Alpha => "Alpha",
+            StreamKind::Beta => "Beta",
+            StreamKind::Gamma => "Gamma",
+            StreamKind::Delta => "Delta",
+            StreamKind::Theta => "Theta",
+            StreamKind::Accelerometer => "Accelerometer",
+            StreamKind::Gyro => "Gyro",
+            StreamKind::Affect => "Affect",
+            StreamKind::Markers => "Markers",
+        }
+    }
+
+    /// Stream type, channel labels, unit and nominal sample rate, 0 for irregular
+    fn description(self) -> (&'static str, &'static [&'static str], &'static str, f32) {
+        const ELECTRODES: &[&str] = &["TP9", "AF7", "AF8", "TP10"];
+        const AXES: &[&str] = &["X", "Y", "Z"];
+        match self {
+            StreamKind::Eeg => ("EEG", ELECTRODES, "microvolts", 256.0),
+            StreamKind::Alpha
+            | StreamKind::Beta
+            | StreamKind::Gamma
+            | StreamKind::Delta
+            | StreamKind::Theta => ("BandPower", ELECTRODES, "microvolts", 10.0),
+            StreamKind::Accelerometer => ("Accelerometer", AXES, "g", 52.0),
+            StreamKind::Gyro => ("Gyroscope", AXES, "degrees per second", 52.0),
+            StreamKind::Affect => ("Affect", &["Valence", "Arousal"], "normalized", 0.0),
+            StreamKind::Markers => ("Markers", &["Event"], "", 0.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Values {
+    Float(Vec<f32>),
+    Text(String),
+}
+
+enum Command {
+    Sample {
+        kind: StreamKind,
+        source: String, // Headset address or participant label
+        timestamp: f64,
+        offset: f64, // Add to the timestamp for this machine's clock
+        values: Values,
+    },
+    Close(Sender<Result<RecordedFile, String>>),
+}
+
+/// Writes the XDF file on its own thread until closed
+pub struct XdfRecorder {
+    sender: Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl XdfRecorder {
+    /// Create the file now so a problem is reported at startup, then record in the background
+    pub fn start(path: &Path) -> Result<Self, String> {
+        let mut file = XdfFile::create(path)
+            .map_err(|e| format!("Can not create {}: {}", path.display(), e))?;
+        let (sender, receiver) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            let mut last_write = Instant::now();
+            loop {
+                match receiver.recv_timeout(CHUNK_INTERVAL) {
+                    Ok(Command::Sample {
+                        kind,
+                        source,
+                        timestamp,
+                        offset,
+                        values,
+                    }) => file.sample(kind, &source, timestamp, offset, values),
+                    Ok(Command::Close(reply)) => {
+                        let _ = reply.send(file.close());
+                        break;
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        if let Err(e) = file.close() {
+                            error!("{}", e);
+                        }
+                        break;
+                    }
+                }
+                // Samples arrive faster than the timeout, so write on time rather than when they pause
+                if last_write.elapsed() >= CHUNK_INTERVAL {
+                    file.write_pending();
+                    last_write = Instant::now();
+                }
+            }
+        });
+
+        Ok(XdfRecorder {
+            sender,
+            thread: Some(thread),
+        })
+    }
+
+    /// Records the messages, valence, arousal and events sent to it as a MuseMessageSink
+    pub fn sink(&self) -> XdfSink {
+        XdfSink {
+            sender: self.sender.clone(),
+        }
+    }
+
+    /// Write everything pending and the stream footers. Later samples are ignored.
+    pub fn close(mut self) -> Result<RecordedFile, String> {
+        let (reply, result) = mpsc::channel();
+        self.sender
+            .send(Command::Close(reply))
+            .map_err(|_| "XDF recorder has stopped".to_string())?;
+        let result = result
+            .recv()
+            .unwrap_or_else(|_| Err("XDF recorder has stopped".to_string()));
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        result
+    }
+}
+
+impl Drop for XdfRecorder {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let (reply, _result) = mpsc::channel();
+            let _ = self.sender.send(Command::Close(reply));
+            if thread.join().is_err() {
+                error!("XDF recorder thread panicked, the file may be incomplete");
+            }
+        }
+    }
+}
+
+/// Sends samples to the XDF recorder thread
+#[derive(Clone)]
+pub struct XdfSink {
+    sender: Sender<Command>,
+}
+
+impl XdfSink {
+    fn send(&self, kind: StreamKind, source: String, timestamp: f64, offset: f64, values: Values) {
+        let _ = self.sender.send(Command::Sample {
+            kind,
+            source,
+            timestamp,
+            offset,
+            values,
+        }); // After close() there is nowhere to write
+    }
+}
+
+impl MuseMessageSink for XdfSink {
+    fn send_message(&self, muse_message: &MuseMessage) {
+        let (kind, values) = match &muse_message.muse_message_type {
+            MuseMessageType::Eeg { eeg } => (StreamKind::Eeg, eeg.to_vec()),
+            MuseMessageType::Alpha { alpha } => (StreamKind::Alpha, alpha.to_vec()),
+            MuseMessageType::Beta { beta } => (StreamKind::Beta, beta.to_vec()),
+            MuseMessageType::Gamma { gamma } => (StreamKind::Gamma, gamma.to_vec()),
+            MuseMessageType::Delta { a, b, c, d } => (StreamKind::Delta, vec![*a, *b, *c, *d]),
+            MuseMessageType::Theta { a, b, c, d } => (StreamKind::Theta, vec![*a, *b, *c, *d]),
+            MuseMessageType::Accelerometer { x, y, z } => {
+                (StreamKind::Accelerometer, vec![*x, *y, *z])
+            }
+            MuseMessageType::Gyro { x, y, z } => (StreamKind::Gyro, vec![*x, *y, *z]),
+            _ => return,
+        };
+        let local = seconds(muse_message.message_time);
+        let (timestamp, offset) = match muse_message.timetag {
+            Some(timetag) if !timetag.is_immediate() => {
+                let sent = seconds(timetag.to_date_time());
+                (sent, local - sent)
+            }
+            _ => (local, 0.0),
+        };
+        let source = muse_message.ip_address.ip().to_string();
+
+        self.send(kind, source, timestamp, offset, Values::Float(values));
+    }
+
+    fn send_affect(
+        &self,
+        participant: usize,
+        time: DateTime<Local>,
+        valence: Option<f32>,
+        arousal: Option<f32>,
+    ) {
+        let values = vec![valence.unwrap_or(f32::NAN), arousal.unwrap_or(f32::NAN)];
+        let source = participant_label(participant);
+
+        self.send(
+            StreamKind::Affect,
+            source,
+            seconds(time),
+            0.0,
+            Values::Float(values),
+        );
+    }
+
+    fn send_event(&self, time: DateTime<Local>, event: &SessionEvent) {
+        let values = Values::Text(event.to_string());
+
+        self.send(
+            StreamKind::Markers,
+            SOURCE.to_string(),
+            seconds(time),
+            0.0,
+            values,
+        );
+    }
+}
+
+/// One stream's header values and the samples not yet written
+struct Stream {
+    kind: StreamKind,
+    source: String,
+    pending: Vec<(f64, Values)>,
+    sample_count: u64,
+    first_timestamp: Option<f64>,
+    last_timestamp: f64,
+    last_clock_offset: Option<f64>, // Local time of the most recent ClockOffset chunk
+}
+
+/// The open file, on the recorder thread
+struct XdfFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    streams: Vec<Stream>,   // Stream ID is the index + 1
+    failed: Option<String>, // After the first error nothing more is written
+}
+
+impl XdfFile {
+    fn create(path: &Path) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        let header = format!(
+            "<?xml version=\"1.0\"?><info><version>1.0</version><datetime>{}</datetime></info>",
+            Local::now().to_rfc3339()
+        );
+        write_chunk(&mut writer, FILE_HEADER, header.as_bytes())?;
+
+        Ok(XdfFile {
+            path: path.to_path_buf(),
+            writer,
+            streams: Vec::new(),
+            failed: None,
+        })
+    }
+
+    fn sample(
+        &mut self,
+        kind: StreamKind,
+        source: &str,
+        timestamp: f64,
+        offset: f64,
+        values: Values,
+    ) {
+        if self.failed.is_some() {
+            return;
+        }
+        let result = self.add_sample(kind, source, timestamp, offset, values);
+        self.check(result);
+    }
+
+    fn add_sample(
+        &mut self,
+        kind: StreamKind,
+        source: &str,
+        timestamp: f64,
+        offset: f64,
+        values: Values,
+    ) -> io::Result<()> {
+        let index = match self
+            .streams
+            .iter()
+            .position(|stream| stream.kind == kind && stream.source == source)
+        {
+            Some(index) => index,
+            None => {
+                let stream = Stream {
+                    kind,
+                    source: source.to_string(),
+                    pending: Vec::new(),
+                    sample_count: 0,
+                    first_timestamp: None,
+                    last_timestamp: timestamp,
+                    last_clock_offset: None,
+                };
+                let mut content = stream_id(self.streams.len()).to_vec();
+                content.extend(stream_header(&stream, timestamp).as_bytes());
+                write_chunk(&mut self.writer, STREAM_HEADER, &content)?;
+                self.streams.push(stream);
+                self.streams.len() - 1
+            }
+        };
+        let local = timestamp + offset;
+        let stream = &mut self.streams[index];
+        let is_offset_due = match stream.last_clock_offset {
+            Some(last) => local - last >= CLOCK_OFFSET_INTERVAL,
+            None => true,
+        };
+        if is_offset_due {
+            let mut content = stream_id(index).to_vec();
+            content.extend(&local.to_le_bytes());
+            content.extend(&offset.to_le_bytes());
+            write_chunk(&mut self.writer, CLOCK_OFFSET, &content)?;
+            stream.last_clock_offset = Some(local);
+        }
+        stream.first_timestamp = stream.first_timestamp.or(Some(timestamp));
+        stream.last_timestamp = timestamp;
+        stream.sample_count += 1;
+        stream.pending.push((timestamp, values));
+
+        Ok(())
+    }
+
+    /// One Samples chunk for each stream with samples waiting
+    fn write_pending(&mut self) {
+        if self.failed.is_some() {
+            return;
+        }
+        let result = self.write_samples();
+        self.check(result);
+    }
+
+    fn write_samples(&mut self) -> io::Result<()> {
+        for (index, stream) in self.streams.iter_mut().enumerate() {
+            if stream.pending.is_empty() {
+                continue;
+            }
+            let mut content = stream_id(index).to_vec();
+            content.extend(varlen(stream.pending.len() as u64));
+            for (timestamp, values) in stream.pending.drain(..) {
+                content.push(8);
+                content.extend(&timestamp.to_le_bytes());
+                match values {
+                    Values::Float(values) => {
+                        for value in values {
+                            content.extend(&value.to_le_bytes());
+                        }
+                    }
+                    Values::Text(text) => {
+                        content.extend(varlen(text.len() as u64));
+                        content.extend(text.as_bytes());
+                    }
+                }
+            }
+            write_chunk(&mut self.writer, SAMPLES, &content)?;
+        }
+
+        self.writer.flush()
+    }
+
+    /// Pending samples and a footer for each stream
+    fn close(&mut self) -> Result<RecordedFile, String> {
+        self.write_pending();
+        if self.failed.is_none() {
+            let result = self.write_footers();
+            self.check(result);
+        }
+        if let Some(e) = &self.failed {
+            return Err(e.clone());
+        }
+
+        Ok(RecordedFile {
+            name: self
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            rows: self.streams.iter().map(|stream| stream.sample_count).sum(),
+            sha256: sha256(&self.path).ok(),
+        })
+    }
+
+    fn write_footers(&mut self) -> io::Result<()> {
+        for (index, stream) in self.streams.iter().enumerate() {
+            let mut content = stream_id(index).to_vec();
+            content.extend(stream_footer(stream).as_bytes());
+            write_chunk(&mut self.writer, STREAM_FOOTER, &content)?;
+        }
+
+        self.writer.flush()
+    }
+
+    /// Report the first error, after which nothing more is written
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            if self.failed.is_none() {
+                let e = format!("Can not write {}: {}", self.path.display(), e);
+                error!("{}", e);
+                self.failed = Some(e);
+            }
+        }
+    }
+}
+
+fn seconds(time: DateTime<Local>) -> f64 {
+    time.timestamp() as f64 + time.timestamp_subsec_micros() as f64 / 1_000_000.0
+}
+
+/// Stream IDs start at 1
+fn stream_id(index: usize) -> [u8; 4] {
+    (index as u32 + 1).to_le_bytes()
+}
+
+/// Variable length integer: the number of bytes (1, 4 or 8), then the value
+fn varlen(value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if value <= u8::MAX as u64 {
+        bytes.push(1);
+        bytes.push(value as u8);
+    } else if value <= u32::MAX as u64 {
+        bytes.push(4);
+        bytes.extend(&(value as u32).to_le_bytes());
+    } else {
+        bytes.push(8);
+        bytes.extend(&value.to_le_bytes());
+    }
+
+    bytes
+}
+
+/// Length (which counts the tag), tag, then content
+fn write_chunk(writer: &mut impl Write, tag: u16, content: &[u8]) -> io::Result<()> {
+    writer.write_all(&varlen(content.len() as u64 + 2))?;
+    writer.write_all(&tag.to_le_bytes())?;
+    writer.write_all(content)
+}
+
+fn stream_header(stream: &Stream, created_at: f64) -> String {
+    let (stream_type, channels, unit, nominal_srate) = stream.kind.description();
+    let channel_count = channels.len();
+    let channel_format = match stream.kind {
+        StreamKind::Markers => "string",
+        _ => "float32",
+    };
+    let channels: String = channels
+        .iter()
+        .map(|label| {
+            format!(
+                "<channel><label>{}</label><unit>{}</unit><type>{}</type></channel>",
+                label, unit, stream_type
+            )
+        })
+        .collect();
+
+    format!(
+        "<?xml version=\"1.0\"?><info><name>{} {}</name><type>{}</type><channel_count>{}</channel_count>\
+         <nominal_srate>{}</nominal_srate><channel_format>{}</channel_format><source_id>{} {}</source_id>\
+         <version>1.0</version><created_at>{}</created_at><hostname>{}</hostname>\
+         <desc><channels>{}</channels></desc></info>",
+        stream.source,
+        stream.kind.name(),
+        stream_type,
+        channel_count,
+        nominal_srate,
+        channel_format,
+        stream.source,
+        stream.kind.name(),
+        created_at,
+        stream.source,
+        channels
+    )
+}
+
+fn stream_footer(stream: &Stream) -> String {
+    format!(
+        "<?xml version=\"1.0\"?><info><first_timestamp>{}</first_timestamp>\
+         <last_timestamp>{}</last_timestamp><sample_count>{}</sample_count></info>",
+        stream.first_timestamp.unwrap_or(stream.last_timestamp),
+        stream.last_timestamp,
+        stream.sample_count
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock_offset::OscTimetag;
+    use chrono::TimeZone;
+    use std::convert::TryInto;
+    use std::fs;
+
+    /// (tag, content) of each chunk after the magic
+    fn chunks(bytes: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut position = MAGIC.len();
+        while position < bytes.len() {
+            let length_bytes = bytes[position] as usize;
+            let mut length = [0; 8];
+            length[..length_bytes]
+                .copy_from_slice(&bytes[position + 1..position + 1 + length_bytes]);
+            let length = u64::from_le_bytes(length) as usize;
+            position += 1 + length_bytes;
+            let tag = u16::from_le_bytes([bytes[position], bytes[position + 1]]);
+            chunks.push((tag, bytes[position + 2..position + length].to_vec()));
+            position += length;
+        }
+
+        chunks
+    }
+
+    #[test]
+    fn test_varlen() {
+        assert_eq!(vec![1, 200], varlen(200));
+        assert_eq!(vec![4, 0, 1, 0, 0], varlen(256));
+    }
+
+    #[test]
+    fn test_record_streams() {
+        let path = std::env::temp_dir().join("meme xdf test.xdf");
+        let recorder = XdfRecorder::start(&path).unwrap();
+        let sink = recorder.sink();
+        let sent = Local.timestamp(1_582_616_149, 0);
+        let timetag = OscTimetag::new(1_582_616_149 + 2_208_988_800, 0);
+        for i in 0..3 {
+            sink.send_message(&MuseMessage {
+                message_time: sent + chrono::Duration::milliseconds(250),
+                receive_time: sent + chrono::Duration::milliseconds(300),
+                timetag: Some(timetag),
+                ip_address: "192.168.1.20:5000".parse().unwrap(),
+                muse_message_type: MuseMessageType::Eeg {
+                    eeg: [800.0 + i as f32; 4],
+                },
+            });
+        }
+        sink.send_affect(0, sent, Some(0.5), None);
+        sink.send_event(
+            sent,
+            &SessionEvent::StageStart {
+                stage: "NEGATIVE_B",
+            },
+        );
+        let file = recorder.close().unwrap();
+        assert_eq!("meme xdf test.xdf", file.name);
+        assert_eq!(5, file.rows);
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(MAGIC, &bytes[..4]);
+        let chunks = chunks(&bytes);
+        let tags: Vec<u16> = chunks.iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(vec![1, 2, 4, 2, 4, 2, 4, 3, 3, 3, 6, 6, 6], tags);
+
+        let eeg_header = String::from_utf8_lossy(&chunks[1].1[4..]).to_string();
+        assert!(eeg_header.contains("<name>192.168.1.20 EEG</name>"));
+        assert!(eeg_header.contains("<nominal_srate>256</nominal_srate>"));
+        let offset = f64::from_le_bytes(chunks[2].1[12..20].try_into().unwrap());
+        assert!((offset - 0.25).abs() < 1e-6);
+
+        let eeg_samples = &chunks[7].1;
+        assert_eq!(&[1, 0, 0, 0, 1, 3, 8], &eeg_samples[..7]);
+        let timestamp = f64::from_le_bytes(eeg_samples[7..15].try_into().unwrap());
+        assert_eq!(1_582_616_149.0, timestamp);
+        let markers = &chunks[9].1;
+        assert_eq!(b"StageStart:NEGATIVE_B", &markers[17..]);
+
+        fs::remove_file(path).unwrap();
+    }
+}